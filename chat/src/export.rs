@@ -0,0 +1,337 @@
+//! Renders entries as standalone documents, for attaching a discussion to
+//! something outside of nerdtalk.
+
+use std::{fmt, fmt::Write, str::FromStr};
+
+use crate::{
+    markdown::{self, Block, Inline},
+    Attachment, Content, Entry, EntryKind,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON-encoded [`Entry`] per line, for feeding into other tools.
+    JsonLines,
+    Markdown,
+    /// A single HTML page with its styles inlined.
+    Html,
+    PlainText,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [
+        Format::JsonLines,
+        Format::Markdown,
+        Format::Html,
+        Format::PlainText,
+    ];
+
+    /// The usual file extension, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::JsonLines => "jsonl",
+            Format::Markdown => "md",
+            Format::Html => "html",
+            Format::PlainText => "txt",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownFormat(pub String);
+
+impl fmt::Display for UnknownFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown export format {:?}, expected one of jsonl, md, html, or txt",
+            self.0
+        )
+    }
+}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "md" | "markdown" => Ok(Format::Markdown),
+            "html" => Ok(Format::Html),
+            "txt" | "text" | "plain" => Ok(Format::PlainText),
+            _ => Err(UnknownFormat(name.to_owned())),
+        }
+    }
+}
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+fn marker(entry: &Entry) -> Option<&'static str> {
+    match entry.content {
+        Content::Original(_) => None,
+        Content::Edited(_) => Some("edited"),
+        Content::Deleted => Some("deleted"),
+    }
+}
+
+/// What goes between the author and the text, e.g. `alice: hi` but `alice
+/// waves`.
+fn separator(entry: &Entry) -> &'static str {
+    match entry.kind {
+        EntryKind::Message => ": ",
+        EntryKind::Action | EntryKind::Notice => " ",
+    }
+}
+
+fn kind_marker(entry: &Entry) -> Option<&'static str> {
+    match entry.kind {
+        EntryKind::Message => None,
+        EntryKind::Action => Some("action"),
+        EntryKind::Notice => Some("notice"),
+    }
+}
+
+fn describe_attachment(attachment: &Attachment) -> String {
+    format!("{} ({} bytes)", attachment.file_name, attachment.size)
+}
+
+/// Renders `entries`, oldest first, as a document titled `title`.
+pub fn export(entries: &[Entry], format: Format, title: &str) -> String {
+    match format {
+        Format::JsonLines => json_lines(entries),
+        Format::Markdown => markdown(entries, title),
+        Format::Html => html(entries, title),
+        Format::PlainText => plain_text(entries),
+    }
+}
+
+fn json_lines(entries: &[Entry]) -> String {
+    let mut output = String::new();
+    for entry in entries {
+        output.push_str(
+            &serde_json::to_string(entry).expect("entries serialize"),
+        );
+        output.push('\n');
+    }
+    output
+}
+
+fn plain_text(entries: &[Entry]) -> String {
+    let mut output = String::new();
+    for entry in entries {
+        let text = match entry.text_content() {
+            Some(text) => markdown::parse(text).plain_text(),
+            None => "(deleted)".to_owned(),
+        };
+        // Indent continuation lines so each entry stays visually one unit.
+        let text = text.replace('\n', "\n    ");
+        let _ = write!(
+            output,
+            "[{}] {}{}{}",
+            entry.metadata.timestamp.format(TIMESTAMP_FORMAT),
+            entry.metadata.username,
+            separator(entry),
+            text
+        );
+        if let Content::Edited(_) = entry.content {
+            output.push_str(" (edited)");
+        }
+        for attachment in &entry.attachments {
+            let _ = write!(
+                output,
+                " [attachment: {}]",
+                describe_attachment(attachment)
+            );
+        }
+        output.push('\n');
+    }
+    output
+}
+
+fn markdown(entries: &[Entry], title: &str) -> String {
+    let mut output = format!("# {}\n", title);
+    for entry in entries {
+        let _ = write!(
+            output,
+            "\n**{}** · {} · #{}",
+            entry.metadata.username,
+            entry.metadata.timestamp.format(TIMESTAMP_FORMAT),
+            entry.slot_number
+        );
+        for marker in kind_marker(entry).into_iter().chain(marker(entry)) {
+            let _ = write!(output, " · {}", marker);
+        }
+        output.push_str("\n\n");
+        match entry.text_content() {
+            // Messages are already markdown.
+            Some(text) => {
+                output.push_str(text.trim_end());
+                output.push('\n');
+            }
+            None => output.push_str("*(deleted)*\n"),
+        }
+        if !entry.attachments.is_empty() {
+            output.push('\n');
+            for attachment in &entry.attachments {
+                let _ = writeln!(
+                    output,
+                    "- Attachment: {}",
+                    describe_attachment(attachment)
+                );
+            }
+        }
+    }
+    output
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Only links that can't run code in the exported page are kept as links.
+fn is_safe_url(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    ["https://", "http://", "mailto:"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+}
+
+fn html_inlines(output: &mut String, inlines: &[Inline]) {
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) => {
+                output.push_str(&escape_html(text).replace('\n', "<br>\n"))
+            }
+            Inline::Bold(inlines) => {
+                output.push_str("<strong>");
+                html_inlines(output, inlines);
+                output.push_str("</strong>");
+            }
+            Inline::Italic(inlines) => {
+                output.push_str("<em>");
+                html_inlines(output, inlines);
+                output.push_str("</em>");
+            }
+            Inline::Code(code) => {
+                let _ = write!(output, "<code>{}</code>", escape_html(code));
+            }
+            Inline::Link { text, url } if is_safe_url(url) => {
+                let _ = write!(output, "<a href=\"{}\">", escape_html(url));
+                html_inlines(output, text);
+                output.push_str("</a>");
+            }
+            Inline::Link { text, .. } => html_inlines(output, text),
+            Inline::Mention(name) => {
+                let _ = write!(
+                    output,
+                    "<span class=\"mention\">@{}</span>",
+                    escape_html(name)
+                );
+            }
+        }
+    }
+}
+
+fn html_blocks(output: &mut String, blocks: &[Block]) {
+    for block in blocks {
+        match block {
+            Block::Paragraph(inlines) => {
+                output.push_str("<p>");
+                html_inlines(output, inlines);
+                output.push_str("</p>\n");
+            }
+            Block::CodeBlock { language, code } => {
+                match language {
+                    Some(language) => {
+                        let _ = write!(
+                            output,
+                            "<pre><code class=\"language-{}\">",
+                            escape_html(language)
+                        );
+                    }
+                    None => output.push_str("<pre><code>"),
+                }
+                output.push_str(&escape_html(code));
+                output.push_str("</code></pre>\n");
+            }
+            Block::Quote(blocks) => {
+                output.push_str("<blockquote>\n");
+                html_blocks(output, blocks);
+                output.push_str("</blockquote>\n");
+            }
+        }
+    }
+}
+
+const HTML_STYLE: &str = "\
+body { font-family: sans-serif; max-width: 48em; margin: 2em auto; }
+.entry { border-bottom: 1px solid #ddd; padding: 0.5em 0; }
+.author { font-weight: bold; }
+time, .marker, .slot { color: #777; font-size: 0.9em; }
+.action, .notice { font-style: italic; }
+.deleted { color: #777; font-style: italic; }
+.mention { color: #a0a; font-weight: bold; }
+pre { background: #f4f4f4; padding: 0.5em; overflow-x: auto; }
+blockquote { border-left: 3px solid #ccc; margin: 0; padding-left: 1em; }
+";
+
+fn html(entries: &[Entry], title: &str) -> String {
+    let title = escape_html(title);
+    let mut output = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta \
+         charset=\"utf-8\">\n<title>{title}</title>\n<style>\n{HTML_STYLE}</\
+         style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    for entry in entries {
+        let _ = write!(
+            output,
+            "<article class=\"entry{kind}\" id=\"slot-{slot}\">\n<header><span \
+             class=\"author\">{author}</span> <time \
+             datetime=\"{datetime}\">{time}</time> <span \
+             class=\"slot\">#{slot}</span>",
+            slot = entry.slot_number,
+            kind = kind_marker(entry)
+                .map(|kind| format!(" {}", kind))
+                .unwrap_or_default(),
+            author = escape_html(&entry.metadata.username),
+            datetime = entry.metadata.timestamp.to_rfc3339(),
+            time = entry.metadata.timestamp.format(TIMESTAMP_FORMAT),
+        );
+        if let Some(marker) = marker(entry) {
+            let _ =
+                write!(output, " <span class=\"marker\">({})</span>", marker);
+        }
+        output.push_str("</header>\n");
+        match entry.text_content() {
+            Some(text) => {
+                html_blocks(&mut output, &markdown::parse(text).blocks)
+            }
+            None => output.push_str("<p class=\"deleted\">(deleted)</p>\n"),
+        }
+        if !entry.attachments.is_empty() {
+            output.push_str("<ul class=\"attachments\">\n");
+            for attachment in &entry.attachments {
+                let _ = writeln!(
+                    output,
+                    "<li>{}</li>",
+                    escape_html(&describe_attachment(attachment))
+                );
+            }
+            output.push_str("</ul>\n");
+        }
+        output.push_str("</article>\n");
+    }
+    output.push_str("</body>\n</html>\n");
+    output
+}
@@ -1,42 +1,162 @@
+use std::{fmt, io};
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub mod export;
+pub mod markdown;
+pub mod timeline;
+
+/// A named, independently numbered chat log.
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct ChannelName(pub String);
+
+impl Default for ChannelName {
+    fn default() -> Self {
+        Self("general".to_owned())
+    }
+}
+
+impl fmt::Display for ChannelName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Metadata {
+    /// Qualified as `user@server` if the entry was relayed from another
+    /// server.
     pub username: String,
     pub timestamp: DateTime<Utc>,
+    /// Where the entry was posted, if that server is federated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Origin>,
+}
+
+/// The federated server an entry was posted on, and its place in the order
+/// entries were posted there, which unlike its slot never changes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Origin {
+    pub server: String,
+    pub slot_number: usize,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MessageText(pub String);
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl MessageText {
+    /// Parses the message as [`markdown`].
+    pub fn parse(&self) -> markdown::Document {
+        markdown::parse(&self.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Content {
     Original(MessageText),
     Edited(MessageText),
     Deleted,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Content address of an attachment: the lowercase hex SHA-256 digest of its
+/// bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AttachmentId(pub String);
+
+impl AttachmentId {
+    pub fn from_contents(bytes: &[u8]) -> Self {
+        Self::from_digest(Sha256::digest(bytes).as_slice())
+    }
+
+    /// Like [`AttachmentId::from_contents`], but reading the contents from
+    /// `reader` a piece at a time.
+    pub fn from_reader(mut reader: impl io::Read) -> io::Result<Self> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut reader, &mut hasher)?;
+        Ok(Self::from_digest(hasher.finalize().as_slice()))
+    }
+
+    fn from_digest(digest: &[u8]) -> Self {
+        Self(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Whether this could have been produced by
+    /// [`AttachmentId::from_contents`], i.e., whether it is safe to use as a
+    /// file name.
+    pub fn is_well_formed(&self) -> bool {
+        self.0.len() == 64
+            && self
+                .0
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    }
+}
+
+impl fmt::Display for AttachmentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A file stored on the server that an [`Entry`] refers to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: AttachmentId,
+    pub file_name: String,
+    pub size: u64,
+}
+
+/// How an entry should be presented.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum EntryKind {
+    #[default]
+    Message,
+    /// Something the author did, e.g. from `/me waves`, shown as "alice
+    /// waves".
+    Action,
+    /// Something the server announces about the author, like a topic change,
+    /// shown after their name like an action.
+    Notice,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Entry {
+    /// Slot numbers count up from zero separately in each channel.
+    #[serde(default)]
+    pub channel: ChannelName,
     pub slot_number: usize,
     pub metadata: Metadata,
     pub content: Content,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub kind: EntryKind,
 }
 
 impl Entry {
     pub fn new_timestamped_now(
+        channel: ChannelName,
         slot_number: usize,
         username: String,
         content: Content,
     ) -> Self {
         Self {
+            channel,
             slot_number,
             metadata: Metadata {
                 username,
                 timestamp: Utc::now(),
+                origin: None,
             },
             content,
+            attachments: vec![],
+            kind: EntryKind::Message,
         }
     }
 
@@ -0,0 +1,369 @@
+//! The markdown subset chat messages are written in, parsed into an AST so
+//! every client renders a message the same way.
+//!
+//! Supported syntax:
+//! - `**bold**`, `*italic*` or `_italic_`, and `` `inline code` ``
+//! - `[text](url)` links and `@name` mentions
+//! - fenced code blocks with an optional language tag
+//! - `>` quotes, which may contain any other block
+//! - `\` escapes the next punctuation character
+//!
+//! Anything that doesn't parse as markup, like an unclosed `**`, is kept as
+//! plain text, so parsing never fails.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Document {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Block {
+    /// Consecutive non-blank lines. Line breaks inside the paragraph are kept
+    /// as `"\n"` in the text.
+    Paragraph(Vec<Inline>),
+    CodeBlock {
+        language: Option<String>,
+        code: String,
+    },
+    Quote(Vec<Block>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Inline {
+    Text(String),
+    Bold(Vec<Inline>),
+    Italic(Vec<Inline>),
+    Code(String),
+    Link {
+        text: Vec<Inline>,
+        url: String,
+    },
+    /// `@name`, stored without the `@`.
+    Mention(String),
+}
+
+const FENCE: &str = "```";
+
+/// Parses `text` into a [`Document`].
+pub fn parse(text: &str) -> Document {
+    let lines = text.lines().collect::<Vec<_>>();
+    Document {
+        blocks: parse_blocks(&lines),
+    }
+}
+
+fn quote_line(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix('>')?;
+    Some(rest.strip_prefix(' ').unwrap_or(rest))
+}
+
+fn parse_blocks(lines: &[&str]) -> Vec<Block> {
+    let mut blocks = vec![];
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        if line.trim().is_empty() {
+            index += 1;
+        } else if let Some(info) = line.trim_start().strip_prefix(FENCE) {
+            let language = Some(info.trim())
+                .filter(|language| !language.is_empty())
+                .map(str::to_owned);
+            let body_start = index + 1;
+            let mut body_end = body_start;
+            while body_end < lines.len() && lines[body_end].trim() != FENCE {
+                body_end += 1;
+            }
+            blocks.push(Block::CodeBlock {
+                language,
+                code: lines[body_start..body_end].join("\n"),
+            });
+            // Skip the closing fence, if there is one.
+            index = body_end + 1;
+        } else if quote_line(line).is_some() {
+            let mut quoted = vec![];
+            while let Some(inner) =
+                lines.get(index).and_then(|line| quote_line(line))
+            {
+                quoted.push(inner);
+                index += 1;
+            }
+            blocks.push(Block::Quote(parse_blocks(&quoted)));
+        } else {
+            let start = index;
+            while index < lines.len()
+                && !lines[index].trim().is_empty()
+                && !lines[index].trim_start().starts_with(FENCE)
+                && quote_line(lines[index]).is_none()
+            {
+                index += 1;
+            }
+            blocks.push(Block::Paragraph(parse_inlines(
+                &lines[start..index].join("\n"),
+            )));
+        }
+    }
+    blocks
+}
+
+fn is_escapable(c: char) -> bool {
+    c.is_ascii_punctuation()
+}
+
+fn is_mention_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Byte offset of the first unescaped `delimiter` in `text` at or after
+/// `from`, skipping over inline code spans. For a single `*`, occurrences
+/// that are part of a `**` are skipped too.
+fn find_closing(text: &str, from: usize, delimiter: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut index = from;
+    while index < text.len() {
+        if bytes[index] == b'\\' {
+            // Skip the backslash and whatever it escapes.
+            index +=
+                1 + text[index + 1..].chars().next().map_or(0, char::len_utf8);
+            continue;
+        }
+        if bytes[index] == b'`' && delimiter != "`" {
+            if let Some(end) = text[index + 1..].find('`') {
+                index += end + 2;
+                continue;
+            }
+        }
+        if text[index..].starts_with(delimiter) {
+            if delimiter == "*" && text[index..].starts_with("**") {
+                index += 2;
+                continue;
+            }
+            // In `***`, the last two close a `**` that contains a `*...*`.
+            if delimiter == "**" && text[index..].starts_with("***") {
+                index += 1;
+                continue;
+            }
+            return Some(index);
+        }
+        index += text[index..].chars().next().map_or(1, char::len_utf8);
+    }
+    None
+}
+
+struct InlineParser<'a> {
+    text: &'a str,
+    position: usize,
+    inlines: Vec<Inline>,
+    pending_text: String,
+}
+
+impl<'a> InlineParser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            position: 0,
+            inlines: vec![],
+            pending_text: String::new(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn previous_char(&self) -> Option<char> {
+        self.text[..self.position].chars().next_back()
+    }
+
+    fn push(&mut self, inline: Inline) {
+        self.flush_text();
+        self.inlines.push(inline);
+    }
+
+    fn flush_text(&mut self) {
+        if !self.pending_text.is_empty() {
+            self.inlines
+                .push(Inline::Text(std::mem::take(&mut self.pending_text)));
+        }
+    }
+
+    /// Parses a `delimiter`-wrapped span starting at the current position,
+    /// returning the text between the delimiters.
+    fn delimited(&mut self, delimiter: &str) -> Option<&'a str> {
+        let content_start = self.position + delimiter.len();
+        let content_end = find_closing(self.text, content_start, delimiter)?;
+        let content = &self.text[content_start..content_end];
+        if content.is_empty() || content.starts_with(char::is_whitespace) {
+            return None;
+        }
+        self.position = content_end + delimiter.len();
+        Some(content)
+    }
+
+    fn link(&mut self) -> Option<Inline> {
+        let label_end = find_closing(self.text, self.position + 1, "]")?;
+        let url_start = label_end + 1;
+        if !self.text[url_start..].starts_with('(') {
+            return None;
+        }
+        let url_end = url_start + self.text[url_start..].find(')')?;
+        let label = &self.text[self.position + 1..label_end];
+        let url = &self.text[url_start + 1..url_end];
+        if url.is_empty() || url.contains(char::is_whitespace) {
+            return None;
+        }
+        self.position = url_end + 1;
+        Some(Inline::Link {
+            text: parse_inlines(label),
+            url: url.to_owned(),
+        })
+    }
+
+    fn mention(&mut self) -> Option<Inline> {
+        if self.previous_char().is_some_and(char::is_alphanumeric) {
+            return None;
+        }
+        let name_length = self.rest()[1..]
+            .find(|c| !is_mention_char(c))
+            .unwrap_or(self.rest().len() - 1);
+        // A trailing `.` ends the sentence rather than the name.
+        let name = self.rest()[1..1 + name_length].trim_end_matches('.');
+        if name.is_empty() {
+            return None;
+        }
+        self.position += 1 + name.len();
+        Some(Inline::Mention(name.to_owned()))
+    }
+
+    fn parse(mut self) -> Vec<Inline> {
+        while let Some(c) = self.rest().chars().next() {
+            let start = self.position;
+            let parsed = match c {
+                '\\' => match self.rest()[1..].chars().next() {
+                    Some(escaped) if is_escapable(escaped) => {
+                        self.pending_text.push(escaped);
+                        self.position += 1 + escaped.len_utf8();
+                        continue;
+                    }
+                    _ => None,
+                },
+                '`' => self
+                    .delimited("`")
+                    .map(|code| Inline::Code(code.to_owned())),
+                '*' if self.rest().starts_with("**") => self
+                    .delimited("**")
+                    .map(|content| Inline::Bold(parse_inlines(content))),
+                '*' => self
+                    .delimited("*")
+                    .map(|content| Inline::Italic(parse_inlines(content))),
+                // Underscores inside words, like in `snake_case`, are not
+                // emphasis.
+                '_' if !self
+                    .previous_char()
+                    .is_some_and(char::is_alphanumeric) =>
+                {
+                    self.delimited("_")
+                        .map(|content| Inline::Italic(parse_inlines(content)))
+                }
+                '[' => self.link(),
+                '@' => self.mention(),
+                _ => None,
+            };
+            match parsed {
+                Some(inline) => self.push(inline),
+                None => {
+                    self.position = start + c.len_utf8();
+                    self.pending_text.push(c);
+                }
+            }
+        }
+        self.flush_text();
+        self.inlines
+    }
+}
+
+/// Parses a single paragraph's worth of inline markup.
+pub fn parse_inlines(text: &str) -> Vec<Inline> {
+    InlineParser::new(text).parse()
+}
+
+impl Document {
+    /// The text a reader would see, without any markup.
+    pub fn plain_text(&self) -> String {
+        blocks_plain_text(&self.blocks)
+    }
+
+    /// Every user mentioned anywhere in the document.
+    pub fn mentions(&self) -> Vec<&str> {
+        let mut mentions = vec![];
+        for block in &self.blocks {
+            block.collect_mentions(&mut mentions);
+        }
+        mentions
+    }
+}
+
+fn blocks_plain_text(blocks: &[Block]) -> String {
+    blocks
+        .iter()
+        .map(|block| match block {
+            Block::Paragraph(inlines) => inlines_plain_text(inlines),
+            Block::CodeBlock { code, .. } => code.clone(),
+            Block::Quote(blocks) => blocks_plain_text(blocks),
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The text a reader would see for `inlines`, without any markup.
+pub fn inlines_plain_text(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text(text) | Inline::Code(text) => text.clone(),
+            Inline::Bold(inlines) | Inline::Italic(inlines) => {
+                inlines_plain_text(inlines)
+            }
+            Inline::Link { text, .. } => inlines_plain_text(text),
+            Inline::Mention(name) => format!("@{}", name),
+        })
+        .collect()
+}
+
+impl Block {
+    fn collect_mentions<'a>(&'a self, mentions: &mut Vec<&'a str>) {
+        match self {
+            Block::Paragraph(inlines) => {
+                for inline in inlines {
+                    inline.collect_mentions(mentions);
+                }
+            }
+            Block::CodeBlock { .. } => {}
+            Block::Quote(blocks) => {
+                for block in blocks {
+                    block.collect_mentions(mentions);
+                }
+            }
+        }
+    }
+}
+
+impl Inline {
+    fn collect_mentions<'a>(&'a self, mentions: &mut Vec<&'a str>) {
+        match self {
+            Inline::Mention(name) => mentions.push(name),
+            Inline::Bold(inlines) | Inline::Italic(inlines) => {
+                for inline in inlines {
+                    inline.collect_mentions(mentions);
+                }
+            }
+            Inline::Link { text, .. } => {
+                for inline in text {
+                    inline.collect_mentions(mentions);
+                }
+            }
+            Inline::Text(_) | Inline::Code(_) => {}
+        }
+    }
+}
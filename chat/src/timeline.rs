@@ -0,0 +1,207 @@
+//! A client's view of one channel's chat log, assembled from whatever pieces
+//! of it the server has sent so far.
+//!
+//! Pieces can arrive in any order and more than once: a page of history can
+//! cross paths with new entries, and an edit can arrive before the stale copy
+//! of the entry it edits. Merging is idempotent, and an entry never goes back
+//! to an earlier state: an original never replaces an edit and nothing
+//! replaces a deletion.
+//!
+//! On a federated server an entry can also move to a later slot, when one
+//! posted before it arrives late from another server. Entries are told apart
+//! by their [`Origin`] there: a copy in an earlier slot than one already loaded
+//! is stale, a copy in a later slot replaces the earlier one, and a different
+//! entry in a slot replaces whatever was there.
+//!
+//! The server may also compact away old history, after which the timeline
+//! drops and ignores entries before [`Timeline::history_start`].
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::{Content, Entry, Origin};
+
+/// How far along an entry is in its life, so stale copies can be ignored.
+fn content_rank(content: &Content) -> u8 {
+    match content {
+        Content::Original(_) => 0,
+        Content::Edited(_) => 1,
+        Content::Deleted => 2,
+    }
+}
+
+/// Entries of a single channel, ordered by slot number.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timeline {
+    /// Sorted by slot number, with at most one entry per slot.
+    entries: Vec<Entry>,
+    /// The slot of each loaded entry with an origin.
+    slots_by_origin: HashMap<Origin, usize>,
+    history_start: usize,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merges a single entry, whether new, updated, or part of a range.
+    /// Returns whether the timeline changed.
+    pub fn insert(&mut self, entry: Entry) -> bool {
+        if entry.slot_number < self.history_start {
+            return false;
+        }
+        if let Some(origin) = &entry.metadata.origin {
+            match self.slots_by_origin.get(origin) {
+                Some(&slot_number) if slot_number > entry.slot_number => {
+                    return false;
+                }
+                Some(&slot_number) if slot_number < entry.slot_number => {
+                    if let Ok(index) = self.search(slot_number) {
+                        self.remove(index);
+                    }
+                }
+                _ => {}
+            }
+        }
+        match self.search(entry.slot_number) {
+            Ok(index) => {
+                let existing = &self.entries[index];
+                let is_other_entry = matches!(
+                    (&existing.metadata.origin, &entry.metadata.origin),
+                    (Some(existing), Some(new)) if existing != new
+                );
+                if !is_other_entry
+                    && (content_rank(&entry.content)
+                        < content_rank(&existing.content)
+                        || *existing == entry)
+                {
+                    return false;
+                }
+                self.remove(index);
+                self.add(index, entry);
+            }
+            Err(index) => self.add(index, entry),
+        }
+        true
+    }
+
+    fn add(&mut self, index: usize, entry: Entry) {
+        if let Some(origin) = &entry.metadata.origin {
+            self.slots_by_origin
+                .insert(origin.clone(), entry.slot_number);
+        }
+        self.entries.insert(index, entry);
+    }
+
+    fn remove(&mut self, index: usize) {
+        let entry = self.entries.remove(index);
+        if let Some(origin) = &entry.metadata.origin {
+            self.slots_by_origin.remove(origin);
+        }
+    }
+
+    /// Merges every entry in `entries`, such as a page of history. Returns
+    /// whether the timeline changed.
+    pub fn merge(&mut self, entries: impl IntoIterator<Item = Entry>) -> bool {
+        let mut changed = false;
+        for entry in entries {
+            changed |= self.insert(entry);
+        }
+        changed
+    }
+
+    /// The oldest slot the server still has. Nothing before it can be
+    /// loaded.
+    pub fn history_start(&self) -> usize {
+        self.history_start
+    }
+
+    /// Records that the server's history now starts at `slot_number`,
+    /// dropping anything older. Returns whether the timeline changed.
+    pub fn set_history_start(&mut self, slot_number: usize) -> bool {
+        if slot_number <= self.history_start {
+            return false;
+        }
+        self.history_start = slot_number;
+        let start = self
+            .entries
+            .partition_point(|entry| entry.slot_number < slot_number);
+        for entry in self.entries.drain(..start) {
+            if let Some(origin) = &entry.metadata.origin {
+                self.slots_by_origin.remove(origin);
+            }
+        }
+        true
+    }
+
+    /// Whether the server has entries older than the oldest loaded one.
+    pub fn has_older_history(&self) -> bool {
+        self.first_slot()
+            .is_some_and(|first_slot| first_slot > self.history_start)
+    }
+
+    fn search(&self, slot_number: usize) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by_key(&slot_number, |entry| entry.slot_number)
+    }
+
+    /// Every loaded entry, oldest first.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Entry> + '_ {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, slot_number: usize) -> Option<&Entry> {
+        self.search(slot_number)
+            .ok()
+            .map(|index| &self.entries[index])
+    }
+
+    /// Where the entry in `slot_number` is in [`Timeline::entries`].
+    pub fn position(&self, slot_number: usize) -> Option<usize> {
+        self.search(slot_number).ok()
+    }
+
+    pub fn first_slot(&self) -> Option<usize> {
+        self.entries.first().map(|entry| entry.slot_number)
+    }
+
+    pub fn last_slot(&self) -> Option<usize> {
+        self.entries.last().map(|entry| entry.slot_number)
+    }
+
+    /// Runs of missing slots between the oldest and newest loaded entries.
+    /// History older than the oldest loaded entry is not a gap.
+    pub fn gaps(&self) -> Vec<Range<usize>> {
+        self.entries
+            .windows(2)
+            .filter(|pair| pair[1].slot_number > pair[0].slot_number + 1)
+            .map(|pair| pair[0].slot_number + 1..pair[1].slot_number)
+            .collect()
+    }
+
+    /// Runs of missing slots up to `head_slot`, the newest slot on the server,
+    /// including any missing after the newest loaded entry.
+    pub fn gaps_up_to(&self, head_slot: Option<usize>) -> Vec<Range<usize>> {
+        let mut gaps = self.gaps();
+        if let Some(head_slot) = head_slot {
+            let next_slot =
+                self.last_slot().map_or(self.history_start, |slot| slot + 1);
+            if next_slot <= head_slot {
+                gaps.push(next_slot..head_slot + 1);
+            }
+        }
+        gaps
+    }
+}
@@ -0,0 +1,144 @@
+use chat::{
+    export::{self, Format},
+    Attachment, AttachmentId, ChannelName, Content, Entry, MessageText, Origin,
+};
+use chrono::{TimeZone, Utc};
+
+fn entry(slot_number: usize, username: &str, content: Content) -> Entry {
+    let mut entry = Entry::new_timestamped_now(
+        ChannelName::default(),
+        slot_number,
+        username.to_owned(),
+        content,
+    );
+    entry.metadata.timestamp = Utc
+        .with_ymd_and_hms(2025, 1, 2, 3, 4, slot_number as u32)
+        .unwrap();
+    entry
+}
+
+fn entries() -> Vec<Entry> {
+    let mut with_attachment = entry(
+        2,
+        "ethan",
+        Content::Original(MessageText("logs attached".to_owned())),
+    );
+    with_attachment.attachments.push(Attachment {
+        id: AttachmentId::from_contents(b"log"),
+        file_name: "server.log".to_owned(),
+        size: 3,
+    });
+    vec![
+        entry(
+            0,
+            "haadi",
+            Content::Original(MessageText(
+                "**down** again\n<script>x</script>".to_owned(),
+            )),
+        ),
+        entry(
+            1,
+            "jeff",
+            Content::Edited(MessageText("ping @ethan".to_owned())),
+        ),
+        with_attachment,
+        entry(3, "jeff", Content::Deleted),
+    ]
+}
+
+#[test]
+fn formats_parse_from_names_and_extensions() {
+    for format in Format::ALL {
+        assert_eq!(format.extension().parse::<Format>().unwrap(), format);
+    }
+    assert_eq!("Markdown".parse::<Format>().unwrap(), Format::Markdown);
+    assert!("pdf".parse::<Format>().is_err());
+}
+
+#[test]
+fn plain_text() {
+    assert_eq!(
+        export::export(&entries(), Format::PlainText, "#general"),
+        "[2025-01-02 03:04:00 UTC] haadi: down again\n    <script>x</script>\n\
+         [2025-01-02 03:04:01 UTC] jeff: ping @ethan (edited)\n\
+         [2025-01-02 03:04:02 UTC] ethan: logs attached [attachment: server.log (3 bytes)]\n\
+         [2025-01-02 03:04:03 UTC] jeff: (deleted)\n"
+    );
+}
+
+#[test]
+fn markdown() {
+    assert_eq!(
+        export::export(&entries(), Format::Markdown, "#general"),
+        "# #general\n\
+         \n**haadi** · 2025-01-02 03:04:00 UTC · #0\n\n**down** again\n<script>x</script>\n\
+         \n**jeff** · 2025-01-02 03:04:01 UTC · #1 · edited\n\nping @ethan\n\
+         \n**ethan** · 2025-01-02 03:04:02 UTC · #2\n\nlogs attached\n\n- Attachment: server.log (3 bytes)\n\
+         \n**jeff** · 2025-01-02 03:04:03 UTC · #3 · deleted\n\n*(deleted)*\n"
+    );
+}
+
+#[test]
+fn json_lines_round_trip() {
+    let exported = export::export(&entries(), Format::JsonLines, "#general");
+    let parsed = exported
+        .lines()
+        .map(|line| serde_json::from_str::<Entry>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(parsed, entries());
+}
+
+#[test]
+fn origins_round_trip_and_are_omitted_for_local_entries() {
+    let mut relayed = entry(
+        4,
+        "haadi@alpha",
+        Content::Original(MessageText("from afar".to_owned())),
+    );
+    relayed.metadata.origin = Some(Origin {
+        server: "alpha".to_owned(),
+        slot_number: 17,
+    });
+    let exported =
+        export::export(&[relayed.clone()], Format::JsonLines, "#general");
+    assert!(
+        exported.contains(r#""origin":{"server":"alpha","slot_number":17}"#)
+    );
+    assert_eq!(
+        serde_json::from_str::<Entry>(exported.trim()).unwrap(),
+        relayed
+    );
+
+    let local = export::export(&entries(), Format::JsonLines, "#general");
+    assert!(!local.contains("origin"));
+}
+
+#[test]
+fn html_is_escaped_and_rendered() {
+    let html = export::export(&entries(), Format::Html, "<#general>");
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<title>&lt;#general&gt;</title>"));
+    assert!(html.contains(
+        "<p><strong>down</strong> again<br>\n&lt;script&gt;x&lt;/script&gt;</p>"
+    ));
+    assert!(!html.contains("<script>"));
+    assert!(html.contains("<span class=\"marker\">(edited)</span>"));
+    assert!(html.contains("<span class=\"mention\">@ethan</span>"));
+    assert!(html.contains("<li>server.log (3 bytes)</li>"));
+    assert!(html.contains("<p class=\"deleted\">(deleted)</p>"));
+    assert!(html.contains("datetime=\"2025-01-02T03:04:03+00:00\""));
+}
+
+#[test]
+fn html_drops_unsafe_links() {
+    let entries = [entry(
+        0,
+        "haadi",
+        Content::Original(MessageText(
+            "[safe](https://x.y) [unsafe](javascript:alert)".to_owned(),
+        )),
+    )];
+    let html = export::export(&entries, Format::Html, "#general");
+    assert!(html.contains("<a href=\"https://x.y\">safe</a> unsafe"));
+    assert!(!html.contains("javascript"));
+}
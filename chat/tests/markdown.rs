@@ -0,0 +1,238 @@
+use chat::markdown::{self, Block, Document, Inline};
+
+fn text(text: &str) -> Inline {
+    Inline::Text(text.to_owned())
+}
+
+fn paragraph(inlines: Vec<Inline>) -> Document {
+    Document {
+        blocks: vec![Block::Paragraph(inlines)],
+    }
+}
+
+#[test]
+fn plain_text_is_a_single_text_node() {
+    assert_eq!(
+        markdown::parse("hello world"),
+        paragraph(vec![text("hello world")])
+    );
+}
+
+#[test]
+fn empty_message_has_no_blocks() {
+    assert_eq!(markdown::parse(""), Document { blocks: vec![] });
+    assert_eq!(markdown::parse("\n\n"), Document { blocks: vec![] });
+}
+
+#[test]
+fn bold_and_italic() {
+    assert_eq!(
+        markdown::parse("a **b** *c* _d_"),
+        paragraph(vec![
+            text("a "),
+            Inline::Bold(vec![text("b")]),
+            text(" "),
+            Inline::Italic(vec![text("c")]),
+            text(" "),
+            Inline::Italic(vec![text("d")]),
+        ])
+    );
+}
+
+#[test]
+fn emphasis_nests() {
+    assert_eq!(
+        markdown::parse("**bold *and italic***"),
+        paragraph(vec![Inline::Bold(vec![
+            text("bold "),
+            Inline::Italic(vec![text("and italic")]),
+        ])])
+    );
+}
+
+#[test]
+fn unclosed_delimiters_are_literal() {
+    assert_eq!(
+        markdown::parse("**not bold"),
+        paragraph(vec![text("**not bold")])
+    );
+    assert_eq!(
+        markdown::parse("2 * 3 = 6"),
+        paragraph(vec![text("2 * 3 = 6")])
+    );
+    assert_eq!(markdown::parse("`oops"), paragraph(vec![text("`oops")]));
+}
+
+#[test]
+fn underscores_inside_words_are_not_emphasis() {
+    assert_eq!(
+        markdown::parse("call snake_case_name now"),
+        paragraph(vec![text("call snake_case_name now")])
+    );
+}
+
+#[test]
+fn inline_code_is_not_parsed_further() {
+    assert_eq!(
+        markdown::parse("run `cargo **test**` please"),
+        paragraph(vec![
+            text("run "),
+            Inline::Code("cargo **test**".to_owned()),
+            text(" please"),
+        ])
+    );
+}
+
+#[test]
+fn delimiters_inside_code_do_not_close_emphasis() {
+    assert_eq!(
+        markdown::parse("**see `a**b`**"),
+        paragraph(vec![Inline::Bold(vec![
+            text("see "),
+            Inline::Code("a**b".to_owned()),
+        ])])
+    );
+}
+
+#[test]
+fn links() {
+    assert_eq!(
+        markdown::parse("see [the **docs**](https://example.com/a_b)"),
+        paragraph(vec![
+            text("see "),
+            Inline::Link {
+                text: vec![text("the "), Inline::Bold(vec![text("docs")])],
+                url: "https://example.com/a_b".to_owned(),
+            },
+        ])
+    );
+}
+
+#[test]
+fn brackets_without_url_are_literal() {
+    assert_eq!(
+        markdown::parse("[not a link] (nope)"),
+        paragraph(vec![text("[not a link] (nope)")])
+    );
+}
+
+#[test]
+fn mentions() {
+    assert_eq!(
+        markdown::parse("ping @haadi and @jeff.huang."),
+        paragraph(vec![
+            text("ping "),
+            Inline::Mention("haadi".to_owned()),
+            text(" and "),
+            Inline::Mention("jeff.huang".to_owned()),
+            text("."),
+        ])
+    );
+}
+
+#[test]
+fn email_addresses_are_not_mentions() {
+    assert_eq!(
+        markdown::parse("mail me@example.com"),
+        paragraph(vec![text("mail me@example.com")])
+    );
+    assert_eq!(markdown::parse("@ alone"), paragraph(vec![text("@ alone")]));
+}
+
+#[test]
+fn escapes() {
+    assert_eq!(
+        markdown::parse(r"\*not italic\* and \@nobody"),
+        paragraph(vec![text("*not italic* and @nobody")])
+    );
+    assert_eq!(markdown::parse(r"a \n b"), paragraph(vec![text(r"a \n b")]));
+}
+
+#[test]
+fn escapes_before_multibyte_characters() {
+    assert_eq!(
+        markdown::parse(r"**\é**"),
+        paragraph(vec![Inline::Bold(vec![text(r"\é")])])
+    );
+}
+
+#[test]
+fn fenced_code_blocks() {
+    assert_eq!(
+        markdown::parse("look:\n```rust\nfn main() {}\n\n// **no**\n```\nnice"),
+        Document {
+            blocks: vec![
+                Block::Paragraph(vec![text("look:")]),
+                Block::CodeBlock {
+                    language: Some("rust".to_owned()),
+                    code: "fn main() {}\n\n// **no**".to_owned(),
+                },
+                Block::Paragraph(vec![text("nice")]),
+            ]
+        }
+    );
+}
+
+#[test]
+fn unterminated_code_block_runs_to_the_end() {
+    assert_eq!(
+        markdown::parse("```\nstill code"),
+        Document {
+            blocks: vec![Block::CodeBlock {
+                language: None,
+                code: "still code".to_owned(),
+            }]
+        }
+    );
+}
+
+#[test]
+fn quotes_contain_blocks() {
+    assert_eq!(
+        markdown::parse("> **said**\n>\n> > nested\nreply"),
+        Document {
+            blocks: vec![
+                Block::Quote(vec![
+                    Block::Paragraph(vec![Inline::Bold(vec![text("said")])]),
+                    Block::Quote(vec![Block::Paragraph(vec![text("nested")])]),
+                ]),
+                Block::Paragraph(vec![text("reply")]),
+            ]
+        }
+    );
+}
+
+#[test]
+fn paragraphs_keep_line_breaks() {
+    assert_eq!(
+        markdown::parse("one\ntwo\n\nthree"),
+        Document {
+            blocks: vec![
+                Block::Paragraph(vec![text("one\ntwo")]),
+                Block::Paragraph(vec![text("three")]),
+            ]
+        }
+    );
+}
+
+#[test]
+fn plain_text_strips_markup() {
+    let document =
+        markdown::parse("**hi** @ethan, see [this](https://x.y)\n> `code`");
+    assert_eq!(document.plain_text(), "hi @ethan, see this\n\ncode");
+}
+
+#[test]
+fn mentions_are_collected_from_every_block() {
+    let document =
+        markdown::parse("@a **@b**\n> [@c](https://x.y)\n```\n@d\n```");
+    assert_eq!(document.mentions(), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn message_text_parses_itself() {
+    assert_eq!(
+        chat::MessageText("*hey*".to_owned()).parse(),
+        paragraph(vec![Inline::Italic(vec![text("hey")])])
+    );
+}
@@ -0,0 +1,300 @@
+use chat::{
+    timeline::Timeline, ChannelName, Content, Entry, MessageText, Origin,
+};
+use proptest::prelude::*;
+
+fn entry(slot_number: usize, content: Content) -> Entry {
+    let mut entry = Entry::new_timestamped_now(
+        ChannelName::default(),
+        slot_number,
+        "ethan".to_owned(),
+        content,
+    );
+    // Keep copies of the same slot comparable.
+    entry.metadata.timestamp = Default::default();
+    entry
+}
+
+fn original(slot_number: usize) -> Entry {
+    entry(
+        slot_number,
+        Content::Original(MessageText(format!("message {}", slot_number))),
+    )
+}
+
+fn edited(slot_number: usize) -> Entry {
+    entry(
+        slot_number,
+        Content::Edited(MessageText(format!("edited {}", slot_number))),
+    )
+}
+
+fn deleted(slot_number: usize) -> Entry {
+    entry(slot_number, Content::Deleted)
+}
+
+fn slots(timeline: &Timeline) -> Vec<usize> {
+    timeline.iter().map(|entry| entry.slot_number).collect()
+}
+
+#[test]
+fn ranges_merge_in_slot_order() {
+    let mut timeline = Timeline::new();
+    timeline.merge((5..8).map(original));
+    timeline.merge((0..3).map(original));
+    timeline.insert(original(3));
+    assert_eq!(slots(&timeline), vec![0, 1, 2, 3, 5, 6, 7]);
+    assert_eq!(timeline.position(5), Some(4));
+    assert_eq!(timeline.get(4), None);
+    assert_eq!(timeline.first_slot(), Some(0));
+    assert_eq!(timeline.last_slot(), Some(7));
+}
+
+#[test]
+fn merging_twice_changes_nothing() {
+    let mut timeline = Timeline::new();
+    assert!(timeline.merge((0..4).map(original)));
+    let before = timeline.clone();
+    assert!(!timeline.merge((0..4).map(original)));
+    assert_eq!(timeline, before);
+}
+
+#[test]
+fn stale_copies_do_not_undo_edits_or_deletions() {
+    let mut timeline = Timeline::new();
+    timeline.insert(edited(0));
+    timeline.insert(deleted(1));
+    assert!(!timeline.merge([original(0), original(1), edited(1)]));
+    assert_eq!(timeline.get(0), Some(&edited(0)));
+    assert_eq!(timeline.get(1), Some(&deleted(1)));
+}
+
+#[test]
+fn later_edits_replace_earlier_ones() {
+    let mut timeline = Timeline::new();
+    timeline.insert(edited(0));
+    let mut again = edited(0);
+    again.content = Content::Edited(MessageText("again".to_owned()));
+    assert!(timeline.insert(again.clone()));
+    assert_eq!(timeline.get(0), Some(&again));
+}
+
+#[test]
+fn gaps_are_missing_runs_between_loaded_entries() {
+    let mut timeline = Timeline::new();
+    assert_eq!(timeline.gaps(), vec![]);
+    assert_eq!(timeline.gaps_up_to(Some(2)), vec![0..3]);
+
+    timeline.merge([3, 4, 7, 10].map(original));
+    assert_eq!(timeline.gaps(), vec![5..7, 8..10]);
+    assert_eq!(timeline.gaps_up_to(None), vec![5..7, 8..10]);
+    assert_eq!(timeline.gaps_up_to(Some(10)), vec![5..7, 8..10]);
+    assert_eq!(timeline.gaps_up_to(Some(12)), vec![5..7, 8..10, 11..13]);
+}
+
+/// How each slot of a channel ends up.
+#[derive(Clone, Copy, Debug)]
+enum Fate {
+    Kept,
+    Edited,
+    Deleted,
+}
+
+fn fate() -> impl Strategy<Value = Fate> {
+    prop_oneof![Just(Fate::Kept), Just(Fate::Edited), Just(Fate::Deleted)]
+}
+
+/// Every message a server could send a client about a channel whose slots
+/// end up as `fates`: each slot's original and any update, plus pages of
+/// history cut at `cuts`, which may be taken before or after the updates.
+fn deliveries(
+    fates: &[Fate],
+    cuts: &[usize],
+    pages_are_stale: bool,
+) -> Vec<Vec<Entry>> {
+    let final_entry = |slot_number: usize| match fates[slot_number] {
+        Fate::Kept => original(slot_number),
+        Fate::Edited => edited(slot_number),
+        Fate::Deleted => deleted(slot_number),
+    };
+
+    let mut deliveries = vec![];
+    for (slot_number, fate) in fates.iter().enumerate() {
+        deliveries.push(vec![original(slot_number)]);
+        if !matches!(fate, Fate::Kept) {
+            deliveries.push(vec![final_entry(slot_number)]);
+        }
+    }
+
+    let mut bounds = cuts
+        .iter()
+        .map(|cut| cut % (fates.len() + 1))
+        .chain([0, fates.len()])
+        .collect::<Vec<_>>();
+    bounds.sort_unstable();
+    for page in bounds.windows(2) {
+        deliveries.push(
+            (page[0]..page[1])
+                .map(|slot_number| {
+                    if pages_are_stale {
+                        original(slot_number)
+                    } else {
+                        final_entry(slot_number)
+                    }
+                })
+                .collect(),
+        );
+    }
+    deliveries
+}
+
+fn expected(fates: &[Fate]) -> Timeline {
+    let mut timeline = Timeline::new();
+    for (slot_number, fate) in fates.iter().enumerate() {
+        timeline.insert(match fate {
+            Fate::Kept => original(slot_number),
+            Fate::Edited => edited(slot_number),
+            Fate::Deleted => deleted(slot_number),
+        });
+    }
+    timeline
+}
+
+fn shuffled_deliveries() -> impl Strategy<Value = (Vec<Fate>, Vec<Vec<Entry>>)>
+{
+    (
+        prop::collection::vec(fate(), 0..30),
+        prop::collection::vec(any::<usize>(), 0..5),
+        any::<bool>(),
+    )
+        .prop_flat_map(|(fates, cuts, pages_are_stale)| {
+            let deliveries = deliveries(&fates, &cuts, pages_are_stale);
+            (Just(fates), Just(deliveries).prop_shuffle())
+        })
+}
+
+proptest! {
+    #[test]
+    fn any_delivery_order_converges((fates, deliveries) in shuffled_deliveries()) {
+        let mut timeline = Timeline::new();
+        for delivery in deliveries {
+            timeline.merge(delivery);
+        }
+        prop_assert_eq!(timeline, expected(&fates));
+    }
+
+    #[test]
+    fn redelivery_is_idempotent(
+        (fates, deliveries) in shuffled_deliveries(),
+        repeats in prop::collection::vec(any::<prop::sample::Index>(), 0..10),
+    ) {
+        let mut timeline = Timeline::new();
+        for delivery in &deliveries {
+            timeline.merge(delivery.clone());
+        }
+        if !deliveries.is_empty() {
+            for repeat in repeats {
+                let delivery = repeat.get(&deliveries).clone();
+                prop_assert!(!timeline.merge(delivery));
+            }
+        }
+        prop_assert_eq!(timeline, expected(&fates));
+    }
+
+    #[test]
+    fn gaps_cover_exactly_the_missing_slots(
+        loaded in prop::collection::btree_set(0usize..60, 0..30),
+        head_slot in prop::option::of(0usize..70),
+    ) {
+        let mut timeline = Timeline::new();
+        timeline.merge(loaded.iter().rev().map(|&slot| original(slot)));
+        prop_assert!(slots(&timeline).windows(2).all(|pair| pair[0] < pair[1]));
+
+        let missing = timeline
+            .gaps_up_to(head_slot)
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let first_slot = loaded.first().copied().unwrap_or(0);
+        let last_slot = loaded
+            .last()
+            .copied()
+            .into_iter()
+            .chain(head_slot)
+            .max();
+        let expected_missing = match last_slot {
+            Some(last_slot) => (first_slot..=last_slot)
+                .filter(|slot| !loaded.contains(slot))
+                .collect(),
+            None => vec![],
+        };
+        prop_assert_eq!(missing, expected_missing);
+    }
+}
+
+#[test]
+fn compacted_history_is_dropped_and_ignored() {
+    let mut timeline = Timeline::new();
+    timeline.merge((0..6).map(original));
+    assert!(!timeline.has_older_history());
+
+    assert!(timeline.set_history_start(3));
+    assert_eq!(slots(&timeline), vec![3, 4, 5]);
+    assert!(!timeline.set_history_start(2));
+    assert!(!timeline.insert(original(1)));
+    assert_eq!(timeline.history_start(), 3);
+    assert!(!timeline.has_older_history());
+
+    let mut later = Timeline::new();
+    later.set_history_start(3);
+    assert_eq!(later.gaps_up_to(Some(4)), vec![3..5]);
+    later.insert(original(5));
+    assert!(later.has_older_history());
+}
+
+/// `entry`, posted `n`th on the federated server `server`.
+fn from(server: &str, n: usize, mut entry: Entry) -> Entry {
+    entry.metadata.origin = Some(Origin {
+        server: server.to_owned(),
+        slot_number: n,
+    });
+    entry
+}
+
+#[test]
+fn federated_entries_move_to_later_slots() {
+    let mut timeline = Timeline::new();
+    timeline
+        .merge([from("alpha", 0, original(0)), from("alpha", 1, edited(1))]);
+
+    // An entry from beta merged in before alpha's edited one moves it on.
+    let mut moved = from("alpha", 1, edited(1));
+    moved.slot_number = 2;
+    assert!(timeline.insert(moved.clone()));
+    assert!(timeline.insert(from("beta", 0, original(1))));
+    assert_eq!(slots(&timeline), vec![0, 1, 2]);
+    assert_eq!(
+        timeline
+            .get(1)
+            .unwrap()
+            .metadata
+            .origin
+            .as_ref()
+            .unwrap()
+            .server,
+        "beta"
+    );
+    assert_eq!(*timeline.get(2).unwrap(), moved);
+
+    // Copies from before the move are stale.
+    assert!(!timeline.insert(from("alpha", 1, edited(1))));
+    assert_eq!(slots(&timeline), vec![0, 1, 2]);
+
+    // The new entry replaces whatever a slot held, even an edit, if it
+    // arrives before news of the move.
+    let mut timeline = Timeline::new();
+    timeline.insert(from("alpha", 1, edited(1)));
+    assert!(timeline.insert(from("beta", 0, original(1))));
+    assert!(timeline.insert(moved));
+    assert_eq!(slots(&timeline), vec![1, 2]);
+}
@@ -0,0 +1,89 @@
+//! Measures how many posts per second a server commits with many clients
+//! posting at once, spread across several channels.
+//!
+//! ```sh
+//! cargo run --release -p client-connect --features local \
+//!     --example post_benchmark -- wss://127.0.0.1:12345 1000 10 20
+//! ```
+
+use std::{env, error::Error, process, str::FromStr, time::Instant};
+
+use futures_util::future;
+
+fn argument<T: FromStr>(index: usize, default: T) -> T {
+    env::args()
+        .nth(index)
+        .map(|argument| {
+            argument.parse().unwrap_or_else(|_| {
+                eprintln!("Argument {} must be a number", index);
+                process::exit(2);
+            })
+        })
+        .unwrap_or(default)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let url = env::args().nth(1).expect(
+        "usage: post_benchmark <wss:// address> [clients] [channels] \
+         [posts per client]",
+    );
+    let clients: usize = argument(2, 1000);
+    let channels: usize = argument(3, 10);
+    let posts_per_client: usize = argument(4, 20);
+
+    let mut connections = Vec::with_capacity(clients);
+    for client in 0..clients {
+        let (connection, tx, mut rx) =
+            client_connect::connect_to_server(&url).await?;
+        tx.send(comms::ClientMessage::LogIn {
+            username: format!("bench-{}", client),
+            password: None,
+        })?;
+        // Every client receives every new entry, which it has to keep up with
+        // for the server's queues not to grow.
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        connections.push(connection);
+    }
+    println!("Connected {} clients", clients);
+
+    let start = Instant::now();
+    let posters =
+        connections
+            .iter()
+            .enumerate()
+            .map(|(client, connection)| async move {
+                let username = format!("bench-{}", client);
+                let channel = chat::ChannelName(format!(
+                    "bench-{}",
+                    client % channels.max(1)
+                ));
+                for post in 0..posts_per_client {
+                    connection
+                        .post(
+                            comms::Nonce::new_unique(),
+                            channel.clone(),
+                            format!("post {} from {}", post, username),
+                            vec![],
+                        )
+                        .await?;
+                }
+                Ok::<_, client_connect::PostError>(())
+            });
+    for result in future::join_all(posters).await {
+        result?;
+    }
+    let elapsed = start.elapsed();
+
+    let posts = clients * posts_per_client;
+    println!(
+        "{} posts to {} channels in {:.2?}: {:.0} posts/sec",
+        posts,
+        channels,
+        elapsed,
+        posts as f64 / elapsed.as_secs_f64()
+    );
+
+    // Closing each connection gracefully would only slow the benchmark down.
+    process::exit(0);
+}
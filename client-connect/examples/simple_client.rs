@@ -1,10 +1,9 @@
-use std::env;
+use std::{env, error::Error};
 
-use client_connect::ClientConnectionResult;
 use tokio::io::AsyncBufReadExt;
 
 #[tokio::main]
-async fn main() -> ClientConnectionResult<()> {
+async fn main() -> Result<(), Box<dyn Error>> {
     let url = env::args()
         .nth(1)
         .expect("Pass the server's wss:// address as a command-line argument");
@@ -12,6 +11,10 @@ async fn main() -> ClientConnectionResult<()> {
 
     let (connection, tx, mut rx) =
         client_connect::connect_to_server(&url).await?;
+    tx.send(comms::ClientMessage::LogIn {
+        username,
+        password: env::var("NERDTALK_PASSWORD").ok(),
+    })?;
 
     let stdin = tokio::io::stdin();
     let mut lines = tokio::io::BufReader::new(stdin).lines();
@@ -19,11 +22,22 @@ async fn main() -> ClientConnectionResult<()> {
         if line.is_empty() {
             break;
         }
-        tx.send(comms::ClientMessage::Post {
-            username: username.clone(),
-            content: line,
-        })
-        .expect("todo");
+        match connection
+            .post(
+                comms::Nonce::new_unique(),
+                chat::ChannelName::default(),
+                line,
+                vec![],
+            )
+            .await?
+        {
+            client_connect::PostOutcome::Committed(slot_number) => {
+                println!("posted to slot {}", slot_number);
+            }
+            client_connect::PostOutcome::CommandResponse(text) => {
+                println!("{}", text);
+            }
+        }
     }
 
     tokio::spawn(async move {
@@ -38,10 +52,15 @@ async fn main() -> ClientConnectionResult<()> {
                     );
                 }
                 comms::ServerMessage::EntryRange {
-                    client_id: _,
-                    entries,
+                    entries, head_slot, ..
                 } => {
-                    println!("got entry range: {:?}", entries);
+                    println!(
+                        "got entry range (head is {:?}): {:?}",
+                        head_slot, entries
+                    );
+                }
+                other => {
+                    println!("got {:?}", other);
                 }
             }
         }
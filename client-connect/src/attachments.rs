@@ -0,0 +1,249 @@
+//! Chunked attachment transfers. [`Upload`] and [`Download`] only produce and
+//! consume [`comms`] messages, so they work over the channels returned by
+//! [`crate::connect_to_server`].
+
+use std::{fmt, io};
+
+use comms::AttachmentError;
+use tokio::sync::mpsc;
+
+/// How far along a transfer is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub transferred: u64,
+    pub total: u64,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.transferred as f64 / self.total as f64
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.transferred >= self.total
+    }
+}
+
+/// How many bytes of an upload may be sent before the server acknowledges
+/// them, so progress follows what the server has actually received.
+pub const UPLOAD_WINDOW: u64 = 4 * comms::ATTACHMENT_CHUNK_SIZE as u64;
+
+/// An upload of a file, read a chunk at a time as it's sent. Send it with
+/// [`send_upload`], then feed the server's
+/// [`comms::ServerMessage::UploadProgress`] replies back into
+/// [`Upload::acknowledge`] and send the chunks now due with [`send_chunks`].
+pub struct Upload {
+    client_id: comms::ClientId,
+    file_name: String,
+    id: chat::AttachmentId,
+    size: u64,
+    source: Box<dyn io::Read + Send>,
+    sent: u64,
+    acknowledged: u64,
+}
+
+impl Upload {
+    /// An upload of an in-memory file.
+    pub fn new(
+        file_name: String,
+        bytes: Vec<u8>,
+    ) -> Result<Self, AttachmentError> {
+        let id = chat::AttachmentId::from_contents(&bytes);
+        let size = bytes.len() as u64;
+        Self::from_reader(file_name, id, size, io::Cursor::new(bytes))
+    }
+
+    /// An upload of the `size` bytes read from `source`, which must hash to
+    /// `id`.
+    pub fn from_reader(
+        file_name: String,
+        id: chat::AttachmentId,
+        size: u64,
+        source: impl io::Read + Send + 'static,
+    ) -> Result<Self, AttachmentError> {
+        if size > comms::MAX_ATTACHMENT_SIZE {
+            return Err(AttachmentError::TooLarge {
+                size,
+                limit: comms::MAX_ATTACHMENT_SIZE,
+            });
+        }
+        Ok(Self {
+            client_id: comms::ClientId::new_unique_per_client(),
+            file_name,
+            id,
+            size,
+            source: Box::new(source),
+            sent: 0,
+            acknowledged: 0,
+        })
+    }
+
+    pub fn client_id(&self) -> &comms::ClientId {
+        &self.client_id
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn begin_message(&self) -> comms::ClientMessage {
+        comms::ClientMessage::UploadBegin {
+            client_id: self.client_id.clone(),
+            file_name: self.file_name.clone(),
+            size: self.size,
+            id: self.id.clone(),
+        }
+    }
+
+    /// The next chunk of the file to send, or `None` once everything has been
+    /// sent or while [`UPLOAD_WINDOW`] bytes are waiting to be acknowledged.
+    pub fn next_chunk(&mut self) -> io::Result<Option<comms::ClientMessage>> {
+        if self.sent >= self.size
+            || self.sent.saturating_sub(self.acknowledged) >= UPLOAD_WINDOW
+        {
+            return Ok(None);
+        }
+        let length =
+            (self.size - self.sent).min(comms::ATTACHMENT_CHUNK_SIZE as u64);
+        let mut bytes = vec![0; length as usize];
+        self.source.read_exact(&mut bytes)?;
+        let message = comms::ClientMessage::UploadChunk {
+            client_id: self.client_id.clone(),
+            offset: self.sent,
+            bytes,
+        };
+        self.sent += length;
+        Ok(Some(message))
+    }
+
+    /// Records that the server has received `received` bytes.
+    pub fn acknowledge(&mut self, received: u64) -> Progress {
+        self.acknowledged = self.acknowledged.max(received);
+        self.progress()
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            transferred: self.acknowledged,
+            total: self.size,
+        }
+    }
+}
+
+/// Why an upload couldn't be sent.
+#[derive(Debug)]
+pub enum SendError {
+    /// The connection was closed.
+    ConnectionClosed,
+    /// The file couldn't be read.
+    Read(io::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::ConnectionClosed => write!(f, "Connection closed"),
+            SendError::Read(error) => write!(f, "Cannot read file: {}", error),
+        }
+    }
+}
+
+/// Starts `upload` on `tx`, sending its first chunks.
+pub fn send_upload(
+    tx: &mpsc::UnboundedSender<comms::ClientMessage>,
+    upload: &mut Upload,
+) -> Result<(), SendError> {
+    tx.send(upload.begin_message())
+        .map_err(|_| SendError::ConnectionClosed)?;
+    send_chunks(tx, upload)
+}
+
+/// Sends the chunks of `upload` that are due now that more of it has been
+/// acknowledged.
+pub fn send_chunks(
+    tx: &mpsc::UnboundedSender<comms::ClientMessage>,
+    upload: &mut Upload,
+) -> Result<(), SendError> {
+    while let Some(chunk) = upload.next_chunk().map_err(SendError::Read)? {
+        tx.send(chunk).map_err(|_| SendError::ConnectionClosed)?;
+    }
+    Ok(())
+}
+
+/// Reassembles an attachment from [`comms::ServerMessage::DownloadChunk`]s.
+pub struct Download {
+    client_id: comms::ClientId,
+    attachment: chat::Attachment,
+    bytes: Vec<u8>,
+}
+
+impl Download {
+    pub fn new(attachment: chat::Attachment) -> Self {
+        Self {
+            client_id: comms::ClientId::new_unique_per_client(),
+            bytes: Vec::with_capacity(attachment.size as usize),
+            attachment,
+        }
+    }
+
+    pub fn client_id(&self) -> &comms::ClientId {
+        &self.client_id
+    }
+
+    pub fn attachment(&self) -> &chat::Attachment {
+        &self.attachment
+    }
+
+    pub fn request_message(&self) -> comms::ClientMessage {
+        comms::ClientMessage::Download {
+            client_id: self.client_id.clone(),
+            id: self.attachment.id.clone(),
+        }
+    }
+
+    /// Appends a chunk that the server sent at `offset`.
+    pub fn receive_chunk(
+        &mut self,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<Progress, AttachmentError> {
+        let expected = self.bytes.len() as u64;
+        if offset != expected {
+            return Err(AttachmentError::UnexpectedOffset {
+                expected,
+                actual: offset,
+            });
+        }
+        if expected + bytes.len() as u64 > self.attachment.size {
+            return Err(AttachmentError::TooLarge {
+                size: expected + bytes.len() as u64,
+                limit: self.attachment.size,
+            });
+        }
+        self.bytes.extend_from_slice(bytes);
+        Ok(self.progress())
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            transferred: self.bytes.len() as u64,
+            total: self.attachment.size,
+        }
+    }
+
+    /// The downloaded contents, provided they hash to the attachment's id.
+    pub fn finish(self) -> Result<Vec<u8>, AttachmentError> {
+        if chat::AttachmentId::from_contents(&self.bytes) == self.attachment.id
+        {
+            Ok(self.bytes)
+        } else {
+            Err(AttachmentError::IntegrityMismatch {
+                expected: self.attachment.id,
+            })
+        }
+    }
+}
@@ -1,19 +1,32 @@
-use std::{error::Error, fmt, mem, sync::Arc};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    future::Future,
+    mem,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use comms::Codable;
-use futures_util::{future, SinkExt, StreamExt};
-use tokio::{net::TcpStream, pin, sync::mpsc, task::JoinHandle};
+use futures_util::future;
+use tokio::{
+    net, pin,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
 use tokio_rustls::rustls as tls;
 use tokio_tungstenite::{
     tungstenite::{
-        self,
-        client::IntoClientRequest,
-        protocol::{frame::coding::CloseCode, CloseFrame, Message},
+        self, client::IntoClientRequest, handshake::client::Request, http::Uri,
     },
-    Connector, MaybeTlsStream, WebSocketStream,
+    Connector,
 };
+use transport::{Bytes, CloseCode, CloseFrame, Frame};
 use webpki::types::{pem::PemObject, CertificateDer};
 
+pub mod attachments;
+
 /// An error that can occur on the client side.
 #[derive(Debug)]
 pub enum ClientConnectionError {
@@ -21,9 +34,12 @@ pub enum ClientConnectionError {
         message: String,
         cause: tls::pki_types::pem::Error,
     },
-    WebSocketFailure(tungstenite::Error),
-    UnexpectedWebSocketMessage(Message),
-    MalformedServerMessage(Message, comms::CodingError),
+    WebSocketFailure(Box<tungstenite::Error>),
+    /// A `quic://` address without a host and port, or whose host doesn't
+    /// resolve.
+    InvalidAddress(String),
+    TransportFailure(transport::Error),
+    MalformedServerMessage(Frame, comms::CodingError),
 }
 
 impl fmt::Display for ClientConnectionError {
@@ -34,9 +50,10 @@ impl fmt::Display for ClientConnectionError {
                 cause,
             } => cause.fmt(f),
             ClientConnectionError::WebSocketFailure(cause) => cause.fmt(f),
-            ClientConnectionError::UnexpectedWebSocketMessage(message) => {
-                write!(f, "Unexpected WebSocket message: {:?}", message)
+            ClientConnectionError::InvalidAddress(address) => {
+                write!(f, "Invalid server address {}", address)
             }
+            ClientConnectionError::TransportFailure(cause) => cause.fmt(f),
             ClientConnectionError::MalformedServerMessage(message, cause) => {
                 write!(f, "Malformed server message {:?}: {}", message, cause)
             }
@@ -46,14 +63,60 @@ impl fmt::Display for ClientConnectionError {
 
 impl Error for ClientConnectionError {}
 
+/// Why a [`ClientConnection::post`] was not acknowledged.
+#[derive(Debug, Clone)]
+pub enum PostError {
+    /// The connection closed before the server acknowledged the post. It may
+    /// or may not have been committed, so retry with the same nonce.
+    ConnectionClosed,
+    /// The server refused to commit the post, e.g. because the poster is
+    /// muted in the channel.
+    Rejected(comms::PostRejection),
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostError::ConnectionClosed => write!(
+                f,
+                "Connection closed before the server acknowledged the post"
+            ),
+            PostError::Rejected(reason) => reason.fmt(f),
+        }
+    }
+}
+
+impl Error for PostError {}
+
+/// How the server settled a [`ClientConnection::post`].
+#[derive(Debug, Clone)]
+pub enum PostOutcome {
+    /// The post was committed to this slot.
+    Committed(usize),
+    /// The post was a slash command that replied instead of committing an
+    /// entry.
+    CommandResponse(String),
+}
+
+/// Posts waiting on a [`comms::ServerMessage::PostAck`],
+/// [`comms::ServerMessage::CommandResponse`], or
+/// [`comms::ServerMessage::PostRejected`], by nonce.
+type PendingPosts = Arc<
+    Mutex<
+        HashMap<
+            comms::Nonce,
+            Vec<oneshot::Sender<Result<PostOutcome, PostError>>>,
+        >,
+    >,
+>;
+
 /// [`std::result::Result`] wrapper for client errors.
 pub type ClientConnectionResult<T> =
     std::result::Result<T, ClientConnectionError>;
 
-/// Opens a TLS-encrypted web socket to the server at `server_address`.
-async fn open_websocket<R: IntoClientRequest + Unpin>(
-    server_address: R,
-) -> ClientConnectionResult<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+/// How to verify the server's certificate: against the testing root authority
+/// when local, and the usual web roots otherwise.
+fn tls_client_config() -> ClientConnectionResult<tls::ClientConfig> {
     let mut root_store;
     if cfg!(feature = "local") {
         root_store = tls::RootCertStore::empty();
@@ -83,11 +146,22 @@ async fn open_websocket<R: IntoClientRequest + Unpin>(
         );
     }
 
-    let tls_client_config = tls::ClientConfig::builder()
+    Ok(tls::ClientConfig::builder()
         .with_root_certificates(root_store)
-        .with_no_client_auth();
+        .with_no_client_auth())
+}
 
-    let tls_connector = Connector::Rustls(Arc::new(tls_client_config));
+/// Opens a web socket to the server at `server_address`, TLS-encrypted
+/// unless it's a `ws://` address.
+async fn open_websocket(
+    server_address: Request,
+) -> ClientConnectionResult<transport::Connection> {
+    // Plain addresses don't need a certificate, even the testing one.
+    let tls_connector = if server_address.uri().scheme_str() == Some("ws") {
+        Connector::Plain
+    } else {
+        Connector::Rustls(Arc::new(tls_client_config()?))
+    };
 
     let (websocket, _) = tokio_tungstenite::connect_async_tls_with_config(
         server_address,
@@ -96,9 +170,46 @@ async fn open_websocket<R: IntoClientRequest + Unpin>(
         Some(tls_connector),
     )
     .await
-    .map_err(ClientConnectionError::WebSocketFailure)?;
+    .map_err(|error| {
+        ClientConnectionError::WebSocketFailure(Box::new(error))
+    })?;
+
+    Ok(websocket.into())
+}
+
+/// Shared by every QUIC connection, so reconnecting to a server resumes the
+/// last session with 0-RTT.
+static QUIC_CONFIG: OnceLock<transport::quic::ClientConfig> = OnceLock::new();
 
-    Ok(websocket)
+/// Opens a QUIC connection to the server at `server_address`, a `quic://`
+/// URL.
+async fn open_quic(
+    server_address: &Uri,
+) -> ClientConnectionResult<transport::Connection> {
+    let invalid_address =
+        || ClientConnectionError::InvalidAddress(server_address.to_string());
+    let host = server_address.host().ok_or_else(invalid_address)?;
+    let port = server_address.port_u16().ok_or_else(invalid_address)?;
+    // IPv6 hosts are bracketed in URLs.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let address = net::lookup_host((host, port))
+        .await
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(invalid_address)?;
+
+    let config = match QUIC_CONFIG.get() {
+        Some(config) => config,
+        None => {
+            let config =
+                transport::quic::ClientConfig::new(tls_client_config()?)
+                    .map_err(ClientConnectionError::TransportFailure)?;
+            QUIC_CONFIG.get_or_init(|| config)
+        }
+    };
+    transport::quic::connect(address, host, config)
+        .await
+        .map_err(ClientConnectionError::TransportFailure)
 }
 
 struct UnboundedBichannel<Sent, Received> {
@@ -123,7 +234,7 @@ fn unbounded_bichannel<From, To>(
 }
 
 async fn client_actor(
-    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    connection: transport::Connection,
     close_connection_channel: UnboundedBichannel<
         Option<CloseFrame>,
         Option<CloseFrame>,
@@ -132,10 +243,11 @@ async fn client_actor(
         ClientConnectionResult<comms::ServerMessage>,
         comms::ClientMessage,
     >,
+    pending_posts: PendingPosts,
 ) {
     println!("client actor spawned");
 
-    let (mut websocket_write, mut websocket_read) = websocket.split();
+    let (mut sender, mut receiver) = connection.split();
     let UnboundedBichannel {
         tx: close_tx,
         rx: mut close_rx,
@@ -145,59 +257,120 @@ async fn client_actor(
         rx: mut user_rx,
     } = channel_with_user;
 
-    tokio::join!(
-        async {
-            while let Some(Ok(message)) = websocket_read.next().await {
-                match message.clone() {
-                    Message::Binary(message_bytes) => {
-                        let server_message =
+    let reader = async {
+        while let Some(frame) = receiver.receive().await {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(error) => {
+                    let _ = user_tx.send(Err(
+                        ClientConnectionError::TransportFailure(error),
+                    ));
+                    break;
+                }
+            };
+            match frame.clone() {
+                Frame::Data(message_bytes) => {
+                    let server_message =
                         comms::ServerMessage::try_from_bytes(&message_bytes)
                             .map_err(|coding_error| {
                                 ClientConnectionError::MalformedServerMessage(
-                                    message,
+                                    frame,
                                     coding_error,
                                 )
                             });
-                        user_tx.send(server_message).expect(
-                            "receiver should not have been dropped/closed",
-                        );
-                    }
-                    Message::Close(close_frame) => {
-                        close_tx.send(close_frame).expect(
-                            "receiver should not have been dropped/closed",
-                        );
-                        break;
+                    let settled = match &server_message {
+                        Ok(comms::ServerMessage::PostAck {
+                            nonce,
+                            slot_number,
+                            ..
+                        }) => Some((
+                            nonce,
+                            Ok(PostOutcome::Committed(*slot_number)),
+                        )),
+                        Ok(comms::ServerMessage::CommandResponse {
+                            nonce,
+                            text,
+                            ..
+                        }) => Some((
+                            nonce,
+                            Ok(PostOutcome::CommandResponse(text.clone())),
+                        )),
+                        Ok(comms::ServerMessage::PostRejected {
+                            nonce,
+                            reason,
+                            ..
+                        }) => Some((
+                            nonce,
+                            Err(PostError::Rejected(reason.clone())),
+                        )),
+                        _ => None,
+                    };
+                    if let Some((nonce, outcome)) = settled {
+                        for ack_tx in pending_posts
+                            .lock()
+                            .expect("pending posts lock poisoned")
+                            .remove(nonce)
+                            .into_iter()
+                            .flatten()
+                        {
+                            let _ = ack_tx.send(outcome.clone());
+                        }
                     }
-                    _ => {}
+                    user_tx
+                        .send(server_message)
+                        .expect("receiver should not have been dropped/closed");
+                }
+                Frame::Close(close_frame) => {
+                    close_tx
+                        .send(close_frame)
+                        .expect("receiver should not have been dropped/closed");
+                    break;
                 }
             }
-        },
-        async {
-            loop {
-                let close_frame = close_rx.recv();
-                let client_message = user_rx.recv();
-                pin!(close_frame, client_message);
-                match future::select(client_message, close_frame).await {
-                    future::Either::Left((Some(client_message), _)) => {
-                        websocket_write
-                            .send(Message::binary(client_message.to_bytes()))
-                            .await
-                            .expect("todo");
-                    }
-                    future::Either::Right((Some(close_frame), _)) => {
-                        websocket_write
-                            .send(Message::Close(close_frame))
-                            .await
-                            .expect("failed to flush item to websocket ig?");
-                        break;
-                    }
-                    _ => {
+        }
+    };
+    let writer = async {
+        loop {
+            let close_frame = close_rx.recv();
+            let client_message = user_rx.recv();
+            pin!(close_frame, client_message);
+            match future::select(client_message, close_frame).await {
+                future::Either::Left((Some(client_message), _)) => {
+                    let frame =
+                        Frame::Data(Bytes::from(client_message.to_bytes()));
+                    if sender.send(frame).await.is_err() {
                         break;
                     }
                 }
+                future::Either::Right((Some(close_frame), _)) => {
+                    // The connection already replies to a close from the
+                    // server, e.g. when a moderator kicks us, so this
+                    // only matters if we're the ones closing.
+                    let _ = sender.send(Frame::Close(close_frame)).await;
+                    break;
+                }
+                _ => {
+                    break;
+                }
             }
-        },
-    );
+        }
+    };
+    // Nothing more can be sent once the connection stops receiving, e.g.
+    // when it drops without a close, but after sending a close the server's
+    // reply is still awaited.
+    pin!(reader);
+    tokio::select! {
+        () = &mut reader => {}
+        () = writer => reader.await,
+    }
+
+    // Refuse new posts first, so none can start waiting after every post
+    // still waiting on an acknowledgement fails.
+    user_rx.close();
+    pending_posts
+        .lock()
+        .expect("pending posts lock poisoned")
+        .clear();
 }
 
 /// Handle for a client connection that automatically closes the connection on
@@ -206,9 +379,46 @@ pub struct ClientConnection {
     close_connection_channel:
         UnboundedBichannel<Option<CloseFrame>, Option<CloseFrame>>,
     actor_thread: Option<JoinHandle<()>>,
+    tx: mpsc::UnboundedSender<comms::ClientMessage>,
+    pending_posts: PendingPosts,
 }
 
 impl ClientConnection {
+    /// Sends a [`comms::ClientMessage::Post`], resolving to the slot number
+    /// the server committed it to, or to the response if it was a slash
+    /// command that didn't commit anything, or to [`PostError::Rejected`] if
+    /// the server refused it. The post is sent right away, in
+    /// order with other messages, whether or not the future is awaited.
+    ///
+    /// To retry a post after [`PostError::ConnectionClosed`], post it again
+    /// with the same `nonce`, possibly on a new connection: the server
+    /// acknowledges it with the original slot instead of committing it twice.
+    pub fn post(
+        &self,
+        nonce: comms::Nonce,
+        channel: chat::ChannelName,
+        content: String,
+        attachments: Vec<chat::Attachment>,
+    ) -> impl Future<Output = Result<PostOutcome, PostError>> {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.pending_posts
+            .lock()
+            .expect("pending posts lock poisoned")
+            .entry(nonce.clone())
+            .or_default()
+            .push(ack_tx);
+        let sent = self.tx.send(comms::ClientMessage::Post {
+            nonce,
+            channel,
+            content,
+            attachments,
+        });
+        async move {
+            sent.map_err(|_| PostError::ConnectionClosed)?;
+            ack_rx.await.map_err(|_| PostError::ConnectionClosed)?
+        }
+    }
+
     // Manually closes the connection.
     pub fn close(mut self) {
         self.async_drop();
@@ -218,16 +428,28 @@ impl ClientConnection {
         if let Some(actor_thread) = mem::take(&mut self.actor_thread) {
             // Forgive me, Ferris, for I have async dropped.
             tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on( async {
-                self.close_connection_channel.tx.send(Some(CloseFrame {
-                    code: CloseCode::Normal,
-                    reason:"client connection handle dropped".into(),
-                })).expect("channel with actor should be open when ClientConnection is being dropped");
-                if let Some(close_frame_response) = self.close_connection_channel.rx.recv().await {
-                    println!("client closing connection: {:?}", close_frame_response);
-                }
-                actor_thread.abort();
-            });
+                tokio::runtime::Handle::current().block_on(async {
+                    // Fails if the actor already stopped because the connection
+                    // dropped, leaving nothing to close.
+                    let closing = self.close_connection_channel.tx.send(Some(
+                        CloseFrame {
+                            code: CloseCode::Normal,
+                            reason: "client connection handle dropped"
+                                .to_owned(),
+                        },
+                    ));
+                    if closing.is_ok() {
+                        if let Some(close_frame_response) =
+                            self.close_connection_channel.rx.recv().await
+                        {
+                            println!(
+                                "client closing connection: {:?}",
+                                close_frame_response
+                            );
+                        }
+                    }
+                    actor_thread.abort();
+                });
             });
         }
     }
@@ -239,9 +461,14 @@ impl Drop for ClientConnection {
     }
 }
 
-/// Spawns a client thread to communicate with the given server over a
-/// TLS-encrypted websocket, returning a client handle. The connection is closed
-/// when the handle is dropped.
+/// Spawns a client thread to communicate with the given server, returning a
+/// client handle. The connection is closed when the handle is dropped.
+///
+/// `wss://` addresses connect over a TLS-encrypted websocket, and
+/// `quic://host:port` addresses over QUIC, which keeps the connection open
+/// across network changes and reconnects faster. `ws://` addresses connect
+/// over an unencrypted websocket, which servers only accept on localhost.
+/// See [`connect_to_unix_socket`] for servers on the same machine.
 ///
 /// # Example
 ///
@@ -260,23 +487,68 @@ pub async fn connect_to_server<R: IntoClientRequest + Unpin>(
     mpsc::UnboundedSender<comms::ClientMessage>,
     mpsc::UnboundedReceiver<ClientConnectionResult<comms::ServerMessage>>,
 )> {
-    let websocket = open_websocket(server_address).await?;
+    let request = server_address.into_client_request().map_err(|error| {
+        ClientConnectionError::WebSocketFailure(Box::new(error))
+    })?;
+    let connection = if request.uri().scheme_str() == Some("quic") {
+        open_quic(request.uri()).await?
+    } else {
+        open_websocket(request).await?
+    };
+    Ok(start_client(connection))
+}
 
+/// Like [`connect_to_server`], but for a server on the same machine, through
+/// the `client.sock` Unix-domain socket at `path` in its data directory. No
+/// certificate is needed.
+pub async fn connect_to_unix_socket(
+    path: impl AsRef<Path>,
+) -> ClientConnectionResult<(
+    ClientConnection,
+    mpsc::UnboundedSender<comms::ClientMessage>,
+    mpsc::UnboundedReceiver<ClientConnectionResult<comms::ServerMessage>>,
+)> {
+    let unix_stream =
+        net::UnixStream::connect(path).await.map_err(|error| {
+            ClientConnectionError::TransportFailure(error.into())
+        })?;
+    // The host is never checked, but the handshake needs one.
+    let (websocket, _) =
+        tokio_tungstenite::client_async("ws://localhost/", unix_stream)
+            .await
+            .map_err(|error| {
+                ClientConnectionError::WebSocketFailure(Box::new(error))
+            })?;
+    Ok(start_client(websocket.into()))
+}
+
+fn start_client(
+    connection: transport::Connection,
+) -> (
+    ClientConnection,
+    mpsc::UnboundedSender<comms::ClientMessage>,
+    mpsc::UnboundedReceiver<ClientConnectionResult<comms::ServerMessage>>,
+) {
     let (local_bichannel, actor_bichannel) = unbounded_bichannel();
     let (user_bichannel, other_actor_bichannel) = unbounded_bichannel();
 
+    let pending_posts = PendingPosts::default();
+
     let actor_thread = tokio::spawn(client_actor(
-        websocket,
+        connection,
         actor_bichannel,
         other_actor_bichannel,
+        pending_posts.clone(),
     ));
 
-    Ok((
+    (
         ClientConnection {
             close_connection_channel: local_bichannel,
             actor_thread: Some(actor_thread),
+            tx: user_bichannel.tx.clone(),
+            pending_posts,
         },
         user_bichannel.tx,
         user_bichannel.rx,
-    ))
+    )
 }
@@ -0,0 +1,41 @@
+use client_connect::attachments::{self, Upload, UPLOAD_WINDOW};
+use tokio::sync::mpsc;
+
+/// Offsets of the chunks waiting in `rx`.
+fn sent_offsets(
+    rx: &mut mpsc::UnboundedReceiver<comms::ClientMessage>,
+) -> Vec<u64> {
+    let mut offsets = vec![];
+    while let Ok(message) = rx.try_recv() {
+        if let comms::ClientMessage::UploadChunk { offset, .. } = message {
+            offsets.push(offset);
+        }
+    }
+    offsets
+}
+
+#[test]
+fn uploads_only_run_a_window_ahead_of_the_server() {
+    let chunk_size = comms::ATTACHMENT_CHUNK_SIZE as u64;
+    let size = UPLOAD_WINDOW + 2 * chunk_size;
+    let mut upload =
+        Upload::new("big.bin".to_owned(), vec![7; size as usize]).unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    attachments::send_upload(&tx, &mut upload).unwrap();
+    let window = (0..UPLOAD_WINDOW).step_by(chunk_size as usize);
+    assert_eq!(sent_offsets(&mut rx), window.collect::<Vec<_>>());
+    assert_eq!(upload.progress().transferred, 0);
+
+    // Each acknowledged chunk lets one more go.
+    let progress = upload.acknowledge(chunk_size);
+    attachments::send_chunks(&tx, &mut upload).unwrap();
+    assert_eq!(sent_offsets(&mut rx), [UPLOAD_WINDOW]);
+    assert_eq!(progress.transferred, chunk_size);
+
+    upload.acknowledge(size - chunk_size);
+    attachments::send_chunks(&tx, &mut upload).unwrap();
+    assert_eq!(sent_offsets(&mut rx), [UPLOAD_WINDOW + chunk_size]);
+    attachments::send_chunks(&tx, &mut upload).unwrap();
+    assert!(sent_offsets(&mut rx).is_empty());
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use client_connect::PostError;
+use comms::{Codable, Nonce};
+use futures_util::StreamExt;
+use tokio::{net::TcpListener, time::timeout};
+
+/// Stands in for a server that accepts a WebSocket, waits for one post, and
+/// then drops the connection without closing it, returning the post.
+async fn dropping_server() -> (String, tokio::task::JoinHandle<Nonce>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("ws://{}/", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut websocket =
+            tokio_tungstenite::accept_async(stream).await.unwrap();
+        let message = websocket.next().await.unwrap().unwrap();
+        let comms::ClientMessage::Post { nonce, .. } =
+            comms::ClientMessage::try_from_bytes(&message.into_data()).unwrap()
+        else {
+            panic!("expected a post");
+        };
+        nonce
+    });
+    (address, handle)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn posts_fail_when_the_connection_drops_mid_post() {
+    let (address, server) = dropping_server().await;
+    let (connection, _tx, mut rx) =
+        client_connect::connect_to_server(address).await.unwrap();
+
+    let nonce = Nonce::new_unique();
+    let post = connection.post(
+        nonce.clone(),
+        chat::ChannelName::default(),
+        "hello".to_owned(),
+        vec![],
+    );
+    assert_eq!(server.await.unwrap(), nonce);
+
+    let outcome = timeout(Duration::from_secs(5), post)
+        .await
+        .expect("post should fail rather than hang");
+    assert!(matches!(outcome, Err(PostError::ConnectionClosed)));
+
+    // The connection's end is reported, and later posts fail right away
+    // instead of panicking.
+    let reported = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+    assert!(matches!(reported, Some(Err(_)) | None));
+    let retry = connection.post(
+        nonce,
+        chat::ChannelName::default(),
+        "hello".to_owned(),
+        vec![],
+    );
+    let outcome = timeout(Duration::from_secs(5), retry).await.unwrap();
+    assert!(matches!(outcome, Err(PostError::ConnectionClosed)));
+}
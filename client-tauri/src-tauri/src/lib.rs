@@ -4,11 +4,18 @@ fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
+/// Parses a message the same way the TUI does, so the frontend only has to
+/// render the resulting [`chat::markdown::Document`].
+#[tauri::command]
+fn parse_markdown(text: &str) -> chat::markdown::Document {
+    chat::markdown::parse(text)
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet])
+        .invoke_handler(tauri::generate_handler![greet, parse_markdown])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
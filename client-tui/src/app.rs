@@ -1,7 +1,16 @@
 // TODO: call for history on scroll up
 
-use std::{io, sync::Arc, time};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time,
+};
 
+use chat::timeline::Timeline;
+use client_connect::attachments::{self, Download, SendError, Upload};
 use copypasta::{ClipboardContext, ClipboardProvider};
 use crossterm::{
     cursor::{DisableBlinking, EnableBlinking, SetCursorStyle},
@@ -21,10 +30,13 @@ use ratatui::{
 };
 use tokio::sync::{mpsc, RwLock};
 
-use crate::vim;
+use crate::{command_line::ExCommand, rich_text, vim};
 
 const TIMESTAMP_LENGTH: usize = 34;
 
+/// How many of the newest entries to load on startup when everything is read.
+const INITIAL_HISTORY: usize = 50;
+
 /// Indicates which part of the UI is currently in “focus.”
 #[derive(Debug, PartialEq)]
 pub enum Focus {
@@ -32,7 +44,34 @@ pub enum Focus {
     Input,
 }
 
+/// A one-line prompt opened from normal mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Prompt {
+    /// `:`, for an [`ExCommand`].
+    ExCommand,
+    /// `/`, for a server-side search.
+    Search,
+}
+
+impl Prompt {
+    fn prefix(&self) -> char {
+        match self {
+            Prompt::ExCommand => ':',
+            Prompt::Search => '/',
+        }
+    }
+}
+
+/// Results of the last `/` search, stepped through with `n` and `N`.
+struct SearchResults {
+    client_id: comms::ClientId,
+    query: String,
+    hits: Vec<comms::SearchHit>,
+    current: usize,
+}
+
 pub struct App {
+    username: String,
     input: String,
     editing_context: vim::EditingContext,
     exit: bool,
@@ -40,12 +79,42 @@ pub struct App {
     command_buffer: vim::CommandBuffer,
     clipboard: ClipboardContext,
     tx: mpsc::UnboundedSender<comms::ClientMessage>,
+    server_events: mpsc::UnboundedReceiver<comms::ServerMessage>,
     visual_anchor: Option<usize>,
+    /// Feedback for the last `:` command, shown in the input box title.
+    status: Option<String>,
+    uploads: HashMap<comms::ClientId, Upload>,
+    /// Uploads whose files were opened and hashed off the UI loop, ready to
+    /// send, or why they couldn't be.
+    opened_uploads_tx: mpsc::UnboundedSender<Result<Upload, String>>,
+    opened_uploads: mpsc::UnboundedReceiver<Result<Upload, String>>,
+    downloads: HashMap<comms::ClientId, Download>,
+    prompt: Option<Prompt>,
+    search: Option<SearchResults>,
+    /// Slot number to move the cursor to once its history has arrived.
+    pending_jump: Option<usize>,
+    /// The first slot that was unread when we connected, which the "new
+    /// messages" divider is drawn above.
+    first_unread_slot: Option<usize>,
+    /// The newest slot the server knows we've read, from any session. `None`
+    /// until the server welcomes us.
+    read_slot: Option<Option<usize>>,
+    /// The oldest slot the server still has, from [`Timeline`].
+    history_start: usize,
+    /// Slash commands the server handles, for completion.
+    commands: Vec<comms::CommandInfo>,
+    topic: Option<String>,
 }
 
 impl App {
-    pub fn new(tx: mpsc::UnboundedSender<comms::ClientMessage>) -> Self {
+    pub fn new(
+        username: String,
+        tx: mpsc::UnboundedSender<comms::ClientMessage>,
+        server_events: mpsc::UnboundedReceiver<comms::ServerMessage>,
+    ) -> Self {
+        let (opened_uploads_tx, opened_uploads) = mpsc::unbounded_channel();
         Self {
+            username,
             input: String::new(),
             editing_context: vim::EditingContext::default(),
             exit: false,
@@ -56,25 +125,44 @@ impl App {
                 copypasta::ClipboardContext::new().unwrap()
             }),
             tx,
+            server_events,
             visual_anchor: None,
+            status: None,
+            uploads: HashMap::new(),
+            opened_uploads_tx,
+            opened_uploads,
+            downloads: HashMap::new(),
+            prompt: None,
+            search: None,
+            pending_jump: None,
+            first_unread_slot: None,
+            read_slot: None,
+            history_start: 0,
+            commands: vec![],
+            topic: None,
         }
     }
 
     pub async fn run(
         &mut self,
         terminal: &mut DefaultTerminal,
-        messages: Arc<RwLock<Vec<chat::Entry>>>,
+        timeline: Arc<RwLock<Timeline>>,
     ) -> Result<(), io::Error> {
         let mut interval =
             tokio::time::interval(time::Duration::from_millis(20));
         while !self.exit {
             {
-                let messages = messages.read().await;
-                let messages_ref = &*messages;
+                let timeline = timeline.read().await;
+                let messages_ref = timeline.entries();
+                self.history_start = timeline.history_start();
+                self.handle_opened_uploads();
+                self.handle_server_events(messages_ref);
+                self.resolve_pending_jump(messages_ref);
+                self.report_read_position(messages_ref);
                 terminal.draw(|frame| self.draw(messages_ref, frame))?;
                 self.update_cursor_shape(terminal)?;
                 self.handle_events(messages_ref)?;
-                drop(messages);
+                drop(timeline);
             }
             interval.tick().await;
         }
@@ -116,32 +204,58 @@ impl App {
         frame: &mut Frame,
         area: Rect,
     ) {
-        let text_lines: Vec<Line> = messages
+        let mut text_lines: Vec<Line> = messages
             .iter()
             .enumerate()
             .map(|(i, message)| {
-                let default_line = Line::from_iter([
+                let content_spans = match &message.content {
+                    chat::Content::Original(message_text)
+                    | chat::Content::Edited(message_text) => {
+                        rich_text::document_spans(&message_text.parse())
+                    }
+                    chat::Content::Deleted => vec![Span::styled(
+                        "(deleted)",
+                        Style::new().dim().italic(),
+                    )],
+                };
+                let default_line = [
                     Span::styled(
                         format!("[{}] ", message.metadata.timestamp),
                         Style::new().dim(),
                     ),
                     Span::styled(
-                        &message.metadata.username,
+                        message.metadata.username.clone(),
                         Style::new().yellow(),
                     ),
-                    Span::raw(format!(
-                        ": {}",
-                        message.text_content().expect("todo: deleted messaged")
-                    )),
+                    Span::raw(separator(message)),
+                ]
+                .into_iter()
+                .chain(content_spans.into_iter().map(
+                    |span| match message.kind {
+                        chat::EntryKind::Message => span,
+                        chat::EntryKind::Action => span.italic(),
+                        chat::EntryKind::Notice => span.italic().dim(),
+                    },
+                ))
+                .chain([Span::styled(
+                    if matches!(message.content, chat::Content::Edited(_)) {
+                        " (edited)"
+                    } else {
+                        ""
+                    },
+                    Style::new().dim(),
+                )])
+                .chain(message.attachments.iter().map(|attachment| {
                     Span::styled(
-                        if matches!(message.content, chat::Content::Edited(_)) {
-                            " (edited)"
-                        } else {
-                            ""
-                        },
-                        Style::new().dim(),
-                    ),
-                ]);
+                        format!(
+                            " [{} ({})]",
+                            attachment.file_name,
+                            format_size(attachment.size)
+                        ),
+                        Style::new().cyan(),
+                    )
+                }))
+                .collect::<Line>();
 
                 if i == self.messages_cursor
                     && self.editing_context.focus == Focus::Messages
@@ -149,12 +263,11 @@ impl App {
                 {
                     if let Some(anchor) = self.visual_anchor {
                         let formatted_text = format!(
-                            "[{}] {}: {}{}",
+                            "[{}] {}{}{}{}",
                             message.metadata.timestamp,
                             message.metadata.username,
-                            message
-                                .text_content()
-                                .expect("todo: deleted message"),
+                            separator(message),
+                            message.text_content().unwrap_or_default(),
                             if matches!(
                                 message.content,
                                 chat::Content::Edited(_)
@@ -188,6 +301,12 @@ impl App {
                 }
             })
             .collect();
+        if let Some(divider_index) = self.divider_index(messages) {
+            text_lines.insert(
+                divider_index,
+                Line::from("── new messages ──").red().centered(),
+            );
+        }
 
         let inner_height = area.height.saturating_sub(2);
         let total_lines = text_lines.len() as u16;
@@ -195,25 +314,29 @@ impl App {
         if self.messages_cursor >= messages.len() {
             self.messages_cursor = messages.len().saturating_sub(1);
         }
+        let cursor_line = self.line_of(messages, self.messages_cursor) as u16;
 
         let max_scroll = total_lines.saturating_sub(inner_height);
         self.editing_context.scroll_offset =
             self.editing_context.scroll_offset.min(max_scroll);
 
-        if (self.messages_cursor as u16) < self.editing_context.scroll_offset {
-            self.editing_context.scroll_offset = self.messages_cursor as u16;
-        } else if (self.messages_cursor as u16)
+        if cursor_line < self.editing_context.scroll_offset {
+            self.editing_context.scroll_offset = cursor_line;
+        } else if cursor_line
             >= (self.editing_context.scroll_offset + inner_height)
         {
             self.editing_context.scroll_offset =
-                (self.messages_cursor as u16).saturating_sub(inner_height - 1);
+                cursor_line.saturating_sub(inner_height - 1);
         }
 
         let messages_paragraph = Paragraph::new(Text::from(text_lines))
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title(" Messages ")
+                    .title(match &self.topic {
+                        Some(topic) => format!(" Messages — {} ", topic),
+                        None => " Messages ".to_owned(),
+                    })
                     .border_set(border::THICK),
             )
             .wrap(Wrap { trim: true })
@@ -265,17 +388,19 @@ impl App {
         // }
 
         if self.editing_context.focus == Focus::Messages {
-            let relative_y = (self.messages_cursor as u16)
-                .saturating_sub(self.editing_context.scroll_offset);
+            let relative_y =
+                cursor_line.saturating_sub(self.editing_context.scroll_offset);
 
             let message = messages
                 .get(self.messages_cursor)
                 .expect("message does not exist??");
-            let line_length =
-                message.text_content().expect("message is deleted").len();
+            let line_length = message.text_content().unwrap_or_default().len();
 
-            let metadata_offset =
-                TIMESTAMP_LENGTH + message.metadata.username.len();
+            // `TIMESTAMP_LENGTH` counts a message's `: ` separator.
+            let metadata_offset = TIMESTAMP_LENGTH
+                + message.metadata.username.len()
+                + separator(message).len()
+                - ": ".len();
             let relative_x = (self.editing_context.cursor_pos).min(line_length)
                 + metadata_offset;
 
@@ -312,8 +437,12 @@ impl App {
             .constraints([Constraint::Min(1), Constraint::Length(5)])
             .split(area);
 
+        let title = match &self.status {
+            Some(status) => format!(" Input — {} ", status),
+            None => " Input ".to_owned(),
+        };
         let input_paragraph = Paragraph::new(Text::from(displayed_text))
-            .block(Block::default().borders(Borders::ALL).title(" Input "))
+            .block(Block::default().borders(Borders::ALL).title(title))
             .wrap(Wrap { trim: false });
 
         let mode_span = self.mode_indicator_span();
@@ -324,18 +453,12 @@ impl App {
         frame.render_widget(mode_paragraph, input_chunks[1]);
 
         if self.editing_context.focus == Focus::Input {
-            let line_index = if available_width_for_text > 0 {
-                self.editing_context.cursor_pos as u16
-                    / available_width_for_text
-            } else {
-                0
-            };
-            let col_index = if available_width_for_text > 0 {
-                self.editing_context.cursor_pos as u16
-                    % available_width_for_text
-            } else {
-                0
-            };
+            let line_index = (self.editing_context.cursor_pos as u16)
+                .checked_div(available_width_for_text)
+                .unwrap_or(0);
+            let col_index = (self.editing_context.cursor_pos as u16)
+                .checked_rem(available_width_for_text)
+                .unwrap_or(0);
 
             let cursor_x = input_chunks[0].x + 1 + col_index;
             let cursor_y = input_chunks[0].y + 1 + line_index;
@@ -408,6 +531,26 @@ impl App {
                 self.command_buffer.clear();
                 return;
             }
+            KeyCode::Char(':') if self.command_buffer.is_empty() => {
+                self.open_prompt(Prompt::ExCommand);
+                return;
+            }
+            KeyCode::Char('/') if self.command_buffer.is_empty() => {
+                self.open_prompt(Prompt::Search);
+                return;
+            }
+            KeyCode::Char('n')
+                if self.command_buffer.is_empty() && self.search.is_some() =>
+            {
+                self.step_search(messages, 1);
+                return;
+            }
+            KeyCode::Char('N')
+                if self.command_buffer.is_empty() && self.search.is_some() =>
+            {
+                self.step_search(messages, -1);
+                return;
+            }
 
             // Use the Vim engine for the rest
             KeyCode::Char(c) => {
@@ -417,7 +560,7 @@ impl App {
                             .get(self.messages_cursor)
                             .unwrap()
                             .text_content()
-                            .unwrap()
+                            .unwrap_or_default()
                             .len();
                     }
                     Focus::Input => {
@@ -436,7 +579,7 @@ impl App {
                     .get(self.messages_cursor)
                     .unwrap()
                     .text_content()
-                    .unwrap()
+                    .unwrap_or_default()
                     .to_string();
             }
 
@@ -461,6 +604,10 @@ impl App {
         match key_event.code {
             KeyCode::Esc => {
                 self.editing_context.mode = vim::Mode::Normal;
+                if self.prompt.take().is_some() {
+                    self.input.clear();
+                    self.editing_context.cursor_pos = 0;
+                }
             }
             KeyCode::Left => {
                 self.editing_context.cursor_pos =
@@ -471,11 +618,10 @@ impl App {
                     (self.editing_context.cursor_pos + 1).min(self.input.len());
             }
             KeyCode::Enter => self.send_message(messages),
-            KeyCode::Backspace => {
-                if self.editing_context.cursor_pos > 0 {
-                    self.editing_context.cursor_pos -= 1;
-                    self.input.remove(self.editing_context.cursor_pos);
-                }
+            KeyCode::Tab => self.complete_command(),
+            KeyCode::Backspace if self.editing_context.cursor_pos > 0 => {
+                self.editing_context.cursor_pos -= 1;
+                self.input.remove(self.editing_context.cursor_pos);
             }
             KeyCode::Char(c) => {
                 self.input.insert(self.editing_context.cursor_pos, c);
@@ -514,7 +660,7 @@ impl App {
                             .get(self.messages_cursor)
                             .expect("message does not exist??")
                             .text_content()
-                            .expect("message is deleted")
+                            .unwrap_or_default()
                             .len();
                         self.editing_context.cursor_pos =
                             (self.editing_context.cursor_pos + 1)
@@ -536,7 +682,7 @@ impl App {
                                 .get(self.messages_cursor)
                                 .expect("message does not exist??")
                                 .text_content()
-                                .expect("message is deleted"),
+                                .unwrap_or_default(),
                         );
                     }
                     Focus::Input => {
@@ -580,33 +726,587 @@ impl App {
     }
 
     fn send_message(&mut self, messages: &[chat::Entry]) {
-        let trimmed = self.input.trim();
+        let trimmed = self.input.trim().to_owned();
+        // A prompt whose prefix was deleted is just a message.
+        if let Some(prompt) = self.prompt.take() {
+            if let Some(line) = trimmed.strip_prefix(prompt.prefix()) {
+                match prompt {
+                    Prompt::ExCommand => match ExCommand::parse(line) {
+                        Ok(command) => self.run_ex_command(messages, command),
+                        Err(error) => self.status = Some(error.to_string()),
+                    },
+                    Prompt::Search => self.search(line),
+                }
+                self.input.clear();
+                self.editing_context.cursor_pos = 0;
+                return;
+            }
+        }
         if !trimmed.is_empty() {
-            self.tx
-                .send(comms::ClientMessage::Post {
-                    username: "jeff".to_string(),
-                    content: trimmed.to_string(),
-                })
-                .expect("channel closed on server");
+            self.post(trimmed, vec![]);
         }
         self.input.clear();
         self.editing_context.cursor_pos = 0;
         self.scroll_to_bottom(messages);
     }
 
+    /// Completes the slash command name being typed at the start of the
+    /// input as far as it's unambiguous, listing the candidates.
+    fn complete_command(&mut self) {
+        let cursor_pos = self.editing_context.cursor_pos;
+        let Some(typed) = self.input[..cursor_pos].strip_prefix('/') else {
+            return;
+        };
+        if self.prompt.is_some()
+            || typed.starts_with('/')
+            || typed.contains(char::is_whitespace)
+        {
+            return;
+        }
+        let candidates = self
+            .commands
+            .iter()
+            .filter(|command| command.name.starts_with(typed))
+            .collect::<Vec<_>>();
+        let Some(first) = candidates.first() else {
+            self.status = Some(format!("No command starts with /{}", typed));
+            return;
+        };
+        let mut common = first.name.as_str();
+        for candidate in &candidates[1..] {
+            let length = common
+                .char_indices()
+                .zip(candidate.name.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(
+                    common.len().min(candidate.name.len()),
+                    |((index, _), _)| index,
+                );
+            common = &common[..length];
+        }
+        let mut completion = common[typed.len()..].to_owned();
+        if let [command] = candidates[..] {
+            completion.push(' ');
+            self.status =
+                Some(format!("{} — {}", command.usage, command.description));
+        } else {
+            self.status = Some(
+                candidates
+                    .iter()
+                    .map(|command| format!("/{}", command.name))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        }
+        self.input.insert_str(cursor_pos, &completion);
+        self.editing_context.cursor_pos += completion.len();
+    }
+
+    fn open_prompt(&mut self, prompt: Prompt) {
+        self.prompt = Some(prompt);
+        self.input = prompt.prefix().to_string();
+        self.editing_context.focus = Focus::Input;
+        self.editing_context.mode = vim::Mode::Insert;
+        self.editing_context.cursor_pos = self.input.len();
+    }
+
+    fn search(&mut self, query: &str) {
+        let query = query.trim();
+        if query.is_empty() {
+            return;
+        }
+        let client_id = comms::ClientId::new_unique_per_client();
+        self.tx
+            .send(comms::ClientMessage::Search {
+                client_id: client_id.clone(),
+                query: comms::SearchQuery {
+                    text: query.to_owned(),
+                    channel: Some(chat::ChannelName::default()),
+                    ..Default::default()
+                },
+                limit: comms::MAX_SEARCH_PAGE_SIZE,
+                after: None,
+            })
+            .expect("channel closed on server");
+        self.search = Some(SearchResults {
+            client_id,
+            query: query.to_owned(),
+            hits: vec![],
+            current: 0,
+        });
+        self.status = Some(format!("Searching for {}", query));
+    }
+
+    /// Moves `step` hits through the search results, wrapping around. Hits
+    /// are ordered newest first, so `n` goes back in time.
+    fn step_search(&mut self, messages: &[chat::Entry], step: isize) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        if search.hits.is_empty() {
+            return;
+        }
+        search.current = (search.current as isize + step)
+            .rem_euclid(search.hits.len() as isize)
+            as usize;
+        self.jump_to_current_hit(messages);
+    }
+
+    fn jump_to_current_hit(&mut self, messages: &[chat::Entry]) {
+        let Some(search) = &self.search else {
+            return;
+        };
+        let slot_number = search.hits[search.current].entry.slot_number;
+        self.status = Some(format!(
+            "/{} [{}/{}]",
+            search.query,
+            search.current + 1,
+            search.hits.len()
+        ));
+        self.jump_to_slot(messages, slot_number);
+    }
+
+    /// Moves the cursor to `slot_number`, first requesting its history if it
+    /// isn't loaded yet.
+    fn jump_to_slot(&mut self, messages: &[chat::Entry], slot_number: usize) {
+        if !messages
+            .iter()
+            .any(|entry| entry.slot_number == slot_number)
+        {
+            // A hit older than everything loaded is loaded along with the
+            // history between them. One in a gap left by deletion or
+            // compaction, or newer than everything loaded, comes with the
+            // entries just before it, and the rest of the gap is loaded like
+            // any other.
+            let (count, up_to_slot_number) = match messages.first() {
+                Some(first) if slot_number < first.slot_number => {
+                    (first.slot_number - slot_number, first.slot_number - 1)
+                }
+                _ => {
+                    let loaded_before = messages
+                        .iter()
+                        .rev()
+                        .find(|entry| entry.slot_number < slot_number)
+                        .map_or(0, |entry| entry.slot_number + 1);
+                    let count = (slot_number + 1)
+                        .saturating_sub(loaded_before)
+                        .min(INITIAL_HISTORY);
+                    (count, slot_number)
+                }
+            };
+            self.tx
+                .send(comms::ClientMessage::Request {
+                    client_id: comms::ClientId::new_unique_per_client(),
+                    channel: chat::ChannelName::default(),
+                    count,
+                    up_to_slot_number: Some(up_to_slot_number),
+                })
+                .expect("channel closed on server");
+        }
+        self.pending_jump = Some(slot_number);
+        self.resolve_pending_jump(messages);
+    }
+
+    fn resolve_pending_jump(&mut self, messages: &[chat::Entry]) {
+        let Some(slot_number) = self.pending_jump else {
+            return;
+        };
+        if let Some(index) = messages
+            .iter()
+            .position(|entry| entry.slot_number == slot_number)
+        {
+            self.pending_jump = None;
+            self.messages_cursor = index;
+            self.editing_context.focus = Focus::Messages;
+            self.editing_context.mode = vim::Mode::Normal;
+            self.editing_context.cursor_pos = 0;
+        }
+    }
+
+    /// Index in the messages area of the "new messages" divider, which is
+    /// drawn above the first unread entry.
+    fn divider_index(&self, messages: &[chat::Entry]) -> Option<usize> {
+        let first_unread_slot = self.first_unread_slot?;
+        messages
+            .iter()
+            .position(|entry| entry.slot_number == first_unread_slot)
+    }
+
+    /// The line the message at `index` is drawn on, accounting for the
+    /// divider.
+    fn line_of(&self, messages: &[chat::Entry], index: usize) -> usize {
+        match self.divider_index(messages) {
+            Some(divider_index) if divider_index <= index => index + 1,
+            _ => index,
+        }
+    }
+
+    /// Loads history for the default channel, jumping to the first unread
+    /// entry if there is one.
+    fn handle_welcome(&mut self, channels: Vec<comms::ChannelReadState>) {
+        self.tx
+            .send(comms::ClientMessage::ListCommands {
+                client_id: comms::ClientId::new_unique_per_client(),
+            })
+            .expect("channel closed on server");
+        let default_channel = chat::ChannelName::default();
+        let Some(state) = channels
+            .into_iter()
+            .find(|state| state.channel == default_channel)
+        else {
+            // Nothing has been posted yet.
+            self.read_slot = Some(None);
+            return;
+        };
+        self.read_slot = Some(state.last_read_slot);
+        self.topic = state.topic;
+        let Some(head_slot) = state.head_slot else {
+            return;
+        };
+
+        let mut count = INITIAL_HISTORY;
+        if state.unread_count > 0 {
+            // Unread entries may have been compacted away since.
+            let first_unread_slot = state
+                .last_read_slot
+                .map_or(0, |slot_number| slot_number + 1)
+                .max(state.history_start);
+            count = count.max(head_slot + 1 - first_unread_slot);
+            self.first_unread_slot = Some(first_unread_slot);
+            self.pending_jump = Some(first_unread_slot);
+            self.status = Some(format!(
+                "{} unread, {} mention(s)",
+                state.unread_count, state.mention_count
+            ));
+        }
+        self.tx
+            .send(comms::ClientMessage::Request {
+                client_id: comms::ClientId::new_unique_per_client(),
+                channel: default_channel,
+                count,
+                up_to_slot_number: None,
+            })
+            .expect("channel closed on server");
+    }
+
+    /// Tells the server how far we've read: everything loaded while typing,
+    /// or up to the cursor while browsing.
+    fn report_read_position(&mut self, messages: &[chat::Entry]) {
+        let Some(read_slot) = self.read_slot else {
+            return;
+        };
+        if self.pending_jump.is_some() {
+            return;
+        }
+        let seen = match self.editing_context.focus {
+            Focus::Input => messages.last(),
+            Focus::Messages => messages.get(self.messages_cursor),
+        };
+        let Some(seen_slot) = seen.map(|entry| entry.slot_number) else {
+            return;
+        };
+        if read_slot >= Some(seen_slot) {
+            return;
+        }
+        self.read_slot = Some(Some(seen_slot));
+        self.tx
+            .send(comms::ClientMessage::MarkRead {
+                channel: chat::ChannelName::default(),
+                slot_number: seen_slot,
+            })
+            .expect("channel closed on server");
+    }
+
+    fn post(&self, content: String, attachments: Vec<chat::Attachment>) {
+        self.tx
+            .send(comms::ClientMessage::Post {
+                nonce: comms::Nonce::new_unique(),
+                channel: chat::ChannelName::default(),
+                content,
+                attachments,
+            })
+            .expect("channel closed on server");
+    }
+
+    fn run_ex_command(&mut self, messages: &[chat::Entry], command: ExCommand) {
+        match command {
+            ExCommand::Upload(path) => self.upload(&path),
+            ExCommand::Save(slot_number) => {
+                let Some(entry) = messages
+                    .iter()
+                    .find(|entry| entry.slot_number == slot_number)
+                else {
+                    self.status =
+                        Some(format!("No loaded message #{}", slot_number));
+                    return;
+                };
+                if entry.attachments.is_empty() {
+                    self.status = Some(format!(
+                        "Message #{} has no attachments",
+                        slot_number
+                    ));
+                    return;
+                }
+                for attachment in &entry.attachments {
+                    let download = Download::new(attachment.clone());
+                    self.tx
+                        .send(download.request_message())
+                        .expect("channel closed on server");
+                    self.downloads
+                        .insert(download.client_id().clone(), download);
+                }
+                self.status = Some(format!(
+                    "Downloading {} attachment(s)",
+                    entry.attachments.len()
+                ));
+            }
+            ExCommand::Export { format, path } => {
+                let channel = chat::ChannelName::default();
+                let path = path.unwrap_or_else(|| {
+                    PathBuf::from(format!(
+                        "{}.{}",
+                        channel.0,
+                        format.extension()
+                    ))
+                });
+                let document = chat::export::export(
+                    messages,
+                    format,
+                    &channel.to_string(),
+                );
+                self.status = Some(match fs::write(&path, document) {
+                    Ok(()) => format!(
+                        "Exported {} message(s) to {}",
+                        messages.len(),
+                        path.display()
+                    ),
+                    Err(error) => {
+                        format!(
+                            "Cannot export to {}: {}",
+                            path.display(),
+                            error
+                        )
+                    }
+                });
+            }
+        }
+    }
+
+    /// Opens and hashes the file at `path` in the background, since it may be
+    /// large, then starts uploading it.
+    fn upload(&mut self, path: &Path) {
+        let file_name = path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        self.status = Some(format!("Reading {}", file_name));
+        let path = path.to_owned();
+        let opened_uploads_tx = self.opened_uploads_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let upload =
+                open_upload(&path, file_name.clone()).map_err(|error| {
+                    format!("Cannot upload {}: {}", file_name, error)
+                });
+            let _ = opened_uploads_tx.send(upload);
+        });
+    }
+
+    /// Starts sending uploads once their files are opened.
+    fn handle_opened_uploads(&mut self) {
+        while let Ok(opened) = self.opened_uploads.try_recv() {
+            let mut upload = match opened {
+                Ok(upload) => upload,
+                Err(error) => {
+                    self.status = Some(error);
+                    continue;
+                }
+            };
+            match attachments::send_upload(&self.tx, &mut upload) {
+                Ok(()) => {
+                    self.status =
+                        Some(format!("Uploading {}", upload.file_name()));
+                    self.uploads.insert(upload.client_id().clone(), upload);
+                }
+                Err(SendError::ConnectionClosed) => {
+                    panic!("channel closed on server")
+                }
+                Err(error) => {
+                    self.status = Some(format!(
+                        "Cannot upload {}: {}",
+                        upload.file_name(),
+                        error
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Handles read positions and replies to transfers and searches started
+    /// from prompts.
+    fn handle_server_events(&mut self, messages: &[chat::Entry]) {
+        while let Ok(server_message) = self.server_events.try_recv() {
+            match server_message {
+                comms::ServerMessage::Welcome { channels } => {
+                    self.handle_welcome(channels);
+                }
+                comms::ServerMessage::ReadPosition {
+                    channel,
+                    last_read_slot,
+                } if channel == chat::ChannelName::default() => {
+                    // Another session of ours read further.
+                    if let Some(read_slot) = &mut self.read_slot {
+                        *read_slot = (*read_slot).max(Some(last_read_slot));
+                    }
+                }
+                comms::ServerMessage::UploadProgress {
+                    client_id,
+                    received,
+                } => {
+                    let Some(upload) = self.uploads.get_mut(&client_id) else {
+                        continue;
+                    };
+                    let progress = upload.acknowledge(received);
+                    self.status = Some(
+                        match attachments::send_chunks(&self.tx, upload) {
+                            Ok(()) => format!(
+                                "Uploading {}: {:.0}%",
+                                upload.file_name(),
+                                progress.fraction() * 100.0
+                            ),
+                            Err(SendError::ConnectionClosed) => {
+                                panic!("channel closed on server")
+                            }
+                            Err(error) => {
+                                let status = format!(
+                                    "Cannot upload {}: {}",
+                                    upload.file_name(),
+                                    error
+                                );
+                                self.uploads.remove(&client_id);
+                                status
+                            }
+                        },
+                    );
+                }
+                comms::ServerMessage::UploadComplete {
+                    client_id,
+                    attachment,
+                } if self.uploads.remove(&client_id).is_some() => {
+                    self.status =
+                        Some(format!("Uploaded {}", attachment.file_name));
+                    self.post(String::new(), vec![attachment]);
+                }
+                comms::ServerMessage::DownloadChunk {
+                    client_id,
+                    offset,
+                    bytes,
+                    ..
+                } => {
+                    let Some(download) = self.downloads.get_mut(&client_id)
+                    else {
+                        continue;
+                    };
+                    match download.receive_chunk(offset, &bytes) {
+                        Ok(progress) if progress.is_done() => {
+                            let download = self
+                                .downloads
+                                .remove(&client_id)
+                                .expect("download is in progress");
+                            self.status = Some(save_download(download));
+                        }
+                        Ok(progress) => {
+                            self.status = Some(format!(
+                                "Downloading {}: {:.0}%",
+                                download.attachment().file_name,
+                                progress.fraction() * 100.0
+                            ));
+                        }
+                        Err(error) => {
+                            self.downloads.remove(&client_id);
+                            self.status = Some(error.to_string());
+                        }
+                    }
+                }
+                comms::ServerMessage::SearchResults {
+                    client_id, hits, ..
+                } => {
+                    let Some(search) = &mut self.search else {
+                        continue;
+                    };
+                    if search.client_id != client_id {
+                        continue;
+                    }
+                    if hits.is_empty() {
+                        self.status = Some(format!(
+                            "Pattern not found: {}",
+                            search.query
+                        ));
+                        self.search = None;
+                        continue;
+                    }
+                    search.hits = hits;
+                    search.current = 0;
+                    self.jump_to_current_hit(messages);
+                }
+                comms::ServerMessage::CommandList { commands, .. } => {
+                    self.commands = commands;
+                }
+                comms::ServerMessage::CommandResponse { text, .. } => {
+                    self.status = Some(text.replace('\n', " · "));
+                }
+                comms::ServerMessage::PostRejected { reason, .. } => {
+                    self.status = Some(reason.to_string());
+                }
+                comms::ServerMessage::PermissionDenied(denial) => {
+                    self.status = Some(denial.to_string());
+                }
+                comms::ServerMessage::ModerationFailed { reason, .. }
+                | comms::ServerMessage::Disconnected { reason }
+                | comms::ServerMessage::SystemNotice { text: reason } => {
+                    self.status = Some(reason);
+                }
+                comms::ServerMessage::UsernameChanged { username } => {
+                    self.status = Some(format!("You are now {}", username));
+                    self.username = username;
+                }
+                comms::ServerMessage::Topic { channel, topic }
+                    if channel == chat::ChannelName::default() =>
+                {
+                    self.topic = Some(topic);
+                }
+                comms::ServerMessage::AttachmentFailure {
+                    client_id,
+                    error,
+                } => {
+                    self.uploads.remove(&client_id);
+                    self.downloads.remove(&client_id);
+                    self.status = Some(error.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
     fn scroll_to_bottom(&mut self, messages: &[chat::Entry]) {
         if !messages.is_empty() {
             self.messages_cursor = messages.len() - 1;
         }
-        let total_lines = messages.len() as u16;
+        let total_lines = self.line_of(messages, messages.len()) as u16;
         self.editing_context.scroll_offset = total_lines.saturating_sub(1);
     }
 
     fn scroll_up(&mut self, messages: &[chat::Entry], lines: usize) {
-        if self.messages_cursor == 0 {
+        let first_loaded_slot = messages.first().map(|entry| entry.slot_number);
+        if self.messages_cursor == 0
+            && first_loaded_slot == Some(self.history_start)
+        {
+            self.status =
+                Some(format!("History starts at #{}", self.history_start));
+        } else if self.messages_cursor == 0 {
             self.tx
                 .send(comms::ClientMessage::Request {
                     client_id: comms::ClientId::new_unique_per_client(),
+                    channel: chat::ChannelName::default(),
                     count: 50,
                     up_to_slot_number: Some(
                         messages
@@ -701,7 +1401,7 @@ impl App {
         Ok(())
     }
 
-    fn mode_indicator_span(&self) -> ratatui::text::Span {
+    fn mode_indicator_span(&self) -> ratatui::text::Span<'_> {
         match self.editing_context.mode {
             vim::Mode::Normal => {
                 if self.command_buffer.is_empty() {
@@ -732,6 +1432,101 @@ impl App {
     }
 }
 
+/// Opens the file at `path` to upload as `file_name`, reading it through once
+/// to hash it, so this blocks.
+fn open_upload(path: &Path, file_name: String) -> Result<Upload, String> {
+    let file = fs::File::open(path).map_err(|error| error.to_string())?;
+    let size = file.metadata().map_err(|error| error.to_string())?.len();
+    let id = chat::AttachmentId::from_reader(&file)
+        .map_err(|error| error.to_string())?;
+    let file = fs::File::open(path).map_err(|error| error.to_string())?;
+    Upload::from_reader(file_name, id, size, file)
+        .map_err(|error| error.to_string())
+}
+
+/// Writes a finished download into the current directory, returning a status
+/// message describing the outcome. An existing file is never overwritten:
+/// the download is saved under the first free name like `cat (1).png`.
+fn save_download(download: Download) -> String {
+    let attachment = download.attachment().clone();
+    // Only keep the final path component so a malicious file name can't
+    // escape the current directory.
+    let file_name = Path::new(&attachment.file_name)
+        .file_name()
+        .map(|file_name| file_name.to_os_string())
+        .unwrap_or_else(|| attachment.id.0.clone().into());
+    let bytes = match download.finish() {
+        Ok(bytes) => bytes,
+        Err(error) => return error.to_string(),
+    };
+    let saved = create_new_file(Path::new(&file_name))
+        .and_then(|(mut file, path)| file.write_all(&bytes).map(|()| path));
+    match saved {
+        Ok(path) if path.as_os_str() == file_name => {
+            format!("Saved {}", path.display())
+        }
+        Ok(path) => format!(
+            "Saved {} as {}, since {} exists",
+            file_name.to_string_lossy(),
+            path.display(),
+            file_name.to_string_lossy()
+        ),
+        Err(error) => {
+            format!("Cannot save {}: {}", file_name.to_string_lossy(), error)
+        }
+    }
+}
+
+/// Creates `path`, or if it exists, the first of `name (1).ext`,
+/// `name (2).ext`, and so on that doesn't, returning it and where it is.
+fn create_new_file(path: &Path) -> io::Result<(fs::File, PathBuf)> {
+    let stem = path.file_stem().unwrap_or(path.as_os_str());
+    let mut candidate = path.to_owned();
+    for n in 1.. {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(file) => return Ok((file, candidate)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(error) => return Err(error),
+        }
+        let mut name = stem.to_owned();
+        name.push(format!(" ({})", n));
+        if let Some(extension) = path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        candidate = path.with_file_name(name);
+    }
+    unreachable!("ran out of file names")
+}
+
+/// What goes between the author and the text, e.g. `alice: hi` but `alice
+/// waves`.
+fn separator(entry: &chat::Entry) -> &'static str {
+    match entry.kind {
+        chat::EntryKind::Message => ": ",
+        chat::EntryKind::Action | chat::EntryKind::Notice => " ",
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
 /// A helper function to render text with a highlighted region (for Visual
 /// mode). We can use this for either the `input` string or the currently
 /// focused message line. This returns a single [`Line`] so it’s most suitable
@@ -0,0 +1,90 @@
+//! Commands typed into the input box after a `:`, e.g. `:upload notes.txt`.
+
+use std::{fmt, path::PathBuf};
+
+#[derive(Debug)]
+pub enum ExCommand {
+    /// Uploads the file at the given path and posts it as a message.
+    Upload(PathBuf),
+    /// Saves the attachments of the message with the given slot number into
+    /// the current directory.
+    Save(usize),
+    /// Writes the loaded messages to a file in the given format, or to
+    /// `general.<extension>` in the current directory if no path is given.
+    Export {
+        format: chat::export::Format,
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug)]
+pub enum ExCommandError {
+    Unknown(String),
+    MissingArgument(&'static str),
+    InvalidArgument(String),
+}
+
+impl fmt::Display for ExCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExCommandError::Unknown(name) => {
+                write!(f, "Not an editor command: {}", name)
+            }
+            ExCommandError::MissingArgument(usage) => {
+                write!(f, "Usage: {}", usage)
+            }
+            ExCommandError::InvalidArgument(argument) => {
+                write!(f, "Invalid argument: {}", argument)
+            }
+        }
+    }
+}
+
+impl ExCommand {
+    /// Parses `line`, which should not include the leading `:`.
+    pub fn parse(line: &str) -> Result<Self, ExCommandError> {
+        let line = line.trim();
+        let (name, argument) = line
+            .split_once(char::is_whitespace)
+            .map(|(name, argument)| (name, argument.trim()))
+            .unwrap_or((line, ""));
+
+        match name {
+            "upload" => {
+                if argument.is_empty() {
+                    return Err(ExCommandError::MissingArgument(
+                        ":upload <path>",
+                    ));
+                }
+                Ok(ExCommand::Upload(PathBuf::from(argument)))
+            }
+            "save" => {
+                if argument.is_empty() {
+                    return Err(ExCommandError::MissingArgument(":save <n>"));
+                }
+                argument.parse().map(ExCommand::Save).map_err(|_| {
+                    ExCommandError::InvalidArgument(argument.to_owned())
+                })
+            }
+            "export" => {
+                let (format, path) = argument
+                    .split_once(char::is_whitespace)
+                    .map(|(format, path)| (format, path.trim()))
+                    .unwrap_or((argument, ""));
+                if format.is_empty() {
+                    return Err(ExCommandError::MissingArgument(
+                        ":export jsonl|md|html|txt [path]",
+                    ));
+                }
+                let format = format.parse().map_err(|_| {
+                    ExCommandError::InvalidArgument(format.to_owned())
+                })?;
+                Ok(ExCommand::Export {
+                    format,
+                    path: (!path.is_empty()).then(|| PathBuf::from(path)),
+                })
+            }
+            _ => Err(ExCommandError::Unknown(name.to_owned())),
+        }
+    }
+}
@@ -1,2 +1,4 @@
 pub mod app;
+pub mod command_line;
+pub mod rich_text;
 pub mod vim;
@@ -1,76 +1,96 @@
 use std::{env, io, sync::Arc};
 
+use chat::timeline::Timeline;
 use client_tui::app::App;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 
 #[tokio::main]
 async fn main() -> Result<(), io::Error> {
     let url = env::args().nth(1).unwrap_or_else(|| {
-        panic!("Pass the server's wss:// address as a command-line argument")
+        panic!(
+            "Pass the server's wss://, quic://, or ws:// address, or unix: \
+             and the path to its client.sock, and optionally your username, \
+             as command-line arguments"
+        )
     });
 
-    let (connection, tx, mut rx) = client_connect::connect_to_server(&url)
-        .await
-        .map_err(io::Error::other)?;
+    let (connection, tx, mut rx) = match url.strip_prefix("unix:") {
+        Some(path) => client_connect::connect_to_unix_socket(path).await,
+        None => client_connect::connect_to_server(&url).await,
+    }
+    .map_err(io::Error::other)?;
 
-    let messages = Arc::new(RwLock::new(vec![]));
-    let app_messages = messages.clone();
+    let username = env::args().nth(2).unwrap_or_else(|| "jeff".to_owned());
 
-    tx.send(comms::ClientMessage::Request {
-        client_id: comms::ClientId::new_unique_per_client(),
-        count: 50,
-        up_to_slot_number: None,
+    let timeline = Arc::new(RwLock::new(Timeline::new()));
+    let app_timeline = timeline.clone();
+
+    // History is requested once the server replies with our read positions.
+    // Needed for names with a password in the server's config.
+    let password = env::var("NERDTALK_PASSWORD").ok();
+    tx.send(comms::ClientMessage::LogIn {
+        username: username.clone(),
+        password,
     })
     .expect("todo");
 
-    let mut app = App::new(tx);
+    let (server_events_tx, server_events_rx) = mpsc::unbounded_channel();
+    let gap_tx = tx.clone();
+    let mut app = App::new(username, tx, server_events_rx);
 
     tokio::spawn(async move {
         while let Some(server_message) = rx.recv().await {
             let server_message = server_message.expect("todo");
-            match server_message {
-                comms::ServerMessage::NewEntry(chat_log_entry) => loop {
-                    if let Ok(mut lock) = messages.try_write() {
-                        lock.push(chat_log_entry);
-                        break;
-                    }
-                },
+            let (entries, head_slot, history_start) = match server_message {
+                // The TUI only shows the default channel for now.
+                comms::ServerMessage::NewEntry(chat_log_entry)
+                | comms::ServerMessage::UpdatedEntry(chat_log_entry)
+                    if chat_log_entry.channel
+                        != chat::ChannelName::default() =>
+                {
+                    continue;
+                }
+                comms::ServerMessage::NewEntry(chat_log_entry)
+                | comms::ServerMessage::UpdatedEntry(chat_log_entry) => {
+                    (vec![chat_log_entry], None, None)
+                }
                 comms::ServerMessage::EntryRange {
-                    client_id: _,
                     entries,
-                } => {
-                    if !entries.is_empty() {
-                        // since we don't have to worry about updates until
-                        // v0.2, this is going to be
-                        // contiguous
-                        // TODO(haadi): I'm sure you can find a smarter way,
-                        // e.g., if your only requests are for earlier messages,
-                        // you can just automatically insert them at the start
-                        // of the array instead of "finding" the insertion point
-                        loop {
-                            if let Ok(mut lock) = messages.try_write() {
-                                let insertion_point = lock
-                                    .iter()
-                                    .enumerate()
-                                    .find_map(|(index, entry)| {
-                                        if entry.slot_number
-                                            == entries[0].slot_number
-                                        {
-                                            Some(index)
-                                        } else {
-                                            None
-                                        }
-                                    })
-                                    .unwrap_or(0);
-                                lock.splice(
-                                    insertion_point..insertion_point,
-                                    entries,
-                                );
-                                break;
-                            }
-                        }
-                    }
+                    head_slot,
+                    history_start,
+                    ..
+                } => (entries, head_slot, Some(history_start)),
+                comms::ServerMessage::HistoryStart {
+                    channel,
+                    slot_number,
+                } if channel == chat::ChannelName::default() => {
+                    (vec![], None, Some(slot_number))
+                }
+                // Everything else is a reply the app is waiting on.
+                other => {
+                    let _ = server_events_tx.send(other);
+                    continue;
+                }
+            };
+
+            let mut timeline = timeline.write().await;
+            let known_gaps = timeline.gaps();
+            if let Some(history_start) = history_start {
+                timeline.set_history_start(history_start);
+            }
+            timeline.merge(entries);
+            // Fill in anything we missed, like entries posted while a page of
+            // history was in flight.
+            for gap in timeline.gaps_up_to(head_slot) {
+                if known_gaps.contains(&gap) {
+                    continue;
                 }
+                let _ = gap_tx.send(comms::ClientMessage::RequestAfter {
+                    client_id: comms::ClientId::new_unique_per_client(),
+                    channel: chat::ChannelName::default(),
+                    after_slot_number: gap.start.checked_sub(1),
+                    limit: gap.len(),
+                });
             }
         }
     });
@@ -78,7 +98,7 @@ async fn main() -> Result<(), io::Error> {
     crossterm::terminal::enable_raw_mode()?;
     let mut terminal = ratatui::init();
 
-    let app_result = app.run(&mut terminal, app_messages).await;
+    let app_result = app.run(&mut terminal, app_timeline).await;
     ratatui::restore();
     connection.close();
     app_result
@@ -0,0 +1,101 @@
+//! Renders [`chat::markdown`] documents as styled ratatui spans.
+//!
+//! Each message is drawn on a single [`ratatui::text::Line`], so line breaks
+//! and block boundaries are shown as a dimmed `↵` instead.
+
+use chat::markdown::{Block, Document, Inline};
+use ratatui::{
+    style::{Color, Modifier, Style, Stylize},
+    text::Span,
+};
+
+const LINE_BREAK: &str = " ↵ ";
+
+fn code_style() -> Style {
+    Style::new().fg(Color::LightRed).bg(Color::DarkGray)
+}
+
+fn push_text(spans: &mut Vec<Span<'static>>, text: &str, style: Style) {
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            spans.push(Span::styled(LINE_BREAK, Style::new().dim()));
+        }
+        if !line.is_empty() {
+            spans.push(Span::styled(line.to_owned(), style));
+        }
+    }
+}
+
+fn push_inlines(
+    spans: &mut Vec<Span<'static>>,
+    inlines: &[Inline],
+    style: Style,
+) {
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) => push_text(spans, text, style),
+            Inline::Bold(inlines) => {
+                push_inlines(spans, inlines, style.add_modifier(Modifier::BOLD))
+            }
+            Inline::Italic(inlines) => push_inlines(
+                spans,
+                inlines,
+                style.add_modifier(Modifier::ITALIC),
+            ),
+            Inline::Code(code) => spans
+                .push(Span::styled(code.clone(), style.patch(code_style()))),
+            Inline::Link { text, url } => {
+                push_inlines(
+                    spans,
+                    text,
+                    style.fg(Color::Blue).add_modifier(Modifier::UNDERLINED),
+                );
+                if chat::markdown::inlines_plain_text(text) != *url {
+                    spans.push(Span::styled(
+                        format!(" <{}>", url),
+                        Style::new().dim(),
+                    ));
+                }
+            }
+            Inline::Mention(name) => spans.push(Span::styled(
+                format!("@{}", name),
+                style.fg(Color::Magenta).add_modifier(Modifier::BOLD),
+            )),
+        }
+    }
+}
+
+fn push_blocks(spans: &mut Vec<Span<'static>>, blocks: &[Block], style: Style) {
+    for (index, block) in blocks.iter().enumerate() {
+        if index > 0 {
+            spans.push(Span::styled(LINE_BREAK, Style::new().dim()));
+        }
+        match block {
+            Block::Paragraph(inlines) => push_inlines(spans, inlines, style),
+            Block::CodeBlock { language, code } => {
+                if let Some(language) = language {
+                    spans.push(Span::styled(
+                        format!("[{}] ", language),
+                        Style::new().dim(),
+                    ));
+                }
+                push_text(spans, code, style.patch(code_style()));
+            }
+            Block::Quote(blocks) => {
+                spans.push(Span::styled("│ ", Style::new().dim()));
+                push_blocks(
+                    spans,
+                    blocks,
+                    style.add_modifier(Modifier::ITALIC | Modifier::DIM),
+                );
+            }
+        }
+    }
+}
+
+/// Styled spans for `document`, suitable for placing on one line.
+pub fn document_spans(document: &Document) -> Vec<Span<'static>> {
+    let mut spans = vec![];
+    push_blocks(&mut spans, &document.blocks, Style::new());
+    spans
+}
@@ -494,11 +494,11 @@ impl EditingContext {
                             }
                             self.mode = Mode::Insert;
                         }
-                        MultiCommand::Replace(c) => {
-                            if self.cursor_pos < text.len() {
-                                text.remove(self.cursor_pos);
-                                text.insert(self.cursor_pos, c);
-                            }
+                        MultiCommand::Replace(c)
+                            if self.cursor_pos < text.len() =>
+                        {
+                            text.remove(self.cursor_pos);
+                            text.insert(self.cursor_pos, c);
                         }
                         _ => {} // Yank works for both
                     }
@@ -555,52 +555,47 @@ fn delete_helper(
                 *cursor_pos = start_pos;
             }
         }
-        Noun::Motion(Motion::FindCharForward(ch)) => {
-            if *cursor_pos < text.len() {
-                if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
-                    let match_pos = *cursor_pos + 1 + rel_pos;
-                    let end = match_pos + 1;
-                    if end <= text.len() {
-                        let removed =
-                            text.drain(*cursor_pos..end).collect::<String>();
-                        let _ = clipboard.set_contents(removed);
-                    }
-                }
-            }
-        }
-        Noun::Motion(Motion::FindCharBackward(ch)) => {
-            if *cursor_pos > 0 {
-                if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
+        Noun::Motion(Motion::FindCharForward(ch))
+            if *cursor_pos < text.len() =>
+        {
+            if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
+                let match_pos = *cursor_pos + 1 + rel_pos;
+                let end = match_pos + 1;
+                if end <= text.len() {
                     let removed =
-                        text.drain(found_pos..*cursor_pos).collect::<String>();
+                        text.drain(*cursor_pos..end).collect::<String>();
                     let _ = clipboard.set_contents(removed);
-                    *cursor_pos = found_pos;
                 }
             }
         }
-        Noun::Motion(Motion::TillCharForward(ch)) => {
-            if *cursor_pos < text.len() {
-                if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
-                    let match_pos = *cursor_pos + 1 + rel_pos;
-                    if match_pos > *cursor_pos {
-                        let removed = text
-                            .drain(*cursor_pos..match_pos)
-                            .collect::<String>();
-                        let _ = clipboard.set_contents(removed);
-                    }
+        Noun::Motion(Motion::FindCharBackward(ch)) if *cursor_pos > 0 => {
+            if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
+                let removed =
+                    text.drain(found_pos..*cursor_pos).collect::<String>();
+                let _ = clipboard.set_contents(removed);
+                *cursor_pos = found_pos;
+            }
+        }
+        Noun::Motion(Motion::TillCharForward(ch))
+            if *cursor_pos < text.len() =>
+        {
+            if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
+                let match_pos = *cursor_pos + 1 + rel_pos;
+                if match_pos > *cursor_pos {
+                    let removed =
+                        text.drain(*cursor_pos..match_pos).collect::<String>();
+                    let _ = clipboard.set_contents(removed);
                 }
             }
         }
-        Noun::Motion(Motion::TillCharBackward(ch)) => {
-            if *cursor_pos > 0 {
-                if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
-                    let start = found_pos + 1;
-                    if start < *cursor_pos {
-                        let removed =
-                            text.drain(start..*cursor_pos).collect::<String>();
-                        let _ = clipboard.set_contents(removed);
-                        *cursor_pos = start;
-                    }
+        Noun::Motion(Motion::TillCharBackward(ch)) if *cursor_pos > 0 => {
+            if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
+                let start = found_pos + 1;
+                if start < *cursor_pos {
+                    let removed =
+                        text.drain(start..*cursor_pos).collect::<String>();
+                    let _ = clipboard.set_contents(removed);
+                    *cursor_pos = start;
                 }
             }
         }
@@ -674,44 +669,40 @@ fn yank_helper(
                 let _ = clipboard.set_contents(selection.to_string());
             }
         }
-        Noun::Motion(Motion::FindCharForward(ch)) => {
-            if *cursor_pos < text.len() {
-                if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
-                    let match_pos = *cursor_pos + 1 + rel_pos;
-                    let end = match_pos + 1; // 'f' includes the found char
-                    if end <= text.len() {
-                        let selection = &text[*cursor_pos..end];
-                        let _ = clipboard.set_contents(selection.to_string());
-                    }
+        Noun::Motion(Motion::FindCharForward(ch))
+            if *cursor_pos < text.len() =>
+        {
+            if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
+                let match_pos = *cursor_pos + 1 + rel_pos;
+                let end = match_pos + 1; // 'f' includes the found char
+                if end <= text.len() {
+                    let selection = &text[*cursor_pos..end];
+                    let _ = clipboard.set_contents(selection.to_string());
                 }
             }
         }
-        Noun::Motion(Motion::FindCharBackward(ch)) => {
-            if *cursor_pos > 0 {
-                if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
-                    let selection = &text[found_pos..*cursor_pos];
-                    let _ = clipboard.set_contents(selection.to_string());
-                }
+        Noun::Motion(Motion::FindCharBackward(ch)) if *cursor_pos > 0 => {
+            if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
+                let selection = &text[found_pos..*cursor_pos];
+                let _ = clipboard.set_contents(selection.to_string());
             }
         }
-        Noun::Motion(Motion::TillCharForward(ch)) => {
-            if *cursor_pos < text.len() {
-                if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
-                    let match_pos = *cursor_pos + 1 + rel_pos;
-                    // 't' excludes the found char
-                    let selection = &text[*cursor_pos..match_pos];
-                    let _ = clipboard.set_contents(selection.to_string());
-                }
+        Noun::Motion(Motion::TillCharForward(ch))
+            if *cursor_pos < text.len() =>
+        {
+            if let Some(rel_pos) = text[*cursor_pos + 1..].find(ch) {
+                let match_pos = *cursor_pos + 1 + rel_pos;
+                // 't' excludes the found char
+                let selection = &text[*cursor_pos..match_pos];
+                let _ = clipboard.set_contents(selection.to_string());
             }
         }
-        Noun::Motion(Motion::TillCharBackward(ch)) => {
-            if *cursor_pos > 0 {
-                if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
-                    let start = found_pos + 1;
-                    if start < *cursor_pos {
-                        let selection = &text[start..*cursor_pos];
-                        let _ = clipboard.set_contents(selection.to_string());
-                    }
+        Noun::Motion(Motion::TillCharBackward(ch)) if *cursor_pos > 0 => {
+            if let Some(found_pos) = text[..*cursor_pos].rfind(ch) {
+                let start = found_pos + 1;
+                if start < *cursor_pos {
+                    let selection = &text[start..*cursor_pos];
+                    let _ = clipboard.set_contents(selection.to_string());
                 }
             }
         }
@@ -0,0 +1,129 @@
+//! Compares the two ways a server can send one new entry to many sessions:
+//! cloning and serializing the message for each session, or serializing it
+//! once into a buffer the sessions share.
+//!
+//! ```sh
+//! cargo run --release -p comms --example broadcast_encoding -- 1000
+//! ```
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env, hint,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use comms::Codable;
+
+/// Counts allocations on top of the system allocator.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        System.dealloc(pointer, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ROUNDS: usize = 100;
+
+struct Measurement {
+    elapsed: Duration,
+    allocations: usize,
+    allocated_bytes: usize,
+}
+
+/// Runs `broadcast` [`ROUNDS`] times, averaging what one run costs.
+fn measure(mut broadcast: impl FnMut()) -> Measurement {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        broadcast();
+    }
+    Measurement {
+        elapsed: start.elapsed() / ROUNDS as u32,
+        allocations: (ALLOCATIONS.load(Ordering::Relaxed) - allocations)
+            / ROUNDS,
+        allocated_bytes: (ALLOCATED_BYTES.load(Ordering::Relaxed)
+            - allocated_bytes)
+            / ROUNDS,
+    }
+}
+
+fn report(name: &str, measurement: &Measurement) {
+    println!(
+        "{:<16} {:>10.2?} {:>12} allocations {:>12} bytes",
+        name,
+        measurement.elapsed,
+        measurement.allocations,
+        measurement.allocated_bytes
+    );
+}
+
+fn main() {
+    let sessions = env::args()
+        .nth(1)
+        .map(|sessions| sessions.parse().expect("sessions must be a number"))
+        .unwrap_or(1000);
+
+    let message = comms::ServerMessage::NewEntry(chat::Entry {
+        channel: chat::ChannelName::default(),
+        slot_number: 12345,
+        metadata: chat::Metadata {
+            username: "alice".to_owned(),
+            timestamp: chrono::Utc::now(),
+            origin: None,
+        },
+        content: chat::Content::Original(chat::MessageText(
+            "has anyone tried the new build? it fixed scrolling for me, but \
+             the topic bar still flickers when someone joins"
+                .to_owned(),
+        )),
+        attachments: vec![],
+        kind: chat::EntryKind::Message,
+    });
+
+    let mut outboxes = Vec::with_capacity(sessions);
+    let per_session = measure(|| {
+        for _ in 0..sessions {
+            let message = message.clone();
+            outboxes.push(message.to_bytes());
+        }
+        hint::black_box(&outboxes);
+        outboxes.clear();
+    });
+
+    let mut shared_outboxes = Vec::with_capacity(sessions);
+    let once = measure(|| {
+        let encoded = Arc::<[u8]>::from(message.to_bytes());
+        for _ in 0..sessions {
+            shared_outboxes.push(encoded.clone());
+        }
+        hint::black_box(&shared_outboxes);
+        shared_outboxes.clear();
+    });
+
+    println!("Broadcasting one new entry to {} sessions:", sessions);
+    report("per session", &per_session);
+    report("encoded once", &once);
+    println!(
+        "{:.1}x faster, {:.1}x fewer allocated bytes",
+        per_session.elapsed.as_secs_f64() / once.elapsed.as_secs_f64(),
+        per_session.allocated_bytes as f64 / once.allocated_bytes.max(1) as f64
+    );
+}
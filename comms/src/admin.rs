@@ -0,0 +1,111 @@
+//! The server's admin socket protocol, for operating the server from the same
+//! machine: one JSON [`Request`] per line, each answered by one JSON
+//! [`Response`] line.
+
+use std::{fmt, net::SocketAddr};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::Codable;
+
+/// Names a session for as long as the server runs. Unlike the address a
+/// client connected from, no two sessions ever share one.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+pub struct SessionId(pub u64);
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    ListSessions,
+    /// Closes `session`, telling the client `reason`.
+    Disconnect {
+        session: SessionId,
+        reason: String,
+    },
+    /// Shows `text` to every connected session as
+    /// [`crate::ServerMessage::SystemNotice`].
+    Broadcast {
+        text: String,
+    },
+    /// Replaces the log filter, in the same syntax as the `LOG` environment
+    /// variable, e.g. `debug` or `info,server=trace`.
+    SetLogLevel {
+        filter: String,
+    },
+    /// Reads `config.json` again. Replication settings only take effect on
+    /// restart.
+    ReloadConfig,
+    /// Makes a follower stop following its leader and accept writes.
+    Promote,
+    Stats,
+    /// Lists webhooks and how delivering to them has gone.
+    Webhooks,
+}
+
+impl Codable for Request {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    /// Where the client connected from, unless it used the server's
+    /// Unix-domain socket.
+    pub address: Option<SocketAddr>,
+    pub username: Option<String>,
+    pub connected_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub started_at: DateTime<Utc>,
+    pub sessions: usize,
+    /// Distinct usernames logged in across all sessions.
+    pub users: usize,
+    pub channels: usize,
+    /// Entries still stored across all channels.
+    pub entries: usize,
+    /// Client messages handled since the server started.
+    pub messages_handled: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookStatus {
+    pub name: String,
+    pub url: String,
+    /// Entries delivered since the server started.
+    pub delivered: u64,
+    /// Entries still being delivered, including ones waiting to be retried.
+    pub pending: u64,
+    /// Entries given up on after every attempt failed.
+    pub failed: u64,
+    pub last_delivered_at: Option<DateTime<Utc>>,
+    /// When the last attempt that failed did, and why.
+    pub last_error: Option<(DateTime<Utc>, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Sessions(Vec<SessionInfo>),
+    Stats(Stats),
+    Webhooks(Vec<WebhookStatus>),
+    Done,
+    Error(String),
+}
+
+impl Codable for Response {}
@@ -1,8 +1,18 @@
+use std::{
+    fmt,
+    ops::Range,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time,
+};
+
 use chat::Entry;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 pub use serde_json::Error as CodingError;
 
+pub mod admin;
+
 pub trait Codable {
     fn to_bytes(&self) -> Vec<u8>
     where
@@ -19,8 +29,21 @@ pub trait Codable {
     }
 }
 
+/// Largest attachment, in bytes, the server will accept.
+pub const MAX_ATTACHMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Largest number of attachment bytes carried by a single
+/// [`ClientMessage::UploadChunk`] or [`ServerMessage::DownloadChunk`].
+pub const ATTACHMENT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest number of uploads one session can have in progress at once.
+pub const MAX_CONCURRENT_UPLOADS: usize = 4;
+
+/// Largest number of hits the server returns in one page of search results.
+pub const MAX_SEARCH_PAGE_SIZE: usize = 100;
+
 /// Opaque unique-per-client identifier.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ClientId {
     timestamp: DateTime<Utc>,
 }
@@ -35,27 +58,580 @@ impl ClientId {
     }
 }
 
+/// Identifies a post so the server can recognize a resend of one it already
+/// committed. Reuse the nonce when retrying a post whose
+/// [`ServerMessage::PostAck`] never arrived.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Nonce(pub String);
+
+impl Nonce {
+    /// Unique across clients in practice: combines the time, the process, and
+    /// a per-process counter.
+    pub fn new_unique() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        Self(format!(
+            "{:x}-{:x}-{:x}",
+            nanos,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+}
+
+/// Why an attachment upload or download did not go through.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AttachmentError {
+    TooLarge { size: u64, limit: u64 },
+    ChunkTooLarge { length: usize, limit: usize },
+    UnexpectedOffset { expected: u64, actual: u64 },
+    IntegrityMismatch { expected: chat::AttachmentId },
+    UnknownUpload,
+    TooManyUploads { limit: usize },
+    NotFound(chat::AttachmentId),
+    Storage(String),
+    PermissionDenied(PermissionDenied),
+    NotLoggedIn,
+}
+
+impl fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachmentError::TooLarge { size, limit } => write!(
+                f,
+                "Attachment is {} bytes but the limit is {} bytes",
+                size, limit
+            ),
+            AttachmentError::ChunkTooLarge { length, limit } => write!(
+                f,
+                "Attachment chunk is {} bytes but the limit is {} bytes",
+                length, limit
+            ),
+            AttachmentError::UnexpectedOffset { expected, actual } => write!(
+                f,
+                "Expected attachment chunk at offset {} but got offset {}",
+                expected, actual
+            ),
+            AttachmentError::IntegrityMismatch { expected } => {
+                write!(f, "Attachment contents do not hash to {}", expected)
+            }
+            AttachmentError::UnknownUpload => {
+                write!(f, "No upload is in progress for this client id")
+            }
+            AttachmentError::TooManyUploads { limit } => {
+                write!(f, "Only {} uploads can be in progress at once", limit)
+            }
+            AttachmentError::NotFound(id) => {
+                write!(f, "No attachment {} is stored", id)
+            }
+            AttachmentError::Storage(message) => {
+                write!(f, "Failed to access attachment storage: {}", message)
+            }
+            AttachmentError::PermissionDenied(denial) => denial.fmt(f),
+            AttachmentError::NotLoggedIn => write!(f, "Log in first"),
+        }
+    }
+}
+
+/// What to look for with [`ClientMessage::Search`]. Every given filter must
+/// match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchQuery {
+    /// Words that must all appear in a message, ignoring case.
+    pub text: String,
+    pub author: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub channel: Option<chat::ChannelName>,
+}
+
+/// Where a page of search results left off. Results are ordered newest
+/// first, so the next page holds the hits that sort after this one.
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct SearchCursor {
+    pub timestamp: DateTime<Utc>,
+    pub channel: chat::ChannelName,
+    pub slot_number: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub entry: chat::Entry,
+    /// Byte ranges of the matched words in the entry's text.
+    pub matches: Vec<Range<usize>>,
+}
+
+/// A slash command the server handles, for clients to offer as completions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandInfo {
+    /// Without the leading `/`.
+    pub name: String,
+    /// e.g. `/topic [new topic]`.
+    pub usage: String,
+    pub description: String,
+}
+
+/// Something a moderator does with [`ClientMessage::Moderate`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ModerationAction {
+    /// Disconnects every session logged in as `username`.
+    Kick {
+        username: String,
+    },
+    /// Disconnects `username` and keeps them, and the addresses they were
+    /// connected from, out until `until`, or for good if `None`.
+    Ban {
+        username: String,
+        until: Option<DateTime<Utc>>,
+    },
+    Unban {
+        username: String,
+    },
+    /// Rejects `username`'s posts to the channel until `until`, or until
+    /// they're unmuted if `None`.
+    Mute {
+        username: String,
+        until: Option<DateTime<Utc>>,
+    },
+    Unmute {
+        username: String,
+    },
+    /// Deletes an entry, whoever wrote it.
+    RemoveEntry {
+        slot_number: usize,
+    },
+}
+
+/// What a user may do in a channel, or server-wide. Each role can do
+/// everything the ones before it can.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can read and mark entries read, but not post.
+    ReadOnly,
+    #[default]
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] =
+        [Role::ReadOnly, Role::Member, Role::Moderator, Role::Admin];
+
+    /// How the role is written in config and commands.
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read_only",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// The least role with `permission`.
+    fn least_with(permission: Permission) -> Role {
+        match permission {
+            Permission::Post
+            | Permission::CreateChannel
+            | Permission::Upload
+            | Permission::EditOwn
+            | Permission::DeleteOwn
+            | Permission::SetTopic => Role::Member,
+            Permission::DeleteAny | Permission::Moderate => Role::Moderator,
+            Permission::EditAny | Permission::AssignRoles => Role::Admin,
+        }
+    }
+
+    pub fn allows(self, permission: Permission) -> bool {
+        self >= Role::least_with(permission)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::ReadOnly => write!(f, "read-only"),
+            role => role.name().fmt(f),
+        }
+    }
+}
+
+/// Something a [`Role`] may or may not allow. Reading is always allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Post,
+    /// Post to a channel that doesn't exist yet.
+    CreateChannel,
+    Upload,
+    EditOwn,
+    EditAny,
+    DeleteOwn,
+    DeleteAny,
+    SetTopic,
+    /// Kick, ban, and mute users.
+    Moderate,
+    AssignRoles,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Permission::Post => "post",
+            Permission::CreateChannel => "create channels",
+            Permission::Upload => "upload attachments",
+            Permission::EditOwn => "edit their own entries",
+            Permission::EditAny => "edit other users' entries",
+            Permission::DeleteOwn => "delete their own entries",
+            Permission::DeleteAny => "delete other users' entries",
+            Permission::SetTopic => "set the topic",
+            Permission::Moderate => "moderate users",
+            Permission::AssignRoles => "assign roles",
+        };
+        action.fmt(f)
+    }
+}
+
+/// Why the server refused a request: `username` has `role`, which doesn't
+/// allow `permission`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionDenied {
+    pub username: String,
+    pub role: Role,
+    pub permission: Permission,
+    /// Where the role applies, or `None` for server-wide permissions like
+    /// [`Permission::Upload`].
+    pub channel: Option<chat::ChannelName>,
+}
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is {} and can't {}",
+            self.username, self.role, self.permission
+        )?;
+        if let Some(channel) = &self.channel {
+            write!(f, " in {}", channel)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a [`ClientMessage::Post`] wasn't committed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PostRejection {
+    /// A moderator muted the poster in the channel until `until`, or until
+    /// they're unmuted.
+    Muted {
+        until: Option<DateTime<Utc>>,
+    },
+    PermissionDenied(PermissionDenied),
+    NotLoggedIn,
+}
+
+impl fmt::Display for PostRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostRejection::Muted { until: None } => {
+                write!(f, "You are muted in this channel")
+            }
+            PostRejection::Muted { until: Some(until) } => write!(
+                f,
+                "You are muted in this channel until {}",
+                until.format("%Y-%m-%d %H:%M UTC")
+            ),
+            PostRejection::PermissionDenied(denial) => denial.fmt(f),
+            PostRejection::NotLoggedIn => write!(f, "Log in first"),
+        }
+    }
+}
+
+/// What a user has and hasn't read in a channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelReadState {
+    pub channel: chat::ChannelName,
+    /// The newest slot in the channel, if it has any entries.
+    pub head_slot: Option<usize>,
+    /// The oldest slot in the channel that retention hasn't removed.
+    #[serde(default)]
+    pub history_start: usize,
+    /// The newest slot the user has read, if any.
+    pub last_read_slot: Option<usize>,
+    /// Entries by other users after `last_read_slot`.
+    pub unread_count: usize,
+    /// Unread entries that mention the user.
+    pub mention_count: usize,
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// The user's role in the channel.
+    #[serde(default)]
+    pub role: Role,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-    Post {
+    /// Associates this session with `username`. The server replies with
+    /// [`ServerMessage::Welcome`], or disconnects the session if it can't log
+    /// in as `username`: names with a password in the server's config need
+    /// it, admins' and moderators' names can only be used with one, and other
+    /// names only by one session at a time.
+    LogIn {
         username: String,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Appends an entry. The server replies with [`ServerMessage::PostAck`]
+    /// once it's committed, and only commits each `nonce` once. Needs
+    /// [`Permission::Post`], and [`Permission::CreateChannel`] if `channel`
+    /// is new. Posts come from the username the session logged in as, so
+    /// sessions must log in first.
+    ///
+    /// Content starting with `/` runs a slash command instead, which either
+    /// commits an entry of its own or replies with
+    /// [`ServerMessage::CommandResponse`]. Each `nonce` runs a command only
+    /// once, and a resend gets the same reply, even after a rename. Start
+    /// with `//` to post a literal `/`.
+    Post {
+        nonce: Nonce,
+        #[serde(default)]
+        channel: chat::ChannelName,
         content: String,
+        /// Attachments previously uploaded with
+        /// [`ClientMessage::UploadBegin`].
+        #[serde(default)]
+        attachments: Vec<chat::Attachment>,
     },
+    /// Pages backwards: the last `count` entries up to and including
+    /// `up_to_slot_number`, or up to the newest entry if `None`.
     Request {
         client_id: ClientId,
+        #[serde(default)]
+        channel: chat::ChannelName,
         count: usize,
         up_to_slot_number: Option<usize>,
     },
+    /// Pages forwards: the first `limit` entries after `after_slot_number`, or
+    /// from the start of the channel if `None`. Used to catch up after a
+    /// reconnect.
+    RequestAfter {
+        client_id: ClientId,
+        channel: chat::ChannelName,
+        after_slot_number: Option<usize>,
+        limit: usize,
+    },
+    /// Replaces the text of an entry for the logged-in user. Needs
+    /// [`Permission::EditOwn`], or [`Permission::EditAny`] for someone else's.
+    Edit {
+        channel: chat::ChannelName,
+        slot_number: usize,
+        content: String,
+    },
+    /// Deletes an entry for the logged-in user. The slot stays taken. Needs
+    /// [`Permission::DeleteOwn`], or [`Permission::DeleteAny`] for someone
+    /// else's.
+    Delete {
+        channel: chat::ChannelName,
+        slot_number: usize,
+    },
+    /// Records that the logged-in user has read `channel` up to and including
+    /// `slot_number`, or its head slot if that's earlier. Read positions never
+    /// move backwards, and are ignored in channels that don't exist.
+    MarkRead {
+        channel: chat::ChannelName,
+        slot_number: usize,
+    },
+    /// Requests the slash commands the server handles, which it sends back
+    /// as [`ServerMessage::CommandList`].
+    ListCommands { client_id: ClientId },
+    /// Takes a moderator action in `channel`, where it's announced along with
+    /// `reason`. Needs [`Permission::Moderate`], or
+    /// [`Permission::DeleteAny`] to remove an entry.
+    Moderate {
+        channel: chat::ChannelName,
+        action: ModerationAction,
+        reason: Option<String>,
+    },
+    /// Gives `username` `role` in `channel`, or server-wide if `channel` is
+    /// `None`. A `role` of `None` clears the assignment, so they fall back to
+    /// their server-wide or default role. Needs
+    /// [`Permission::AssignRoles`].
+    AssignRole {
+        channel: Option<chat::ChannelName>,
+        username: String,
+        role: Option<Role>,
+    },
+    /// Requests up to `limit` entries matching `query`, starting after
+    /// `after` if given.
+    Search {
+        client_id: ClientId,
+        query: SearchQuery,
+        limit: usize,
+        after: Option<SearchCursor>,
+    },
+    /// Starts an upload of `size` bytes that should hash to `id`. The bytes
+    /// follow in order as [`ClientMessage::UploadChunk`]s with the same
+    /// `client_id`. Sessions must log in first.
+    UploadBegin {
+        client_id: ClientId,
+        file_name: String,
+        size: u64,
+        id: chat::AttachmentId,
+    },
+    UploadChunk {
+        client_id: ClientId,
+        offset: u64,
+        bytes: Vec<u8>,
+    },
+    /// Requests the contents of a stored attachment, which are sent back as
+    /// [`ServerMessage::DownloadChunk`]s.
+    Download {
+        client_id: ClientId,
+        id: chat::AttachmentId,
+    },
 }
 
 impl Codable for ClientMessage {}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
+    /// Sent after [`ClientMessage::LogIn`] with the user's read state in
+    /// every channel.
+    Welcome {
+        channels: Vec<ChannelReadState>,
+    },
+    /// The user's read position moved, possibly from another of their
+    /// sessions.
+    ReadPosition {
+        channel: chat::ChannelName,
+        last_read_slot: usize,
+    },
+    /// Sent to the poster once their [`ClientMessage::Post`] is committed,
+    /// including when it was a resend of an already-committed post.
+    PostAck {
+        nonce: Nonce,
+        channel: chat::ChannelName,
+        slot_number: usize,
+    },
+    /// Reply to a slash command that didn't commit an entry, for only the
+    /// session that ran it to see. Also settles the post with `nonce`.
+    CommandResponse {
+        nonce: Nonce,
+        channel: chat::ChannelName,
+        text: String,
+    },
+    CommandList {
+        client_id: ClientId,
+        commands: Vec<CommandInfo>,
+    },
+    /// The post with `nonce` wasn't committed. Also settles the post.
+    PostRejected {
+        nonce: Nonce,
+        channel: chat::ChannelName,
+        reason: PostRejection,
+    },
+    /// A request that doesn't get a reply of its own, like
+    /// [`ClientMessage::Edit`], was refused.
+    PermissionDenied(PermissionDenied),
+    /// `username`'s role in `channel`, or server-wide if `None`, was
+    /// assigned, or cleared if `role` is `None`.
+    RoleAssigned {
+        channel: Option<chat::ChannelName>,
+        username: String,
+        role: Option<Role>,
+    },
+    /// A [`ClientMessage::Moderate`] from this session wasn't carried out.
+    ModerationFailed {
+        action: ModerationAction,
+        reason: String,
+    },
+    /// A moderator kicked or banned this session's user, or an operator
+    /// disconnected the session. The server closes the connection right
+    /// after.
+    Disconnected {
+        reason: String,
+    },
+    /// An announcement from the server's operators to everyone connected.
+    SystemNotice {
+        text: String,
+    },
+    /// This session's username was changed with `/nick`. Post under the new
+    /// name from now on.
+    UsernameChanged {
+        username: String,
+    },
+    /// `channel`'s topic was changed.
+    Topic {
+        channel: chat::ChannelName,
+        topic: String,
+    },
     NewEntry(Entry),
+    /// An existing entry was edited or deleted, or moved to a later slot by
+    /// an entry from a federated server merged in before it.
+    UpdatedEntry(Entry),
+    /// Reply to [`ClientMessage::Request`] or [`ClientMessage::RequestAfter`],
+    /// with entries oldest first.
     EntryRange {
         client_id: ClientId,
         entries: Vec<chat::Entry>,
+        /// The channel's newest slot when the reply was sent. A client is
+        /// caught up once it has every entry up to here.
+        #[serde(default)]
+        head_slot: Option<usize>,
+        /// The channel's oldest slot. Older history was removed by the
+        /// server's retention policy, so there's no point asking for it.
+        #[serde(default)]
+        history_start: usize,
+    },
+    /// Retention removed every entry before `slot_number` in `channel`.
+    HistoryStart {
+        channel: chat::ChannelName,
+        slot_number: usize,
+    },
+    /// The server has received the first `received` bytes of an upload.
+    UploadProgress {
+        client_id: ClientId,
+        received: u64,
+    },
+    /// The upload was verified and stored; `attachment` can now be posted.
+    UploadComplete {
+        client_id: ClientId,
+        attachment: chat::Attachment,
+    },
+    DownloadChunk {
+        client_id: ClientId,
+        id: chat::AttachmentId,
+        offset: u64,
+        total_size: u64,
+        bytes: Vec<u8>,
+    },
+    AttachmentFailure {
+        client_id: ClientId,
+        error: AttachmentError,
+    },
+    SearchResults {
+        client_id: ClientId,
+        hits: Vec<SearchHit>,
+        /// Pass as `after` to get the next page, if there is one.
+        next_page: Option<SearchCursor>,
     },
 }
 
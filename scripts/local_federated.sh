@@ -0,0 +1,40 @@
+#!/bin/sh
+
+# Runs a server on port 12347 that shares "general" with
+# ./scripts/local_server.sh, which needs
+# "federation": {
+#   "name": "alpha",
+#   "listen_address": "127.0.0.1:12360",
+#   "peers": {
+#     "beta": {
+#       "address": "127.0.0.1:12361",
+#       "secret": "local",
+#       "channels": ["general"]
+#     }
+#   }
+# }
+# in nerdtalk_data/config.json.
+
+FOLDER=nerdtalk_federated
+
+mkdir -p $FOLDER
+if [ ! -f $FOLDER/config.json ]; then
+    cat > $FOLDER/config.json <<CONFIG
+{
+  "metrics_address": "127.0.0.1:9466",
+  "federation": {
+    "name": "beta",
+    "listen_address": "127.0.0.1:12361",
+    "peers": {
+      "alpha": {
+        "address": "127.0.0.1:12360",
+        "secret": "local",
+        "channels": ["general"]
+      }
+    }
+  }
+}
+CONFIG
+fi
+
+LOG=info cargo run --features local --bin server 127.0.0.1:12347 $FOLDER
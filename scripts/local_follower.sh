@@ -0,0 +1,19 @@
+#!/bin/sh
+
+# Runs a follower of ./scripts/local_server.sh on port 12346. The leader needs
+# "replication": { "listen_address": "127.0.0.1:12350", "secret": "local" }
+# in nerdtalk_data/config.json.
+
+FOLDER=nerdtalk_follower
+
+mkdir -p $FOLDER
+if [ ! -f $FOLDER/config.json ]; then
+    cat > $FOLDER/config.json <<CONFIG
+{
+  "metrics_address": "127.0.0.1:9465",
+  "replication": { "leader_address": "127.0.0.1:12350", "secret": "local" }
+}
+CONFIG
+fi
+
+LOG=info cargo run --features local --bin server 127.0.0.1:12346 $FOLDER
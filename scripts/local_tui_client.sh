@@ -1,3 +1,3 @@
 #!/bin/sh
 
-cargo run --features local --bin client-tui wss://127.0.0.1:12345/
+cargo run --features local --bin client-tui wss://127.0.0.1:12345/ "$@"
@@ -0,0 +1,210 @@
+//! Operates a running server through its admin socket.
+//!
+//! ```text
+//! server-admin [--socket <path>] <command>
+//! ```
+//!
+//! where `<command>` is one of `sessions`, `disconnect <session> [reason]`,
+//! `broadcast <text>`, `log-level <filter>`, `reload-config`, `promote`,
+//! `stats`, or `webhooks`.
+
+use std::{
+    env, error, fmt,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use comms::{
+    admin::{Request, Response, SessionId},
+    Codable,
+};
+
+const USAGE: &str = "Usage: server-admin [--socket <path>] <command>
+
+Commands:
+  sessions                       List connected sessions
+  disconnect <session> [reason]  Close the session numbered <session>
+  broadcast <text>               Show a notice to every session
+  log-level <filter>             Change the log filter, e.g. debug
+  reload-config                  Read config.json again
+  promote                        Stop following the leader and accept writes
+  stats                          Show server statistics
+  webhooks                       Show how delivering to webhooks has gone";
+
+#[derive(Debug)]
+enum Error {
+    Io(io::Error),
+    Usage(String),
+    Server(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => error.fmt(f),
+            Error::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            Error::Server(message) => message.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+fn usage_error(message: &str) -> Error {
+    Error::Usage(message.to_owned())
+}
+
+fn parse_request(
+    mut args: impl Iterator<Item = String>,
+) -> Result<Request, Error> {
+    let command = args.next().ok_or_else(|| usage_error("Missing command"))?;
+    let request = match command.as_str() {
+        "sessions" => Request::ListSessions,
+        "disconnect" => {
+            let session = args
+                .next()
+                .ok_or_else(|| usage_error("disconnect takes a session"))?;
+            let session = session.parse().map(SessionId).map_err(|_| {
+                usage_error(&format!("Invalid session {}", session))
+            })?;
+            let reason = args.collect::<Vec<_>>().join(" ");
+            return Ok(Request::Disconnect {
+                session,
+                reason: if reason.is_empty() {
+                    "Disconnected by an operator".to_owned()
+                } else {
+                    reason
+                },
+            });
+        }
+        "broadcast" => {
+            let text = args.collect::<Vec<_>>().join(" ");
+            if text.is_empty() {
+                return Err(usage_error("broadcast takes a message"));
+            }
+            return Ok(Request::Broadcast { text });
+        }
+        "log-level" => Request::SetLogLevel {
+            filter: args
+                .next()
+                .ok_or_else(|| usage_error("log-level takes a filter"))?,
+        },
+        "reload-config" => Request::ReloadConfig,
+        "promote" => Request::Promote,
+        "stats" => Request::Stats,
+        "webhooks" => Request::Webhooks,
+        _ => return Err(usage_error(&format!("Unknown command {}", command))),
+    };
+    match args.next() {
+        Some(extra) => Err(usage_error(&format!("Unexpected {}", extra))),
+        None => Ok(request),
+    }
+}
+
+fn send(socket: &Path, request: &Request) -> Result<Response, Error> {
+    let mut stream = UnixStream::connect(socket).map_err(|error| {
+        Error::Io(io::Error::new(
+            error.kind(),
+            format!("Failed to connect to {}: {}", socket.display(), error),
+        ))
+    })?;
+    let mut bytes = request.to_bytes();
+    bytes.push(b'\n');
+    stream.write_all(&bytes).map_err(Error::Io)?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(Error::Io)?;
+    Response::try_from_bytes(line.as_bytes()).map_err(|error| {
+        Error::Server(format!("Malformed response: {}", error))
+    })
+}
+
+fn run() -> Result<(), Error> {
+    let mut args = env::args().skip(1).peekable();
+    let mut socket = PathBuf::from("nerdtalk_data/admin.sock");
+    if args.peek().map(String::as_str) == Some("--socket") {
+        args.next();
+        socket = PathBuf::from(
+            args.next()
+                .ok_or_else(|| usage_error("--socket takes a path"))?,
+        );
+    }
+    let request = parse_request(args)?;
+
+    match send(&socket, &request)? {
+        Response::Sessions(sessions) => {
+            if sessions.is_empty() {
+                println!("No sessions");
+            }
+            for session in sessions {
+                let address = match session.address {
+                    Some(address) => address.to_string(),
+                    None => "client.sock".to_owned(),
+                };
+                println!(
+                    "{:<6} {:<24} {:<16} since {}",
+                    session.id,
+                    address,
+                    session.username.as_deref().unwrap_or("(logged out)"),
+                    session.connected_at.format("%Y-%m-%d %H:%M:%S UTC")
+                );
+            }
+        }
+        Response::Stats(stats) => {
+            let uptime = chrono::Utc::now() - stats.started_at;
+            println!(
+                "Up since {} ({}h {}m)",
+                stats.started_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                uptime.num_hours(),
+                uptime.num_minutes() % 60
+            );
+            println!("Sessions:         {}", stats.sessions);
+            println!("Users:            {}", stats.users);
+            println!("Channels:         {}", stats.channels);
+            println!("Entries:          {}", stats.entries);
+            println!("Messages handled: {}", stats.messages_handled);
+        }
+        Response::Webhooks(webhooks) => {
+            if webhooks.is_empty() {
+                println!("No webhooks");
+            }
+            for webhook in webhooks {
+                println!("{} ({})", webhook.name, webhook.url);
+                println!(
+                    "  {} delivered, {} pending, {} failed",
+                    webhook.delivered, webhook.pending, webhook.failed
+                );
+                if let Some(delivered_at) = webhook.last_delivered_at {
+                    println!(
+                        "  Last delivered {}",
+                        delivered_at.format("%Y-%m-%d %H:%M:%S UTC")
+                    );
+                }
+                if let Some((failed_at, error)) = webhook.last_error {
+                    println!(
+                        "  Last failed {}: {}",
+                        failed_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                        error
+                    );
+                }
+            }
+        }
+        Response::Done => println!("Done"),
+        Response::Error(message) => return Err(Error::Server(message)),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
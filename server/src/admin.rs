@@ -0,0 +1,413 @@
+//! The admin socket: a Unix-domain socket in the data directory that accepts
+//! [`comms::admin::Request`]s from operators on the same machine, keeping
+//! them off the public WebSocket.
+
+use std::{
+    collections::HashSet, fs, io, os::unix::fs::PermissionsExt, path::Path,
+};
+
+use comms::{
+    admin::{Request, Response, SessionInfo, Stats},
+    Codable,
+};
+use futures_util::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    audit::Event, channel, config::Config, logging, state::ServerState,
+};
+
+/// A request, who sent it, and where to send the response.
+pub type AdminRequest = (Request, String, oneshot::Sender<Response>);
+
+/// Listens on `path`, replacing any socket left there by a previous run, and
+/// forwards requests to `request_tx`. Only the user running the server can
+/// connect.
+pub fn listen(
+    path: &Path,
+    request_tx: mpsc::UnboundedSender<AdminRequest>,
+) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    tracing::info!("Admin socket listening on {}", path.display());
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let request_tx = request_tx.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve(stream, request_tx).await {
+                    tracing::warn!("Admin socket connection failed: {}", error);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(
+    stream: UnixStream,
+    request_tx: mpsc::UnboundedSender<AdminRequest>,
+) -> io::Result<()> {
+    let operator = match stream.peer_cred() {
+        Ok(credentials) => format!("admin socket (uid {})", credentials.uid()),
+        Err(_) => "admin socket".to_owned(),
+    };
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match Request::try_from_bytes(line.as_bytes()) {
+            Ok(request) => {
+                tracing::info!(
+                    %operator,
+                    request = %logging::body(&request),
+                    "Admin request"
+                );
+                let (response_tx, response_rx) = oneshot::channel();
+                request_tx
+                    .send((request, operator.clone(), response_tx))
+                    .map_err(|_| io::Error::other("server is shutting down"))?;
+                response_rx.await.map_err(io::Error::other)?
+            }
+            Err(error) => {
+                Response::Error(format!("Malformed request: {}", error))
+            }
+        };
+        let mut bytes = response.to_bytes();
+        bytes.push(b'\n');
+        write.write_all(&bytes).await?;
+    }
+    Ok(())
+}
+
+impl ServerState {
+    /// Handles `request`, returning the response to wait for off the
+    /// server's loop, since stats are counted by every channel.
+    pub async fn handle_admin(
+        &mut self,
+        request: Request,
+        operator: &str,
+    ) -> BoxFuture<'static, Response> {
+        let response = match request {
+            Request::ListSessions => {
+                let sessions = self.sessions.read().await;
+                let mut infos = sessions
+                    .iter()
+                    .filter(|(_, session)| !session.is_closed())
+                    .map(|(id, session)| SessionInfo {
+                        id: *id,
+                        address: session.origin.address(),
+                        username: session.username.clone(),
+                        connected_at: session.connected_at,
+                    })
+                    .collect::<Vec<_>>();
+                infos.sort_by_key(|info| info.connected_at);
+                Response::Sessions(infos)
+            }
+            Request::Disconnect { session, reason } => {
+                let mut sessions = self.sessions.write().await;
+                let Some(session) = sessions
+                    .get_mut(&session)
+                    .filter(|session| !session.is_closed())
+                else {
+                    let error = format!("No session {}", session);
+                    return future::ready(Response::Error(error)).boxed();
+                };
+                self.audit_log.record(
+                    Some(operator),
+                    Event::Disconnect {
+                        address: session.ip(),
+                        username: session.username.clone(),
+                        reason: reason.clone(),
+                    },
+                );
+                session.close(reason);
+                Response::Done
+            }
+            Request::Broadcast { text } => {
+                self.broadcast(&comms::ServerMessage::SystemNotice { text })
+                    .await;
+                Response::Done
+            }
+            Request::SetLogLevel { filter } => {
+                match crate::logging::set_filter(&filter) {
+                    Ok(()) => {
+                        tracing::info!(%filter, "Log filter replaced");
+                        Response::Done
+                    }
+                    Err(error) => {
+                        Response::Error(format!("Invalid filter: {}", error))
+                    }
+                }
+            }
+            Request::ReloadConfig => match Config::load(&self.config_path) {
+                Ok(mut config) => {
+                    config.replication = self.config.replication.clone();
+                    config.federation = self.config.federation.clone();
+                    for (channel, handle) in &self.channels {
+                        handle.send(channel::Request::SetRetention(
+                            config.retention_for(channel).clone(),
+                        ));
+                    }
+                    self.config = config;
+                    self.audit_log.record(Some(operator), Event::ConfigReload);
+                    Response::Done
+                }
+                Err(error) => Response::Error(format!(
+                    "Failed to read {}: {}",
+                    self.config_path.display(),
+                    error
+                )),
+            },
+            Request::Promote => match self.leader.take() {
+                Some((leader, follow_task)) => {
+                    follow_task.abort();
+                    self.config.replication.leader_address = None;
+                    tracing::info!(%leader, "Promoted to leader");
+                    self.audit_log
+                        .record(Some(operator), Event::Promotion { leader });
+                    Response::Done
+                }
+                None => Response::Error(
+                    "This server isn't following a leader".to_owned(),
+                ),
+            },
+            Request::Stats => {
+                let lengths =
+                    future::join_all(self.channels.values().map(|handle| {
+                        handle
+                            .request(|reply| channel::Request::Length { reply })
+                    }));
+                let sessions = self.sessions.read().await;
+                let users = sessions
+                    .values()
+                    .filter_map(|session| session.username.as_deref())
+                    .collect::<HashSet<_>>();
+                let stats = Stats {
+                    started_at: self.started_at,
+                    sessions: sessions
+                        .values()
+                        .filter(|session| !session.is_closed())
+                        .count(),
+                    users: users.len(),
+                    channels: self.channels.len(),
+                    entries: 0,
+                    messages_handled: self.messages_handled,
+                };
+                return async move {
+                    let entries =
+                        lengths.await.into_iter().flatten().sum::<usize>();
+                    Response::Stats(Stats { entries, ..stats })
+                }
+                .boxed();
+            }
+            Request::Webhooks => Response::Webhooks(self.webhooks.statuses()),
+        };
+        future::ready(response).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use comms::admin::SessionId;
+
+    use super::*;
+    use crate::{testing, Origin};
+
+    fn remote() -> Origin {
+        Origin::Remote(SocketAddr::from(([192, 0, 2, 1], 4000)))
+    }
+
+    #[tokio::test]
+    async fn lists_open_sessions() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        let (ethan, _ethan_rx) =
+            testing::connect(&state.sessions, remote(), Some("ethan")).await;
+        let (anonymous, _anonymous_rx) =
+            testing::connect(&state.sessions, Origin::ClientSocket, None).await;
+        let (gone, gone_rx) =
+            testing::connect(&state.sessions, remote(), Some("alice")).await;
+        drop(gone_rx);
+
+        let Response::Sessions(infos) = state
+            .handle_admin(Request::ListSessions, "test")
+            .await
+            .await
+        else {
+            panic!("expected sessions");
+        };
+        let ids = infos.iter().map(|info| info.id).collect::<Vec<_>>();
+        assert_eq!(ids, [ethan, anonymous]);
+        assert!(!ids.contains(&gone));
+        assert_eq!(infos[0].username.as_deref(), Some("ethan"));
+        assert_eq!(infos[0].address, remote().address());
+        assert_eq!(infos[1].address, None);
+    }
+
+    #[tokio::test]
+    async fn disconnects_sessions() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, remote(), Some("ethan")).await;
+
+        let response = state
+            .handle_admin(
+                Request::Disconnect {
+                    session: ethan,
+                    reason: "spamming".to_owned(),
+                },
+                "test",
+            )
+            .await
+            .await;
+        assert!(matches!(response, Response::Done));
+        assert!(matches!(
+            &testing::received(&mut ethan_rx)[..],
+            [comms::ServerMessage::Disconnected { reason }] if reason == "spamming"
+        ));
+
+        // It's already closed, and there was never a session 7.
+        for session in [ethan, SessionId(7)] {
+            let response = state
+                .handle_admin(
+                    Request::Disconnect {
+                        session,
+                        reason: String::new(),
+                    },
+                    "test",
+                )
+                .await
+                .await;
+            assert!(matches!(response, Response::Error(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcasts_to_every_session() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        let (_, mut ethan_rx) =
+            testing::connect(&state.sessions, remote(), Some("ethan")).await;
+        let (_, mut anonymous_rx) =
+            testing::connect(&state.sessions, Origin::ClientSocket, None).await;
+
+        let response = state
+            .handle_admin(
+                Request::Broadcast {
+                    text: "Restarting soon".to_owned(),
+                },
+                "test",
+            )
+            .await
+            .await;
+        assert!(matches!(response, Response::Done));
+        for rx in [&mut ethan_rx, &mut anonymous_rx] {
+            assert!(matches!(
+                &testing::received(rx)[..],
+                [comms::ServerMessage::SystemNotice { text }]
+                    if text == "Restarting soon"
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn reloads_config() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        fs::write(&state.config_path, r#"{"admins": ["ethan"]}"#).unwrap();
+        let response = state
+            .handle_admin(Request::ReloadConfig, "test")
+            .await
+            .await;
+        assert!(matches!(response, Response::Done));
+        assert_eq!(state.config.admins, ["ethan"]);
+
+        // A broken config is reported and the old one kept.
+        fs::write(&state.config_path, "{").unwrap();
+        let response = state
+            .handle_admin(Request::ReloadConfig, "test")
+            .await
+            .await;
+        assert!(matches!(response, Response::Error(_)));
+        assert_eq!(state.config.admins, ["ethan"]);
+    }
+
+    #[tokio::test]
+    async fn reports_stats_and_refuses_promotion_of_leaders() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        let _ethan =
+            testing::connect(&state.sessions, remote(), Some("ethan")).await;
+        let _phone =
+            testing::connect(&state.sessions, remote(), Some("ethan")).await;
+        let _anonymous =
+            testing::connect(&state.sessions, remote(), None).await;
+
+        let Response::Stats(stats) =
+            state.handle_admin(Request::Stats, "test").await.await
+        else {
+            panic!("expected stats");
+        };
+        assert_eq!(stats.sessions, 3);
+        assert_eq!(stats.users, 1);
+        assert_eq!(stats.channels, 0);
+
+        let response = state.handle_admin(Request::Promote, "test").await.await;
+        assert!(matches!(response, Response::Error(_)));
+    }
+
+    /// Sends `line` over the admin socket, returning the response.
+    async fn ask(client: &mut BufReader<UnixStream>, line: &[u8]) -> Response {
+        client.get_mut().write_all(line).await.unwrap();
+        client.get_mut().write_all(b"\n").await.unwrap();
+        let mut response = String::new();
+        client.read_line(&mut response).await.unwrap();
+        Response::try_from_bytes(response.as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_requests_line_by_line() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (request_tx, mut request_rx) = mpsc::unbounded_channel();
+        tokio::spawn(serve(server, request_tx));
+        tokio::spawn(async move {
+            while let Some((request, _, response_tx)) = request_rx.recv().await
+            {
+                let response = match request {
+                    Request::Stats => Response::Error("no stats".to_owned()),
+                    _ => Response::Done,
+                };
+                let _ = response_tx.send(response);
+            }
+        });
+
+        let mut client = BufReader::new(client);
+        assert!(matches!(
+            ask(&mut client, &Request::Promote.to_bytes()).await,
+            Response::Done
+        ));
+        assert!(matches!(
+            ask(&mut client, &Request::Stats.to_bytes()).await,
+            Response::Error(error) if error == "no stats"
+        ));
+        assert!(matches!(
+            ask(&mut client, b"{\"Reboot\": null}").await,
+            Response::Error(error) if error.starts_with("Malformed request")
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use comms::{admin::SessionId, AttachmentError};
+
+struct PendingUpload {
+    file_name: String,
+    size: u64,
+    id: chat::AttachmentId,
+    bytes: Vec<u8>,
+}
+
+pub enum UploadStatus {
+    InProgress { received: u64 },
+    Complete(chat::Attachment),
+}
+
+/// Content-addressed attachment storage on local disk. Each attachment lives
+/// in a file named after its [`chat::AttachmentId`], so uploading the same
+/// bytes twice stores them once.
+pub struct AttachmentStore {
+    directory: PathBuf,
+    pending: HashMap<(SessionId, comms::ClientId), PendingUpload>,
+    /// How many [`Release`]s are deciding whether to delete each attachment.
+    /// Posts can't refer to these until they're done.
+    releasing: Arc<Mutex<HashMap<chat::AttachmentId, usize>>>,
+}
+
+/// Attachments a channel no longer refers to, being checked against every
+/// other channel before they're deleted.
+pub struct Release {
+    directory: PathBuf,
+    releasing: Arc<Mutex<HashMap<chat::AttachmentId, usize>>>,
+    attachments: Vec<chat::Attachment>,
+}
+
+fn storage_error(error: io::Error) -> AttachmentError {
+    AttachmentError::Storage(error.to_string())
+}
+
+impl AttachmentStore {
+    pub fn open(directory: impl AsRef<Path>) -> io::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            pending: HashMap::new(),
+            releasing: Arc::default(),
+        })
+    }
+
+    fn path_of(&self, id: &chat::AttachmentId) -> Option<PathBuf> {
+        path_in(&self.directory, id)
+    }
+
+    pub fn begin(
+        &mut self,
+        sender: SessionId,
+        client_id: comms::ClientId,
+        file_name: String,
+        size: u64,
+        id: chat::AttachmentId,
+    ) -> Result<(), AttachmentError> {
+        if size > comms::MAX_ATTACHMENT_SIZE {
+            return Err(AttachmentError::TooLarge {
+                size,
+                limit: comms::MAX_ATTACHMENT_SIZE,
+            });
+        }
+        if !id.is_well_formed() {
+            return Err(AttachmentError::IntegrityMismatch { expected: id });
+        }
+        let key = (sender, client_id);
+        let in_progress = self
+            .pending
+            .keys()
+            .filter(|(session, _)| *session == sender)
+            .count();
+        if !self.pending.contains_key(&key)
+            && in_progress >= comms::MAX_CONCURRENT_UPLOADS
+        {
+            return Err(AttachmentError::TooManyUploads {
+                limit: comms::MAX_CONCURRENT_UPLOADS,
+            });
+        }
+        // The buffer grows as chunks arrive, so announcing a large upload
+        // doesn't reserve anything by itself.
+        self.pending.insert(
+            key,
+            PendingUpload {
+                file_name,
+                size,
+                id,
+                bytes: vec![],
+            },
+        );
+        Ok(())
+    }
+
+    /// Discards every upload `session` still had in progress.
+    pub fn forget_session(&mut self, session: SessionId) {
+        self.pending.retain(|(sender, _), _| *sender != session);
+    }
+
+    /// Appends `bytes` to the upload started by `sender` with `client_id`,
+    /// storing the attachment once all of its bytes have arrived and hash
+    /// correctly. A failed upload is discarded.
+    pub fn receive_chunk(
+        &mut self,
+        sender: SessionId,
+        client_id: comms::ClientId,
+        offset: u64,
+        bytes: Vec<u8>,
+    ) -> Result<UploadStatus, AttachmentError> {
+        let key = (sender, client_id);
+        let pending = self
+            .pending
+            .get_mut(&key)
+            .ok_or(AttachmentError::UnknownUpload)?;
+
+        let received = pending.bytes.len() as u64;
+        let error = if bytes.len() > comms::ATTACHMENT_CHUNK_SIZE {
+            Some(AttachmentError::ChunkTooLarge {
+                length: bytes.len(),
+                limit: comms::ATTACHMENT_CHUNK_SIZE,
+            })
+        } else if offset != received {
+            Some(AttachmentError::UnexpectedOffset {
+                expected: received,
+                actual: offset,
+            })
+        } else if received + bytes.len() as u64 > pending.size {
+            Some(AttachmentError::TooLarge {
+                size: received + bytes.len() as u64,
+                limit: pending.size,
+            })
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            self.pending.remove(&key);
+            return Err(error);
+        }
+
+        pending.bytes.extend_from_slice(&bytes);
+        let received = pending.bytes.len() as u64;
+        if received < pending.size {
+            return Ok(UploadStatus::InProgress { received });
+        }
+
+        let pending = self.pending.remove(&key).expect("checked above");
+        if chat::AttachmentId::from_contents(&pending.bytes) != pending.id {
+            return Err(AttachmentError::IntegrityMismatch {
+                expected: pending.id,
+            });
+        }
+        self.write(&pending.id, &pending.bytes)
+            .map_err(storage_error)?;
+
+        Ok(UploadStatus::Complete(chat::Attachment {
+            id: pending.id,
+            file_name: pending.file_name,
+            size: pending.size,
+        }))
+    }
+
+    fn write(&self, id: &chat::AttachmentId, bytes: &[u8]) -> io::Result<()> {
+        let path = self.path_of(id).expect("id was validated on upload");
+        if path.exists() {
+            return Ok(());
+        }
+        // Write to a temporary file first so a crash never leaves a
+        // truncated file under a valid content address.
+        let temporary_path = path.with_extension("partial");
+        fs::write(&temporary_path, bytes)?;
+        fs::rename(temporary_path, path)
+    }
+
+    pub fn read(
+        &self,
+        id: &chat::AttachmentId,
+    ) -> Result<Vec<u8>, AttachmentError> {
+        let path = self
+            .path_of(id)
+            .ok_or_else(|| AttachmentError::NotFound(id.clone()))?;
+        fs::read(path).map_err(|error| match error.kind() {
+            io::ErrorKind::NotFound => AttachmentError::NotFound(id.clone()),
+            _ => storage_error(error),
+        })
+    }
+
+    /// Starts releasing `attachments`, which posts can't refer to until the
+    /// release is finished.
+    pub fn release(&self, attachments: Vec<chat::Attachment>) -> Release {
+        let mut releasing = self.releasing.lock().unwrap();
+        for attachment in &attachments {
+            *releasing.entry(attachment.id.clone()).or_default() += 1;
+        }
+        Release {
+            directory: self.directory.clone(),
+            releasing: self.releasing.clone(),
+            attachments,
+        }
+    }
+
+    /// Whether `attachment` refers to a stored file of the right size.
+    pub fn contains(&self, attachment: &chat::Attachment) -> bool {
+        if self.releasing.lock().unwrap().contains_key(&attachment.id) {
+            return false;
+        }
+        self.path_of(&attachment.id)
+            .and_then(|path| fs::metadata(path).ok())
+            .is_some_and(|metadata| metadata.len() == attachment.size)
+    }
+}
+
+impl Release {
+    /// Deletes the released attachments not in `referenced`, and lets posts
+    /// refer to the rest again.
+    pub fn finish(self, referenced: &[chat::Attachment]) {
+        for attachment in &self.attachments {
+            if referenced.contains(attachment) {
+                continue;
+            }
+            if let Err(error) = remove(&self.directory, &attachment.id) {
+                tracing::error!(
+                    "Failed to delete attachment {}: {}",
+                    attachment.id,
+                    error
+                );
+            }
+        }
+        let mut releasing = self.releasing.lock().unwrap();
+        for attachment in &self.attachments {
+            if let Some(count) = releasing.get_mut(&attachment.id) {
+                *count -= 1;
+                if *count == 0 {
+                    releasing.remove(&attachment.id);
+                }
+            }
+        }
+    }
+}
+
+fn path_in(directory: &Path, id: &chat::AttachmentId) -> Option<PathBuf> {
+    id.is_well_formed().then(|| directory.join(&id.0))
+}
+
+/// Deletes the stored file for `id` in `directory`, if there is one.
+fn remove(directory: &Path, id: &chat::AttachmentId) -> io::Result<()> {
+    let Some(path) = path_in(directory, id) else {
+        return Ok(());
+    };
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_id(n: u32) -> comms::ClientId {
+        let timestamp = chrono::DateTime::from_timestamp(n.into(), 0);
+        serde_json::from_value(serde_json::json!({ "timestamp": timestamp }))
+            .unwrap()
+    }
+
+    fn begin(
+        store: &mut AttachmentStore,
+        session: u64,
+        n: u32,
+    ) -> Result<(), AttachmentError> {
+        store.begin(
+            SessionId(session),
+            client_id(n),
+            "file.txt".to_owned(),
+            comms::MAX_ATTACHMENT_SIZE,
+            chat::AttachmentId::from_contents(b"contents"),
+        )
+    }
+
+    #[test]
+    fn caps_uploads_in_progress_per_session() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut store = AttachmentStore::open(directory.path()).unwrap();
+        for n in 0..comms::MAX_CONCURRENT_UPLOADS as u32 {
+            begin(&mut store, 0, n).unwrap();
+        }
+        assert!(matches!(
+            begin(&mut store, 0, 99),
+            Err(AttachmentError::TooManyUploads { .. })
+        ));
+        // Restarting an upload doesn't count twice, and other sessions have
+        // their own limit.
+        begin(&mut store, 0, 0).unwrap();
+        begin(&mut store, 1, 99).unwrap();
+    }
+
+    #[test]
+    fn forgets_uploads_of_ended_sessions() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut store = AttachmentStore::open(directory.path()).unwrap();
+        for n in 0..comms::MAX_CONCURRENT_UPLOADS as u32 {
+            begin(&mut store, 0, n).unwrap();
+        }
+        begin(&mut store, 1, 0).unwrap();
+        store.forget_session(SessionId(0));
+        assert!(matches!(
+            store.receive_chunk(SessionId(0), client_id(0), 0, vec![0]),
+            Err(AttachmentError::UnknownUpload)
+        ));
+        begin(&mut store, 0, 99).unwrap();
+        assert!(store
+            .receive_chunk(SessionId(1), client_id(0), 0, vec![0])
+            .is_ok());
+    }
+
+    #[test]
+    fn stores_completed_uploads() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut store = AttachmentStore::open(directory.path()).unwrap();
+        let bytes = b"contents".to_vec();
+        let id = chat::AttachmentId::from_contents(&bytes);
+        store
+            .begin(
+                SessionId(0),
+                client_id(0),
+                "file.txt".to_owned(),
+                bytes.len() as u64,
+                id.clone(),
+            )
+            .unwrap();
+        let Ok(UploadStatus::Complete(attachment)) =
+            store.receive_chunk(SessionId(0), client_id(0), 0, bytes.clone())
+        else {
+            panic!("upload didn't complete");
+        };
+        assert_eq!(attachment.id, id);
+        assert!(store.contains(&attachment));
+        assert_eq!(store.read(&id).unwrap(), bytes);
+    }
+}
@@ -0,0 +1,685 @@
+//! An append-only log of security-relevant events, each record carrying the
+//! hash of the one before so that editing, removing, or reordering records
+//! breaks the chain, and the `server audit` subcommand, which verifies and
+//! searches it.
+//!
+//! Records removed from the end leave an intact, shorter chain, so that
+//! can't be detected from the log alone. `server audit verify` prints the
+//! last record's hash to keep somewhere else and compare against later.
+
+use std::{
+    fmt, fs,
+    io::{self, BufRead, Write},
+    mem,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::Error;
+
+/// The `previous_hash` of the first record.
+const GENESIS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// `address` is unset for clients on `client.sock`.
+    Login {
+        address: Option<IpAddr>,
+    },
+    /// A user tried to log in under a banned or invalid username.
+    LoginRefused {
+        address: Option<IpAddr>,
+        reason: String,
+    },
+    /// A banned address tried to connect.
+    ConnectionRefused {
+        address: IpAddr,
+    },
+    Rename {
+        username: String,
+    },
+    Moderation {
+        channel: chat::ChannelName,
+        action: comms::ModerationAction,
+        reason: Option<String>,
+    },
+    Deletion {
+        channel: chat::ChannelName,
+        slot_number: usize,
+        author: String,
+    },
+    RoleChange {
+        channel: Option<chat::ChannelName>,
+        username: String,
+        role: Option<comms::Role>,
+    },
+    /// An operator closed a session through the admin socket.
+    Disconnect {
+        address: Option<IpAddr>,
+        username: Option<String>,
+        reason: String,
+    },
+    ConfigReload,
+    /// An operator made a follower stop following `leader` and accept
+    /// writes.
+    Promotion {
+        leader: SocketAddr,
+    },
+}
+
+impl Event {
+    /// What happened, e.g. `login` or `ban`, for filtering.
+    pub fn action(&self) -> &'static str {
+        match self {
+            Event::Login { .. } => "login",
+            Event::LoginRefused { .. } => "login_refused",
+            Event::ConnectionRefused { .. } => "connection_refused",
+            Event::Rename { .. } => "rename",
+            Event::Moderation { action, .. } => match action {
+                comms::ModerationAction::Kick { .. } => "kick",
+                comms::ModerationAction::Ban { .. } => "ban",
+                comms::ModerationAction::Unban { .. } => "unban",
+                comms::ModerationAction::Mute { .. } => "mute",
+                comms::ModerationAction::Unmute { .. } => "unmute",
+                comms::ModerationAction::RemoveEntry { .. } => "remove_entry",
+            },
+            Event::Deletion { .. } => "deletion",
+            Event::RoleChange { .. } => "role_change",
+            Event::Disconnect { .. } => "disconnect",
+            Event::ConfigReload => "config_reload",
+            Event::Promotion { .. } => "promotion",
+        }
+    }
+
+    /// The user the event happened to, if not whoever did it.
+    fn subject(&self) -> Option<&str> {
+        match self {
+            Event::Login { .. }
+            | Event::LoginRefused { .. }
+            | Event::ConnectionRefused { .. }
+            | Event::ConfigReload
+            | Event::Promotion { .. } => None,
+            Event::Rename { username } | Event::RoleChange { username, .. } => {
+                Some(username)
+            }
+            Event::Moderation { action, .. } => match action {
+                comms::ModerationAction::Kick { username }
+                | comms::ModerationAction::Ban { username, .. }
+                | comms::ModerationAction::Unban { username }
+                | comms::ModerationAction::Mute { username, .. }
+                | comms::ModerationAction::Unmute { username } => {
+                    Some(username)
+                }
+                comms::ModerationAction::RemoveEntry { .. } => None,
+            },
+            Event::Deletion { author, .. } => Some(author),
+            Event::Disconnect { username, .. } => username.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Who did it, if anyone had logged in.
+    pub actor: Option<String>,
+    pub event: Event,
+    pub previous_hash: String,
+    /// Hash of every other field, including `previous_hash`.
+    pub hash: String,
+}
+
+impl Record {
+    fn digest(&self) -> String {
+        let fields = (
+            self.sequence,
+            &self.timestamp,
+            &self.actor,
+            &self.event,
+            &self.previous_hash,
+        );
+        let bytes = serde_json::to_vec(&fields).expect("failed to serialize");
+        Sha256::digest(bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Whether `username` did this or had it done to them.
+    pub fn involves(&self, username: &str) -> bool {
+        self.actor.as_deref() == Some(username)
+            || self.event.subject() == Some(username)
+    }
+}
+
+/// Where the chain continues from.
+struct Tail {
+    sequence: u64,
+    hash: String,
+}
+
+/// An event waiting to be appended, stamped with when it happened.
+struct Pending {
+    timestamp: DateTime<Utc>,
+    actor: Option<String>,
+    event: Event,
+}
+
+/// Appends records from a task of its own, so the file is never written from
+/// the server's loop.
+pub struct AuditLog {
+    pending_tx: mpsc::UnboundedSender<Pending>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path` to append to it, creating it if needed. A
+    /// broken chain is logged, not refused, so the server still starts; new
+    /// records carry on from the last one.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let tail = match verify(&path) {
+            Ok(Some(last)) => Tail {
+                sequence: last.sequence + 1,
+                hash: last.hash,
+            },
+            Ok(None) => Tail {
+                sequence: 0,
+                hash: GENESIS_HASH.to_owned(),
+            },
+            Err(VerifyError::Io(error)) => return Err(error),
+            Err(error) => {
+                tracing::error!(
+                    "Audit log {} is broken: {}",
+                    path.display(),
+                    error
+                );
+                let last =
+                    read_records(&path)?
+                        .filter_map(Result::ok)
+                        .last()
+                        .ok_or_else(|| io::Error::other(error.to_string()))?;
+                Tail {
+                    sequence: last.sequence + 1,
+                    hash: last.hash,
+                }
+            }
+        };
+        let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_records(path, tail, pending_rx));
+        Ok(Self { pending_tx })
+    }
+
+    /// Queues a record of `actor` causing `event`, logging rather than
+    /// failing if it can't be written so the event itself still goes ahead.
+    pub fn record(&self, actor: Option<&str>, event: Event) {
+        let pending = Pending {
+            timestamp: Utc::now(),
+            actor: actor.map(str::to_owned),
+            event,
+        };
+        if let Err(mpsc::error::SendError(pending)) =
+            self.pending_tx.send(pending)
+        {
+            tracing::error!(
+                "Failed to record {:?}: the audit log stopped",
+                pending.event
+            );
+        }
+    }
+}
+
+/// Appends records in batches as they're queued, for as long as the server
+/// runs.
+async fn write_records(
+    path: PathBuf,
+    mut tail: Tail,
+    mut pending_rx: mpsc::UnboundedReceiver<Pending>,
+) {
+    let mut batch = vec![];
+    while pending_rx.recv_many(&mut batch, 64).await > 0 {
+        let path = path.clone();
+        let pending = mem::take(&mut batch);
+        let appended = tokio::task::spawn_blocking(move || {
+            append(&path, &mut tail, pending);
+            tail
+        })
+        .await;
+        match appended {
+            Ok(appended_tail) => tail = appended_tail,
+            Err(error) => {
+                tracing::error!("Audit log stopped: {}", error);
+                return;
+            }
+        }
+    }
+}
+
+/// Appends a record of each of `pending` to the log at `path`, carrying on
+/// the chain from `tail`. Records that can't be written are logged and left
+/// out of the chain.
+fn append(path: &Path, tail: &mut Tail, pending: Vec<Pending>) {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path);
+    for Pending {
+        timestamp,
+        actor,
+        event,
+    } in pending
+    {
+        let mut record = Record {
+            sequence: tail.sequence,
+            timestamp,
+            actor,
+            event,
+            previous_hash: tail.hash.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.digest();
+        let result = serde_json::to_vec(&record)
+            .map_err(io::Error::other)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                match &mut file {
+                    Ok(file) => file.write_all(&line),
+                    Err(error) => {
+                        Err(io::Error::new(error.kind(), error.to_string()))
+                    }
+                }
+            });
+        match result {
+            Ok(()) => {
+                tail.sequence += 1;
+                tail.hash = record.hash;
+            }
+            Err(error) => {
+                tracing::error!(
+                    "Failed to record {:?}: {}",
+                    record.event,
+                    error
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(io::Error),
+    Malformed {
+        line: usize,
+        error: serde_json::Error,
+    },
+    Broken {
+        sequence: u64,
+        problem: &'static str,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Io(error) => error.fmt(f),
+            VerifyError::Malformed { line, error } => {
+                write!(f, "Line {} is not a record: {}", line, error)
+            }
+            VerifyError::Broken { sequence, problem } => {
+                write!(f, "Record {} {}", sequence, problem)
+            }
+        }
+    }
+}
+
+impl VerifyError {
+    fn into_error(self) -> Error {
+        match self {
+            VerifyError::Io(error) => Error::Io(error),
+            error => Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                error.to_string(),
+            )),
+        }
+    }
+}
+
+fn read_records(
+    path: &Path,
+) -> io::Result<impl Iterator<Item = Result<Record, VerifyError>>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => Some(file),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error),
+    };
+    Ok(file
+        .into_iter()
+        .flat_map(|file| io::BufReader::new(file).lines())
+        .enumerate()
+        .map(|(index, line)| {
+            let line = line.map_err(VerifyError::Io)?;
+            serde_json::from_str(&line).map_err(|error| {
+                VerifyError::Malformed {
+                    line: index + 1,
+                    error,
+                }
+            })
+        }))
+}
+
+/// Checks every record's hash and link to the one before, returning the last
+/// record if there are any.
+pub fn verify(path: &Path) -> Result<Option<Record>, VerifyError> {
+    let mut last: Option<Record> = None;
+    for record in read_records(path).map_err(VerifyError::Io)? {
+        let record = record?;
+        let (expected_sequence, expected_hash) = match &last {
+            Some(last) => (last.sequence + 1, last.hash.as_str()),
+            None => (0, GENESIS_HASH),
+        };
+        let broken = |problem| VerifyError::Broken {
+            sequence: record.sequence,
+            problem,
+        };
+        if record.sequence != expected_sequence {
+            return Err(broken("is out of sequence, so records are missing"));
+        }
+        if record.previous_hash != expected_hash {
+            return Err(broken("doesn't follow the record before it"));
+        }
+        if record.hash != record.digest() {
+            return Err(broken("was modified after it was written"));
+        }
+        last = Some(record);
+    }
+    Ok(last)
+}
+
+const USAGE: &str = "Usage: server audit verify <data directory>\n       \
+                     server audit list <data directory> [--user <username>] \
+                     [--action <action>] [--since <time>] [--until <time>]";
+
+fn usage_error(problem: &str) -> Error {
+    Error::Usage(format!("{}\n{}", problem, USAGE))
+}
+
+/// Reads RFC 3339 times, or dates, which mean midnight UTC.
+fn parse_time(
+    flag: &str,
+    value: Option<String>,
+) -> Result<DateTime<Utc>, Error> {
+    let value =
+        value.ok_or_else(|| usage_error(&format!("{} takes a time", flag)))?;
+    DateTime::parse_from_rfc3339(&value)
+        .map(|time| time.to_utc())
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+                .map(|time| time.and_utc())
+        })
+        .ok_or_else(|| {
+            usage_error(&format!(
+                "{} takes a time like 2024-06-01 or 2024-06-01T12:00:00Z",
+                flag
+            ))
+        })
+}
+
+/// Verifies the audit log, or prints the records matching every given filter
+/// as JSON lines. `args` follow the subcommand name.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    let command = args.next().ok_or_else(|| usage_error("Missing command"))?;
+    let path = PathBuf::from(
+        args.next()
+            .ok_or_else(|| usage_error("Missing data directory"))?,
+    )
+    .join("audit.jsonl");
+
+    match command.as_str() {
+        "verify" => {
+            if let Some(flag) = args.next() {
+                return Err(usage_error(&format!("Unknown option {}", flag)));
+            }
+            match verify(&path) {
+                Ok(Some(last)) => {
+                    println!(
+                        "{} records, chain intact, last hash {}",
+                        last.sequence + 1,
+                        last.hash
+                    );
+                    Ok(())
+                }
+                Ok(None) => {
+                    println!("0 records");
+                    Ok(())
+                }
+                Err(error) => Err(error.into_error()),
+            }
+        }
+        "list" => {
+            let mut user = None;
+            let mut action = None;
+            let mut since = None;
+            let mut until = None;
+            while let Some(flag) = args.next() {
+                match flag.as_str() {
+                    "--user" => {
+                        user = Some(args.next().ok_or_else(|| {
+                            usage_error("--user takes a username")
+                        })?);
+                    }
+                    "--action" => {
+                        action = Some(args.next().ok_or_else(|| {
+                            usage_error("--action takes an action")
+                        })?);
+                    }
+                    "--since" => {
+                        since = Some(parse_time("--since", args.next())?)
+                    }
+                    "--until" => {
+                        until = Some(parse_time("--until", args.next())?)
+                    }
+                    _ => {
+                        return Err(usage_error(&format!(
+                            "Unknown option {}",
+                            flag
+                        )))
+                    }
+                }
+            }
+
+            let mut stdout = io::stdout().lock();
+            for record in read_records(&path).map_err(Error::Io)? {
+                let record = record.map_err(VerifyError::into_error)?;
+                if user.as_ref().is_some_and(|user| !record.involves(user))
+                    || action
+                        .as_ref()
+                        .is_some_and(|action| record.event.action() != action)
+                    || since.is_some_and(|since| record.timestamp < since)
+                    || until.is_some_and(|until| record.timestamp > until)
+                {
+                    continue;
+                }
+                let line = serde_json::to_string(&record)
+                    .map_err(|error| Error::Io(io::Error::other(error)))?;
+                writeln!(stdout, "{}", line).map_err(Error::Io)?;
+            }
+            Ok(())
+        }
+        _ => Err(usage_error(&format!("Unknown command {}", command))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn login(actor: &str) -> Pending {
+        Pending {
+            timestamp: Utc::now(),
+            actor: Some(actor.to_owned()),
+            event: Event::Login { address: None },
+        }
+    }
+
+    /// A log of a login by each of `actors`, as its lines.
+    fn write_log(path: &Path, actors: &[&str]) -> Vec<String> {
+        let mut tail = Tail {
+            sequence: 0,
+            hash: GENESIS_HASH.to_owned(),
+        };
+        append(
+            path,
+            &mut tail,
+            actors.iter().map(|actor| login(actor)).collect(),
+        );
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn rewrite_log(path: &Path, lines: &[String]) {
+        fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    fn broken_at(path: &Path) -> (u64, &'static str) {
+        match verify(path) {
+            Err(VerifyError::Broken { sequence, problem }) => {
+                (sequence, problem)
+            }
+            other => panic!("expected a broken chain, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifies_an_intact_chain() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        assert!(verify(&path).unwrap().is_none());
+        write_log(&path, &["alice", "bob", "carol"]);
+        let last = verify(&path).unwrap().unwrap();
+        assert_eq!(last.sequence, 2);
+        assert_eq!(last.actor.as_deref(), Some("carol"));
+    }
+
+    #[test]
+    fn catches_edited_records() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        let mut lines = write_log(&path, &["alice", "bob", "carol"]);
+
+        let mut record: Record = serde_json::from_str(&lines[1]).unwrap();
+        record.actor = Some("mallory".to_owned());
+        lines[1] = serde_json::to_string(&record).unwrap();
+        rewrite_log(&path, &lines);
+        assert_eq!(broken_at(&path), (1, "was modified after it was written"));
+
+        // Rehashing the edited record breaks the link from the next one.
+        record.hash = record.digest();
+        lines[1] = serde_json::to_string(&record).unwrap();
+        rewrite_log(&path, &lines);
+        assert_eq!(
+            broken_at(&path),
+            (2, "doesn't follow the record before it")
+        );
+    }
+
+    #[test]
+    fn catches_removed_records() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        let mut lines = write_log(&path, &["alice", "bob", "carol"]);
+        lines.remove(1);
+        rewrite_log(&path, &lines);
+        assert_eq!(
+            broken_at(&path),
+            (2, "is out of sequence, so records are missing")
+        );
+
+        // Including the first.
+        let path = directory.path().join("audit-2.jsonl");
+        let mut lines = write_log(&path, &["alice", "bob"]);
+        lines.remove(0);
+        rewrite_log(&path, &lines);
+        assert_eq!(broken_at(&path).0, 1);
+    }
+
+    #[test]
+    fn catches_reordered_records() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        let mut lines = write_log(&path, &["alice", "bob", "carol"]);
+        lines.swap(1, 2);
+        rewrite_log(&path, &lines);
+        assert_eq!(broken_at(&path).0, 2);
+
+        // Renumbering them to match still breaks the links.
+        let mut records = lines
+            .iter()
+            .map(|line| serde_json::from_str::<Record>(line).unwrap())
+            .collect::<Vec<_>>();
+        records[1].sequence = 1;
+        records[2].sequence = 2;
+        for record in &mut records {
+            record.hash = record.digest();
+        }
+        let lines = records
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect::<Vec<_>>();
+        rewrite_log(&path, &lines);
+        assert_eq!(
+            broken_at(&path),
+            (1, "doesn't follow the record before it")
+        );
+    }
+
+    #[test]
+    fn cannot_catch_truncation_alone() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        let lines = write_log(&path, &["alice", "bob", "carol"]);
+        let head_hash = verify(&path).unwrap().unwrap().hash;
+
+        rewrite_log(&path, &lines[..2]);
+        let last = verify(&path).unwrap().unwrap();
+        // Only the head hash kept elsewhere shows the last record is gone.
+        assert_eq!(last.sequence, 1);
+        assert_ne!(last.hash, head_hash);
+    }
+
+    #[tokio::test]
+    async fn records_from_its_own_task() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        write_log(&path, &["alice"]);
+
+        let audit_log = AuditLog::open(&path).unwrap();
+        audit_log.record(Some("bob"), Event::ConfigReload);
+        audit_log.record(
+            None,
+            Event::ConnectionRefused {
+                address: IpAddr::from([192, 0, 2, 1]),
+            },
+        );
+        for _ in 0..100 {
+            if verify(&path).unwrap().unwrap().sequence == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let last = verify(&path).unwrap().unwrap();
+        assert_eq!(last.sequence, 2);
+        assert_eq!(last.event.action(), "connection_refused");
+    }
+}
@@ -0,0 +1,64 @@
+//! Sending one message to many sessions. A broadcast is encoded once into a
+//! reference-counted buffer that every session's writer shares, rather than
+//! being cloned and serialized again for each session.
+
+use std::collections::HashMap;
+
+use comms::{admin::SessionId, Codable};
+use transport::{Bytes, Frame};
+
+use crate::{logging, Outbox};
+
+/// A server message encoded for the wire. Cloning it only bumps a reference
+/// count.
+#[derive(Clone)]
+pub struct Encoded {
+    bytes: Bytes,
+}
+
+impl Encoded {
+    pub fn new(message: &comms::ServerMessage) -> Self {
+        tracing::debug!(body = %logging::body(message), "Broadcasting");
+        Self {
+            bytes: Bytes::from(message.to_bytes()),
+        }
+    }
+
+    pub fn to_frame(&self) -> Frame {
+        Frame::Data(self.bytes.clone())
+    }
+}
+
+/// Sessions that receive every broadcast, by session.
+#[derive(Default)]
+pub struct Subscribers {
+    outboxes: HashMap<SessionId, Outbox>,
+}
+
+impl Subscribers {
+    /// Adds `outbox`, replacing any earlier one for the same session.
+    pub fn insert(&mut self, outbox: Outbox) {
+        self.outboxes.insert(outbox.session, outbox);
+    }
+
+    /// Sends `message` to every subscriber, forgetting those whose connections
+    /// have ended. Returns how many it was sent to.
+    pub fn broadcast(&mut self, message: &comms::ServerMessage) -> usize {
+        self.outboxes.retain(|_, outbox| !outbox.is_closed());
+        let encoded = Encoded::new(message);
+        for outbox in self.outboxes.values() {
+            outbox.send_encoded(&encoded);
+        }
+        self.outboxes.len()
+    }
+}
+
+impl FromIterator<Outbox> for Subscribers {
+    fn from_iter<I: IntoIterator<Item = Outbox>>(outboxes: I) -> Self {
+        let mut subscribers = Self::default();
+        for outbox in outboxes {
+            subscribers.insert(outbox);
+        }
+        subscribers
+    }
+}
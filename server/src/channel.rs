@@ -0,0 +1,797 @@
+//! Each channel runs in its own task, which owns the channel's log, storage,
+//! search index, subscribers, followers, and peers. The main loop routes
+//! requests about a channel to its task, so a slow request in one channel
+//! doesn't hold up the others, and busy channels run on separate cores.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    audit::{AuditLog, Event},
+    broadcast::Subscribers,
+    chat_log::{ChangeError, Committed, FakeChatLog, Relayed, Replicated},
+    config::RetentionPolicy,
+    metrics::METRICS,
+    recent_posts::RecentPosts,
+    replication::Record,
+    search::SearchIndex,
+    storage::LogStorage,
+    Outbox,
+};
+
+/// What [`Request::Change`] does to an entry.
+pub enum Change {
+    Edit(String),
+    Delete,
+}
+
+/// Which part of the history [`Request::History`] asks for.
+pub enum History {
+    /// The last `count` entries up to `up_to_slot_number`.
+    Before {
+        count: usize,
+        up_to_slot_number: Option<usize>,
+    },
+    /// Up to `limit` entries after `after_slot_number`.
+    After {
+        after_slot_number: Option<usize>,
+        limit: usize,
+    },
+}
+
+/// Part of a channel's history, and where the history starts and ends.
+#[derive(Serialize)]
+pub struct Page {
+    pub entries: Vec<chat::Entry>,
+    pub head_slot: Option<usize>,
+    pub history_start: usize,
+}
+
+#[derive(Clone)]
+pub struct NewEntry {
+    pub kind: chat::EntryKind,
+    pub username: String,
+    pub content: String,
+    pub attachments: Vec<chat::Attachment>,
+}
+
+pub enum Request {
+    /// Commits an entry and sends it to every subscriber. With a nonce, also
+    /// acknowledges it to `outbox`, or only acknowledges the earlier post if
+    /// the same user already committed that nonce.
+    Append {
+        entry: NewEntry,
+        ack: Option<(Outbox, comms::Nonce)>,
+    },
+    History {
+        outbox: Outbox,
+        client_id: comms::ClientId,
+        range: History,
+    },
+    /// Replies with part of the history, for readers without a session.
+    Read {
+        range: History,
+        reply: oneshot::Sender<Page>,
+    },
+    /// Edits or deletes an entry for `username`, who needs the permission to
+    /// change their own entries or anyone's, depending on who wrote it.
+    Change {
+        outbox: Outbox,
+        username: String,
+        role: comms::Role,
+        slot_number: usize,
+        change: Change,
+    },
+    /// Deletes an entry on a moderator's behalf.
+    Remove {
+        slot_number: usize,
+        reply: oneshot::Sender<Result<chat::Entry, ChangeError>>,
+    },
+    /// Subscribes `outbox` to new entries and replies with `username`'s read
+    /// state.
+    Join {
+        outbox: Outbox,
+        username: String,
+        last_read_slot: Option<usize>,
+        reply: oneshot::Sender<comms::ChannelReadState>,
+    },
+    Search {
+        query: comms::SearchQuery,
+        limit: usize,
+        after: Option<comms::SearchCursor>,
+        reply: oneshot::Sender<Vec<(comms::SearchCursor, comms::SearchHit)>>,
+    },
+    /// Replies with which of `attachments` the channel still refers to.
+    References {
+        attachments: Vec<chat::Attachment>,
+        reply: oneshot::Sender<Vec<chat::Attachment>>,
+    },
+    /// Replies with the next slot to be posted to.
+    NextSlot {
+        reply: oneshot::Sender<usize>,
+    },
+    /// Catches a follower up from `next_slot` and sends it every record
+    /// stored from then on.
+    Follow {
+        records_tx: mpsc::UnboundedSender<Record>,
+        next_slot: usize,
+    },
+    /// Applies a record the leader committed.
+    Replicate(Record),
+    /// Replies with the next slot expected from the peer called `server`.
+    OriginNextSlot {
+        server: String,
+        reply: oneshot::Sender<usize>,
+    },
+    /// Catches the peer called `server` up on entries posted here from its
+    /// `next_slot`, and sends it every one posted or changed from then on.
+    Federate {
+        server: String,
+        entries_tx: mpsc::UnboundedSender<chat::Entry>,
+        next_slot: usize,
+    },
+    /// Commits an entry posted on the peer called `server`, in its slot
+    /// there.
+    Relay {
+        server: String,
+        entry: chat::Entry,
+    },
+    SetRetention(RetentionPolicy),
+    /// Applies the retention policy.
+    Compact,
+    /// Replies with how many entries are stored.
+    Length {
+        reply: oneshot::Sender<usize>,
+    },
+}
+
+/// Sends requests to a channel's task.
+#[derive(Clone)]
+pub struct ChannelHandle {
+    request_tx: mpsc::UnboundedSender<(Request, tracing::Span)>,
+}
+
+impl ChannelHandle {
+    /// Queues `request`, to be handled within the current span.
+    pub fn send(&self, request: Request) {
+        if self
+            .request_tx
+            .send((request, tracing::Span::current()))
+            .is_err()
+        {
+            tracing::error!("Dropping request to a channel that has stopped");
+        }
+    }
+
+    /// Sends the request `make` builds and waits for the reply, or `None` if
+    /// the channel has stopped.
+    pub async fn ask<T>(
+        &self,
+        make: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> Option<T> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(make(reply_tx));
+        reply_rx.await.ok()
+    }
+
+    /// Sends the request `make` builds now, returning where the reply will
+    /// arrive, which errs if the channel has stopped. The server's loop sends
+    /// requests this way and waits in a task of its own, so a slow channel
+    /// doesn't hold up every session.
+    pub fn request<T>(
+        &self,
+        make: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> oneshot::Receiver<T> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(make(reply_tx));
+        reply_rx
+    }
+}
+
+#[cfg(test)]
+impl ChannelHandle {
+    /// A handle to a channel that's held up, as if its storage were slow,
+    /// until whoever has the returned requests answers them.
+    pub fn stalled() -> (Self, mpsc::UnboundedReceiver<(Request, tracing::Span)>)
+    {
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        (Self { request_tx }, request_rx)
+    }
+}
+
+/// Whether `server` relays `entry` to its peers: anything posted there,
+/// except notices, which are about what happened on that server.
+fn is_relayable(entry: &chat::Entry, server: Option<&str>) -> bool {
+    let posted_there = match &entry.metadata.origin {
+        Some(origin) => Some(origin.server.as_str()) == server,
+        None => true,
+    };
+    posted_there && entry.kind != chat::EntryKind::Notice
+}
+
+/// Where a channel streams what it stores, besides its subscribers.
+#[derive(Default)]
+pub struct Streams {
+    /// Sent every record stored.
+    pub followers: Vec<mpsc::UnboundedSender<Record>>,
+    /// Sent every entry posted here, by the peer's name.
+    pub peers: Vec<(String, mpsc::UnboundedSender<chat::Entry>)>,
+    /// Sent every new entry posted or relayed here, if there are webhooks.
+    pub webhooks: Option<mpsc::UnboundedSender<chat::Entry>>,
+}
+
+struct Channel {
+    chat_log: FakeChatLog,
+    storage: LogStorage,
+    search_index: SearchIndex,
+    recent_posts: RecentPosts,
+    retention: RetentionPolicy,
+    subscribers: Subscribers,
+    followers: Vec<mpsc::UnboundedSender<Record>>,
+    /// Where entries posted here are relayed to each peer, by name.
+    peers: Vec<(String, mpsc::UnboundedSender<chat::Entry>)>,
+    webhooks: Option<mpsc::UnboundedSender<chat::Entry>>,
+    audit_log: Arc<AuditLog>,
+    /// Where attachments no longer referred to by this channel are sent, to
+    /// be deleted unless another channel refers to them.
+    released_tx: mpsc::UnboundedSender<Vec<chat::Attachment>>,
+}
+
+/// Starts the task for `chat_log`'s channel, which stores it in `storage`,
+/// sends new entries to `subscribers`, and streams what it stores to
+/// `streams`.
+pub fn spawn(
+    chat_log: FakeChatLog,
+    storage: LogStorage,
+    retention: RetentionPolicy,
+    subscribers: Subscribers,
+    Streams {
+        followers,
+        peers,
+        webhooks,
+    }: Streams,
+    audit_log: Arc<AuditLog>,
+    released_tx: mpsc::UnboundedSender<Vec<chat::Attachment>>,
+) -> ChannelHandle {
+    let (request_tx, mut request_rx) =
+        mpsc::unbounded_channel::<(Request, tracing::Span)>();
+    tokio::spawn(async move {
+        let mut search_index = SearchIndex::default();
+        for entry in chat_log.entries_after(None) {
+            search_index.index(entry);
+        }
+        let mut channel = Channel {
+            chat_log,
+            storage,
+            search_index,
+            recent_posts: RecentPosts::default(),
+            retention,
+            subscribers,
+            followers,
+            peers,
+            webhooks,
+            audit_log,
+            released_tx,
+        };
+        while let Some((request, span)) = request_rx.recv().await {
+            span.in_scope(|| channel.handle(request));
+        }
+    });
+    ChannelHandle { request_tx }
+}
+
+impl Channel {
+    fn name(&self) -> &chat::ChannelName {
+        self.chat_log.channel()
+    }
+
+    fn page(&self, range: History) -> Page {
+        let start = Instant::now();
+        let entries = match range {
+            History::Before {
+                count,
+                up_to_slot_number,
+            } => self.chat_log.entries(count, up_to_slot_number),
+            History::After {
+                after_slot_number,
+                limit,
+            } => self
+                .chat_log
+                .entries_after(after_slot_number)
+                .iter()
+                .take(limit)
+                .cloned()
+                .collect(),
+        };
+        METRICS.history_request_duration.observe(start.elapsed());
+        Page {
+            entries,
+            head_slot: self.chat_log.head_slot(),
+            history_start: self.chat_log.history_start(),
+        }
+    }
+
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Append { entry, ack } => self.append(entry, ack),
+            Request::History {
+                outbox,
+                client_id,
+                range,
+            } => {
+                let Page {
+                    entries,
+                    head_slot,
+                    history_start,
+                } = self.page(range);
+                outbox.send(comms::ServerMessage::EntryRange {
+                    client_id,
+                    entries,
+                    head_slot,
+                    history_start,
+                });
+            }
+            Request::Read { range, reply } => {
+                let _ = reply.send(self.page(range));
+            }
+            Request::Change {
+                outbox,
+                username,
+                role,
+                slot_number,
+                change,
+            } => self.change(outbox, username, role, slot_number, change),
+            Request::Remove { slot_number, reply } => {
+                let result = self.chat_log.delete(slot_number);
+                if let Ok(entry) = &result {
+                    self.store(entry);
+                    self.search_index.index(entry);
+                    self.subscribers.broadcast(
+                        &comms::ServerMessage::UpdatedEntry(entry.clone()),
+                    );
+                }
+                let _ = reply.send(result);
+            }
+            Request::Join {
+                outbox,
+                username,
+                last_read_slot,
+                reply,
+            } => {
+                let _ = reply
+                    .send(self.chat_log.read_state(&username, last_read_slot));
+                self.subscribers.insert(outbox);
+            }
+            Request::Search {
+                query,
+                limit,
+                after,
+                reply,
+            } => {
+                let _ = reply.send(self.search_index.matches(
+                    &query,
+                    limit,
+                    after.as_ref(),
+                    |_, slot_number| self.chat_log.get(slot_number),
+                ));
+            }
+            Request::References { attachments, reply } => {
+                let _ = reply.send(
+                    attachments
+                        .into_iter()
+                        .filter(|attachment| {
+                            self.chat_log.references(&attachment.id)
+                        })
+                        .collect(),
+                );
+            }
+            Request::NextSlot { reply } => {
+                let _ = reply.send(self.chat_log.next_slot());
+            }
+            Request::Follow {
+                records_tx,
+                next_slot,
+            } => self.follow(records_tx, next_slot),
+            Request::Replicate(record) => self.replicate(record),
+            Request::OriginNextSlot { server, reply } => {
+                let _ = reply.send(
+                    self.chat_log
+                        .origin_next_slots()
+                        .get(&server)
+                        .copied()
+                        .unwrap_or_default(),
+                );
+            }
+            Request::Federate {
+                server,
+                entries_tx,
+                next_slot,
+            } => self.federate(server, entries_tx, next_slot),
+            Request::Relay { server, entry } => self.relay(server, entry),
+            Request::SetRetention(retention) => self.retention = retention,
+            Request::Compact => self.compact(),
+            Request::Length { reply } => {
+                let _ = reply.send(self.chat_log.len());
+            }
+        }
+    }
+
+    /// Records a new, edited, or deleted entry, logging rather than failing
+    /// so the change still reaches subscribers and followers, and relays it
+    /// if it was posted here.
+    fn store(&mut self, entry: &chat::Entry) {
+        self.record(entry);
+        if is_relayable(entry, self.chat_log.server()) {
+            self.peers.retain(|(_, entries_tx)| {
+                entries_tx.send(entry.clone()).is_ok()
+            });
+        }
+    }
+
+    /// Records an entry's new state for storage and followers only.
+    fn record(&mut self, entry: &chat::Entry) {
+        if let Err(error) = self.storage.record_entry(&self.chat_log, entry) {
+            tracing::error!(
+                "Failed to store {} slot {}: {}",
+                entry.channel,
+                entry.slot_number,
+                error
+            );
+        }
+        self.send_to_followers(Record::Entry(entry.clone()));
+    }
+
+    /// Stores a newly committed entry along with the entries it moved, and
+    /// tells subscribers about the moves. Peers aren't told, since each
+    /// merges entries into the same slots itself. Returns the entry for the
+    /// caller to announce.
+    fn commit(&mut self, Committed { entry, moved }: Committed) -> chat::Entry {
+        // Newest first, so followers are only ever sent the next slot or one
+        // they already have.
+        for moved in &moved {
+            self.record(moved);
+            self.search_index.index(moved);
+        }
+        self.store(&entry);
+        self.search_index.index(&entry);
+        self.send_to_webhooks(&entry);
+        for moved in moved {
+            self.subscribers
+                .broadcast(&comms::ServerMessage::UpdatedEntry(moved));
+        }
+        entry
+    }
+
+    /// Records that the history now starts later.
+    fn store_history_start(&mut self) {
+        if let Err(error) = self.storage.record_history_start(&self.chat_log) {
+            tracing::error!(
+                "Failed to store compaction of {}: {}",
+                self.name(),
+                error
+            );
+        }
+        self.send_to_followers(Record::HistoryStart {
+            channel: self.name().clone(),
+            slot_number: self.chat_log.history_start(),
+        });
+    }
+
+    /// Sends `record` to every follower, forgetting those that have
+    /// disconnected.
+    fn send_to_followers(&mut self, record: Record) {
+        self.followers
+            .retain(|records_tx| records_tx.send(record.clone()).is_ok());
+    }
+
+    fn send_to_webhooks(&mut self, entry: &chat::Entry) {
+        if let Some(webhooks_tx) = &self.webhooks {
+            if webhooks_tx.send(entry.clone()).is_err() {
+                self.webhooks = None;
+            }
+        }
+    }
+
+    fn federate(
+        &mut self,
+        server: String,
+        entries_tx: mpsc::UnboundedSender<chat::Entry>,
+        next_slot: usize,
+    ) {
+        // As when catching up a follower, but only with entries posted here.
+        let caught_up = self
+            .chat_log
+            .entries_after(None)
+            .iter()
+            .filter(|entry| {
+                let origin_slot = entry
+                    .metadata
+                    .origin
+                    .as_ref()
+                    .map_or(entry.slot_number, |origin| origin.slot_number);
+                is_relayable(entry, self.chat_log.server())
+                    && (origin_slot >= next_slot
+                        || !matches!(entry.content, chat::Content::Original(_)))
+            })
+            .all(|entry| entries_tx.send(entry.clone()).is_ok());
+        if caught_up {
+            self.peers.retain(|(peer, _)| *peer != server);
+            self.peers.push((server, entries_tx));
+        }
+    }
+
+    fn relay(&mut self, server: String, mut entry: chat::Entry) {
+        if !is_relayable(&entry, Some(&server)) {
+            tracing::warn!(
+                %server,
+                slot_number = entry.slot_number,
+                "Refusing relayed entry that wasn't posted on its server"
+            );
+            return;
+        }
+        entry.metadata.username =
+            format!("{}@{}", entry.metadata.username, server);
+        // Peers from before entries were numbered by origin only send their
+        // own, numbered by slot.
+        let origin_slot = entry
+            .metadata
+            .origin
+            .take()
+            .map_or(entry.slot_number, |origin| origin.slot_number);
+        entry.metadata.origin = Some(chat::Origin {
+            server,
+            slot_number: origin_slot,
+        });
+        entry.channel = self.name().clone();
+        // The files stay on the peer.
+        entry.attachments.clear();
+        match self.chat_log.relay(entry) {
+            Some(Relayed::New(committed)) => {
+                let entry = self.commit(committed);
+                self.subscribers
+                    .broadcast(&comms::ServerMessage::NewEntry(entry));
+            }
+            Some(Relayed::Updated(entry)) => {
+                self.store(&entry);
+                self.search_index.index(&entry);
+                self.subscribers
+                    .broadcast(&comms::ServerMessage::UpdatedEntry(entry));
+            }
+            None => {}
+        }
+    }
+
+    fn follow(
+        &mut self,
+        records_tx: mpsc::UnboundedSender<Record>,
+        next_slot: usize,
+    ) {
+        let history_start = Record::HistoryStart {
+            channel: self.name().clone(),
+            slot_number: self.chat_log.history_start(),
+        };
+        // Entries only change by being edited or deleted, so those are the
+        // only ones before `next_slot` the follower may be missing.
+        let entries = self
+            .chat_log
+            .entries_after(None)
+            .iter()
+            .filter(|entry| {
+                entry.slot_number >= next_slot
+                    || !matches!(entry.content, chat::Content::Original(_))
+            })
+            .cloned()
+            .map(Record::Entry);
+        let caught_up = [history_start]
+            .into_iter()
+            .chain(entries)
+            .all(|record| records_tx.send(record).is_ok());
+        if caught_up {
+            self.followers.push(records_tx);
+        }
+    }
+
+    fn replicate(&mut self, record: Record) {
+        match record {
+            Record::Entry(entry) => {
+                let slot_number = entry.slot_number;
+                match self.chat_log.replicate(entry.clone()) {
+                    Ok(replicated) => {
+                        self.store(&entry);
+                        self.search_index.index(&entry);
+                        self.subscribers.broadcast(&match replicated {
+                            Replicated::New => {
+                                comms::ServerMessage::NewEntry(entry)
+                            }
+                            Replicated::Updated => {
+                                comms::ServerMessage::UpdatedEntry(entry)
+                            }
+                        });
+                    }
+                    Err(error) => tracing::error!(
+                        slot_number,
+                        %error,
+                        "Skipping replicated entry"
+                    ),
+                }
+            }
+            Record::HistoryStart { slot_number, .. } => {
+                if slot_number <= self.chat_log.history_start() {
+                    return;
+                }
+                for entry in self.chat_log.advance_history_start(slot_number) {
+                    self.search_index.forget(&entry);
+                }
+                self.store_history_start();
+                self.subscribers.broadcast(
+                    &comms::ServerMessage::HistoryStart {
+                        channel: self.name().clone(),
+                        slot_number,
+                    },
+                );
+            }
+        }
+    }
+
+    fn append(
+        &mut self,
+        new_entry: NewEntry,
+        ack: Option<(Outbox, comms::Nonce)>,
+    ) {
+        if let Some((outbox, nonce)) = &ack {
+            if let Some((channel, slot_number)) =
+                self.recent_posts.get(&new_entry.username, nonce)
+            {
+                tracing::info!(?nonce, "Acknowledging resent post");
+                outbox.send(comms::ServerMessage::PostAck {
+                    nonce: nonce.clone(),
+                    channel,
+                    slot_number,
+                });
+                return;
+            }
+        }
+
+        let committed = self.chat_log.post(
+            new_entry.kind,
+            new_entry.username,
+            new_entry.content,
+            new_entry.attachments,
+        );
+        METRICS.messages_posted.fetch_add(1, Ordering::Relaxed);
+        let entry = self.commit(committed);
+
+        if let Some((outbox, nonce)) = ack {
+            self.recent_posts.insert(
+                entry.metadata.username.clone(),
+                nonce.clone(),
+                (entry.channel.clone(), entry.slot_number),
+            );
+            outbox.send(comms::ServerMessage::PostAck {
+                nonce,
+                channel: entry.channel.clone(),
+                slot_number: entry.slot_number,
+            });
+        }
+        let recipients = self
+            .subscribers
+            .broadcast(&comms::ServerMessage::NewEntry(entry));
+        METRICS
+            .messages_broadcast
+            .fetch_add(recipients as u64, Ordering::Relaxed);
+        self.compact();
+    }
+
+    fn change(
+        &mut self,
+        outbox: Outbox,
+        username: String,
+        role: comms::Role,
+        slot_number: usize,
+        change: Change,
+    ) {
+        let is_own = self
+            .chat_log
+            .get(slot_number)
+            .is_some_and(|entry| entry.metadata.username == username);
+        let permission = match (&change, is_own) {
+            (Change::Edit(_), true) => comms::Permission::EditOwn,
+            (Change::Edit(_), false) => comms::Permission::EditAny,
+            (Change::Delete, true) => comms::Permission::DeleteOwn,
+            (Change::Delete, false) => comms::Permission::DeleteAny,
+        };
+        if !role.allows(permission) {
+            let denial = comms::PermissionDenied {
+                username,
+                role,
+                permission,
+                channel: Some(self.name().clone()),
+            };
+            tracing::info!(slot_number, %denial, "Rejected change");
+            outbox.send(comms::ServerMessage::PermissionDenied(denial));
+            return;
+        }
+        let result = match change {
+            Change::Edit(content) => self.chat_log.edit(slot_number, content),
+            Change::Delete => self.chat_log.delete(slot_number),
+        };
+        match result {
+            Ok(entry) => {
+                self.store(&entry);
+                self.search_index.index(&entry);
+                if entry.content == chat::Content::Deleted {
+                    self.audit_log.record(
+                        Some(&username),
+                        Event::Deletion {
+                            channel: self.name().clone(),
+                            slot_number,
+                            author: entry.metadata.username.clone(),
+                        },
+                    );
+                }
+                self.subscribers
+                    .broadcast(&comms::ServerMessage::UpdatedEntry(entry));
+            }
+            Err(error) => {
+                tracing::warn!(slot_number, %error, "Rejected change");
+            }
+        }
+    }
+
+    /// Applies the retention policy, telling subscribers what was compacted.
+    fn compact(&mut self) {
+        let compacted =
+            self.chat_log.compact(&self.retention, chrono::Utc::now());
+        if compacted.is_empty() {
+            return;
+        }
+        tracing::info!(
+            "Compacted {}: removed {} and tombstoned {} entries",
+            self.name(),
+            compacted.removed.len(),
+            compacted.tombstoned.len()
+        );
+
+        if !compacted.removed.is_empty() {
+            self.store_history_start();
+        }
+        for entry in &compacted.tombstoned {
+            self.store(entry);
+        }
+
+        for entry in &compacted.removed {
+            self.search_index.forget(entry);
+        }
+        for entry in &compacted.tombstoned {
+            self.search_index.index(entry);
+        }
+
+        // Attachments are content-addressed, so an entry here or in another
+        // channel may still share the same file.
+        let released = compacted
+            .released_attachments
+            .into_iter()
+            .filter(|attachment| !self.chat_log.references(&attachment.id))
+            .collect::<Vec<_>>();
+        if !released.is_empty() {
+            let _ = self.released_tx.send(released);
+        }
+
+        if !compacted.removed.is_empty() {
+            self.subscribers
+                .broadcast(&comms::ServerMessage::HistoryStart {
+                    channel: self.name().clone(),
+                    slot_number: self.chat_log.history_start(),
+                });
+        }
+        for entry in compacted.tombstoned {
+            self.subscribers
+                .broadcast(&comms::ServerMessage::UpdatedEntry(entry));
+        }
+    }
+}
@@ -0,0 +1,773 @@
+use std::{cmp, collections::HashMap, fmt, mem};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::config::{Compaction, RetentionPolicy};
+
+#[derive(Debug)]
+pub enum ChangeError {
+    NoSuchSlot(usize),
+    AlreadyDeleted,
+}
+
+impl fmt::Display for ChangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeError::NoSuchSlot(slot_number) => {
+                write!(f, "No entry in slot {}", slot_number)
+            }
+            ChangeError::AlreadyDeleted => write!(f, "Entry is deleted"),
+        }
+    }
+}
+
+/// What [`FakeChatLog::compact`] did.
+#[derive(Default)]
+pub struct Compacted {
+    /// Entries dropped from the start of the log.
+    pub removed: Vec<chat::Entry>,
+    /// Entries that are now tombstones, in their new state.
+    pub tombstoned: Vec<chat::Entry>,
+    /// Attachments the compacted entries referred to.
+    pub released_attachments: Vec<chat::Attachment>,
+}
+
+impl Compacted {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.tombstoned.is_empty()
+    }
+}
+
+/// How [`FakeChatLog::replicate`] changed the log.
+pub enum Replicated {
+    New,
+    Updated,
+}
+
+/// How [`FakeChatLog::relay`] changed the log.
+pub enum Relayed {
+    New(Committed),
+    Updated(chat::Entry),
+}
+
+/// A new entry, committed in its place in the merged order.
+pub struct Committed {
+    pub entry: chat::Entry,
+    /// The entries from its slot on, each now one slot later, newest first.
+    pub moved: Vec<chat::Entry>,
+}
+
+/// Where `entry` goes in a federated channel, which is the same on every
+/// server sharing it: by when it was posted, then where.
+fn merge_key(entry: &chat::Entry) -> (DateTime<Utc>, Option<&str>, usize) {
+    let origin = entry.metadata.origin.as_ref();
+    (
+        entry.metadata.timestamp,
+        origin.map(|origin| origin.server.as_str()),
+        origin.map_or(entry.slot_number, |origin| origin.slot_number),
+    )
+}
+
+/// The chat log of a single channel.
+pub struct FakeChatLog {
+    channel: chat::ChannelName,
+    /// What peers call this server, if it's federated.
+    server: Option<String>,
+    /// The slot of the first entry in `lmao`. Everything before it was
+    /// removed by compaction.
+    history_start: usize,
+    lmao: Vec<chat::Entry>,
+    /// The next slot expected from each server entries are relayed from,
+    /// kept even once compaction removes their entries.
+    origin_next_slots: HashMap<String, usize>,
+}
+
+impl FakeChatLog {
+    pub fn new(channel: chat::ChannelName) -> Self {
+        Self {
+            channel,
+            server: None,
+            history_start: 0,
+            lmao: vec![],
+            origin_next_slots: HashMap::new(),
+        }
+    }
+
+    /// Rebuilds a log from stored `entries`, which must be contiguous from
+    /// `history_start`, and the next slot recorded for each origin.
+    pub fn restore(
+        channel: chat::ChannelName,
+        history_start: usize,
+        entries: Vec<chat::Entry>,
+        mut origin_next_slots: HashMap<String, usize>,
+    ) -> Self {
+        for origin in entries
+            .iter()
+            .filter_map(|entry| entry.metadata.origin.as_ref())
+        {
+            let next_slot =
+                origin_next_slots.entry(origin.server.clone()).or_default();
+            *next_slot = cmp::max(*next_slot, origin.slot_number + 1);
+        }
+        Self {
+            channel,
+            server: None,
+            history_start,
+            lmao: entries,
+            origin_next_slots,
+        }
+    }
+
+    pub fn channel(&self) -> &chat::ChannelName {
+        &self.channel
+    }
+
+    /// Sets what peers call this server, so entries posted here record it as
+    /// their origin. Entries posted before then are numbered there by their
+    /// slot, as they were when relayed.
+    pub fn set_server(&mut self, server: Option<String>) {
+        let Some(server) = server else {
+            return;
+        };
+        for entry in &mut self.lmao {
+            if entry.metadata.origin.is_none() {
+                entry.metadata.origin = Some(chat::Origin {
+                    server: server.clone(),
+                    slot_number: entry.slot_number,
+                });
+            }
+        }
+        let next_slot = self.next_slot();
+        let next_origin_slot =
+            self.origin_next_slots.entry(server.clone()).or_default();
+        *next_origin_slot = cmp::max(*next_origin_slot, next_slot);
+        self.server = Some(server);
+    }
+
+    /// What peers call this server, if it's federated.
+    pub fn server(&self) -> Option<&str> {
+        self.server.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lmao.len()
+    }
+
+    /// The oldest slot still in the log, or the next slot to be posted to if
+    /// it's empty.
+    pub fn history_start(&self) -> usize {
+        self.history_start
+    }
+
+    /// The slot the next entry will be posted to.
+    pub fn next_slot(&self) -> usize {
+        self.history_start + self.lmao.len()
+    }
+
+    fn index_of(&self, slot_number: usize) -> Option<usize> {
+        slot_number
+            .checked_sub(self.history_start)
+            .filter(|index| *index < self.lmao.len())
+    }
+
+    pub fn post(
+        &mut self,
+        kind: chat::EntryKind,
+        username: String,
+        content: String,
+        attachments: Vec<chat::Attachment>,
+    ) -> Committed {
+        let mut entry = chat::Entry::new_timestamped_now(
+            self.channel.clone(),
+            self.next_slot(),
+            username,
+            chat::Content::Original(chat::MessageText(content)),
+        );
+        entry.attachments = attachments;
+        entry.kind = kind;
+        if let Some(server) = &self.server {
+            let next_slot =
+                self.origin_next_slots.entry(server.clone()).or_default();
+            entry.metadata.origin = Some(chat::Origin {
+                server: server.clone(),
+                slot_number: *next_slot,
+            });
+            *next_slot += 1;
+        }
+        self.insert(entry)
+    }
+
+    /// Commits `entry` after every entry that merges before it, moving the
+    /// rest one slot later. Logs that aren't federated just append it.
+    fn insert(&mut self, mut entry: chat::Entry) -> Committed {
+        let index = if self.server.is_some() {
+            let key = merge_key(&entry);
+            self.lmao.len()
+                - self
+                    .lmao
+                    .iter()
+                    .rev()
+                    .take_while(|existing| merge_key(existing) > key)
+                    .count()
+        } else {
+            self.lmao.len()
+        };
+        entry.slot_number = self.history_start + index;
+        self.lmao.insert(index, entry.clone());
+        let moved = self.lmao[index + 1..]
+            .iter_mut()
+            .rev()
+            .map(|later| {
+                later.slot_number += 1;
+                later.clone()
+            })
+            .collect();
+        Committed { entry, moved }
+    }
+
+    /// Appends an entry that was originally posted elsewhere at `timestamp`,
+    /// returning its slot.
+    pub fn import(
+        &mut self,
+        username: String,
+        timestamp: DateTime<Utc>,
+        content: chat::Content,
+    ) -> usize {
+        let slot_number = self.history_start + self.lmao.len();
+        let mut entry = chat::Entry::new_timestamped_now(
+            self.channel.clone(),
+            slot_number,
+            username,
+            content,
+        );
+        entry.metadata.timestamp = timestamp;
+        self.lmao.push(entry);
+        slot_number
+    }
+
+    /// Applies an entry as a leader committed it: either the entry for the
+    /// next slot or a new state of one already in the log.
+    pub fn replicate(
+        &mut self,
+        entry: chat::Entry,
+    ) -> Result<Replicated, ChangeError> {
+        if let Some(index) = self.index_of(entry.slot_number) {
+            self.lmao[index] = entry;
+            Ok(Replicated::Updated)
+        } else if entry.slot_number == self.next_slot() {
+            self.lmao.push(entry);
+            Ok(Replicated::New)
+        } else {
+            Err(ChangeError::NoSuchSlot(entry.slot_number))
+        }
+    }
+
+    /// Commits an entry relayed from its origin server: a new entry if it's
+    /// the next one from there, or a new state of one already relayed. New
+    /// entries are merged in by [`merge_key`], like those posted here, so
+    /// every server sharing the channel ends up with the same order however
+    /// the entries arrive. Returns `None` for entries already relayed but
+    /// since compacted or deleted here.
+    pub fn relay(&mut self, mut entry: chat::Entry) -> Option<Relayed> {
+        let origin = entry.metadata.origin.clone()?;
+        let next_slot = self
+            .origin_next_slots
+            .get(&origin.server)
+            .copied()
+            .unwrap_or_default();
+        if origin.slot_number >= next_slot {
+            self.origin_next_slots
+                .insert(origin.server, origin.slot_number + 1);
+            return Some(Relayed::New(self.insert(entry)));
+        }
+        let existing = self
+            .lmao
+            .iter_mut()
+            .rev()
+            .find(|existing| existing.metadata.origin.as_ref() == Some(&origin))
+            .filter(|existing| existing.content != chat::Content::Deleted)?;
+        entry.slot_number = existing.slot_number;
+        *existing = entry.clone();
+        Some(Relayed::Updated(entry))
+    }
+
+    /// The next slot expected from each server entries are relayed from.
+    pub fn origin_next_slots(&self) -> &HashMap<String, usize> {
+        &self.origin_next_slots
+    }
+
+    /// Drops every entry before `slot_number`, as a leader's compaction did,
+    /// returning them.
+    pub fn advance_history_start(
+        &mut self,
+        slot_number: usize,
+    ) -> Vec<chat::Entry> {
+        if slot_number <= self.history_start {
+            return vec![];
+        }
+        let removed_count =
+            (slot_number - self.history_start).min(self.lmao.len());
+        self.history_start = slot_number;
+        self.lmao.drain(..removed_count).collect()
+    }
+
+    pub fn get(&self, slot_number: usize) -> Option<&chat::Entry> {
+        self.index_of(slot_number).map(|index| &self.lmao[index])
+    }
+
+    /// Whether any entry refers to the attachment `id`.
+    pub fn references(&self, id: &chat::AttachmentId) -> bool {
+        self.lmao.iter().any(|entry| {
+            entry
+                .attachments
+                .iter()
+                .any(|attachment| attachment.id == *id)
+        })
+    }
+
+    pub fn head_slot(&self) -> Option<usize> {
+        self.lmao.last().map(|entry| entry.slot_number)
+    }
+
+    /// Entries in slots after `slot_number`, or every entry if there is none.
+    pub fn entries_after(&self, slot_number: Option<usize>) -> &[chat::Entry] {
+        let start = slot_number.map_or(0, |slot_number| {
+            self.lmao
+                .partition_point(|entry| entry.slot_number <= slot_number)
+        });
+        &self.lmao[start..]
+    }
+
+    /// What `username` hasn't read yet, given that they last read
+    /// `last_read_slot`.
+    pub fn read_state(
+        &self,
+        username: &str,
+        last_read_slot: Option<usize>,
+    ) -> comms::ChannelReadState {
+        let mut unread_count = 0;
+        let mut mention_count = 0;
+        for entry in self.entries_after(last_read_slot) {
+            if entry.metadata.username == username {
+                continue;
+            }
+            let Some(text) = entry.text_content() else {
+                continue;
+            };
+            unread_count += 1;
+            if chat::markdown::parse(text).mentions().contains(&username) {
+                mention_count += 1;
+            }
+        }
+        comms::ChannelReadState {
+            channel: self.channel.clone(),
+            head_slot: self.head_slot(),
+            history_start: self.history_start,
+            last_read_slot,
+            unread_count,
+            mention_count,
+            topic: None,
+            role: comms::Role::default(),
+        }
+    }
+
+    fn live_entry_mut(
+        &mut self,
+        slot_number: usize,
+    ) -> Result<&mut chat::Entry, ChangeError> {
+        let index = self
+            .index_of(slot_number)
+            .ok_or(ChangeError::NoSuchSlot(slot_number))?;
+        let entry = &mut self.lmao[index];
+        if matches!(entry.content, chat::Content::Deleted) {
+            return Err(ChangeError::AlreadyDeleted);
+        }
+        Ok(entry)
+    }
+
+    pub fn edit(
+        &mut self,
+        slot_number: usize,
+        content: String,
+    ) -> Result<chat::Entry, ChangeError> {
+        let entry = self.live_entry_mut(slot_number)?;
+        entry.content = chat::Content::Edited(chat::MessageText(content));
+        Ok(entry.clone())
+    }
+
+    pub fn delete(
+        &mut self,
+        slot_number: usize,
+    ) -> Result<chat::Entry, ChangeError> {
+        let entry = self.live_entry_mut(slot_number)?;
+        entry.content = chat::Content::Deleted;
+        entry.attachments.clear();
+        Ok(entry.clone())
+    }
+
+    /// The last `count` entries up to and including `last_slot`, or up to the
+    /// newest entry if `None`. Slots before [`FakeChatLog::history_start`] have
+    /// been compacted away, so there may be fewer than `count`.
+    pub fn entries(
+        &self,
+        count: usize,
+        last_slot: Option<usize>,
+    ) -> Vec<chat::Entry> {
+        let Some(head_slot) = self.head_slot() else {
+            return vec![];
+        };
+        let last_slot = last_slot
+            .map_or(head_slot, |last_slot| cmp::min(last_slot, head_slot));
+        let Some(last_index) = last_slot.checked_sub(self.history_start) else {
+            return vec![];
+        };
+        let count = cmp::min(count, last_index + 1);
+
+        // needs to +1 before -count
+        self.lmao[last_index + 1 - count..last_index + 1].to_owned()
+    }
+
+    /// How many of the oldest entries `policy` no longer keeps.
+    fn expired_prefix(
+        &self,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+    ) -> usize {
+        let by_age = policy
+            .max_age_seconds
+            .and_then(|seconds| {
+                TimeDelta::try_seconds(seconds.try_into().ok()?)
+            })
+            .and_then(|max_age| now.checked_sub_signed(max_age))
+            .map_or(0, |cutoff| {
+                self.lmao
+                    .iter()
+                    .take_while(|entry| entry.metadata.timestamp < cutoff)
+                    .count()
+            });
+        let by_count = policy.max_entries.map_or(0, |max_entries| {
+            match policy.compaction {
+                Compaction::Remove => {
+                    self.lmao.len().saturating_sub(max_entries)
+                }
+                // Tombstones don't count towards the limit, so skip past
+                // enough live entries to leave `max_entries` of them.
+                Compaction::Tombstone => {
+                    let mut excess = self
+                        .lmao
+                        .iter()
+                        .filter(|entry| entry.text_content().is_some())
+                        .count()
+                        .saturating_sub(max_entries);
+                    self.lmao
+                        .iter()
+                        .take_while(|entry| {
+                            if excess == 0 {
+                                return false;
+                            }
+                            if entry.text_content().is_some() {
+                                excess -= 1;
+                            }
+                            true
+                        })
+                        .count()
+                }
+            }
+        });
+        cmp::max(by_age, by_count)
+    }
+
+    /// Removes or tombstones the entries `policy` no longer keeps.
+    pub fn compact(
+        &mut self,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+    ) -> Compacted {
+        if policy.keeps_everything() {
+            return Compacted::default();
+        }
+        let expired = self.expired_prefix(policy, now);
+        let mut compacted = Compacted::default();
+        match policy.compaction {
+            Compaction::Remove => {
+                compacted.removed = self.lmao.drain(..expired).collect();
+                self.history_start += expired;
+                if self.lmao.capacity() > 2 * self.lmao.len() {
+                    self.lmao.shrink_to_fit();
+                }
+                compacted.released_attachments = compacted
+                    .removed
+                    .iter()
+                    .flat_map(|entry| entry.attachments.iter().cloned())
+                    .collect();
+            }
+            Compaction::Tombstone => {
+                for entry in &mut self.lmao[..expired] {
+                    if matches!(entry.content, chat::Content::Deleted) {
+                        continue;
+                    }
+                    entry.content = chat::Content::Deleted;
+                    compacted
+                        .released_attachments
+                        .append(&mut mem::take(&mut entry.attachments));
+                    compacted.tombstoned.push(entry.clone());
+                }
+            }
+        }
+        compacted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log of `count` entries, the one in slot `n` posted `count - n`
+    /// minutes before `now`.
+    fn log_of(count: usize, now: DateTime<Utc>) -> FakeChatLog {
+        let mut log = FakeChatLog::new(chat::ChannelName::default());
+        for n in 0..count {
+            log.import(
+                "ethan".to_owned(),
+                now - TimeDelta::minutes((count - n) as i64),
+                chat::Content::Original(chat::MessageText(n.to_string())),
+            );
+        }
+        log
+    }
+
+    fn policy(
+        max_age_seconds: Option<u64>,
+        max_entries: Option<usize>,
+        compaction: Compaction,
+    ) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_seconds,
+            max_entries,
+            compaction,
+        }
+    }
+
+    #[test]
+    fn expired_prefix_keeps_the_newest_entries() {
+        let now = Utc::now();
+        let log = log_of(10, now);
+        assert_eq!(
+            log.expired_prefix(&policy(None, Some(3), Compaction::Remove), now),
+            7
+        );
+        assert_eq!(
+            log.expired_prefix(
+                &policy(None, Some(20), Compaction::Remove),
+                now
+            ),
+            0
+        );
+        // Entries are 1 to 10 minutes old, so five are older than 5m30s.
+        assert_eq!(
+            log.expired_prefix(
+                &policy(Some(330), None, Compaction::Remove),
+                now
+            ),
+            5
+        );
+        // Whichever keeps less wins.
+        assert_eq!(
+            log.expired_prefix(
+                &policy(Some(330), Some(3), Compaction::Remove),
+                now
+            ),
+            7
+        );
+        assert_eq!(
+            log.expired_prefix(
+                &policy(Some(330), Some(8), Compaction::Remove),
+                now
+            ),
+            5
+        );
+    }
+
+    #[test]
+    fn expired_prefix_skips_tombstones_when_counting() {
+        let now = Utc::now();
+        let mut log = log_of(10, now);
+        log.delete(1).unwrap();
+        log.delete(8).unwrap();
+        // Eight live entries, so five of them go: slots 0 and 2 to 5, with
+        // the tombstone in slot 1 between them.
+        let tombstone = policy(None, Some(3), Compaction::Tombstone);
+        assert_eq!(log.expired_prefix(&tombstone, now), 6);
+        // Removing counts every slot.
+        let remove = policy(None, Some(3), Compaction::Remove);
+        assert_eq!(log.expired_prefix(&remove, now), 7);
+    }
+
+    #[test]
+    fn compact_removes_old_entries() {
+        let now = Utc::now();
+        let mut log = log_of(10, now);
+        log.lmao[2].attachments.push(chat::Attachment {
+            id: chat::AttachmentId("a".repeat(64)),
+            file_name: "cat.png".to_owned(),
+            size: 3,
+        });
+
+        let compacted =
+            log.compact(&policy(None, Some(4), Compaction::Remove), now);
+        assert_eq!(compacted.removed.len(), 6);
+        assert!(compacted.tombstoned.is_empty());
+        assert_eq!(compacted.released_attachments.len(), 1);
+        assert_eq!(log.history_start(), 6);
+        assert_eq!(log.len(), 4);
+        assert_eq!(log.next_slot(), 10);
+        assert!(log.get(5).is_none());
+        assert_eq!(log.get(6).unwrap().text_content(), Some("6"));
+
+        // New entries carry on from where the log left off.
+        let Committed { entry, moved } = log.post(
+            chat::EntryKind::Message,
+            "ethan".to_owned(),
+            "10".to_owned(),
+            vec![],
+        );
+        assert_eq!(entry.slot_number, 10);
+        assert!(moved.is_empty());
+        assert_eq!(log.entries(2, None).len(), 2);
+        assert!(log
+            .compact(&policy(None, Some(5), Compaction::Remove), now)
+            .is_empty());
+    }
+
+    #[test]
+    fn compact_tombstones_keep_slots_contiguous() {
+        let now = Utc::now();
+        let mut log = log_of(10, now);
+        log.delete(1).unwrap();
+
+        let tombstone = policy(None, Some(3), Compaction::Tombstone);
+        let compacted = log.compact(&tombstone, now);
+        assert!(compacted.removed.is_empty());
+        // Slot 1 was already a tombstone.
+        let tombstoned: Vec<_> = compacted
+            .tombstoned
+            .iter()
+            .map(|entry| entry.slot_number)
+            .collect();
+        assert_eq!(tombstoned, [0, 2, 3, 4, 5, 6]);
+        assert_eq!(log.history_start(), 0);
+        assert_eq!(log.len(), 10);
+        assert_eq!(log.get(6).unwrap().content, chat::Content::Deleted);
+        assert_eq!(log.get(7).unwrap().text_content(), Some("7"));
+
+        // Tombstones don't count towards the limit, so compacting again
+        // leaves the three live entries alone.
+        assert!(log.compact(&tombstone, now).is_empty());
+    }
+
+    fn federated(server: &str) -> FakeChatLog {
+        let mut log = FakeChatLog::new(chat::ChannelName::default());
+        log.set_server(Some(server.to_owned()));
+        log
+    }
+
+    /// Posts as `username`, `minutes` after `start`, which must be before now
+    /// so the post still goes last.
+    fn post_at(
+        log: &mut FakeChatLog,
+        username: &str,
+        start: DateTime<Utc>,
+        minutes: i64,
+    ) -> chat::Entry {
+        let Committed { entry, moved } = log.post(
+            chat::EntryKind::Message,
+            username.to_owned(),
+            format!("from {}", username),
+            vec![],
+        );
+        assert!(moved.is_empty());
+        let index = log.index_of(entry.slot_number).unwrap();
+        log.lmao[index].metadata.timestamp =
+            start + TimeDelta::minutes(minutes);
+        log.lmao[index].clone()
+    }
+
+    fn contents(log: &FakeChatLog) -> Vec<String> {
+        log.entries_after(None)
+            .iter()
+            .map(|entry| match &entry.content {
+                chat::Content::Original(text) => text.0.clone(),
+                other => format!("{:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn relayed_entries_merge_into_the_same_order_on_every_server() {
+        let start = Utc::now() - TimeDelta::hours(1);
+        let mut alpha = federated("alpha");
+        let mut beta = federated("beta");
+
+        // Each server commits its own posts before the other's arrive, though
+        // each server's arrive in the order they were posted there.
+        let alice = post_at(&mut alpha, "alice", start, 1);
+        let bob = post_at(&mut beta, "bob", start, 2);
+        let carol = post_at(&mut alpha, "carol", start, 3);
+
+        let Some(Relayed::New(Committed { entry, moved })) = alpha.relay(bob)
+        else {
+            panic!("bob's post wasn't new to alpha");
+        };
+        assert_eq!(entry.slot_number, 1);
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].slot_number, 2);
+
+        let Some(Relayed::New(Committed { entry, moved })) =
+            beta.relay(alice.clone())
+        else {
+            panic!("alice's post wasn't new to beta");
+        };
+        assert_eq!(entry.slot_number, 0);
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].slot_number, 1);
+        beta.relay(carol).unwrap();
+
+        assert_eq!(contents(&alpha), ["from alice", "from bob", "from carol"]);
+        assert_eq!(alpha.entries_after(None), beta.entries_after(None));
+
+        // Relaying an entry again, as after reconnecting, replaces it in its
+        // slot rather than merging it in again.
+        assert!(matches!(beta.relay(alice), Some(Relayed::Updated(_))));
+        assert_eq!(beta.len(), 3);
+        assert_eq!(beta.origin_next_slots().get("alpha"), Some(&2));
+    }
+
+    #[test]
+    fn naming_the_server_numbers_earlier_entries_by_slot() {
+        let mut log = log_of(2, Utc::now());
+        log.set_server(Some("alpha".to_owned()));
+        assert_eq!(
+            log.get(1).unwrap().metadata.origin,
+            Some(chat::Origin {
+                server: "alpha".to_owned(),
+                slot_number: 1,
+            })
+        );
+        let Committed { entry, .. } = log.post(
+            chat::EntryKind::Message,
+            "ethan".to_owned(),
+            "2".to_owned(),
+            vec![],
+        );
+        assert_eq!(entry.metadata.origin.unwrap().slot_number, 2);
+    }
+
+    #[test]
+    fn compact_without_limits_keeps_everything() {
+        let now = Utc::now();
+        let mut log = log_of(3, now);
+        let keep = policy(None, None, Compaction::Remove);
+        assert!(log.compact(&keep, now + TimeDelta::days(365)).is_empty());
+        assert_eq!(log.len(), 3);
+    }
+}
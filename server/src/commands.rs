@@ -0,0 +1,520 @@
+//! Slash commands: posts starting with `/` that the server acts on instead of
+//! committing as they are.
+
+use chrono::{TimeDelta, Utc};
+
+/// What running a command should do, which the server then carries out.
+pub enum Outcome {
+    /// Commit an entry from the invoking user.
+    Post {
+        kind: chat::EntryKind,
+        content: String,
+    },
+    /// Tell only the invoking session something.
+    Respond(String),
+    /// Change the channel's topic and announce it.
+    SetTopic(String),
+    /// Change the invoking session's username and announce it.
+    Rename(String),
+    /// Take a moderator action in the channel, if the invoking user is a
+    /// moderator.
+    Moderate {
+        action: comms::ModerationAction,
+        reason: Option<String>,
+    },
+    /// Give a user a role in the channel, or clear it if `None`, if the
+    /// invoking user is an admin.
+    AssignRole {
+        username: String,
+        role: Option<comms::Role>,
+    },
+}
+
+/// What a command can see about where it was run.
+pub struct Context<'a> {
+    pub username: &'a str,
+    pub channel: &'a chat::ChannelName,
+    pub topic: Option<&'a str>,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    run: fn(&Context, &str) -> Outcome,
+}
+
+impl Command {
+    pub fn info(&self) -> comms::CommandInfo {
+        comms::CommandInfo {
+            name: self.name.to_owned(),
+            usage: self.usage.to_owned(),
+            description: self.description.to_owned(),
+        }
+    }
+}
+
+fn usage(command: &str) -> Outcome {
+    let usage = COMMANDS
+        .iter()
+        .find(|known| known.name == command)
+        .map_or(command, |known| known.usage);
+    Outcome::Respond(format!("Usage: {}", usage))
+}
+
+/// Splits off the first word of `arguments`.
+fn next_word(arguments: &str) -> (&str, &str) {
+    let (word, rest) = arguments
+        .split_once(char::is_whitespace)
+        .unwrap_or((arguments, ""));
+    (word, rest.trim_start())
+}
+
+fn reason(rest: &str) -> Option<String> {
+    Some(rest.to_owned()).filter(|reason| !reason.is_empty())
+}
+
+/// Parses durations like `30s`, `10m`, `12h`, `7d`, or `2w`.
+fn parse_duration(duration: &str) -> Option<TimeDelta> {
+    let unit = duration.chars().last()?;
+    let count = duration[..duration.len() - unit.len_utf8()]
+        .parse::<i64>()
+        .ok()?;
+    match unit {
+        's' => TimeDelta::try_seconds(count),
+        'm' => TimeDelta::try_minutes(count),
+        'h' => TimeDelta::try_hours(count),
+        'd' => TimeDelta::try_days(count),
+        'w' => TimeDelta::try_weeks(count),
+        _ => None,
+    }
+}
+
+/// Reads `<username> [duration] [reason]` for `/ban` and `/mute`, where the
+/// ban or mute lasts for good without a duration.
+fn restriction(
+    command: &str,
+    arguments: &str,
+    action: fn(
+        String,
+        Option<chrono::DateTime<Utc>>,
+    ) -> comms::ModerationAction,
+) -> Outcome {
+    let (username, rest) = next_word(arguments);
+    if username.is_empty() {
+        return usage(command);
+    }
+    let (duration, after_duration) = next_word(rest);
+    let (until, rest) = match parse_duration(duration) {
+        Some(duration) => match Utc::now().checked_add_signed(duration) {
+            Some(until) => (Some(until), after_duration),
+            None => return usage(command),
+        },
+        None => (None, rest),
+    };
+    Outcome::Moderate {
+        action: action(username.to_owned(), until),
+        reason: reason(rest),
+    }
+}
+
+/// Reads `<username>` for `/unban` and `/unmute`.
+fn lift(
+    command: &str,
+    arguments: &str,
+    action: fn(String) -> comms::ModerationAction,
+) -> Outcome {
+    if arguments.is_empty() || arguments.contains(char::is_whitespace) {
+        return usage(command);
+    }
+    Outcome::Moderate {
+        action: action(arguments.to_owned()),
+        reason: None,
+    }
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "/help",
+        description: "List the available commands",
+        run: |_, _| {
+            let lines = COMMANDS
+                .iter()
+                .map(|command| {
+                    format!("{} — {}", command.usage, command.description)
+                })
+                .collect::<Vec<_>>();
+            Outcome::Respond(lines.join("\n"))
+        },
+    },
+    Command {
+        name: "me",
+        usage: "/me <action>",
+        description: "Describe something you're doing",
+        run: |_, arguments| {
+            if arguments.is_empty() {
+                return usage("me");
+            }
+            Outcome::Post {
+                kind: chat::EntryKind::Action,
+                content: arguments.to_owned(),
+            }
+        },
+    },
+    Command {
+        name: "nick",
+        usage: "/nick <username>",
+        description: "Change your username",
+        run: |context, arguments| {
+            if arguments.is_empty() || arguments.contains(char::is_whitespace) {
+                return usage("nick");
+            }
+            if arguments.contains('@') {
+                return Outcome::Respond(
+                    "Usernames can't contain @, which marks users on other \
+                     servers"
+                        .to_owned(),
+                );
+            }
+            if arguments == context.username {
+                return Outcome::Respond(format!(
+                    "You're already {}",
+                    context.username
+                ));
+            }
+            Outcome::Rename(arguments.to_owned())
+        },
+    },
+    Command {
+        name: "kick",
+        usage: "/kick <username> [reason]",
+        description: "Disconnect a user (moderators only)",
+        run: |_, arguments| {
+            let (username, rest) = next_word(arguments);
+            if username.is_empty() {
+                return usage("kick");
+            }
+            Outcome::Moderate {
+                action: comms::ModerationAction::Kick {
+                    username: username.to_owned(),
+                },
+                reason: reason(rest),
+            }
+        },
+    },
+    Command {
+        name: "ban",
+        usage: "/ban <username> [duration, e.g. 7d] [reason]",
+        description: "Disconnect a user and keep them out (moderators only)",
+        run: |_, arguments| {
+            restriction("ban", arguments, |username, until| {
+                comms::ModerationAction::Ban { username, until }
+            })
+        },
+    },
+    Command {
+        name: "unban",
+        usage: "/unban <username>",
+        description: "Lift a ban (moderators only)",
+        run: |_, arguments| {
+            lift("unban", arguments, |username| {
+                comms::ModerationAction::Unban { username }
+            })
+        },
+    },
+    Command {
+        name: "mute",
+        usage: "/mute <username> [duration, e.g. 10m] [reason]",
+        description: "Stop a user posting in this channel (moderators only)",
+        run: |_, arguments| {
+            restriction("mute", arguments, |username, until| {
+                comms::ModerationAction::Mute { username, until }
+            })
+        },
+    },
+    Command {
+        name: "unmute",
+        usage: "/unmute <username>",
+        description: "Lift a mute in this channel (moderators only)",
+        run: |_, arguments| {
+            lift("unmute", arguments, |username| {
+                comms::ModerationAction::Unmute { username }
+            })
+        },
+    },
+    Command {
+        name: "role",
+        usage: "/role <username> <read_only|member|moderator|admin|default>",
+        description: "Set a user's role in this channel (admins only)",
+        run: |_, arguments| {
+            let (username, role) = next_word(arguments);
+            if username.is_empty() || role.contains(char::is_whitespace) {
+                return usage("role");
+            }
+            let role = match role {
+                "default" => None,
+                name => match comms::Role::ALL
+                    .into_iter()
+                    .find(|role| role.name() == name)
+                {
+                    Some(role) => Some(role),
+                    None => return usage("role"),
+                },
+            };
+            Outcome::AssignRole {
+                username: username.to_owned(),
+                role,
+            }
+        },
+    },
+    Command {
+        name: "remove",
+        usage: "/remove <slot> [reason]",
+        description: "Delete anyone's entry (moderators only)",
+        run: |_, arguments| {
+            let (slot_number, rest) = next_word(arguments);
+            let Ok(slot_number) = slot_number.parse() else {
+                return usage("remove");
+            };
+            Outcome::Moderate {
+                action: comms::ModerationAction::RemoveEntry { slot_number },
+                reason: reason(rest),
+            }
+        },
+    },
+    Command {
+        name: "shrug",
+        usage: "/shrug [message]",
+        description: "Post a message followed by ¯\\_(ツ)_/¯",
+        run: |_, arguments| {
+            // Escaped so the underscores aren't read as emphasis.
+            let shrug = "¯\\\\\\_(ツ)\\_/¯";
+            Outcome::Post {
+                kind: chat::EntryKind::Message,
+                content: if arguments.is_empty() {
+                    shrug.to_owned()
+                } else {
+                    format!("{} {}", arguments, shrug)
+                },
+            }
+        },
+    },
+    Command {
+        name: "topic",
+        usage: "/topic [new topic]",
+        description: "Show or change the channel's topic",
+        run: |context, arguments| {
+            if !arguments.is_empty() {
+                return Outcome::SetTopic(arguments.to_owned());
+            }
+            Outcome::Respond(match context.topic {
+                Some(topic) => {
+                    format!("{}'s topic is: {}", context.channel, topic)
+                }
+                None => format!("{} has no topic", context.channel),
+            })
+        },
+    },
+];
+
+/// What a post should be taken as.
+pub enum Parsed<'a> {
+    Message(&'a str),
+    Command { name: &'a str, arguments: &'a str },
+}
+
+pub fn parse(content: &str) -> Parsed<'_> {
+    match content.strip_prefix('/') {
+        // `//` escapes a leading slash.
+        Some(rest) if rest.starts_with('/') => Parsed::Message(rest),
+        Some(rest) if !rest.is_empty() => {
+            let (name, arguments) =
+                rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            Parsed::Command {
+                name,
+                arguments: arguments.trim(),
+            }
+        }
+        _ => Parsed::Message(content),
+    }
+}
+
+/// Runs the command called `name`.
+pub fn run(context: &Context, name: &str, arguments: &str) -> Outcome {
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(context, arguments),
+        None => Outcome::Respond(format!(
+            "Unknown command /{}. Try /help, or start with // to post a \
+             message starting with /",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_as_ethan(content: &str) -> Outcome {
+        let channel = chat::ChannelName::default();
+        let context = Context {
+            username: "ethan",
+            channel: &channel,
+            topic: Some("Rust"),
+        };
+        match parse(content) {
+            Parsed::Command { name, arguments } => {
+                run(&context, name, arguments)
+            }
+            Parsed::Message(_) => panic!("{:?} isn't a command", content),
+        }
+    }
+
+    fn response(outcome: Outcome) -> String {
+        match outcome {
+            Outcome::Respond(response) => response,
+            _ => panic!("expected a response"),
+        }
+    }
+
+    #[test]
+    fn parses_commands_and_messages() {
+        assert!(matches!(parse("hello"), Parsed::Message("hello")));
+        assert!(matches!(parse("/"), Parsed::Message("/")));
+        assert!(matches!(parse("//me"), Parsed::Message("/me")));
+        assert!(matches!(
+            parse("/me  waves  "),
+            Parsed::Command {
+                name: "me",
+                arguments: "waves"
+            }
+        ));
+        assert!(matches!(
+            parse("/help"),
+            Parsed::Command {
+                name: "help",
+                arguments: ""
+            }
+        ));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s"), TimeDelta::try_seconds(30));
+        assert_eq!(parse_duration("10m"), TimeDelta::try_minutes(10));
+        assert_eq!(parse_duration("2w"), TimeDelta::try_weeks(2));
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("7y"), None);
+        assert_eq!(parse_duration("spam"), None);
+    }
+
+    #[test]
+    fn posts() {
+        assert!(matches!(
+            run_as_ethan("/me waves"),
+            Outcome::Post { kind: chat::EntryKind::Action, content }
+                if content == "waves"
+        ));
+        assert!(matches!(
+            run_as_ethan("/shrug ok"),
+            Outcome::Post { kind: chat::EntryKind::Message, content }
+                if content.starts_with("ok ¯")
+        ));
+        assert!(response(run_as_ethan("/me")).starts_with("Usage: /me"));
+    }
+
+    #[test]
+    fn renames() {
+        assert!(matches!(
+            run_as_ethan("/nick alice"),
+            Outcome::Rename(username) if username == "alice"
+        ));
+        assert!(response(run_as_ethan("/nick ethan")).contains("already"));
+        assert!(response(run_as_ethan("/nick a@b")).contains('@'));
+        assert!(response(run_as_ethan("/nick a b")).starts_with("Usage"));
+    }
+
+    #[test]
+    fn moderates() {
+        assert!(matches!(
+            run_as_ethan("/kick alice being rude"),
+            Outcome::Moderate {
+                action: comms::ModerationAction::Kick { username },
+                reason: Some(reason),
+            } if username == "alice" && reason == "being rude"
+        ));
+        let before = Utc::now();
+        let Outcome::Moderate {
+            action: comms::ModerationAction::Ban { username, until },
+            reason,
+        } = run_as_ethan("/ban alice 7d spam")
+        else {
+            panic!("expected a ban");
+        };
+        assert_eq!(username, "alice");
+        assert!(until.unwrap() >= before + TimeDelta::days(7));
+        assert_eq!(reason.as_deref(), Some("spam"));
+        // Without a duration, everything after the username is the reason.
+        assert!(matches!(
+            run_as_ethan("/mute alice too loud"),
+            Outcome::Moderate {
+                action: comms::ModerationAction::Mute { until: None, .. },
+                reason: Some(reason),
+            } if reason == "too loud"
+        ));
+        assert!(matches!(
+            run_as_ethan("/unban alice"),
+            Outcome::Moderate {
+                action: comms::ModerationAction::Unban { .. },
+                reason: None,
+            }
+        ));
+        assert!(matches!(
+            run_as_ethan("/remove 12 off topic"),
+            Outcome::Moderate {
+                action: comms::ModerationAction::RemoveEntry {
+                    slot_number: 12
+                },
+                ..
+            }
+        ));
+        for content in ["/kick", "/ban", "/unmute a b", "/remove twelve"] {
+            assert!(response(run_as_ethan(content)).starts_with("Usage"));
+        }
+    }
+
+    #[test]
+    fn assigns_roles() {
+        assert!(matches!(
+            run_as_ethan("/role alice moderator"),
+            Outcome::AssignRole {
+                username,
+                role: Some(comms::Role::Moderator),
+            } if username == "alice"
+        ));
+        assert!(matches!(
+            run_as_ethan("/role alice default"),
+            Outcome::AssignRole { role: None, .. }
+        ));
+        assert!(response(run_as_ethan("/role alice king")).starts_with("Usage"));
+        assert!(response(run_as_ethan("/role")).starts_with("Usage"));
+    }
+
+    #[test]
+    fn shows_and_sets_topics() {
+        assert!(response(run_as_ethan("/topic")).ends_with("topic is: Rust"));
+        assert!(matches!(
+            run_as_ethan("/topic Go"),
+            Outcome::SetTopic(topic) if topic == "Go"
+        ));
+    }
+
+    #[test]
+    fn lists_and_rejects_commands() {
+        let help = response(run_as_ethan("/help"));
+        assert_eq!(help.lines().count(), COMMANDS.len());
+        assert!(response(run_as_ethan("/dance")).contains("Unknown command"));
+    }
+}
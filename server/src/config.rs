@@ -0,0 +1,167 @@
+use std::{collections::HashMap, fs, io, net::SocketAddr, path::Path};
+
+use serde::Deserialize;
+
+/// What happens to entries once a [`RetentionPolicy`] no longer keeps them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compaction {
+    /// Drop the entries entirely, moving the start of the channel's history
+    /// forward.
+    #[default]
+    Remove,
+    /// Keep each slot as a deleted entry, so slot numbers stay contiguous but
+    /// the content and attachments are gone.
+    Tombstone,
+}
+
+/// How much of a channel's history to keep. Entries are compacted when they
+/// are older than `max_age_seconds` or beyond the newest `max_entries`,
+/// whichever keeps less.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    pub max_age_seconds: Option<u64>,
+    pub max_entries: Option<usize>,
+    pub compaction: Compaction,
+}
+
+impl RetentionPolicy {
+    pub fn keeps_everything(&self) -> bool {
+        self.max_age_seconds.is_none() && self.max_entries.is_none()
+    }
+}
+
+/// Where a server streams chat logs to and from other servers. Only read at
+/// startup.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Replication {
+    /// Where to accept followers.
+    pub listen_address: Option<SocketAddr>,
+    /// The leader to follow. A follower is read-only until it's promoted.
+    pub leader_address: Option<SocketAddr>,
+    /// Shared by a leader and its followers, which prove to each other that
+    /// they know it when connecting.
+    pub secret: Option<String>,
+}
+
+/// Another server that shares channels with this one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Peer {
+    /// Where the peer accepts other servers.
+    pub address: SocketAddr,
+    /// Shared with the peer, which both prove they know when connecting.
+    pub secret: String,
+    /// Channels shared with the peer. The peer must list them too.
+    pub channels: Vec<chat::ChannelName>,
+}
+
+/// Which servers share channels with this one. Only read at startup.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Federation {
+    /// What other servers call this one, which qualifies the usernames of
+    /// users posting here, e.g. `alice@name`.
+    pub name: Option<String>,
+    /// Where to accept peers.
+    pub listen_address: Option<SocketAddr>,
+    /// Peers by their names.
+    pub peers: HashMap<String, Peer>,
+}
+
+impl Federation {
+    /// Whether `channel` is shared with the peer called `server`.
+    pub fn shares(&self, server: &str, channel: &chat::ChannelName) -> bool {
+        self.peers
+            .get(server)
+            .is_some_and(|peer| peer.channels.contains(channel))
+    }
+}
+
+/// The read-only HTTP API. Only read at startup.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpApi {
+    /// Where to serve it, if set, which must be a loopback address.
+    pub address: Option<SocketAddr>,
+    /// Who may read it, by name, and the token each sends as
+    /// `Authorization: Bearer <token>`.
+    pub tokens: HashMap<String, String>,
+}
+
+/// Where to POST new entries, and which ones: those in `channels`, or in any
+/// channel if there are none, that contain one of `keywords`, ignoring case,
+/// or match the regular expression `pattern`, if either is set.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Webhook {
+    /// An `http://` or `https://` URL.
+    pub url: String,
+    /// Signs each request, so the receiver can check it came from here.
+    pub secret: String,
+    #[serde(default)]
+    pub channels: Vec<chat::ChannelName>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    pub pattern: Option<String>,
+}
+
+/// Server settings, read from `config.json` in the data directory. Every
+/// field is optional.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Applies to channels without their own entry in `retention`.
+    pub default_retention: RetentionPolicy,
+    pub retention: HashMap<chat::ChannelName, RetentionPolicy>,
+    /// Usernames that are admins everywhere, whatever roles are assigned.
+    pub admins: Vec<String>,
+    /// Usernames that are moderators where they have no other role assigned.
+    pub moderators: Vec<String>,
+    /// The role of users with no other role assigned.
+    pub default_role: comms::Role,
+    /// Passwords by username. Logging in as one of these names needs its
+    /// password, and admins and moderators can only log in with one.
+    pub passwords: HashMap<String, String>,
+    /// Where to serve Prometheus metrics, [`crate::metrics::DEFAULT_ADDRESS`]
+    /// if unset. Only read at startup.
+    pub metrics_address: Option<SocketAddr>,
+    /// Where to accept QUIC connections too, if set. Only read at startup.
+    pub quic_address: Option<SocketAddr>,
+    /// Where to accept unencrypted `ws://` connections too, if set, which must
+    /// be a loopback address. Only read at startup.
+    pub plain_address: Option<SocketAddr>,
+    /// Whether to accept unencrypted connections on `client.sock` in the data
+    /// directory, which only the user running the server can connect to.
+    /// Only read at startup.
+    pub client_socket: bool,
+    pub replication: Replication,
+    pub federation: Federation,
+    pub http_api: HttpApi,
+    /// Webhooks by name. Only read at startup.
+    pub webhooks: HashMap<String, Webhook>,
+}
+
+impl Config {
+    /// Reads the config at `path`, or the default config if there is none.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).map_err(io::Error::other)
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn retention_for(
+        &self,
+        channel: &chat::ChannelName,
+    ) -> &RetentionPolicy {
+        self.retention
+            .get(channel)
+            .unwrap_or(&self.default_retention)
+    }
+}
@@ -0,0 +1,189 @@
+//! The `server export` subcommand, which renders a stored channel without
+//! starting the server.
+
+use std::{fs, io::Write, path::PathBuf};
+
+use chat::export::Format;
+
+use crate::{storage::LogStorage, Error};
+
+const USAGE: &str = "Usage: server export <data directory> <channel> \
+                     [--format jsonl|md|html|txt] [--from <slot>] \
+                     [--to <slot>] [--output <path>]";
+
+fn usage_error(problem: &str) -> Error {
+    Error::Usage(format!("{}\n{}", problem, USAGE))
+}
+
+fn parse_slot(flag: &str, value: Option<String>) -> Result<usize, Error> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| usage_error(&format!("{} takes a slot number", flag)))
+}
+
+/// Exports slots `--from` through `--to` of a channel, or all of it, to
+/// `--output` or standard output. `args` follow the subcommand name.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    let data_directory = PathBuf::from(
+        args.next()
+            .ok_or_else(|| usage_error("Missing data directory"))?,
+    );
+    let channel = args.next().ok_or_else(|| usage_error("Missing channel"))?;
+    let channel = chat::ChannelName(channel.trim_start_matches('#').to_owned());
+
+    let mut format = Format::PlainText;
+    let mut from = None;
+    let mut to = None;
+    let mut output = None;
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--format" => {
+                format = args
+                    .next()
+                    .ok_or_else(|| usage_error("--format takes a format"))?
+                    .parse()
+                    .map_err(|error: chat::export::UnknownFormat| {
+                        usage_error(&error.to_string())
+                    })?;
+            }
+            "--from" => from = Some(parse_slot("--from", args.next())?),
+            "--to" => to = Some(parse_slot("--to", args.next())?),
+            "--output" => {
+                output =
+                    Some(PathBuf::from(args.next().ok_or_else(|| {
+                        usage_error("--output takes a path")
+                    })?));
+            }
+            _ => return Err(usage_error(&format!("Unknown option {}", flag))),
+        }
+    }
+
+    let chat_logs = LogStorage::open(data_directory.join("channels"))
+        .and_then(|mut log_storage| log_storage.load())
+        .map_err(Error::Io)?;
+    let chat_log = chat_logs.get(&channel).ok_or_else(|| {
+        Error::Usage(format!("No channel {} is stored", channel))
+    })?;
+    let entries = chat_log
+        .entries_after(from.and_then(|from: usize| from.checked_sub(1)))
+        .iter()
+        .take_while(|entry| entry.slot_number <= to.unwrap_or(usize::MAX))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let document = chat::export::export(&entries, format, &channel.to_string());
+    match output {
+        Some(path) => fs::write(&path, document).map_err(Error::Io)?,
+        None => std::io::stdout()
+            .write_all(document.as_bytes())
+            .map_err(Error::Io)?,
+    }
+    tracing::info!("Exported {} entries from {}", entries.len(), channel);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::chat_log::FakeChatLog;
+
+    /// Stores a channel `general` of `count` entries under `data_directory`.
+    fn store_channel(data_directory: &Path, count: usize) {
+        let mut log_storage =
+            LogStorage::open(data_directory.join("channels")).unwrap();
+        let mut chat_log =
+            FakeChatLog::new(chat::ChannelName("general".to_owned()));
+        for n in 0..count {
+            let entry = chat_log
+                .post(
+                    chat::EntryKind::Message,
+                    "ethan".to_owned(),
+                    format!("message {}", n),
+                    vec![],
+                )
+                .entry;
+            log_storage.record_entry(&chat_log, &entry).unwrap();
+        }
+    }
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn exports_a_range_of_slots() {
+        let directory = tempfile::tempdir().unwrap();
+        store_channel(directory.path(), 5);
+        let output = directory.path().join("general.jsonl");
+        run(args(&[
+            directory.path().to_str().unwrap(),
+            "#general",
+            "--format",
+            "jsonl",
+            "--from",
+            "1",
+            "--to",
+            "3",
+            "--output",
+            output.to_str().unwrap(),
+        ]))
+        .unwrap();
+
+        let slots = fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<chat::Entry>(line)
+                    .unwrap()
+                    .slot_number
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(slots, [1, 2, 3]);
+    }
+
+    #[test]
+    fn exports_everything_by_default() {
+        let directory = tempfile::tempdir().unwrap();
+        store_channel(directory.path(), 3);
+        let output = directory.path().join("general.txt");
+        run(args(&[
+            directory.path().to_str().unwrap(),
+            "general",
+            "--output",
+            output.to_str().unwrap(),
+        ]))
+        .unwrap();
+
+        let document = fs::read_to_string(&output).unwrap();
+        for n in 0..3 {
+            assert!(document.contains(&format!("message {}", n)));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let directory = tempfile::tempdir().unwrap();
+        store_channel(directory.path(), 1);
+        let data_directory = directory.path().to_str().unwrap();
+        for bad_args in [
+            &[][..],
+            &[data_directory],
+            &[data_directory, "general", "--format", "pdf"],
+            &[data_directory, "general", "--from", "first"],
+            &[data_directory, "general", "--to"],
+            &[data_directory, "general", "--verbose"],
+            &[data_directory, "random"],
+        ] {
+            assert!(
+                matches!(run(args(bad_args)), Err(Error::Usage(_))),
+                "{:?} should be rejected",
+                bad_args
+            );
+        }
+    }
+}
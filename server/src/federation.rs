@@ -0,0 +1,258 @@
+//! Federation between independent servers that share channels. Each server
+//! connects to each of its peers to receive what's posted there: both prove
+//! they know the secret they share, and the peer relays every entry posted
+//! to it in the channels they share, in its slot order, including later
+//! edits and deletions.
+//!
+//! A relayed entry is merged in by when and where it was posted, its author
+//! qualified as `user@server` and its origin recorded in [`chat::Metadata`],
+//! so every server sharing a channel ends up with the same order, moving
+//! entries already committed later when one posted before them arrives
+//! late (see [`crate::chat_log::FakeChatLog::relay`]). On connecting, the
+//! receiving server sends the next slot it expects from the peer in each
+//! shared channel over a [`peer::Link`], which authenticates every message
+//! after the handshake, and the peer catches it up the way a leader catches up
+//! a follower (see [`crate::replication`]), so nothing is skipped or relayed
+//! twice across reconnects.
+
+use std::{collections::HashMap, io, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{config, peer};
+
+#[derive(Serialize, Deserialize)]
+enum ListenerMessage {
+    /// Sent on connecting, to be answered like
+    /// [`crate::replication`]'s challenge.
+    Challenge { challenge: String },
+    /// The connector authenticated. The listener sends entries as they were
+    /// committed there on the link from then on.
+    Accepted { proof: String },
+}
+
+#[derive(Serialize, Deserialize)]
+enum ConnectorMessage {
+    /// Once accepted, the connector sends the next slot it expects in each
+    /// channel on the link.
+    Hello {
+        /// The connecting server's name, which the listener knows it by.
+        server: String,
+        proof: String,
+        challenge: String,
+    },
+}
+
+/// A peer that connected and authenticated, to be caught up and sent entries
+/// on `entries_tx`.
+pub struct Peer {
+    pub server: String,
+    pub next_slots: HashMap<chat::ChannelName, usize>,
+    pub entries_tx: mpsc::UnboundedSender<chat::Entry>,
+}
+
+/// What the tasks connected to peers need from the server.
+pub enum PeerEvent {
+    /// The task connected to `server` and needs the next slot expected from
+    /// it in each shared channel.
+    NextSlots {
+        server: String,
+        reply: oneshot::Sender<HashMap<chat::ChannelName, usize>>,
+    },
+    Relay {
+        server: String,
+        entry: chat::Entry,
+    },
+}
+
+/// Accepts the servers in `peers` on `address`, sending each to `peer_tx`
+/// once it authenticates.
+pub async fn listen(
+    address: SocketAddr,
+    peers: HashMap<String, config::Peer>,
+    peer_tx: mpsc::UnboundedSender<Peer>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Accepting peers on {}", address);
+    tokio::spawn(async move {
+        while let Ok((stream, peer_address)) = listener.accept().await {
+            let peers = peers.clone();
+            let peer_tx = peer_tx.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve(stream, &peers, peer_tx).await {
+                    tracing::warn!(
+                        %peer_address,
+                        "Peer connection failed: {}",
+                        error
+                    );
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(
+    stream: TcpStream,
+    peers: &HashMap<String, config::Peer>,
+    peer_tx: mpsc::UnboundedSender<Peer>,
+) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    let challenge = peer::new_challenge();
+    peer::send(
+        &mut write,
+        &ListenerMessage::Challenge {
+            challenge: challenge.clone(),
+        },
+    )
+    .await?;
+    let ConnectorMessage::Hello {
+        server,
+        proof,
+        challenge: peer_challenge,
+    } = peer::receive_handshake(&mut read).await?;
+    let Some(config) = peers
+        .get(&server)
+        .filter(|config| peer::verify(&config.secret, &challenge, &proof))
+    else {
+        return Err(peer::authentication_failed());
+    };
+    peer::send(
+        &mut write,
+        &ListenerMessage::Accepted {
+            proof: peer::prove(&config.secret, &peer_challenge),
+        },
+    )
+    .await?;
+    let mut link = peer::Link::new(
+        write,
+        read,
+        &config.secret,
+        peer::Side::Listener,
+        &challenge,
+        &peer_challenge,
+    );
+    let mut next_slots: HashMap<chat::ChannelName, usize> =
+        link.receive_handshake().await?;
+    tracing::info!(%server, "Peer connected");
+
+    next_slots.retain(|channel, _| config.channels.contains(channel));
+    let (entries_tx, mut entries_rx) = mpsc::unbounded_channel();
+    peer_tx
+        .send(Peer {
+            server,
+            next_slots,
+            entries_tx,
+        })
+        .map_err(|_| io::Error::other("server is shutting down"))?;
+    while let Some(entry) = entries_rx.recv().await {
+        link.send(&entry).await?;
+    }
+    Ok(())
+}
+
+/// Receives entries from the peer called `server` until aborted, as the
+/// server called `name`, reconnecting whenever the connection is lost.
+pub fn connect(
+    name: String,
+    server: String,
+    config: config::Peer,
+    event_tx: mpsc::UnboundedSender<PeerEvent>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut retry_delay = peer::MIN_RETRY_DELAY;
+        loop {
+            if let Err(error) = connect_once(
+                &name,
+                &server,
+                &config,
+                &event_tx,
+                &mut retry_delay,
+            )
+            .await
+            {
+                tracing::warn!(
+                    %server,
+                    "Lost connection to peer, retrying in {:?}: {}",
+                    retry_delay,
+                    error
+                );
+            }
+            tokio::time::sleep(retry_delay).await;
+            retry_delay = (retry_delay * 2).min(peer::MAX_RETRY_DELAY);
+        }
+    })
+}
+
+async fn connect_once(
+    name: &str,
+    server: &str,
+    config: &config::Peer,
+    event_tx: &mpsc::UnboundedSender<PeerEvent>,
+    retry_delay: &mut Duration,
+) -> io::Result<()> {
+    let (read, mut write) =
+        TcpStream::connect(config.address).await?.into_split();
+    let mut read = BufReader::new(read);
+
+    let ListenerMessage::Challenge { challenge } =
+        peer::receive_handshake(&mut read).await?
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "peer didn't start with a challenge",
+        ));
+    };
+    let own_challenge = peer::new_challenge();
+    peer::send(
+        &mut write,
+        &ConnectorMessage::Hello {
+            server: name.to_owned(),
+            proof: peer::prove(&config.secret, &challenge),
+            challenge: own_challenge.clone(),
+        },
+    )
+    .await?;
+    match peer::receive_handshake(&mut read).await? {
+        ListenerMessage::Accepted { proof }
+            if peer::verify(&config.secret, &own_challenge, &proof) => {}
+        _ => return Err(peer::authentication_failed()),
+    }
+    let mut link = peer::Link::new(
+        write,
+        read,
+        &config.secret,
+        peer::Side::Connector,
+        &challenge,
+        &own_challenge,
+    );
+    let (reply, next_slots_rx) = oneshot::channel();
+    event_tx
+        .send(PeerEvent::NextSlots {
+            server: server.to_owned(),
+            reply,
+        })
+        .map_err(|_| io::Error::other("server is shutting down"))?;
+    let next_slots = next_slots_rx.await.map_err(io::Error::other)?;
+    link.send(&next_slots).await?;
+    tracing::info!(%server, "Connected to peer");
+    *retry_delay = peer::MIN_RETRY_DELAY;
+
+    loop {
+        let entry = link.receive().await?;
+        event_tx
+            .send(PeerEvent::Relay {
+                server: server.to_owned(),
+                entry,
+            })
+            .map_err(|_| io::Error::other("server is shutting down"))?;
+    }
+}
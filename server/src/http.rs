@@ -0,0 +1,263 @@
+//! Just enough HTTP/1.1 for the server's small HTTP endpoints: one `GET` per
+//! connection, answered and then closed.
+
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::Instant,
+};
+
+/// Requests bigger than this are refused rather than buffered.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How long a client has to send the head of its request, so one that
+/// connects and sends nothing, or a byte at a time, doesn't hold its task
+/// forever.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The head of a request, which is all a `GET` has.
+pub struct Request {
+    pub method: String,
+    /// The path, without the query, still percent-encoded.
+    pub path: String,
+    /// Decoded query parameters, in order.
+    pub query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// The value of the header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The path's segments, each percent-decoded, so an encoded `/` stays
+    /// within its segment, or `None` if one isn't valid UTF-8 once decoded.
+    pub fn segments(&self) -> Option<Vec<String>> {
+        self.path
+            .trim_matches('/')
+            .split('/')
+            .map(|segment| decode(segment, false))
+            .collect()
+    }
+
+    /// The value of the query parameter called `name`.
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(parameter, _)| parameter == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads the head of a request from `stream`, answering it with an error
+/// instead if it's too big, malformed, or not sent within [`READ_TIMEOUT`].
+/// `None` means there's nothing more to do.
+pub async fn read_request(
+    stream: &mut TcpStream,
+) -> io::Result<Option<Request>> {
+    read_request_within(stream, READ_TIMEOUT).await
+}
+
+async fn read_request_within(
+    stream: &mut TcpStream,
+    timeout: Duration,
+) -> io::Result<Option<Request>> {
+    let deadline = Instant::now() + timeout;
+    let mut bytes = vec![];
+    let mut buffer = [0; 1024];
+    while !bytes.windows(4).any(|window| window == b"\r\n\r\n") {
+        if bytes.len() > MAX_REQUEST_SIZE {
+            respond(
+                stream,
+                "431 Request Header Fields Too Large",
+                "text/plain",
+                "",
+            )
+            .await?;
+            return Ok(None);
+        }
+        let Ok(read) =
+            tokio::time::timeout_at(deadline, stream.read(&mut buffer)).await
+        else {
+            respond(stream, "408 Request Timeout", "text/plain", "").await?;
+            return Ok(None);
+        };
+        let read = read?;
+        if read == 0 {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&buffer[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&bytes);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) =
+        (request_line.next(), request_line.next())
+    else {
+        respond(stream, "400 Bad Request", "text/plain", "").await?;
+        return Ok(None);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let Some(query) = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((decode(name, true)?, decode(value, true)?))
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        respond(stream, "400 Bad Request", "text/plain", "").await?;
+        return Ok(None);
+    };
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect();
+    Ok(Some(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        headers,
+    }))
+}
+
+/// Decodes a percent-encoded path segment or query component, in which `+`
+/// is a space if `plus_is_space`, or `None` if it isn't valid UTF-8 once
+/// decoded.
+fn decode(component: &str, plus_is_space: bool) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = component.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = after.get(..2)?;
+                bytes.push(
+                    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16)
+                        .ok()?,
+                );
+                rest = &after[2..];
+                continue;
+            }
+            b'+' if plus_is_space => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+        rest = after;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+pub async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Sends `request` to [`read_request`], returning what it read and the
+    /// response, if it answered with one.
+    async fn read(request: &'static str) -> (Option<Request>, String) {
+        read_within(request, READ_TIMEOUT).await
+    }
+
+    async fn read_within(
+        request: &'static str,
+        timeout: Duration,
+    ) -> (Option<Request>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = read_request_within(&mut stream, timeout).await.unwrap();
+        // Closing with unread bytes would reset the connection, losing the
+        // response.
+        let _ = tokio::time::timeout(
+            Duration::from_millis(100),
+            stream.read_to_end(&mut vec![]),
+        )
+        .await;
+        drop(stream);
+        (request, client.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn decodes_paths_and_queries() {
+        let (request, _) = read(
+            "GET /channels/rust%2Fhelp/a+b?text=hello+there&who=%40ethan HTTP/1.1\r\nAuthorization: Bearer x\r\n\r\n",
+        )
+        .await;
+        let request = request.unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(
+            request.segments().unwrap(),
+            ["channels", "rust/help", "a+b"]
+        );
+        assert_eq!(request.parameter("text"), Some("hello there"));
+        assert_eq!(request.parameter("who"), Some("@ethan"));
+        assert_eq!(request.header("authorization"), Some("Bearer x"));
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_encodings() {
+        let (request, response) =
+            read("GET /search?text=%ff HTTP/1.1\r\n\r\n").await;
+        assert!(request.is_none());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+
+        let (request, _) = read("GET /channels/%ff HTTP/1.1\r\n\r\n").await;
+        assert!(request.unwrap().segments().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_that_are_too_big() {
+        let request = format!(
+            "GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(2 * MAX_REQUEST_SIZE)
+        );
+        let (request, response) = read(request.leak()).await;
+        assert!(request.is_none());
+        assert!(response
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_slow_requests() {
+        let (request, response) = read_within(
+            "GET /channels HTTP/1.1\r\n",
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(request.is_none());
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+    }
+}
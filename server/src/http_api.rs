@@ -0,0 +1,489 @@
+//! A read-only HTTP API serving the same history and search as
+//! [`comms::ClientMessage`]s, as JSON, for scripts and dashboards that don't
+//! want a WebSocket. Every request needs a token from the config, sent as
+//! `Authorization: Bearer <token>`. Since neither requests nor responses are
+//! encrypted, it's only served on a loopback address.
+//!
+//! - `GET /channels` lists every channel with its topic, head slot, and history
+//!   start.
+//! - `GET /channels/<name>` describes one channel the same way.
+//! - `GET /channels/<name>/entries` returns the last `count` entries up to slot
+//!   `up_to`, or with `after`, up to `limit` entries after that slot.
+//! - `GET /search` searches every channel, or only `channel`, for messages with
+//!   all the words in `text`, filtered by `author`, `since`, and `until`.
+//!   `next_page` in the response is passed as `after` for the next page.
+
+use std::{collections::HashMap, future::Future, io, net::SocketAddr};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    channel::{self, History},
+    http,
+    state::ServerState,
+};
+
+/// How many entries are returned when a request doesn't say.
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// The most entries returned for one request.
+const MAX_PAGE_SIZE: usize = 1000;
+
+const CONTENT_TYPE: &str = "application/json";
+
+pub enum Request {
+    ListChannels,
+    Channel(chat::ChannelName),
+    Entries {
+        channel: chat::ChannelName,
+        range: History,
+    },
+    Search {
+        query: comms::SearchQuery,
+        limit: usize,
+        after: Option<comms::SearchCursor>,
+    },
+}
+
+/// A request and where to send the response, which is `None` if what it
+/// asked for doesn't exist.
+pub type ApiRequest = (Request, oneshot::Sender<Option<serde_json::Value>>);
+
+#[derive(Serialize)]
+struct ChannelInfo {
+    channel: chat::ChannelName,
+    topic: Option<String>,
+    head_slot: Option<usize>,
+    history_start: usize,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    hits: Vec<comms::SearchHit>,
+    next_page: Option<String>,
+}
+
+/// Serves the API on `address` to anyone with one of `tokens`, by who they
+/// were given to, forwarding requests to `request_tx`.
+pub async fn listen(
+    address: SocketAddr,
+    tokens: HashMap<String, String>,
+    request_tx: mpsc::UnboundedSender<ApiRequest>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving the HTTP API on http://{}", address);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tokens = tokens.clone();
+            let request_tx = request_tx.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve(stream, &tokens, request_tx).await {
+                    tracing::warn!("HTTP API request failed: {}", error);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    tokens: &HashMap<String, String>,
+    request_tx: mpsc::UnboundedSender<ApiRequest>,
+) -> io::Result<()> {
+    let Some(request) = http::read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    let Some(reader) = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| holder(tokens, token))
+    else {
+        return respond_error(
+            &mut stream,
+            "401 Unauthorized",
+            "Missing or unknown token",
+        )
+        .await;
+    };
+    if request.method != "GET" {
+        return respond_error(
+            &mut stream,
+            "405 Method Not Allowed",
+            "The API is read-only",
+        )
+        .await;
+    }
+    tracing::info!(%reader, path = %request.path, "HTTP API request");
+
+    let api_request = match parse(&request) {
+        Ok(Some(api_request)) => api_request,
+        Ok(None) => {
+            return respond_error(&mut stream, "404 Not Found", "No such path")
+                .await
+        }
+        Err(message) => {
+            return respond_error(&mut stream, "400 Bad Request", &message)
+                .await
+        }
+    };
+    let (response_tx, response_rx) = oneshot::channel();
+    request_tx
+        .send((api_request, response_tx))
+        .map_err(|_| io::Error::other("server is shutting down"))?;
+    match response_rx.await.map_err(io::Error::other)? {
+        Some(body) => {
+            http::respond(
+                &mut stream,
+                "200 OK",
+                CONTENT_TYPE,
+                &body.to_string(),
+            )
+            .await
+        }
+        None => {
+            respond_error(&mut stream, "404 Not Found", "No such channel").await
+        }
+    }
+}
+
+/// Who was given `token`, comparing digests so how long it takes doesn't
+/// reveal how much of a token matched.
+fn holder<'a>(
+    tokens: &'a HashMap<String, String>,
+    token: &str,
+) -> Option<&'a str> {
+    let digest = Sha256::digest(token);
+    tokens
+        .iter()
+        .find(|(_, known)| Sha256::digest(known) == digest)
+        .map(|(reader, _)| reader.as_str())
+}
+
+async fn respond_error(
+    stream: &mut TcpStream,
+    status: &str,
+    message: &str,
+) -> io::Result<()> {
+    let body = serde_json::json!({ "error": message }).to_string();
+    http::respond(stream, status, CONTENT_TYPE, &body).await
+}
+
+/// What `request` asks for, `None` if its path isn't part of the API, or why
+/// its parameters are invalid.
+fn parse(request: &http::Request) -> Result<Option<Request>, String> {
+    let segments = request
+        .segments()
+        .ok_or_else(|| format!("Invalid path {}", request.path))?;
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+    Ok(Some(match segments.as_slice() {
+        ["channels"] => Request::ListChannels,
+        ["channels", channel] => {
+            Request::Channel(chat::ChannelName((*channel).to_owned()))
+        }
+        ["channels", channel, "entries"] => Request::Entries {
+            channel: chat::ChannelName((*channel).to_owned()),
+            range: match parameter::<usize>(request, "after")? {
+                Some(after_slot_number) => History::After {
+                    after_slot_number: Some(after_slot_number),
+                    limit: page_size(request, "limit")?,
+                },
+                None => History::Before {
+                    count: page_size(request, "count")?,
+                    up_to_slot_number: parameter(request, "up_to")?,
+                },
+            },
+        },
+        ["search"] => Request::Search {
+            query: comms::SearchQuery {
+                text: request.parameter("text").unwrap_or_default().to_owned(),
+                author: request.parameter("author").map(str::to_owned),
+                since: time(request, "since")?,
+                until: time(request, "until")?,
+                channel: request
+                    .parameter("channel")
+                    .map(|channel| chat::ChannelName(channel.to_owned())),
+            },
+            limit: parameter(request, "limit")?
+                .unwrap_or(comms::MAX_SEARCH_PAGE_SIZE),
+            after: request
+                .parameter("after")
+                .map(|after| {
+                    parse_cursor(after)
+                        .ok_or_else(|| format!("Invalid page {}", after))
+                })
+                .transpose()?,
+        },
+        _ => return Ok(None),
+    }))
+}
+
+fn parameter<T: std::str::FromStr>(
+    request: &http::Request,
+    name: &str,
+) -> Result<Option<T>, String> {
+    request
+        .parameter(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid {} {}", name, value))
+        })
+        .transpose()
+}
+
+fn page_size(request: &http::Request, name: &str) -> Result<usize, String> {
+    Ok(parameter(request, name)?
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE))
+}
+
+fn time(
+    request: &http::Request,
+    name: &str,
+) -> Result<Option<DateTime<Utc>>, String> {
+    request
+        .parameter(name)
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| {
+                    format!("Invalid {} {}, expected RFC 3339", name, value)
+                })
+        })
+        .transpose()
+}
+
+/// Formats `cursor` as `<timestamp>/<slot>/<channel>`, with the channel last
+/// since it might contain slashes, and the timestamp in UTC so it has no `+`
+/// to escape.
+fn format_cursor(cursor: &comms::SearchCursor) -> String {
+    format!(
+        "{}/{}/{}",
+        cursor
+            .timestamp
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        cursor.slot_number,
+        cursor.channel.0
+    )
+}
+
+fn parse_cursor(cursor: &str) -> Option<comms::SearchCursor> {
+    let mut parts = cursor.splitn(3, '/');
+    Some(comms::SearchCursor {
+        timestamp: DateTime::parse_from_rfc3339(parts.next()?)
+            .ok()?
+            .with_timezone(&Utc),
+        slot_number: parts.next()?.parse().ok()?,
+        channel: chat::ChannelName(parts.next()?.to_owned()),
+    })
+}
+
+fn json(value: impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).expect("responses serialize to JSON")
+}
+
+impl ServerState {
+    /// Sends the channels what `request` needs now, returning the response
+    /// to wait for off the server's loop.
+    pub fn handle_api(
+        &self,
+        request: Request,
+    ) -> BoxFuture<'static, Option<serde_json::Value>> {
+        match request {
+            Request::ListChannels => {
+                let infos = future::join_all(
+                    self.channels
+                        .keys()
+                        .map(|channel| self.channel_info(channel)),
+                );
+                async move {
+                    let mut channels: Vec<_> =
+                        infos.await.into_iter().flatten().collect();
+                    channels.sort_by(|left, right| {
+                        left.channel.cmp(&right.channel)
+                    });
+                    Some(json(channels))
+                }
+                .boxed()
+            }
+            Request::Channel(channel) => self
+                .channel_info(&channel)
+                .map(|info| info.map(json))
+                .boxed(),
+            Request::Entries { channel, range } => {
+                let page = self.channels.get(&channel).map(|handle| {
+                    handle.request(|reply| channel::Request::Read {
+                        range,
+                        reply,
+                    })
+                });
+                async move { page?.await.ok().map(json) }.boxed()
+            }
+            Request::Search {
+                query,
+                limit,
+                after,
+            } => {
+                let search = self.search(query, limit, after);
+                async move {
+                    let (hits, next_page) = search.await;
+                    Some(json(SearchResults {
+                        hits,
+                        next_page: next_page.as_ref().map(format_cursor),
+                    }))
+                }
+                .boxed()
+            }
+        }
+    }
+
+    fn channel_info(
+        &self,
+        channel: &chat::ChannelName,
+    ) -> impl Future<Output = Option<ChannelInfo>> + Send + 'static {
+        // An empty page still says where the history starts and ends.
+        let page = self.channels.get(channel).map(|handle| {
+            handle.request(|reply| channel::Request::Read {
+                range: History::Before {
+                    count: 0,
+                    up_to_slot_number: None,
+                },
+                reply,
+            })
+        });
+        let channel = channel.clone();
+        let topic = self.topics.get(&channel).map(str::to_owned);
+        async move {
+            let page = page?.await.ok()?;
+            Some(ChannelInfo {
+                channel,
+                topic,
+                head_slot: page.head_slot,
+                history_start: page.history_start,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Sends `request` to [`serve`], answering whatever it asks the server
+    /// for with `{}`, and returns the response's status line and what it
+    /// asked for.
+    async fn ask(request: &str) -> (String, Option<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (request_tx, mut request_rx) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            let tokens =
+                HashMap::from([("dashboard".to_owned(), "token".to_owned())]);
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, &tokens, request_tx).await.unwrap();
+        });
+        let forwarded = tokio::spawn(async move {
+            let (request, response_tx) = request_rx.recv().await?;
+            let _ = response_tx.send(Some(serde_json::json!({})));
+            Some(request)
+        });
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap();
+        (
+            response.lines().next().unwrap_or_default().to_owned(),
+            forwarded.await.unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn needs_a_known_token() {
+        let (status, forwarded) = ask("GET /channels HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        assert!(forwarded.is_none());
+
+        let (status, _) = ask(
+            "GET /channels HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        let (status, forwarded) = ask(
+            "GET /channels HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(matches!(forwarded, Some(Request::ListChannels)));
+    }
+
+    #[tokio::test]
+    async fn decodes_channel_names() {
+        let (status, forwarded) = ask(
+            "GET /channels/rust%2Fhelp/entries?count=5 HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let Some(Request::Entries {
+            channel,
+            range: History::Before { count: 5, .. },
+        }) = forwarded
+        else {
+            panic!("expected a request for entries");
+        };
+        assert_eq!(channel, chat::ChannelName("rust/help".to_owned()));
+
+        let (status, forwarded) = ask(
+            "GET /channels/%ff HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert!(forwarded.is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_parameters_and_unknown_paths() {
+        let (status, _) = ask(
+            "GET /channels/general/entries?after=soon HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+
+        let (status, _) =
+            ask("GET /users HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n")
+                .await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = comms::SearchCursor {
+            timestamp: DateTime::parse_from_rfc3339("2025-01-02T03:04:05Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            slot_number: 7,
+            channel: chat::ChannelName("rust/help".to_owned()),
+        };
+        let formatted = format_cursor(&cursor);
+        assert_eq!(formatted, "2025-01-02T03:04:05Z/7/rust/help");
+        let parsed = parse_cursor(&formatted).unwrap();
+        assert_eq!(parsed.timestamp, cursor.timestamp);
+        assert_eq!(parsed.slot_number, 7);
+        assert_eq!(parsed.channel, cursor.channel);
+        assert!(parse_cursor("yesterday/7/general").is_none());
+    }
+}
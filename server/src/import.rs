@@ -0,0 +1,362 @@
+//! The `server import` subcommand, which converts history exported from
+//! another chat tool into stored channels. The server must not be running on
+//! the same data directory while importing.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+
+use crate::{chat_log::FakeChatLog, storage::LogStorage, Error};
+
+mod discord;
+mod slack;
+
+const USAGE: &str = "Usage: server import slack|discord <data directory> \
+                     <export path> [--dry-run] [--map-user <from>=<to>] \
+                     [--map-channel <from>=<to>]";
+
+fn usage_error(problem: &str) -> Error {
+    Error::Usage(format!("{}\n{}", problem, USAGE))
+}
+
+/// A message converted from an export, before it's given a slot.
+pub struct ImportedMessage {
+    pub channel: chat::ChannelName,
+    pub username: String,
+    pub timestamp: DateTime<Utc>,
+    pub content: chat::Content,
+}
+
+/// Something in an export that couldn't be converted, or only partly.
+pub struct Skipped {
+    /// Where in the export it is, e.g. a file and message index.
+    pub location: String,
+    pub reason: String,
+}
+
+#[derive(Default)]
+pub struct Conversion {
+    pub messages: Vec<ImportedMessage>,
+    pub skipped: Vec<Skipped>,
+    /// The export's user ids and the usernames they were converted to.
+    pub users: BTreeMap<String, String>,
+}
+
+impl Conversion {
+    fn skip(&mut self, location: &str, reason: String) {
+        self.skipped.push(Skipped {
+            location: location.to_owned(),
+            reason,
+        });
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let contents = fs::read(path).map_err(|error| {
+        Error::Io(io::Error::new(
+            error.kind(),
+            format!("{}: {}", path.display(), error),
+        ))
+    })?;
+    serde_json::from_slice(&contents).map_err(|error| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), error),
+        ))
+    })
+}
+
+/// The `.json` files directly in `directory`, in name order.
+fn json_files_in(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = vec![];
+    for directory_entry in fs::read_dir(directory).map_err(Error::Io)? {
+        let path = directory_entry.map_err(Error::Io)?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Replaces each `<...>` markup token in `text` with what `convert` returns
+/// for its inside, leaving it as is if that's `None`.
+fn replace_tokens(
+    text: &str,
+    mut convert: impl FnMut(&str) -> Option<String>,
+) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+        let token = &rest[start + 1..];
+        match token.find(['<', '>']) {
+            Some(end) if token.as_bytes()[end] == b'>' => {
+                match convert(&token[..end]) {
+                    Some(replacement) => output.push_str(&replacement),
+                    None => output.push_str(&rest[start..start + end + 2]),
+                }
+                rest = &token[end + 1..];
+            }
+            _ => {
+                output.push('<');
+                rest = token;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Appends a note about each attachment that wasn't imported, so readers of
+/// the imported message know something was there.
+fn note_attachments(
+    conversion: &mut Conversion,
+    location: &str,
+    text: &mut String,
+    file_names: impl IntoIterator<Item = String>,
+) {
+    for file_name in file_names {
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(&format!("_Attachment not imported: {}_", file_name));
+        conversion.skip(
+            location,
+            format!("attachment {} wasn't imported", file_name),
+        );
+    }
+}
+
+enum Source {
+    Slack,
+    Discord,
+}
+
+fn parse_mapping(
+    flag: &str,
+    value: Option<String>,
+) -> Result<(String, String), Error> {
+    value
+        .as_deref()
+        .and_then(|value| value.split_once('='))
+        .map(|(from, to)| (from.to_owned(), to.to_owned()))
+        .ok_or_else(|| usage_error(&format!("{} takes <from>=<to>", flag)))
+}
+
+/// Imports the export at `<export path>` into `<data directory>`, or with
+/// `--dry-run` just reports what would be imported. `args` follow the
+/// subcommand name.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    let source = match args.next().as_deref() {
+        Some("slack") => Source::Slack,
+        Some("discord") => Source::Discord,
+        Some(other) => {
+            return Err(usage_error(&format!("Unknown source {}", other)))
+        }
+        None => return Err(usage_error("Missing source")),
+    };
+    let data_directory = PathBuf::from(
+        args.next()
+            .ok_or_else(|| usage_error("Missing data directory"))?,
+    );
+    let export_path = PathBuf::from(
+        args.next()
+            .ok_or_else(|| usage_error("Missing export path"))?,
+    );
+
+    let mut dry_run = false;
+    let mut user_mapping = HashMap::new();
+    let mut channel_mapping = HashMap::new();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--dry-run" => dry_run = true,
+            "--map-user" => {
+                let (from, to) = parse_mapping("--map-user", args.next())?;
+                user_mapping.insert(from, to);
+            }
+            "--map-channel" => {
+                let (from, to) = parse_mapping("--map-channel", args.next())?;
+                channel_mapping.insert(
+                    from.trim_start_matches('#').to_owned(),
+                    to.trim_start_matches('#').to_owned(),
+                );
+            }
+            _ => return Err(usage_error(&format!("Unknown option {}", flag))),
+        }
+    }
+
+    let mut conversion = match source {
+        Source::Slack => slack::convert(&export_path)?,
+        Source::Discord => discord::convert(&export_path)?,
+    };
+    for username in conversion.users.values_mut() {
+        if let Some(mapped) = user_mapping.get(username) {
+            username.clone_from(mapped);
+        }
+    }
+
+    let mut channels = BTreeMap::<chat::ChannelName, Vec<_>>::new();
+    for mut message in conversion.messages {
+        if let Some(mapped) = user_mapping.get(&message.username) {
+            message.username.clone_from(mapped);
+        }
+        if let Some(mapped) = channel_mapping.get(&message.channel.0) {
+            message.channel = chat::ChannelName(mapped.clone());
+        }
+        channels
+            .entry(message.channel.clone())
+            .or_default()
+            .push(message);
+    }
+    for messages in channels.values_mut() {
+        messages.sort_by_key(|message| message.timestamp);
+    }
+
+    if dry_run {
+        for (channel, messages) in &channels {
+            println!(
+                "Would import {} message(s) into {}, from {} to {}",
+                messages.len(),
+                channel,
+                messages[0].timestamp,
+                messages[messages.len() - 1].timestamp
+            );
+        }
+        for (id, username) in &conversion.users {
+            println!("User {} becomes {}", id, username);
+        }
+    } else {
+        let mut log_storage = LogStorage::open(data_directory.join("channels"))
+            .map_err(Error::Io)?;
+        let mut chat_logs = log_storage.load().map_err(Error::Io)?;
+        for (channel, messages) in channels {
+            let chat_log = chat_logs
+                .entry(channel.clone())
+                .or_insert_with(|| FakeChatLog::new(channel.clone()));
+            let mut slots = None;
+            for message in messages {
+                let slot_number = chat_log.import(
+                    message.username,
+                    message.timestamp,
+                    message.content,
+                );
+                slots = Some((
+                    slots.map_or(slot_number, |(first, _)| first),
+                    slot_number,
+                ));
+            }
+            log_storage.rewrite(chat_log).map_err(Error::Io)?;
+            if let Some((first, last)) = slots {
+                println!(
+                    "Imported {} into slots {} through {}",
+                    channel, first, last
+                );
+            }
+        }
+    }
+
+    if !conversion.skipped.is_empty() {
+        println!("Could not convert {} item(s):", conversion.skipped.len());
+        for skipped in &conversion.skipped {
+            println!("  {}: {}", skipped.location, skipped.reason);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn replaces_only_complete_tokens() {
+        let upper =
+            |token: &str| (token != "keep").then(|| token.to_ascii_uppercase());
+        assert_eq!(replace_tokens("a <b> c", upper), "a B c");
+        assert_eq!(replace_tokens("<keep> <x>", upper), "<keep> X");
+        assert_eq!(replace_tokens("1 < 2 <y>", upper), "1 < 2 Y");
+        assert_eq!(replace_tokens("<<z>", upper), "<Z");
+        assert_eq!(replace_tokens("open <", upper), "open <");
+    }
+
+    #[test]
+    fn imports_with_mappings() {
+        let directory = tempfile::tempdir().unwrap();
+        let export_path = directory.path().join("export.json");
+        fs::write(
+            &export_path,
+            r#"{
+                "channel": {"id": "2", "name": "general"},
+                "messages": [
+                    {"type": "Default", "timestamp": "2024-01-01T00:01:00Z",
+                     "content": "second", "author": {"id": "1", "name": "eu"}},
+                    {"type": "Default", "timestamp": "2024-01-01T00:00:00Z",
+                     "content": "first", "author": {"id": "1", "name": "eu"}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let data_directory = directory.path().join("data");
+        let import = |extra: &[&str]| {
+            let mut all = vec![
+                "discord",
+                data_directory.to_str().unwrap(),
+                export_path.to_str().unwrap(),
+            ];
+            all.extend(extra);
+            run(args(&all))
+        };
+
+        import(&["--dry-run"]).unwrap();
+        assert!(!data_directory.exists());
+
+        import(&["--map-user", "eu=ethan", "--map-channel", "#general=old"])
+            .unwrap();
+        let chat_logs = LogStorage::open(data_directory.join("channels"))
+            .unwrap()
+            .load()
+            .unwrap();
+        let chat_log = &chat_logs[&chat::ChannelName("old".to_owned())];
+        let entries = chat_log.entries(10, None);
+        // Sorted by when they were posted.
+        assert_eq!(entries[0].text_content(), Some("first"));
+        assert_eq!(entries[1].text_content(), Some("second"));
+        assert!(entries
+            .iter()
+            .all(|entry| entry.metadata.username == "ethan"));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        for bad_args in [
+            &[][..],
+            &["teams", "data", "export"],
+            &["slack", "data"],
+            &["slack", "data", "export", "--map-user", "ethan"],
+            &["slack", "data", "export", "--force"],
+        ] {
+            assert!(
+                matches!(run(args(bad_args)), Err(Error::Usage(_))),
+                "{:?} should be rejected",
+                bad_args
+            );
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Discord channel exports in DiscordChatExporter's JSON format: one file per
+//! channel with the channel and its messages.
+
+use std::{collections::HashMap, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::{
+    json_files_in, note_attachments, read_json, replace_tokens, Conversion,
+    ImportedMessage,
+};
+use crate::Error;
+
+#[derive(Deserialize)]
+struct Export {
+    channel: Channel,
+    messages: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Channel {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Attachment {
+    file_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Message {
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: DateTime<Utc>,
+    timestamp_edited: Option<DateTime<Utc>>,
+    #[serde(default)]
+    content: String,
+    author: User,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    #[serde(default)]
+    embeds: Vec<serde_json::Value>,
+    #[serde(default)]
+    stickers: Vec<serde_json::Value>,
+    #[serde(default)]
+    mentions: Vec<User>,
+}
+
+/// Converts Discord's raw `<...>` markup for mentions, channels, and custom
+/// emoji into text.
+fn convert_text(
+    text: &str,
+    usernames: &HashMap<String, String>,
+    channel_names: &HashMap<String, String>,
+) -> String {
+    replace_tokens(text, |token| {
+        if let Some(user_id) = token.strip_prefix('@') {
+            let user_id = user_id.trim_start_matches('!');
+            return usernames
+                .get(user_id)
+                .map(|username| format!("@{}", username));
+        }
+        if let Some(channel_id) = token.strip_prefix('#') {
+            return channel_names
+                .get(channel_id)
+                .map(|channel_name| format!("#{}", channel_name));
+        }
+        // Custom emoji are `<:name:id>`, or `<a:name:id>` if animated.
+        let emoji = token.strip_prefix("a:").or(token.strip_prefix(':'))?;
+        let (name, _) = emoji.split_once(':')?;
+        Some(format!(":{}:", name))
+    })
+}
+
+/// Reads the export file at `path`, or every export file in it if it's a
+/// directory.
+pub fn convert(path: &Path) -> Result<Conversion, Error> {
+    let paths = if path.is_dir() {
+        json_files_in(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let exports = paths
+        .iter()
+        .map(|path| read_json::<Export>(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let channel_names = exports
+        .iter()
+        .map(|export| (export.channel.id.clone(), export.channel.name.clone()))
+        .collect::<HashMap<_, _>>();
+
+    let mut conversion = Conversion::default();
+    for (path, export) in paths.iter().zip(exports) {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        for (index, value) in export.messages.into_iter().enumerate() {
+            let location = format!("{} message {}", file_name, index);
+            let message = match serde_json::from_value::<Message>(value) {
+                Ok(message) => message,
+                Err(error) => {
+                    conversion.skip(&location, error.to_string());
+                    continue;
+                }
+            };
+            if !matches!(message.kind.as_str(), "Default" | "Reply") {
+                conversion.skip(
+                    &location,
+                    format!("{} events aren't imported", message.kind),
+                );
+                continue;
+            }
+
+            conversion
+                .users
+                .insert(message.author.id.clone(), message.author.name.clone());
+            let usernames = message
+                .mentions
+                .into_iter()
+                .map(|user| (user.id, user.name))
+                .collect::<HashMap<_, _>>();
+            let mut text =
+                convert_text(&message.content, &usernames, &channel_names);
+            if !message.embeds.is_empty() {
+                conversion.skip(
+                    &location,
+                    format!(
+                        "{} embed(s) weren't imported",
+                        message.embeds.len()
+                    ),
+                );
+            }
+            if !message.stickers.is_empty() {
+                conversion.skip(
+                    &location,
+                    format!(
+                        "{} sticker(s) weren't imported",
+                        message.stickers.len()
+                    ),
+                );
+            }
+            note_attachments(
+                &mut conversion,
+                &location,
+                &mut text,
+                message
+                    .attachments
+                    .into_iter()
+                    .map(|attachment| attachment.file_name),
+            );
+            if text.trim().is_empty() {
+                conversion.skip(&location, "message is empty".to_owned());
+                continue;
+            }
+            let text = chat::MessageText(text);
+            conversion.messages.push(ImportedMessage {
+                channel: chat::ChannelName(export.channel.name.clone()),
+                username: message.author.name,
+                timestamp: message.timestamp,
+                content: match message.timestamp_edited {
+                    Some(_) => chat::Content::Edited(text),
+                    None => chat::Content::Original(text),
+                },
+            });
+        }
+    }
+    Ok(conversion)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn converts_markup() {
+        let usernames = HashMap::from([("1".to_owned(), "ethan".to_owned())]);
+        let channel_names =
+            HashMap::from([("2".to_owned(), "general".to_owned())]);
+        assert_eq!(
+            convert_text(
+                "<@1> <@!1> in <#2> <:party:3> <a:wave:4> <@5> <b>",
+                &usernames,
+                &channel_names
+            ),
+            "@ethan @ethan in #general :party: :wave: <@5> <b>"
+        );
+    }
+
+    #[test]
+    fn converts_an_export() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("general.json");
+        fs::write(
+            &path,
+            r#"{
+                "channel": {"id": "2", "name": "general"},
+                "messages": [
+                    {"type": "Default", "timestamp": "2024-01-01T00:00:00Z",
+                     "content": "hi <@1>", "author": {"id": "1", "name": "ethan"},
+                     "mentions": [{"id": "1", "name": "ethan"}]},
+                    {"type": "ChannelPinnedMessage", "timestamp": "2024-01-01T00:01:00Z",
+                     "content": "", "author": {"id": "1", "name": "ethan"}},
+                    {"type": "Reply", "timestamp": "2024-01-01T00:02:00Z",
+                     "timestampEdited": "2024-01-01T00:03:00Z",
+                     "content": "fixed", "author": {"id": "6", "name": "alice"},
+                     "stickers": [{}]},
+                    {"type": "Default", "timestamp": "2024-01-01T00:04:00Z",
+                     "content": "", "author": {"id": "1", "name": "ethan"},
+                     "embeds": [{}]},
+                    {"type": "Default"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let conversion = convert(&path).unwrap();
+        let messages = conversion
+            .messages
+            .iter()
+            .map(|message| (message.username.as_str(), message.content.clone()))
+            .collect::<Vec<_>>();
+        let text = |text: &str| chat::MessageText(text.to_owned());
+        assert_eq!(
+            messages,
+            [
+                ("ethan", chat::Content::Original(text("hi @ethan"))),
+                ("alice", chat::Content::Edited(text("fixed"))),
+            ]
+        );
+        assert_eq!(conversion.users.len(), 2);
+        // The pin, the sticker, the embed, the then-empty message, and the
+        // malformed one.
+        assert_eq!(conversion.skipped.len(), 5);
+
+        // A directory of exports reads each of them.
+        assert_eq!(
+            convert(directory.path()).unwrap().messages.len(),
+            messages.len()
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Slack workspace exports: a directory with `users.json`, `channels.json`,
+//! and a directory per channel of `<date>.json` files of messages.
+
+use std::{collections::HashMap, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::{
+    json_files_in, note_attachments, read_json, replace_tokens, Conversion,
+    ImportedMessage,
+};
+use crate::Error;
+
+#[derive(Deserialize)]
+struct User {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Channel {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct File {
+    name: Option<String>,
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    subtype: Option<String>,
+    user: Option<String>,
+    /// Set instead of `user` on messages from bots.
+    username: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    edited: Option<serde_json::Value>,
+    #[serde(default)]
+    files: Vec<File>,
+}
+
+/// Message subtypes that are something someone said. The rest are events
+/// like joining a channel.
+const CONVERTED_SUBTYPES: [&str; 5] = [
+    "bot_message",
+    "file_share",
+    "me_message",
+    "thread_broadcast",
+    "reply_broadcast",
+];
+
+/// Slack timestamps are seconds since the epoch with a fractional part, e.g.
+/// `1512085950.000216`.
+fn parse_ts(ts: &str) -> Option<DateTime<Utc>> {
+    let (seconds, fraction) = ts.split_once('.').unwrap_or((ts, "0"));
+    let nanoseconds = format!("{:0<9}", fraction).get(..9)?.parse().ok()?;
+    DateTime::from_timestamp(seconds.parse().ok()?, nanoseconds)
+}
+
+/// Converts Slack's `<...>` markup for mentions, channels, and links into
+/// markdown, and unescapes what Slack escapes.
+fn convert_text(
+    text: &str,
+    usernames: &HashMap<String, String>,
+    channel_names: &HashMap<String, String>,
+) -> String {
+    let text = replace_tokens(text, |token| {
+        let (target, label) = match token.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (token, None),
+        };
+        if let Some(user_id) = target.strip_prefix('@') {
+            let username = usernames
+                .get(user_id)
+                .map(String::as_str)
+                .or(label)
+                .unwrap_or(user_id);
+            Some(format!("@{}", username))
+        } else if let Some(channel_id) = target.strip_prefix('#') {
+            let channel_name = channel_names
+                .get(channel_id)
+                .map(String::as_str)
+                .or(label)
+                .unwrap_or(channel_id);
+            Some(format!("#{}", channel_name))
+        } else if let Some(special) = target.strip_prefix('!') {
+            Some(label.map_or_else(|| format!("@{}", special), str::to_owned))
+        } else {
+            match label {
+                Some(label) => Some(format!("[{}]({})", label, target)),
+                None => Some(target.to_owned()),
+            }
+        }
+    });
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Reads the workspace export in `directory`. Direct messages aren't
+/// imported, since nerdtalk only has channels.
+pub fn convert(directory: &Path) -> Result<Conversion, Error> {
+    let mut conversion = Conversion::default();
+    let users: Vec<User> = read_json(&directory.join("users.json"))?;
+    let usernames = users
+        .into_iter()
+        .map(|user| (user.id, user.name))
+        .collect::<HashMap<_, _>>();
+
+    let mut channels: Vec<Channel> =
+        read_json(&directory.join("channels.json"))?;
+    // Private channels, if the export includes them.
+    let groups_path = directory.join("groups.json");
+    if groups_path.exists() {
+        channels.extend(read_json::<Vec<Channel>>(&groups_path)?);
+    }
+    for file_name in ["dms.json", "mpims.json"] {
+        if directory.join(file_name).exists() {
+            conversion
+                .skip(file_name, "direct messages aren't imported".to_owned());
+        }
+    }
+    let channel_names = channels
+        .iter()
+        .map(|channel| (channel.id.clone(), channel.name.clone()))
+        .collect::<HashMap<_, _>>();
+
+    for channel in &channels {
+        let channel_directory = directory.join(&channel.name);
+        // Channels nobody posted in have no directory.
+        if !channel_directory.is_dir() {
+            continue;
+        }
+        for path in json_files_in(&channel_directory)? {
+            let file_location = format!(
+                "{}/{}",
+                channel.name,
+                path.file_name().unwrap_or_default().to_string_lossy()
+            );
+            let values: Vec<serde_json::Value> = read_json(&path)?;
+            for (index, value) in values.into_iter().enumerate() {
+                let location = format!("{} message {}", file_location, index);
+                let message = match serde_json::from_value::<Message>(value) {
+                    Ok(message) => message,
+                    Err(error) => {
+                        conversion.skip(&location, error.to_string());
+                        continue;
+                    }
+                };
+                if let Some(subtype) = &message.subtype {
+                    if !CONVERTED_SUBTYPES.contains(&subtype.as_str()) {
+                        conversion.skip(
+                            &location,
+                            format!("{} events aren't imported", subtype),
+                        );
+                        continue;
+                    }
+                }
+                let Some(timestamp) = parse_ts(&message.ts) else {
+                    conversion.skip(
+                        &location,
+                        format!("invalid timestamp {:?}", message.ts),
+                    );
+                    continue;
+                };
+                let username = match (&message.user, &message.username) {
+                    (Some(user_id), _) => match usernames.get(user_id) {
+                        Some(username) => {
+                            conversion
+                                .users
+                                .insert(user_id.clone(), username.clone());
+                            username.clone()
+                        }
+                        None => {
+                            conversion.skip(
+                                &location,
+                                format!(
+                                    "unknown user {}, imported under their id",
+                                    user_id
+                                ),
+                            );
+                            user_id.clone()
+                        }
+                    },
+                    (None, Some(username)) => username.clone(),
+                    (None, None) => {
+                        conversion.skip(
+                            &location,
+                            "message has no author".to_owned(),
+                        );
+                        continue;
+                    }
+                };
+
+                let mut text =
+                    convert_text(&message.text, &usernames, &channel_names);
+                if message.subtype.as_deref() == Some("me_message") {
+                    text = format!("_{} {}_", username, text);
+                }
+                note_attachments(
+                    &mut conversion,
+                    &location,
+                    &mut text,
+                    message.files.into_iter().map(|file| {
+                        file.name
+                            .or(file.title)
+                            .unwrap_or_else(|| "unnamed file".to_owned())
+                    }),
+                );
+                if text.trim().is_empty() {
+                    conversion.skip(&location, "message is empty".to_owned());
+                    continue;
+                }
+                let text = chat::MessageText(text);
+                conversion.messages.push(ImportedMessage {
+                    channel: chat::ChannelName(channel.name.clone()),
+                    username,
+                    timestamp,
+                    content: match message.edited {
+                        Some(_) => chat::Content::Edited(text),
+                        None => chat::Content::Original(text),
+                    },
+                });
+            }
+        }
+    }
+    Ok(conversion)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        let timestamp = parse_ts("1512085950.000216").unwrap();
+        assert_eq!(timestamp.timestamp(), 1512085950);
+        assert_eq!(timestamp.timestamp_subsec_micros(), 216);
+        assert_eq!(parse_ts("1512085950").unwrap().timestamp(), 1512085950);
+        assert!(parse_ts("yesterday").is_none());
+    }
+
+    #[test]
+    fn converts_markup() {
+        let usernames = HashMap::from([("U1".to_owned(), "ethan".to_owned())]);
+        let channel_names =
+            HashMap::from([("C1".to_owned(), "general".to_owned())]);
+        assert_eq!(
+            convert_text(
+                "<@U1> see <#C1> and <#C2|random>, <!here>",
+                &usernames,
+                &channel_names
+            ),
+            "@ethan see #general and #random, @here"
+        );
+        assert_eq!(
+            convert_text(
+                "<https://example.com|docs> &lt;3 &amp; <https://x.org>",
+                &usernames,
+                &channel_names
+            ),
+            "[docs](https://example.com) <3 & https://x.org"
+        );
+    }
+
+    #[test]
+    fn converts_a_workspace() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path();
+        fs::write(
+            path.join("users.json"),
+            r#"[{"id": "U1", "name": "ethan"}]"#,
+        )
+        .unwrap();
+        fs::write(
+            path.join("channels.json"),
+            r#"[{"id": "C1", "name": "general"}, {"id": "C2", "name": "quiet"}]"#,
+        )
+        .unwrap();
+        fs::write(path.join("dms.json"), "[]").unwrap();
+        fs::create_dir(path.join("general")).unwrap();
+        fs::write(
+            path.join("general").join("2017-12-01.json"),
+            r#"[
+                {"user": "U1", "text": "hi <#C1>", "ts": "1512085950.000216"},
+                {"subtype": "channel_join", "user": "U1", "text": "", "ts": "1512085951.0"},
+                {"user": "U1", "text": "fixed", "ts": "1512085952.0", "edited": {}},
+                {"user": "U9", "text": "who", "ts": "1512085953.0"},
+                {"subtype": "bot_message", "username": "bot", "text": "", "ts": "1512085954.0",
+                 "files": [{"name": "cat.png"}]},
+                {"text": "anonymous", "ts": "1512085955.0"}
+            ]"#,
+        )
+        .unwrap();
+
+        let conversion = convert(path).unwrap();
+        let messages = conversion
+            .messages
+            .iter()
+            .map(|message| (message.username.as_str(), message.content.clone()))
+            .collect::<Vec<_>>();
+        let text = |text: &str| chat::MessageText(text.to_owned());
+        assert_eq!(
+            messages,
+            [
+                ("ethan", chat::Content::Original(text("hi #general"))),
+                ("ethan", chat::Content::Edited(text("fixed"))),
+                ("U9", chat::Content::Original(text("who"))),
+                (
+                    "bot",
+                    chat::Content::Original(text(
+                        "_Attachment not imported: cat.png_"
+                    ))
+                ),
+            ]
+        );
+        assert!(conversion.messages.iter().all(|message| message.channel
+            == chat::ChannelName("general".to_owned())));
+        assert_eq!(conversion.users.get("U1").unwrap(), "ethan");
+        // The direct messages, the join, the unknown user, the attachment,
+        // and the message with no author.
+        assert_eq!(conversion.skipped.len(), 5);
+    }
+}
@@ -0,0 +1,189 @@
+//! Structured logging with `tracing`. The `LOG` environment variable sets the
+//! filter, e.g. `info` or `info,server=debug`, and can be replaced while the
+//! server runs. `LOG_FORMAT=json` logs JSON lines instead of text, and
+//! `LOG_BODIES=1` includes chat contents in logged messages, which are
+//! otherwise redacted.
+
+use std::{
+    env,
+    io::{self, IsTerminal},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use serde::Serialize;
+use tracing_subscriber::{
+    filter::ParseError, fmt, layer::SubscriberExt, reload,
+    util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+/// Fields whose values are chat contents or otherwise up to users.
+const REDACTED_FIELDS: [&str; 4] = ["content", "text", "topic", "bytes"];
+
+/// Fields that are redacted even with `LOG_BODIES=1`.
+const SECRET_FIELDS: [&str; 1] = ["password"];
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+static SHOW_BODIES: AtomicBool = AtomicBool::new(false);
+
+/// Logs according to the environment until [`set_filter`] replaces the
+/// filter.
+pub fn init() {
+    let filter = EnvFilter::try_from_env("LOG")
+        .unwrap_or_else(|_| EnvFilter::new("error"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let json = env::var("LOG_FORMAT").is_ok_and(|format| format == "json");
+    SHOW_BODIES.store(
+        env::var("LOG_BODIES").is_ok_and(|bodies| bodies == "1"),
+        Ordering::Relaxed,
+    );
+
+    let registry = tracing_subscriber::registry().with(filter);
+    let layer = fmt::layer().with_writer(io::stderr);
+    let result = if json {
+        registry
+            .with(layer.json().with_current_span(false))
+            .try_init()
+    } else {
+        registry
+            .with(layer.with_ansi(io::stderr().is_terminal()))
+            .try_init()
+    };
+    if result.is_ok() {
+        let _ = FILTER.set(handle);
+    }
+}
+
+/// Replaces the filter with `filter`, which uses the same syntax as `LOG`.
+pub fn set_filter(filter: &str) -> Result<(), ParseError> {
+    let filter = EnvFilter::try_new(filter)?;
+    if let Some(handle) = FILTER.get() {
+        let _ = handle.reload(filter);
+    }
+    Ok(())
+}
+
+/// The variant name of `message`, e.g. `Post`.
+pub fn kind(message: &impl Serialize) -> String {
+    match serde_json::to_value(message) {
+        Ok(serde_json::Value::String(variant)) => variant,
+        Ok(serde_json::Value::Object(fields)) if fields.len() == 1 => {
+            fields.keys().next().cloned().unwrap_or_default()
+        }
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// `message` as JSON for logging, with chat contents replaced by
+/// `"<redacted>"` unless `LOG_BODIES=1`, and passwords always replaced.
+pub fn body(message: &impl Serialize) -> String {
+    let Ok(mut value) = serde_json::to_value(message) else {
+        return "<unserializable>".to_owned();
+    };
+    redact(&mut value, SHOW_BODIES.load(Ordering::Relaxed));
+    value.to_string()
+}
+
+fn redact(value: &mut serde_json::Value, show_bodies: bool) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                let is_redacted = SECRET_FIELDS.contains(&key.as_str())
+                    || (!show_bodies
+                        && REDACTED_FIELDS.contains(&key.as_str()));
+                if is_redacted && !field.is_null() {
+                    *field = serde_json::Value::from("<redacted>");
+                } else {
+                    redact(field, show_bodies);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(|item| redact(item, show_bodies))
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_message_kinds() {
+        assert_eq!(kind(&comms::admin::Request::Stats), "Stats");
+        assert_eq!(
+            kind(&comms::ClientMessage::LogIn {
+                username: "ethan".to_owned(),
+                password: None,
+            }),
+            "LogIn"
+        );
+        assert_eq!(kind(&42), "unknown");
+    }
+
+    #[test]
+    fn redacts_chat_contents() {
+        let post = comms::ClientMessage::Post {
+            nonce: comms::Nonce::new_unique(),
+            channel: chat::ChannelName::default(),
+            content: "my password is hunter2".to_owned(),
+            attachments: vec![],
+        };
+        let logged = body(&post);
+        assert!(!logged.contains("hunter2"));
+        assert!(logged.contains("\"content\":\"<redacted>\""));
+
+        let entry =
+            comms::ServerMessage::NewEntry(chat::Entry::new_timestamped_now(
+                chat::ChannelName::default(),
+                3,
+                "ethan".to_owned(),
+                chat::Content::Edited(chat::MessageText("secret".to_owned())),
+            ));
+        let logged = body(&entry);
+        assert!(!logged.contains("secret"));
+        // Who and where are kept.
+        assert!(logged.contains("ethan"));
+        assert!(logged.contains("\"slot_number\":3"));
+    }
+
+    #[test]
+    fn redacts_admin_broadcasts() {
+        let broadcast = comms::admin::Request::Broadcast {
+            text: "the new admin password is hunter2".to_owned(),
+        };
+        assert!(!body(&broadcast).contains("hunter2"));
+    }
+
+    #[test]
+    fn redacts_nested_values_but_not_nulls() {
+        let mut value = serde_json::json!({
+            "topic": null,
+            "items": [{"text": "a"}, {"bytes": [1, 2, 3], "size": 3}],
+        });
+        redact(&mut value, false);
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "topic": null,
+                "items": [{"text": "<redacted>"}, {"bytes": "<redacted>", "size": 3}],
+            })
+        );
+    }
+
+    #[test]
+    fn always_redacts_passwords() {
+        let log_in = comms::ClientMessage::LogIn {
+            username: "ethan".to_owned(),
+            password: Some("hunter2".to_owned()),
+        };
+        let mut value = serde_json::to_value(&log_in).unwrap();
+        redact(&mut value, true);
+        assert!(!value.to_string().contains("hunter2"));
+        assert!(value.to_string().contains("ethan"));
+    }
+}
@@ -1,16 +1,32 @@
 use std::{
-    cmp,
     collections::HashMap,
     env, error,
     fmt::{self},
+    fs,
+    future::Future,
     io, net,
-    sync::Arc,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use comms::Codable;
-use futures_util::{SinkExt, StreamExt};
+use attachments::AttachmentStore;
+use audit::AuditLog;
+use comms::{admin::SessionId, Codable};
+use config::Config;
+use metrics::METRICS;
+use moderation::Moderation;
+use read_positions::ReadPositions;
+use roles::Roles;
+use state::ServerState;
+use storage::LogStorage;
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener},
     sync::{mpsc, RwLock},
     task::JoinHandle,
 };
@@ -21,52 +37,50 @@ use tokio_rustls::{
     },
     TlsAcceptor,
 };
-use tokio_tungstenite::tungstenite::Message;
-
-#[derive(Default)]
-struct FakeChatLog {
-    lmao: Vec<chat::Entry>,
-}
-
-impl FakeChatLog {
-    fn post(&mut self, username: String, content: String) -> chat::Entry {
-        let entry = chat::Entry::new_timestamped_now(
-            self.lmao.len(),
-            username,
-            chat::Content::Original(chat::MessageText(content)),
-        );
-        self.lmao.push(entry.clone());
-        entry
-    }
-
-    fn entries(
-        &self,
-        count: usize,
-        last_slot: Option<usize>,
-    ) -> Vec<chat::Entry> {
-        let Some(last_entry) = self.lmao.last() else {
-            return vec![];
-        };
-        let last_slot = last_slot.unwrap_or(last_entry.slot_number);
-
-        let last_index = self
-            .lmao
-            .iter()
-            .enumerate()
-            .rfind(|(_, entry)| entry.slot_number == last_slot)
-            .expect("slot missing todo don't crash server on this lol")
-            .0;
-        let count = cmp::min(count, last_index + 1);
-
-        // needs to +1 before -count
-        self.lmao[last_index + 1 - count..last_index + 1].to_owned()
-    }
-}
+use topics::Topics;
+use tracing::Instrument;
+use transport::{Bytes, CloseCode, CloseFrame, Frame};
+use webhooks::Webhooks;
+
+mod admin;
+mod attachments;
+mod audit;
+mod broadcast;
+mod channel;
+mod chat_log;
+mod commands;
+mod config;
+mod export;
+mod federation;
+mod http;
+mod http_api;
+mod import;
+mod logging;
+mod metrics;
+mod moderation;
+mod peer;
+mod read_positions;
+mod recent_posts;
+mod replication;
+mod roles;
+mod search;
+mod state;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod topics;
+mod webhooks;
+
+/// How often every channel's retention policy is applied, for entries that
+/// age out without anything new being posted.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 enum Error {
     Tls(tokio_rustls::rustls::Error),
     Io(io::Error),
+    Transport(transport::Error),
+    Usage(String),
 }
 
 impl fmt::Display for Error {
@@ -74,6 +88,8 @@ impl fmt::Display for Error {
         match self {
             Error::Tls(error) => error.fmt(f),
             Error::Io(error) => error.fmt(f),
+            Error::Transport(error) => error.fmt(f),
+            Error::Usage(message) => message.fmt(f),
         }
     }
 }
@@ -82,100 +98,399 @@ impl error::Error for Error {}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let _ = env_logger::try_init_from_env("LOG");
+    logging::init();
 
-    let certificate = if cfg!(feature = "local") {
-        CertificateDer::from_pem_file("testing_cert/cert.pem").expect(
-            "Did you remember to run ./scripts/gen_cert.sh for local testing?",
-        )
-    } else {
-        todo!("Ask Peter for midcode certificate")
-    };
-
-    let private_key = if cfg!(feature = "local") {
-        PrivateKeyDer::from_pem_file("testing_cert/key.pem").expect(
-            "Did you remember to run ./scripts/gen_cert.sh for local testing?",
-        )
-    } else {
-        todo!("Ask Peter for midcode private key")
-    };
-
-    let tls_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(vec![certificate], private_key)
-        .map_err(Error::Tls)?;
+    match env::args().nth(1).as_deref() {
+        Some("export") => return export::run(env::args().skip(2)),
+        Some("import") => return import::run(env::args().skip(2)),
+        Some("audit") => return audit::run(env::args().skip(2)),
+        _ => {}
+    }
 
-    let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let tls_config = load_tls_config()?;
 
     let address = env::args()
         .nth(1)
         .expect("Server takes the address:port it should listen to as a command-line argument");
 
-    let listener = TcpListener::bind(&address).await.map_err(Error::Io)?;
-    log::info!("Listening on {}", address);
+    let data_directory = PathBuf::from(
+        env::args()
+            .nth(2)
+            .unwrap_or_else(|| "nerdtalk_data".to_owned()),
+    );
+    let config_path = data_directory.join("config.json");
+    let config = Config::load(&config_path).map_err(Error::Io)?;
+    let attachment_store =
+        AttachmentStore::open(data_directory.join("attachments"))
+            .map_err(Error::Io)?;
+    let read_positions =
+        ReadPositions::open(data_directory.join("read_positions.json"))
+            .map_err(Error::Io)?;
+    let topics =
+        Topics::open(data_directory.join("topics.json")).map_err(Error::Io)?;
+    let roles =
+        Roles::open(data_directory.join("roles.json")).map_err(Error::Io)?;
 
     let (message_tx, mut message_rx) = mpsc::unbounded_channel();
-    let sessions = Arc::new(RwLock::new(HashMap::new()));
-
-    let mut fake_chat_log = FakeChatLog::default();
-
-    let sessions_for_thread = sessions.clone();
-    tokio::spawn(async move {
-        // TODO: thread pool
-        while let Ok((tcp_stream, _)) = listener.accept().await {
-            match tcp_stream.peer_addr() {
-                Ok(client_address) => match new_client_connection(
-                    tcp_stream,
-                    client_address,
-                    &tls_acceptor,
-                    &message_tx,
+    let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+    let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+    let moderation = Arc::new(RwLock::new(
+        Moderation::open(data_directory.join("moderation.json"))
+            .map_err(Error::Io)?,
+    ));
+
+    let mut log_storage =
+        LogStorage::open(data_directory.join("channels")).map_err(Error::Io)?;
+    let chat_logs = log_storage.load().map_err(Error::Io)?;
+
+    let audit_log = Arc::new(
+        AuditLog::open(data_directory.join("audit.jsonl"))
+            .map_err(Error::Io)?,
+    );
+
+    let webhooks =
+        Webhooks::start(config.webhooks.clone()).map_err(|(name, error)| {
+            Error::Usage(format!(
+                "Webhook {} in config.json is invalid: {}",
+                name, error
+            ))
+        })?;
+
+    // Each channel runs in its own task, so posts to different channels are
+    // stored and broadcast in parallel. Channels hand attachments they no
+    // longer need back here, since only the server knows whether another
+    // channel still needs them.
+    let (released_tx, mut released_rx) = mpsc::unbounded_channel();
+    let channels = chat_logs
+        .into_iter()
+        .map(|(channel, mut chat_log)| {
+            chat_log.set_server(config.federation.name.clone());
+            let handle = channel::spawn(
+                chat_log,
+                log_storage.split_off(&channel),
+                config.retention_for(&channel).clone(),
+                broadcast::Subscribers::default(),
+                channel::Streams {
+                    webhooks: webhooks.entries_tx(),
+                    ..Default::default()
+                },
+                audit_log.clone(),
+                released_tx.clone(),
+            );
+            (channel, handle)
+        })
+        .collect();
+
+    let metrics_address = match &config.metrics_address {
+        Some(metrics_address) => *metrics_address,
+        None => metrics::DEFAULT_ADDRESS
+            .parse()
+            .expect("default metrics address is valid"),
+    };
+    // The server is still useful without metrics, e.g. when another server on
+    // the same machine has the port.
+    if let Err(error) = metrics::listen(metrics_address, sessions.clone()).await
+    {
+        tracing::error!(
+            "Failed to serve metrics on {}: {}",
+            metrics_address,
+            error
+        );
+    }
+
+    let replication = config.replication.clone();
+    let secret = || {
+        replication.secret.clone().ok_or_else(|| {
+            Error::Usage("Replication needs a secret in config.json".to_owned())
+        })
+    };
+    let (follower_tx, mut follower_rx) = mpsc::unbounded_channel();
+    if let Some(listen_address) = replication.listen_address {
+        replication::listen(listen_address, secret()?, follower_tx)
+            .await
+            .map_err(Error::Io)?;
+    }
+    let (follower_event_tx, mut follower_event_rx) = mpsc::unbounded_channel();
+    let leader = match replication.leader_address {
+        Some(leader_address) => Some((
+            leader_address,
+            replication::follow(leader_address, secret()?, follower_event_tx),
+        )),
+        None => None,
+    };
+
+    // Peers are connected to in both directions: each server receives what's
+    // posted on a peer over its own connection to that peer.
+    let federation = config.federation.clone();
+    let (peer_tx, mut peer_rx) = mpsc::unbounded_channel();
+    let (peer_event_tx, mut peer_event_rx) = mpsc::unbounded_channel();
+    if federation.listen_address.is_some() || !federation.peers.is_empty() {
+        let name = federation.name.ok_or_else(|| {
+            Error::Usage("Federation needs a name in config.json".to_owned())
+        })?;
+        if let Some(listen_address) = federation.listen_address {
+            federation::listen(
+                listen_address,
+                federation.peers.clone(),
+                peer_tx,
+            )
+            .await
+            .map_err(Error::Io)?;
+        }
+        for (server, peer) in federation.peers {
+            federation::connect(
+                name.clone(),
+                server,
+                peer,
+                peer_event_tx.clone(),
+            );
+        }
+    }
+
+    // Every transport starts sessions like any other, so the rest of the
+    // server doesn't know which one a client used.
+    let acceptor = Acceptor {
+        next_session_id: Arc::new(AtomicU64::new(0)),
+        sessions: sessions.clone(),
+        message_tx,
+        closed_tx,
+        moderation: moderation.clone(),
+        audit_log: audit_log.clone(),
+    };
+
+    match tls_config {
+        Some(tls_config) => {
+            let listener =
+                TcpListener::bind(&address).await.map_err(Error::Io)?;
+            tracing::info!("Listening on {}", address);
+            if let Some(quic_address) = config.quic_address {
+                let quic_listener = transport::quic::Listener::bind(
+                    quic_address,
+                    tls_config.clone(),
                 )
-                .await
-                {
-                    Ok(session) => {
-                        sessions_for_thread
-                            .write()
-                            .await
-                            .insert(client_address, session);
+                .map_err(Error::Transport)?;
+                tracing::info!("Listening for QUIC on {}", quic_address);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    while let Some(incoming) = quic_listener.accept().await {
+                        let acceptor = acceptor.clone();
+                        // The handshake waits for the client to send
+                        // something, so it can't hold up other clients.
+                        tokio::spawn(async move {
+                            acceptor
+                                .start_session(
+                                    Origin::Remote(incoming.remote_address()),
+                                    accept_quic(incoming),
+                                )
+                                .await;
+                        });
                     }
-                    Err(error) => {
-                        log::error!(
-                            "Failed to establish session with client address {}: {}",
-                            client_address,
-                            error
-                        );
-                    }
-                },
-                Err(error) => {
-                    log::warn!(
-                        "Failed to extract client address from TCP stream: {}",
-                        error
-                    );
+                });
+            }
+
+            let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                while let Ok((tcp_stream, client_address)) =
+                    listener.accept().await
+                {
+                    let acceptor = acceptor.clone();
+                    let tls_acceptor = tls_acceptor.clone();
+                    // Like QUIC's, so a slow handshake can't hold up other
+                    // clients.
+                    tokio::spawn(async move {
+                        acceptor
+                            .start_session(
+                                Origin::Remote(client_address),
+                                accept_tls_websocket(tcp_stream, &tls_acceptor),
+                            )
+                            .await;
+                    });
                 }
+            });
+        }
+        None => {
+            if config.quic_address.is_some() {
+                return Err(Error::Usage(
+                    "QUIC needs a certificate: run ./scripts/gen_cert.sh"
+                        .to_owned(),
+                ));
             }
+            tracing::warn!(
+                "Not listening on {} without a certificate, so only plain connections are accepted: run ./scripts/gen_cert.sh for wss://",
+                address
+            );
         }
-    });
-
-    while let Some((sender, message)) = message_rx.recv().await {
-        log::info!("Processing message: {:?}", message);
-        match message {
-            comms::ClientMessage::Post { username, content } => {
-                let entry = fake_chat_log.post(username, content);
-                for (_, session) in sessions.read().await.iter() {
-                    session.send(comms::ServerMessage::NewEntry(entry.clone()));
-                }
+    }
+
+    if let Some(plain_address) = config.plain_address {
+        if !plain_address.ip().is_loopback() {
+            return Err(Error::Usage(
+                "plain_address must be a loopback address, since its connections aren't encrypted"
+                    .to_owned(),
+            ));
+        }
+        let listener =
+            TcpListener::bind(plain_address).await.map_err(Error::Io)?;
+        tracing::info!("Listening for plain ws:// on {}", plain_address);
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            while let Ok((tcp_stream, client_address)) = listener.accept().await
+            {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    acceptor
+                        .start_session(
+                            Origin::Loopback(client_address),
+                            accept_websocket(tcp_stream),
+                        )
+                        .await;
+                });
             }
-            comms::ClientMessage::Request {
-                client_id,
-                count,
-                up_to_slot_number,
-            } => {
-                let entries = fake_chat_log.entries(count, up_to_slot_number);
-                sessions.read().await[&sender].send(
-                    comms::ServerMessage::EntryRange { client_id, entries },
-                );
+        });
+    }
+
+    if config.client_socket {
+        let client_socket_path = data_directory.join("client.sock");
+        let unix_listener =
+            bind_unix_socket(&client_socket_path).map_err(Error::Io)?;
+        tracing::info!(
+            "Listening for clients on {}",
+            client_socket_path.display()
+        );
+        tokio::spawn(async move {
+            while let Ok((unix_stream, _)) = unix_listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    acceptor
+                        .start_session(
+                            Origin::ClientSocket,
+                            accept_websocket(unix_stream),
+                        )
+                        .await;
+                });
+            }
+        });
+    }
+
+    let (admin_tx, mut admin_rx) = mpsc::unbounded_channel();
+    admin::listen(&data_directory.join("admin.sock"), admin_tx)
+        .map_err(Error::Io)?;
+
+    let (api_tx, mut api_rx) = mpsc::unbounded_channel();
+    if let Some(api_address) = config.http_api.address {
+        if !api_address.ip().is_loopback() {
+            return Err(Error::Usage(
+                "http_api.address must be a loopback address, since its requests and tokens aren't encrypted"
+                    .to_owned(),
+            ));
+        }
+        if config.http_api.tokens.is_empty() {
+            return Err(Error::Usage(
+                "The HTTP API needs at least one token in config.json"
+                    .to_owned(),
+            ));
+        }
+        http_api::listen(api_address, config.http_api.tokens.clone(), api_tx)
+            .await
+            .map_err(Error::Io)?;
+    }
+
+    let mut state = ServerState {
+        config,
+        config_path,
+        started_at: chrono::Utc::now(),
+        messages_handled: 0,
+        channels,
+        log_storage,
+        released_tx,
+        attachment_store,
+        read_positions,
+        topics,
+        moderation,
+        audit_log,
+        roles,
+        sessions,
+        followers: vec![],
+        leader,
+        peers: vec![],
+        webhooks,
+        recent_commands: Arc::default(),
+    };
+
+    let mut compaction_interval = tokio::time::interval(COMPACTION_INTERVAL);
+    let mut next_request_id: u64 = 0;
+    loop {
+        let (sender, message) = tokio::select! {
+            received = message_rx.recv() => match received {
+                Some(received) => received,
+                None => break,
+            },
+            Some((request, operator, response_tx)) = admin_rx.recv() => {
+                let response = state.handle_admin(request, &operator).await;
+                tokio::spawn(async move {
+                    let _ = response_tx.send(response.await);
+                });
+                continue;
             }
+            Some((request, response_tx)) = api_rx.recv() => {
+                let response = state.handle_api(request);
+                tokio::spawn(async move {
+                    let _ = response_tx.send(response.await);
+                });
+                continue;
+            }
+            Some(follower) = follower_rx.recv() => {
+                state.add_follower(follower);
+                continue;
+            }
+            Some(event) = follower_event_rx.recv() => {
+                state.handle_follower_event(event).await;
+                continue;
+            }
+            Some(peer) = peer_rx.recv() => {
+                state.add_peer(peer);
+                continue;
+            }
+            Some(event) = peer_event_rx.recv() => {
+                state.handle_peer_event(event).await;
+                continue;
+            }
+            Some(session) = closed_rx.recv() => {
+                state.end_session(session).await;
+                continue;
+            }
+            Some(attachments) = released_rx.recv() => {
+                state.release_attachments(attachments);
+                continue;
+            }
+            _ = compaction_interval.tick() => {
+                state.compact_all();
+                continue;
+            }
+        };
+        let session_span = state
+            .sessions
+            .read()
+            .await
+            .get(&sender)
+            .map_or_else(tracing::Span::none, |session| session.span.clone());
+        let span = tracing::info_span!(
+            parent: &session_span,
+            "request",
+            request_id = next_request_id,
+            kind = %logging::kind(&message),
+        );
+        next_request_id += 1;
+        async {
+            tracing::info!(
+                body = %logging::body(&message),
+                "Processing message"
+            );
+            state.handle(sender, message).await;
         }
+        .instrument(span)
+        .await;
     }
 
     Ok(())
@@ -185,6 +500,8 @@ async fn main() -> Result<(), Error> {
 enum SessionError {
     IO(io::Error),
     WebSocket(tokio_tungstenite::tungstenite::Error),
+    Transport(transport::Error),
+    Banned,
 }
 
 impl fmt::Display for SessionError {
@@ -192,124 +509,410 @@ impl fmt::Display for SessionError {
         match self {
             SessionError::IO(error) => error.fmt(f),
             SessionError::WebSocket(error) => error.fmt(f),
+            SessionError::Transport(error) => error.fmt(f),
+            SessionError::Banned => write!(f, "Address is banned"),
         }
     }
 }
 
 impl error::Error for SessionError {}
 
+/// Where a session's client connected from.
+#[derive(Debug, Clone, Copy)]
+enum Origin {
+    /// Over TLS or QUIC.
+    Remote(net::SocketAddr),
+    /// Over plain `ws://` on a loopback address.
+    Loopback(net::SocketAddr),
+    /// Over `client.sock` in the data directory.
+    ClientSocket,
+}
+
+impl Origin {
+    fn address(&self) -> Option<net::SocketAddr> {
+        match self {
+            Origin::Remote(address) | Origin::Loopback(address) => {
+                Some(*address)
+            }
+            Origin::ClientSocket => None,
+        }
+    }
+
+    /// The address that banning the client bans too. Clients on this machine
+    /// all share one, so banning it would ban every local client.
+    fn bannable_ip(&self) -> Option<net::IpAddr> {
+        match self {
+            Origin::Remote(address) => Some(address.ip()),
+            Origin::Loopback(_) | Origin::ClientSocket => None,
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Remote(address) => address.fmt(f),
+            Origin::Loopback(address) => write!(f, "ws://{}", address),
+            Origin::ClientSocket => write!(f, "client.sock"),
+        }
+    }
+}
+
+/// Where messages to a session's client are queued. Cheap to clone, so each
+/// channel task can hold one for every subscriber.
+#[derive(Clone)]
+struct Outbox {
+    session: SessionId,
+    to_client_tx: mpsc::UnboundedSender<Frame>,
+    /// How many messages are waiting in `to_client_tx`.
+    queued: Arc<AtomicUsize>,
+}
+
+impl Outbox {
+    fn send(&self, message: comms::ServerMessage) {
+        tracing::debug!(
+            session = %self.session,
+            body = %logging::body(&message),
+            "Sending reply"
+        );
+        self.enqueue_or_drop(Frame::Data(Bytes::from(message.to_bytes())));
+    }
+
+    /// Sends a message already encoded for a broadcast.
+    fn send_encoded(&self, message: &broadcast::Encoded) {
+        self.enqueue_or_drop(message.to_frame());
+    }
+
+    fn enqueue_or_drop(&self, frame: Frame) {
+        if self.enqueue(frame).is_err() {
+            tracing::warn!(
+                session = %self.session,
+                "Dropping reply to closed connection"
+            );
+        }
+    }
+
+    fn enqueue(
+        &self,
+        frame: Frame,
+    ) -> Result<(), mpsc::error::SendError<Frame>> {
+        // Counted first so the writer never sees the frame uncounted.
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.to_client_tx.send(frame).inspect_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Whether the connection has ended.
+    fn is_closed(&self) -> bool {
+        self.to_client_tx.is_closed()
+    }
+}
+
 struct Session {
-    client_address: net::SocketAddr,
-    to_client_tx: mpsc::UnboundedSender<Message>,
+    origin: Origin,
+    /// Set once the client sends [`comms::ClientMessage::LogIn`].
+    username: Option<String>,
+    outbox: Outbox,
+    /// Set once a moderator kicks or bans the user, after which nothing more
+    /// from the session is handled.
+    closed: bool,
+    connected_at: chrono::DateTime<chrono::Utc>,
+    /// Parent of everything logged about the session, recording its address
+    /// and, once logged in, its user.
+    span: tracing::Span,
     _join_handle: JoinHandle<()>,
 }
 
+type Sessions = Arc<RwLock<HashMap<SessionId, Session>>>;
+
 impl Session {
     fn send(&self, message: comms::ServerMessage) {
-        log::info!(
-            "Sending reply {:?} to client address {}",
-            message,
-            self.client_address
-        );
-        self.to_client_tx
-            .send(Message::binary(message.to_bytes()))
-            .expect("todo");
+        self.outbox.send(message);
+    }
+
+    /// Tells the client why, then closes the connection.
+    fn close(&mut self, reason: String) {
+        tracing::info!(parent: &self.span, %reason, "Closing connection");
+        self.send(comms::ServerMessage::Disconnected { reason });
+        let _ = self.outbox.enqueue(Frame::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: "disconnected by a moderator".to_owned(),
+        })));
+        self.username = None;
+        self.closed = true;
+    }
+
+    fn set_username(&mut self, username: String) {
+        self.span.record("user", username.as_str());
+        self.username = Some(username);
+    }
+
+    /// The address the client connected from, unless it used `client.sock`.
+    fn ip(&self) -> Option<net::IpAddr> {
+        self.origin.address().map(|address| address.ip())
+    }
+
+    /// Whether the session was closed, by the server or by the connection
+    /// ending.
+    fn is_closed(&self) -> bool {
+        self.closed || self.outbox.is_closed()
     }
 }
 
-async fn new_client_connection(
-    tcp_stream: TcpStream,
-    client_address: net::SocketAddr,
-    tls_acceptor: &TlsAcceptor,
-    message_tx: &mpsc::UnboundedSender<(net::SocketAddr, comms::ClientMessage)>,
-) -> Result<Session, SessionError> {
-    let tls_acceptor = tls_acceptor.clone();
-    let message_tx = message_tx.clone();
+/// The server's TLS configuration, or `None` when testing locally without a
+/// certificate, in which case only plain connections are accepted.
+fn load_tls_config() -> Result<Option<ServerConfig>, Error> {
+    let (certificate, private_key) = if cfg!(feature = "local") {
+        match (
+            CertificateDer::from_pem_file("testing_cert/cert.pem"),
+            PrivateKeyDer::from_pem_file("testing_cert/key.pem"),
+        ) {
+            (Ok(certificate), Ok(private_key)) => (certificate, private_key),
+            _ => return Ok(None),
+        }
+    } else {
+        todo!("Ask Peter for midcode certificate and private key")
+    };
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate], private_key)
+        .map(Some)
+        .map_err(Error::Tls)
+}
 
-    let tls_stream = tls_acceptor
-        .accept(tcp_stream)
+/// Listens on `path`, replacing any socket left there by a previous run.
+/// Only the user running the server can connect.
+fn bind_unix_socket(path: &Path) -> io::Result<UnixListener> {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// What starting a session needs, shared by every transport's listener.
+#[derive(Clone)]
+struct Acceptor {
+    next_session_id: Arc<AtomicU64>,
+    sessions: Sessions,
+    message_tx: mpsc::UnboundedSender<(SessionId, comms::ClientMessage)>,
+    /// Where each session's ID is sent once its connection ends.
+    closed_tx: mpsc::UnboundedSender<SessionId>,
+    moderation: Arc<RwLock<Moderation>>,
+    audit_log: Arc<AuditLog>,
+}
+
+impl Acceptor {
+    /// Starts a session for the client from `origin` once `connect`
+    /// establishes its connection, logging why if it couldn't.
+    async fn start_session(
+        &self,
+        origin: Origin,
+        connect: impl Future<Output = Result<transport::Connection, SessionError>>,
+    ) {
+        let id =
+            SessionId(self.next_session_id.fetch_add(1, Ordering::Relaxed));
+        match new_client_connection(
+            id,
+            origin,
+            connect,
+            &self.message_tx,
+            &self.closed_tx,
+            &self.moderation,
+            &self.audit_log,
+        )
         .await
-        .map_err(SessionError::IO)?;
-    let websocket = tokio_tungstenite::accept_async(tls_stream)
+        {
+            Ok(session) => {
+                let mut sessions = self.sessions.write().await;
+                // A connection that already ended may have been reported
+                // before its session was added.
+                if !session.outbox.is_closed() {
+                    sessions.insert(id, session);
+                }
+            }
+            Err(error) => {
+                tracing::error!(
+                    "Failed to establish session with client from {}: {}",
+                    origin,
+                    error
+                );
+            }
+        }
+    }
+}
+
+/// Accepts a WebSocket over `stream`, which is either encrypted already or
+/// never leaves the machine.
+async fn accept_websocket(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+) -> Result<transport::Connection, SessionError> {
+    let websocket = tokio_tungstenite::accept_async(stream)
         .await
         .map_err(SessionError::WebSocket)?;
+    Ok(websocket.into())
+}
+
+/// Accepts a WebSocket over TLS.
+async fn accept_tls_websocket(
+    tcp_stream: TcpStream,
+    tls_acceptor: &TlsAcceptor,
+) -> Result<transport::Connection, SessionError> {
+    let tls_stream =
+        tls_acceptor.accept(tcp_stream).await.map_err(|error| {
+            METRICS
+                .tls_handshake_failures
+                .fetch_add(1, Ordering::Relaxed);
+            SessionError::IO(error)
+        })?;
+    accept_websocket(tls_stream).await
+}
+
+async fn accept_quic(
+    incoming: transport::quic::Incoming,
+) -> Result<transport::Connection, SessionError> {
+    incoming.accept().await.map_err(|error| {
+        METRICS
+            .tls_handshake_failures
+            .fetch_add(1, Ordering::Relaxed);
+        SessionError::Transport(error)
+    })
+}
 
-    log::info!(
-        "Established connection with client address {}",
-        client_address
+/// Starts session `id` for the client from `origin` once `connect`
+/// establishes its connection, unless the client's address is banned.
+async fn new_client_connection(
+    id: SessionId,
+    origin: Origin,
+    connect: impl Future<Output = Result<transport::Connection, SessionError>>,
+    message_tx: &mpsc::UnboundedSender<(SessionId, comms::ClientMessage)>,
+    closed_tx: &mpsc::UnboundedSender<SessionId>,
+    moderation: &RwLock<Moderation>,
+    audit_log: &AuditLog,
+) -> Result<Session, SessionError> {
+    if let Some(ip) = origin.bannable_ip() {
+        if moderation
+            .read()
+            .await
+            .is_address_banned(ip, chrono::Utc::now())
+        {
+            audit_log
+                .record(None, audit::Event::ConnectionRefused { address: ip });
+            return Err(SessionError::Banned);
+        }
+    }
+
+    let message_tx = message_tx.clone();
+    let closed_tx = closed_tx.clone();
+    let connection = connect.await?;
+
+    let span = tracing::info_span!(
+        "session",
+        id = %id,
+        address = %origin,
+        user = tracing::field::Empty,
     );
+    tracing::info!(parent: &span, "Established connection");
 
-    let (mut websocket_write, mut websocket_read) = websocket.split();
+    let (mut sender, mut receiver) = connection.split();
 
-    let (write_websocket_tx, mut write_websocket_rx) =
-        mpsc::unbounded_channel();
+    let (write_tx, mut write_rx) = mpsc::unbounded_channel();
 
-    let write_websocket_thread_tx = write_websocket_tx.clone();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let queued_for_reader = queued.clone();
+    let queued_for_writer = queued.clone();
+    let write_thread_tx = write_tx.clone();
     let join_handle = tokio::spawn(async move {
-        tokio::join!(
-            async {
-                while let Some(message) = websocket_read.next().await {
-                    match message {
-                        Ok(message) => {
-                            match message {
-                                Message::Binary(message_bytes) => {
-                                    match comms::ClientMessage::try_from_bytes(
-                                        &message_bytes,
-                                    ) {
-                                        Ok(client_message) => {
-                                            log::info!(
-                                            "Received {:?} from client address {}",
-                                           client_message ,
-                                            client_address
-                                        );
-                                            message_tx
-                                            .send((
-                                                client_address,
-                                                client_message,
-                                            ))
-                                            .expect("Failed to queue client message for processing");
-                                        }
-                                        Err(decoding_error) => {
-                                            log::error!("Failed to decode client request message: {}", decoding_error);
-                                        }
-                                    }
-                                }
-                                Message::Close(close_frame) => {
-                                    write_websocket_thread_tx
-                                    .send(Message::Close(close_frame))
-                                    .expect("Failed to close websocket with client");
-                                    log::info!("Closing connection with client address {}", client_address);
-                                    break;
-                                }
-                                _ => todo!(),
+        let reader = async {
+            let mut close_frame = None;
+            while let Some(frame) = receiver.receive().await {
+                match frame {
+                    Ok(Frame::Data(message_bytes)) => {
+                        match comms::ClientMessage::try_from_bytes(
+                            &message_bytes,
+                        ) {
+                            Ok(client_message) => {
+                                tracing::debug!(
+                                    kind = %logging::kind(&client_message),
+                                    "Received message"
+                                );
+                                message_tx
+                                    .send((id, client_message))
+                                    .expect("Failed to queue client message for processing");
+                            }
+                            Err(decoding_error) => {
+                                METRICS
+                                    .decode_errors
+                                    .fetch_add(1, Ordering::Relaxed);
+                                tracing::error!(error = %decoding_error, "Failed to decode client message");
                             }
-                        }
-                        Err(error) => {
-                            todo!("{:?}", error);
                         }
                     }
-                }
-            },
-            async {
-                while let Some(message) = write_websocket_rx.recv().await {
-                    if matches!(message, Message::Close(_)) {
-                        // websocket_write.send(message).await.expect("todo");
+                    Ok(Frame::Close(frame)) => {
+                        tracing::info!("Client closed the connection");
+                        close_frame = frame;
+                        break;
+                    }
+                    Err(error) => {
+                        // Idle timeouts and resets end connections this way,
+                        // e.g. when a QUIC client's network goes away.
+                        tracing::info!(%error, "Connection failed");
                         break;
                     }
-                    websocket_write.send(message).await.unwrap_or_else(|_| {
-                        panic!(
-                            "Failed to send message to client address {}",
-                            client_address
-                        )
-                    });
                 }
             }
-        );
-    });
+            // Has the writer send what's already queued and then close, or
+            // stop as soon as sending fails.
+            queued_for_reader.fetch_add(1, Ordering::Relaxed);
+            let _ = write_thread_tx.send(Frame::Close(close_frame));
+        };
+        let mut writer = Box::pin(async move {
+            while let Some(frame) = write_rx.recv().await {
+                queued_for_writer.fetch_sub(1, Ordering::Relaxed);
+                if matches!(frame, Frame::Close(_)) {
+                    // Fails if the client closed first, which is fine.
+                    let _ = sender.send(frame).await;
+                    break;
+                }
+                if let Err(error) = sender.send(frame).await {
+                    tracing::info!(%error, "Failed to send to client");
+                    break;
+                }
+            }
+        });
+        // The session ends once the writer does, which it always does after
+        // the reader.
+        tokio::select! {
+            () = reader => (&mut writer).await,
+            () = &mut writer => {}
+        }
+        // Closes the outbox before reporting the session closed, so it's
+        // never added after it's removed.
+        drop(writer);
+        let _ = closed_tx.send(id);
+    }
+    .instrument(span.clone()));
 
     Ok(Session {
-        client_address,
-        to_client_tx: write_websocket_tx,
+        origin,
+        username: None,
+        closed: false,
+        connected_at: chrono::Utc::now(),
+        span,
+        outbox: Outbox {
+            session: id,
+            to_client_tx: write_tx,
+            queued,
+        },
         _join_handle: join_handle,
     })
 }
@@ -0,0 +1,330 @@
+//! Load metrics, served over HTTP in the Prometheus text format on a separate
+//! local port.
+
+use std::{
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{http, Sessions};
+
+/// Where metrics are served unless the config says otherwise.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9464";
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// The Prometheus text format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub static METRICS: Metrics = Metrics {
+    messages_posted: AtomicU64::new(0),
+    messages_broadcast: AtomicU64::new(0),
+    tls_handshake_failures: AtomicU64::new(0),
+    decode_errors: AtomicU64::new(0),
+    history_request_duration: Histogram::new(),
+    storage_write_duration: Histogram::new(),
+};
+
+pub struct Metrics {
+    /// Entries committed to any channel.
+    pub messages_posted: AtomicU64,
+    /// New entries sent to sessions, counting each session separately.
+    pub messages_broadcast: AtomicU64,
+    pub tls_handshake_failures: AtomicU64,
+    /// Client messages that weren't a valid [`comms::ClientMessage`].
+    pub decode_errors: AtomicU64,
+    pub history_request_duration: Histogram,
+    /// How long appending to a channel's file takes.
+    pub storage_write_duration: Histogram,
+}
+
+/// Counts of observed durations in each of [`BUCKETS`].
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanoseconds: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanoseconds: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = BUCKETS.iter().position(|bound| seconds <= *bound)
+        {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanoseconds.fetch_add(
+            u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                output,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name, bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            output,
+            "{}_sum {}",
+            name,
+            self.sum_nanoseconds.load(Ordering::Relaxed) as f64 / 1e9
+        );
+        let _ = writeln!(output, "{}_count {}", name, count);
+    }
+}
+
+fn render_counter(output: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} counter", name);
+    let _ = writeln!(output, "{} {}", name, value);
+}
+
+/// Escapes `value` for use inside a quoted label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn render(sessions: &Sessions) -> String {
+    let mut output = String::new();
+
+    let sessions = sessions.read().await;
+    let open_sessions = sessions
+        .values()
+        .filter(|session| !session.is_closed())
+        .collect::<Vec<_>>();
+    output += "# HELP nerdtalk_sessions Open client sessions.\n";
+    output += "# TYPE nerdtalk_sessions gauge\n";
+    let _ = writeln!(output, "nerdtalk_sessions {}", open_sessions.len());
+    output += "# HELP nerdtalk_session_queue_depth Messages waiting to be \
+               written to a session's connection.\n";
+    output += "# TYPE nerdtalk_session_queue_depth gauge\n";
+    for session in open_sessions {
+        let _ = writeln!(
+            output,
+            "nerdtalk_session_queue_depth{{address=\"{}\",username=\"{}\"}} {}",
+            session.origin,
+            escape_label(session.username.as_deref().unwrap_or("")),
+            session.outbox.queue_depth()
+        );
+    }
+    drop(sessions);
+
+    render_counter(
+        &mut output,
+        "nerdtalk_messages_posted_total",
+        "Entries committed to any channel.",
+        METRICS.messages_posted.load(Ordering::Relaxed),
+    );
+    render_counter(
+        &mut output,
+        "nerdtalk_messages_broadcast_total",
+        "New entries sent to sessions, counting each session separately.",
+        METRICS.messages_broadcast.load(Ordering::Relaxed),
+    );
+    render_counter(
+        &mut output,
+        "nerdtalk_tls_handshake_failures_total",
+        "Connections that failed the TLS handshake.",
+        METRICS.tls_handshake_failures.load(Ordering::Relaxed),
+    );
+    render_counter(
+        &mut output,
+        "nerdtalk_decode_errors_total",
+        "Client messages that failed to decode.",
+        METRICS.decode_errors.load(Ordering::Relaxed),
+    );
+    METRICS.history_request_duration.render(
+        &mut output,
+        "nerdtalk_history_request_duration_seconds",
+        "Time to answer requests for channel history.",
+    );
+    METRICS.storage_write_duration.render(
+        &mut output,
+        "nerdtalk_storage_write_duration_seconds",
+        "Time to append a record to a channel's file.",
+    );
+    output
+}
+
+/// Serves metrics at `/metrics` on `address`.
+pub async fn listen(address: SocketAddr, sessions: Sessions) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving metrics on http://{}/metrics", address);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sessions = sessions.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve(stream, &sessions).await {
+                    tracing::warn!("Metrics request failed: {}", error);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(mut stream: TcpStream, sessions: &Sessions) -> io::Result<()> {
+    let Some(request) = http::read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            http::respond(
+                &mut stream,
+                "200 OK",
+                CONTENT_TYPE,
+                &render(sessions).await,
+            )
+            .await
+        }
+        ("GET", _) => {
+            http::respond(&mut stream, "404 Not Found", CONTENT_TYPE, "").await
+        }
+        _ => {
+            http::respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                CONTENT_TYPE,
+                "",
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::RwLock,
+    };
+
+    use super::*;
+    use crate::{testing, Origin};
+
+    #[test]
+    fn renders_cumulative_histograms() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(100));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut output = String::new();
+        histogram.render(&mut output, "latency_seconds", "How long.");
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "# HELP latency_seconds How long.");
+        assert_eq!(lines[1], "# TYPE latency_seconds histogram");
+        assert_eq!(lines[2], "latency_seconds_bucket{le=\"0.0005\"} 1");
+        assert!(lines.contains(&"latency_seconds_bucket{le=\"0.025\"} 2"));
+        assert!(lines.contains(&"latency_seconds_bucket{le=\"0.05\"} 3"));
+        assert!(lines.contains(&"latency_seconds_bucket{le=\"2.5\"} 3"));
+        // Only +Inf counts what's beyond the last bound.
+        assert!(lines.contains(&"latency_seconds_bucket{le=\"+Inf\"} 4"));
+        assert!(lines.contains(&"latency_seconds_sum 10.0501"));
+        assert_eq!(lines.last(), Some(&"latency_seconds_count 4"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("ethan"), "ethan");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[tokio::test]
+    async fn renders_open_sessions() {
+        let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+        let _ethan = testing::connect(
+            &sessions,
+            Origin::Remote(([192, 0, 2, 1], 4000).into()),
+            Some("ethan\""),
+        )
+        .await;
+        let _anonymous =
+            testing::connect(&sessions, Origin::ClientSocket, None).await;
+        let (_, gone_rx) =
+            testing::connect(&sessions, Origin::ClientSocket, None).await;
+        drop(gone_rx);
+
+        let output = render(&sessions).await;
+        assert!(output.contains("\nnerdtalk_sessions 2\n"));
+        assert!(output.contains(
+            "nerdtalk_session_queue_depth{address=\"192.0.2.1:4000\",\
+             username=\"ethan\\\"\"} 0\n"
+        ));
+        assert!(output.contains(
+            "nerdtalk_session_queue_depth{address=\"client.sock\",\
+             username=\"\"} 0\n"
+        ));
+        assert!(
+            output.contains("# TYPE nerdtalk_messages_posted_total counter")
+        );
+        assert!(output.contains(
+            "# TYPE nerdtalk_storage_write_duration_seconds histogram"
+        ));
+    }
+
+    /// Sends `request` to [`serve`], returning the response's status line.
+    async fn status_of(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, &sessions).await.unwrap();
+        });
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap();
+        response.lines().next().unwrap_or_default().to_owned()
+    }
+
+    #[tokio::test]
+    async fn serves_only_metrics() {
+        assert_eq!(
+            status_of("GET /metrics HTTP/1.1\r\n\r\n").await,
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(
+            status_of("GET / HTTP/1.1\r\n\r\n").await,
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(
+            status_of("POST /metrics HTTP/1.1\r\n\r\n").await,
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Bans and mutes.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::chat_log::ChangeError;
+
+#[derive(Debug)]
+pub enum ModerationError {
+    NotLoggedIn,
+    PermissionDenied(comms::PermissionDenied),
+    /// Moderators can only act on users with a lesser role.
+    Outranked {
+        username: String,
+        role: comms::Role,
+    },
+    NotBanned(String),
+    NotMuted(String),
+    Change(ChangeError),
+    Storage(io::Error),
+}
+
+impl fmt::Display for ModerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModerationError::NotLoggedIn => write!(f, "Log in first"),
+            ModerationError::PermissionDenied(denial) => denial.fmt(f),
+            ModerationError::Outranked { username, role } => {
+                write!(
+                    f,
+                    "{} is {} and can't be moderated by you",
+                    username, role
+                )
+            }
+            ModerationError::NotBanned(username) => {
+                write!(f, "{} is not banned", username)
+            }
+            ModerationError::NotMuted(username) => {
+                write!(f, "{} is not muted here", username)
+            }
+            ModerationError::Change(error) => error.fmt(f),
+            ModerationError::Storage(error) => {
+                write!(f, "Failed to save: {}", error)
+            }
+        }
+    }
+}
+
+/// Whether a ban or mute lasting until `until` still applies at `now`.
+fn is_active(until: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+    until.is_none() || until > Some(now)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    /// Lasts for good if `None`.
+    pub until: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+    /// Where the user was connected from when banned, so they can't just log
+    /// in under another name.
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mute {
+    /// Lasts until unmuted if `None`.
+    pub until: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Restrictions {
+    bans: HashMap<String, Ban>,
+    mutes: HashMap<chat::ChannelName, HashMap<String, Mute>>,
+}
+
+/// Current bans and mutes, saved to a JSON file whenever they change. Expired
+/// ones are ignored rather than removed.
+pub struct Moderation {
+    path: PathBuf,
+    restrictions: Restrictions,
+}
+
+impl Moderation {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let restrictions = match fs::read(&path) {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).map_err(io::Error::other)?
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Restrictions::default()
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(Self { path, restrictions })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let temporary_path = self.path.with_extension("partial");
+        fs::write(
+            &temporary_path,
+            serde_json::to_vec(&self.restrictions).map_err(io::Error::other)?,
+        )?;
+        fs::rename(temporary_path, &self.path)
+    }
+
+    pub fn ban_of(&self, username: &str, now: DateTime<Utc>) -> Option<&Ban> {
+        self.restrictions
+            .bans
+            .get(username)
+            .filter(|ban| is_active(ban.until, now))
+    }
+
+    pub fn is_address_banned(
+        &self,
+        address: IpAddr,
+        now: DateTime<Utc>,
+    ) -> bool {
+        self.restrictions.bans.values().any(|ban| {
+            is_active(ban.until, now) && ban.addresses.contains(&address)
+        })
+    }
+
+    pub fn ban(&mut self, username: String, ban: Ban) -> io::Result<()> {
+        self.restrictions.bans.insert(username, ban);
+        self.save()
+    }
+
+    /// Lifts `username`'s ban, returning whether they were banned.
+    pub fn unban(
+        &mut self,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> io::Result<bool> {
+        match self.restrictions.bans.remove(username) {
+            Some(ban) => {
+                self.save()?;
+                Ok(is_active(ban.until, now))
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn mute_of(
+        &self,
+        channel: &chat::ChannelName,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> Option<&Mute> {
+        self.restrictions
+            .mutes
+            .get(channel)?
+            .get(username)
+            .filter(|mute| is_active(mute.until, now))
+    }
+
+    pub fn mute(
+        &mut self,
+        channel: chat::ChannelName,
+        username: String,
+        mute: Mute,
+    ) -> io::Result<()> {
+        self.restrictions
+            .mutes
+            .entry(channel)
+            .or_default()
+            .insert(username, mute);
+        self.save()
+    }
+
+    /// Lifts `username`'s mute in `channel`, returning whether they were
+    /// muted.
+    pub fn unmute(
+        &mut self,
+        channel: &chat::ChannelName,
+        username: &str,
+        now: DateTime<Utc>,
+    ) -> io::Result<bool> {
+        let Some(mute) = self
+            .restrictions
+            .mutes
+            .get_mut(channel)
+            .and_then(|mutes| mutes.remove(username))
+        else {
+            return Ok(false);
+        };
+        self.save()?;
+        Ok(is_active(mute.until, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn general() -> chat::ChannelName {
+        chat::ChannelName::default()
+    }
+
+    #[test]
+    fn bans_expire() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut moderation =
+            Moderation::open(directory.path().join("moderation.json")).unwrap();
+        let now = Utc::now();
+        let address = IpAddr::from([192, 0, 2, 1]);
+        moderation
+            .ban(
+                "alice".to_owned(),
+                Ban {
+                    until: Some(now + TimeDelta::hours(1)),
+                    reason: Some("spam".to_owned()),
+                    addresses: vec![address],
+                },
+            )
+            .unwrap();
+
+        assert!(moderation.ban_of("alice", now).is_some());
+        assert!(moderation.ban_of("bob", now).is_none());
+        assert!(moderation.is_address_banned(address, now));
+        assert!(
+            !moderation.is_address_banned(IpAddr::from([192, 0, 2, 2]), now)
+        );
+
+        let later = now + TimeDelta::hours(2);
+        assert!(moderation.ban_of("alice", later).is_none());
+        assert!(!moderation.is_address_banned(address, later));
+        // An expired ban no longer counts as one to lift.
+        assert!(!moderation.unban("alice", later).unwrap());
+        assert!(!moderation.unban("alice", now).unwrap());
+    }
+
+    #[test]
+    fn mutes_apply_per_channel() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut moderation =
+            Moderation::open(directory.path().join("moderation.json")).unwrap();
+        let now = Utc::now();
+        let random = chat::ChannelName("random".to_owned());
+        moderation
+            .mute(
+                general(),
+                "alice".to_owned(),
+                Mute {
+                    until: None,
+                    reason: None,
+                },
+            )
+            .unwrap();
+
+        assert!(moderation.mute_of(&general(), "alice", now).is_some());
+        assert!(moderation.mute_of(&random, "alice", now).is_none());
+        assert!(!moderation.unmute(&random, "alice", now).unwrap());
+        assert!(moderation.unmute(&general(), "alice", now).unwrap());
+        assert!(moderation.mute_of(&general(), "alice", now).is_none());
+    }
+
+    #[test]
+    fn restrictions_survive_reopening() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("moderation.json");
+        let now = Utc::now();
+        let mut moderation = Moderation::open(&path).unwrap();
+        moderation
+            .ban(
+                "alice".to_owned(),
+                Ban {
+                    until: None,
+                    reason: None,
+                    addresses: vec![],
+                },
+            )
+            .unwrap();
+        moderation
+            .mute(
+                general(),
+                "bob".to_owned(),
+                Mute {
+                    until: Some(now + TimeDelta::minutes(10)),
+                    reason: Some("loud".to_owned()),
+                },
+            )
+            .unwrap();
+
+        let reopened = Moderation::open(&path).unwrap();
+        assert!(reopened.ban_of("alice", now).is_some());
+        let mute = reopened.mute_of(&general(), "bob", now).unwrap();
+        assert_eq!(mute.reason.as_deref(), Some("loud"));
+        assert!(!path.with_extension("partial").exists());
+    }
+}
@@ -0,0 +1,394 @@
+//! Authenticated connections between servers, used for replication and
+//! federation. Each side proves it knows a shared secret by answering a random
+//! challenge from the other with its HMAC-SHA256, and then messages are sent
+//! as one JSON object per line over a [`Link`], which authenticates every
+//! message. Messages aren't encrypted.
+
+use std::{io, time::Duration};
+
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+};
+
+/// How long either side waits for the other to authenticate.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest message either side reads before the handshake succeeds,
+/// so a peer that hasn't authenticated can't make it buffer without limit.
+const MAX_HANDSHAKE_LENGTH: usize = 4 * 1024;
+
+/// The longest message read on a [`Link`]. Entries can be as long as a
+/// client's frame, and escaping them as JSON can double that.
+const MAX_MESSAGE_LENGTH: usize = 2 * transport::quic::MAX_FRAME_LENGTH;
+
+/// How long to wait before reconnecting to another server, doubling up to
+/// [`MAX_RETRY_DELAY`] while it stays unreachable.
+pub const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+pub fn new_challenge() -> String {
+    hex(&rand::random::<[u8; 32]>())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn keyed(secret: &str, challenge: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(challenge.as_bytes());
+    mac
+}
+
+pub fn prove(secret: &str, challenge: &str) -> String {
+    hex(&keyed(secret, challenge).finalize().into_bytes())
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            hex.get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
+
+/// Whether `proof` answers `challenge` for `secret`, compared in constant
+/// time.
+pub fn verify(secret: &str, challenge: &str, proof: &str) -> bool {
+    let Some(proof) = unhex(proof) else {
+        return false;
+    };
+    keyed(secret, challenge).verify_slice(&proof).is_ok()
+}
+
+pub async fn send(
+    write: &mut OwnedWriteHalf,
+    message: &impl Serialize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message).map_err(io::Error::other)?;
+    line.push(b'\n');
+    write.write_all(&line).await
+}
+
+/// Receives the next message, refusing one longer than `max_length` bytes
+/// without reading the rest of it.
+async fn receive<T: DeserializeOwned>(
+    read: &mut BufReader<OwnedReadHalf>,
+    max_length: usize,
+) -> io::Result<T> {
+    let mut line = Vec::new();
+    let limit = max_length as u64 + 1;
+    if read.take(limit).read_until(b'\n', &mut line).await? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed",
+        ));
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > max_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "received a message that's too long",
+        ));
+    }
+    serde_json::from_slice(&line)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub fn authentication_failed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "failed to prove it knows the shared secret",
+    )
+}
+
+/// Receives the other side's next handshake message, giving up after
+/// [`HANDSHAKE_TIMEOUT`].
+pub async fn receive_handshake<T: DeserializeOwned>(
+    read: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, receive(read, MAX_HANDSHAKE_LENGTH))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Which end of a connection a [`Link`] is: the server that accepted it or
+/// the one that connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Listener,
+    Connector,
+}
+
+impl Side {
+    fn label(self) -> &'static str {
+        match self {
+            Side::Listener => "listener",
+            Side::Connector => "connector",
+        }
+    }
+
+    fn other(self) -> Side {
+        match self {
+            Side::Listener => Side::Connector,
+            Side::Connector => Side::Listener,
+        }
+    }
+}
+
+/// A message on a [`Link`]: the message's JSON and the HMAC-SHA256 of its
+/// sequence number and that JSON.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    message: String,
+    mac: String,
+}
+
+/// A connection whose handshake succeeded. Each direction has a key derived
+/// from the secret and both challenges, so it's new for every connection,
+/// and each message is authenticated with its sequence number in that
+/// direction, so one that's modified, injected, replayed, reordered, or
+/// reflected back is refused.
+pub struct Link {
+    write: OwnedWriteHalf,
+    read: BufReader<OwnedReadHalf>,
+    send_key: Vec<u8>,
+    receive_key: Vec<u8>,
+    sent: u64,
+    received: u64,
+}
+
+fn session_key(
+    secret: &str,
+    sender: Side,
+    listener_challenge: &str,
+    connector_challenge: &str,
+) -> Vec<u8> {
+    keyed(
+        secret,
+        &format!(
+            "{} {} {}",
+            sender.label(),
+            listener_challenge,
+            connector_challenge
+        ),
+    )
+    .finalize()
+    .into_bytes()
+    .to_vec()
+}
+
+fn seal(key: &[u8], sequence: u64, message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC takes keys of any length");
+    mac.update(&sequence.to_be_bytes());
+    mac.update(message.as_bytes());
+    mac
+}
+
+impl Link {
+    /// The link for `side` of a connection after the handshake with
+    /// `listener_challenge` and `connector_challenge` succeeded.
+    pub fn new(
+        write: OwnedWriteHalf,
+        read: BufReader<OwnedReadHalf>,
+        secret: &str,
+        side: Side,
+        listener_challenge: &str,
+        connector_challenge: &str,
+    ) -> Self {
+        Self {
+            write,
+            read,
+            send_key: session_key(
+                secret,
+                side,
+                listener_challenge,
+                connector_challenge,
+            ),
+            receive_key: session_key(
+                secret,
+                side.other(),
+                listener_challenge,
+                connector_challenge,
+            ),
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    pub async fn send(&mut self, message: &impl Serialize) -> io::Result<()> {
+        let message =
+            serde_json::to_string(message).map_err(io::Error::other)?;
+        let mac = hex(&seal(&self.send_key, self.sent, &message)
+            .finalize()
+            .into_bytes());
+        self.sent += 1;
+        send(&mut self.write, &Sealed { message, mac }).await
+    }
+
+    pub async fn receive<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        let Sealed { message, mac } =
+            receive(&mut self.read, MAX_MESSAGE_LENGTH).await?;
+        let authentic = unhex(&mac).is_some_and(|mac| {
+            seal(&self.receive_key, self.received, &message)
+                .verify_slice(&mac)
+                .is_ok()
+        });
+        if !authentic {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received a message that failed authentication",
+            ));
+        }
+        self.received += 1;
+        serde_json::from_str(&message)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    /// Receives the other side's next message, giving up after
+    /// [`HANDSHAKE_TIMEOUT`], for messages that finish setting up the
+    /// connection.
+    pub async fn receive_handshake<T: DeserializeOwned>(
+        &mut self,
+    ) -> io::Result<T> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.receive())
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Both ends of a connection, the listener's using the first of
+    /// `secrets` and the connector's the second.
+    async fn links(secrets: (&str, &str)) -> (Link, Link) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connected = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let link = |stream: TcpStream, secret: &str, side| {
+            let (read, write) = stream.into_split();
+            Link::new(
+                write,
+                BufReader::new(read),
+                secret,
+                side,
+                "listener challenge",
+                "connector challenge",
+            )
+        };
+        (
+            link(accepted, secrets.0, Side::Listener),
+            link(connected, secrets.1, Side::Connector),
+        )
+    }
+
+    #[test]
+    fn proofs_answer_their_challenge() {
+        let proof = prove("secret", "challenge");
+        assert!(verify("secret", "challenge", &proof));
+        assert!(!verify("secret", "other challenge", &proof));
+        assert!(!verify("other secret", "challenge", &proof));
+        assert!(!verify("secret", "challenge", "not hex"));
+    }
+
+    #[tokio::test]
+    async fn links_carry_messages_both_ways() {
+        let (mut listener, mut connector) = links(("secret", "secret")).await;
+        listener.send(&"hello").await.unwrap();
+        listener.send(&"again").await.unwrap();
+        connector.send(&42).await.unwrap();
+        assert_eq!(connector.receive::<String>().await.unwrap(), "hello");
+        assert_eq!(connector.receive::<String>().await.unwrap(), "again");
+        assert_eq!(listener.receive::<u32>().await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn links_refuse_other_secrets() {
+        let (mut listener, mut connector) = links(("secret", "other")).await;
+        listener.send(&"hello").await.unwrap();
+        assert!(connector.receive::<String>().await.is_err());
+    }
+
+    /// Sends `line` raw on `link`'s connection, as someone in the middle.
+    async fn inject(link: &mut Link, line: &str) {
+        link.write.write_all(line.as_bytes()).await.unwrap();
+        link.write.write_all(b"\n").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn links_refuse_modified_messages() {
+        let (mut listener, mut connector) = links(("secret", "secret")).await;
+        let mac = hex(&seal(&listener.send_key, 0, "\"hello\"")
+            .finalize()
+            .into_bytes());
+        inject(
+            &mut listener,
+            &serde_json::to_string(&Sealed {
+                message: "\"goodbye\"".to_owned(),
+                mac,
+            })
+            .unwrap(),
+        )
+        .await;
+        assert!(connector.receive::<String>().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn links_refuse_replayed_and_reflected_messages() {
+        let (mut listener, mut connector) = links(("secret", "secret")).await;
+        let sealed = |key: &[u8], sequence| {
+            serde_json::to_string(&Sealed {
+                message: "\"hello\"".to_owned(),
+                mac: hex(&seal(key, sequence, "\"hello\"")
+                    .finalize()
+                    .into_bytes()),
+            })
+            .unwrap()
+        };
+        let first = sealed(&listener.send_key, 0);
+        inject(&mut listener, &first).await;
+        inject(&mut listener, &first).await;
+        assert_eq!(connector.receive::<String>().await.unwrap(), "hello");
+        assert!(connector.receive::<String>().await.is_err());
+
+        // The connector's own first message, sent back to it.
+        let (mut listener, mut connector) = links(("secret", "secret")).await;
+        let reflected = sealed(&connector.send_key, 0);
+        inject(&mut listener, &reflected).await;
+        assert!(connector.receive::<String>().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn links_refuse_messages_that_are_too_long() {
+        let (mut listener, mut connector) = links(("secret", "secret")).await;
+        let line = "a".repeat(MAX_HANDSHAKE_LENGTH + 1);
+        inject(&mut listener, &line).await;
+        let error = receive_handshake::<String>(&mut connector.read)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let (mut listener, mut connector) = links(("secret", "secret")).await;
+        tokio::spawn(async move {
+            let line = "a".repeat(MAX_MESSAGE_LENGTH + 1);
+            let _ = listener.write.write_all(line.as_bytes()).await;
+        });
+        let error = connector.receive::<String>().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+type Positions = HashMap<String, HashMap<chat::ChannelName, usize>>;
+
+/// How long changes wait to be saved, so a burst of them, like a client
+/// scrolling through history, is saved once.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// The last slot each user has read in each channel, saved to a JSON file
+/// shortly after it changes by a task of its own, off the server's loop.
+/// Clones share the same positions.
+#[derive(Clone)]
+pub struct ReadPositions {
+    positions: Arc<Mutex<Positions>>,
+    changed: Arc<Notify>,
+}
+
+impl ReadPositions {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let positions = match fs::read(&path) {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).map_err(io::Error::other)?
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                HashMap::new()
+            }
+            Err(error) => return Err(error),
+        };
+        let positions = Arc::new(Mutex::new(positions));
+        let changed = Arc::new(Notify::new());
+        tokio::spawn(save_changes(path, positions.clone(), changed.clone()));
+        Ok(Self { positions, changed })
+    }
+
+    pub fn get(
+        &self,
+        username: &str,
+        channel: &chat::ChannelName,
+    ) -> Option<usize> {
+        self.positions
+            .lock()
+            .expect("read positions lock poisoned")
+            .get(username)?
+            .get(channel)
+            .copied()
+    }
+
+    /// Moves `username`'s read position in `channel` forward to
+    /// `slot_number`, returning whether it moved.
+    pub fn advance(
+        &mut self,
+        username: &str,
+        channel: &chat::ChannelName,
+        slot_number: usize,
+    ) -> bool {
+        let mut positions =
+            self.positions.lock().expect("read positions lock poisoned");
+        let channels = positions.entry(username.to_owned()).or_default();
+        if channels
+            .get(channel)
+            .is_some_and(|&position| position >= slot_number)
+        {
+            return false;
+        }
+        channels.insert(channel.clone(), slot_number);
+        self.changed.notify_one();
+        true
+    }
+}
+
+/// Saves `positions` to `path` after each burst of changes, for as long as
+/// the server runs.
+async fn save_changes(
+    path: PathBuf,
+    positions: Arc<Mutex<Positions>>,
+    changed: Arc<Notify>,
+) {
+    loop {
+        changed.notified().await;
+        tokio::time::sleep(SAVE_DELAY).await;
+        let bytes = serde_json::to_vec(
+            &*positions.lock().expect("read positions lock poisoned"),
+        )
+        .expect("failed to serialize read positions");
+        let path = path.clone();
+        let saved = tokio::task::spawn_blocking(move || {
+            let temporary_path = path.with_extension("partial");
+            fs::write(&temporary_path, bytes)?;
+            fs::rename(temporary_path, &path)
+        })
+        .await
+        .unwrap_or_else(|error| Err(io::Error::other(error)));
+        if let Err(error) = saved {
+            tracing::error!("Failed to save read positions: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn general() -> chat::ChannelName {
+        chat::ChannelName::default()
+    }
+
+    #[tokio::test]
+    async fn only_moves_forward() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut read_positions =
+            ReadPositions::open(directory.path().join("positions.json"))
+                .unwrap();
+        assert_eq!(read_positions.get("ethan", &general()), None);
+        assert!(read_positions.advance("ethan", &general(), 5));
+        assert!(!read_positions.advance("ethan", &general(), 3));
+        assert!(!read_positions.advance("ethan", &general(), 5));
+        assert_eq!(read_positions.get("ethan", &general()), Some(5));
+        assert_eq!(read_positions.get("alice", &general()), None);
+    }
+
+    #[tokio::test]
+    async fn saves_a_burst_of_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("positions.json");
+        let mut read_positions = ReadPositions::open(&path).unwrap();
+        for slot_number in 0..100 {
+            read_positions.advance("ethan", &general(), slot_number);
+        }
+        assert!(!path.exists());
+
+        tokio::time::sleep(SAVE_DELAY * 3).await;
+        let reopened = ReadPositions::open(&path).unwrap();
+        assert_eq!(reopened.get("ethan", &general()), Some(99));
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How long the server remembers a post's nonce. A resend arriving later than
+/// this is committed again.
+pub const NONCE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+type PostKey = (String, comms::Nonce);
+
+/// How recently settled posts were settled, keyed by poster and nonce, so
+/// that resends are settled the same way instead of committed twice. By
+/// default, that's the channel and slot each post landed in.
+pub struct RecentPosts<T = (chat::ChannelName, usize)> {
+    committed: HashMap<PostKey, T>,
+    /// Keys in the order they were committed, for expiring old ones.
+    order: VecDeque<(Instant, PostKey)>,
+}
+
+impl<T> Default for RecentPosts<T> {
+    fn default() -> Self {
+        Self {
+            committed: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Clone> RecentPosts<T> {
+    fn expire(&mut self, now: Instant) {
+        while let Some((committed_at, _)) = self.order.front() {
+            if now.duration_since(*committed_at) < NONCE_WINDOW {
+                break;
+            }
+            let (_, key) = self.order.pop_front().expect("front exists");
+            self.committed.remove(&key);
+        }
+    }
+
+    /// How `username`'s post with `nonce` was settled, if it was within the
+    /// window.
+    pub fn get(&mut self, username: &str, nonce: &comms::Nonce) -> Option<T> {
+        self.expire(Instant::now());
+        self.committed
+            .get(&(username.to_owned(), nonce.clone()))
+            .cloned()
+    }
+
+    pub fn insert(
+        &mut self,
+        username: String,
+        nonce: comms::Nonce,
+        settled: T,
+    ) {
+        let now = Instant::now();
+        self.expire(now);
+        let key = (username, nonce);
+        self.committed.insert(key.clone(), settled);
+        self.order.push_back((now, key));
+    }
+}
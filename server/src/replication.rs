@@ -0,0 +1,245 @@
+//! Leader/follower replication. A follower connects to its leader over TCP,
+//! both prove they know the shared secret, and the leader streams every
+//! record it commits to any channel's log. Messages are one JSON object per
+//! line, and those after the handshake are authenticated by a
+//! [`peer::Link`].
+//!
+//! Once connected, the follower sends the next slot it needs in each channel.
+//! The leader catches it up by sending each channel's history start, every
+//! entry from that slot on, and every entry before it that was edited or
+//! deleted, since entries only ever change by being edited or deleted. After
+//! that it sends records as they're committed, in commit order per channel.
+//! A follower that disconnects reconnects and catches up the same way.
+
+use std::{collections::HashMap, io, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::peer;
+
+/// A change to a channel's log, in the order the leader committed it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Record {
+    /// Compaction removed everything before `slot_number`.
+    HistoryStart {
+        channel: chat::ChannelName,
+        slot_number: usize,
+    },
+    /// A new entry, or the new state of an edited or deleted one.
+    Entry(chat::Entry),
+}
+
+impl Record {
+    pub fn channel(&self) -> &chat::ChannelName {
+        match self {
+            Record::HistoryStart { channel, .. } => channel,
+            Record::Entry(entry) => &entry.channel,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum LeaderMessage {
+    /// Sent on connecting. The follower must answer with the HMAC-SHA256 of
+    /// `challenge` keyed by the shared secret.
+    Challenge { challenge: String },
+    /// The follower authenticated. `proof` answers its challenge in turn,
+    /// and the leader sends [`Record`]s on the link from then on.
+    Accepted { proof: String },
+}
+
+#[derive(Serialize, Deserialize)]
+enum FollowerMessage {
+    /// Answers the leader's challenge. Once accepted, the follower sends the
+    /// next slot it needs in each channel it has on the link.
+    Hello { proof: String, challenge: String },
+}
+
+/// An authenticated follower, to be caught up and sent records on
+/// `records_tx`.
+pub struct Follower {
+    pub next_slots: HashMap<chat::ChannelName, usize>,
+    pub records_tx: mpsc::UnboundedSender<Record>,
+}
+
+/// What the task following a leader needs from the server.
+pub enum FollowerEvent {
+    /// The task connected and needs the next slot in each channel.
+    NextSlots(oneshot::Sender<HashMap<chat::ChannelName, usize>>),
+    Record(Record),
+}
+
+/// Accepts followers on `address`, sending each to `follower_tx` once it
+/// authenticates.
+pub async fn listen(
+    address: SocketAddr,
+    secret: String,
+    follower_tx: mpsc::UnboundedSender<Follower>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Accepting followers on {}", address);
+    tokio::spawn(async move {
+        while let Ok((stream, follower_address)) = listener.accept().await {
+            let secret = secret.clone();
+            let follower_tx = follower_tx.clone();
+            tokio::spawn(async move {
+                match lead(stream, follower_address, &secret, follower_tx).await
+                {
+                    Ok(()) => {
+                        tracing::info!(%follower_address, "Follower left");
+                    }
+                    Err(error) => tracing::warn!(
+                        %follower_address,
+                        "Follower connection failed: {}",
+                        error
+                    ),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn lead(
+    stream: TcpStream,
+    address: SocketAddr,
+    secret: &str,
+    follower_tx: mpsc::UnboundedSender<Follower>,
+) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    let challenge = peer::new_challenge();
+    peer::send(
+        &mut write,
+        &LeaderMessage::Challenge {
+            challenge: challenge.clone(),
+        },
+    )
+    .await?;
+    let FollowerMessage::Hello {
+        proof,
+        challenge: follower_challenge,
+    } = peer::receive_handshake(&mut read).await?;
+    if !peer::verify(secret, &challenge, &proof) {
+        return Err(peer::authentication_failed());
+    }
+    peer::send(
+        &mut write,
+        &LeaderMessage::Accepted {
+            proof: peer::prove(secret, &follower_challenge),
+        },
+    )
+    .await?;
+    let mut link = peer::Link::new(
+        write,
+        read,
+        secret,
+        peer::Side::Listener,
+        &challenge,
+        &follower_challenge,
+    );
+    let next_slots = link.receive_handshake().await?;
+    tracing::info!(%address, "Follower connected");
+
+    let (records_tx, mut records_rx) = mpsc::unbounded_channel();
+    follower_tx
+        .send(Follower {
+            next_slots,
+            records_tx,
+        })
+        .map_err(|_| io::Error::other("server is shutting down"))?;
+    while let Some(record) = records_rx.recv().await {
+        link.send(&record).await?;
+    }
+    Ok(())
+}
+
+/// Follows the leader at `address` until aborted, reconnecting whenever the
+/// connection is lost.
+pub fn follow(
+    address: SocketAddr,
+    secret: String,
+    event_tx: mpsc::UnboundedSender<FollowerEvent>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut retry_delay = peer::MIN_RETRY_DELAY;
+        loop {
+            if let Err(error) =
+                follow_once(address, &secret, &event_tx, &mut retry_delay).await
+            {
+                tracing::warn!(
+                    leader = %address,
+                    "Lost connection to leader, retrying in {:?}: {}",
+                    retry_delay,
+                    error
+                );
+            }
+            tokio::time::sleep(retry_delay).await;
+            retry_delay = (retry_delay * 2).min(peer::MAX_RETRY_DELAY);
+        }
+    })
+}
+
+async fn follow_once(
+    address: SocketAddr,
+    secret: &str,
+    event_tx: &mpsc::UnboundedSender<FollowerEvent>,
+    retry_delay: &mut Duration,
+) -> io::Result<()> {
+    let (read, mut write) = TcpStream::connect(address).await?.into_split();
+    let mut read = BufReader::new(read);
+
+    let LeaderMessage::Challenge { challenge } =
+        peer::receive_handshake(&mut read).await?
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "leader didn't start with a challenge",
+        ));
+    };
+    let own_challenge = peer::new_challenge();
+    peer::send(
+        &mut write,
+        &FollowerMessage::Hello {
+            proof: peer::prove(secret, &challenge),
+            challenge: own_challenge.clone(),
+        },
+    )
+    .await?;
+    match peer::receive_handshake(&mut read).await? {
+        LeaderMessage::Accepted { proof }
+            if peer::verify(secret, &own_challenge, &proof) => {}
+        _ => return Err(peer::authentication_failed()),
+    }
+    let mut link = peer::Link::new(
+        write,
+        read,
+        secret,
+        peer::Side::Connector,
+        &challenge,
+        &own_challenge,
+    );
+    let (next_slots_tx, next_slots_rx) = oneshot::channel();
+    event_tx
+        .send(FollowerEvent::NextSlots(next_slots_tx))
+        .map_err(|_| io::Error::other("server is shutting down"))?;
+    let next_slots: HashMap<chat::ChannelName, usize> =
+        next_slots_rx.await.map_err(io::Error::other)?;
+    link.send(&next_slots).await?;
+    tracing::info!(leader = %address, "Following leader");
+    *retry_delay = peer::MIN_RETRY_DELAY;
+
+    loop {
+        let record = link.receive().await?;
+        event_tx
+            .send(FollowerEvent::Record(record))
+            .map_err(|_| io::Error::other("server is shutting down"))?;
+    }
+}
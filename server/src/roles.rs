@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use comms::{Permission, PermissionDenied, Role};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+#[derive(Default, Serialize, Deserialize)]
+struct Assignments {
+    server: HashMap<String, Role>,
+    channels: HashMap<chat::ChannelName, HashMap<String, Role>>,
+}
+
+/// Roles assigned to users server-wide and per channel, saved to a JSON file
+/// whenever one changes.
+pub struct Roles {
+    path: PathBuf,
+    assignments: Assignments,
+}
+
+impl Roles {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let assignments = match fs::read(&path) {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).map_err(io::Error::other)?
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Assignments::default()
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(Self { path, assignments })
+    }
+
+    /// `username`'s role in `channel`, or server-wide if `None`. Everyone is
+    /// read-only on a follower, and the config's admins are admins everywhere
+    /// else. Otherwise a channel assignment wins over a server-wide one, which
+    /// wins over the config's moderators and default role.
+    pub fn role_of(
+        &self,
+        config: &Config,
+        username: &str,
+        channel: Option<&chat::ChannelName>,
+    ) -> Role {
+        if config.replication.leader_address.is_some() {
+            return Role::ReadOnly;
+        }
+        if config.admins.iter().any(|admin| admin == username) {
+            return Role::Admin;
+        }
+        channel
+            .and_then(|channel| self.assignments.channels.get(channel))
+            .and_then(|roles| roles.get(username))
+            .or_else(|| self.assignments.server.get(username))
+            .copied()
+            .unwrap_or_else(|| {
+                if config
+                    .moderators
+                    .iter()
+                    .any(|moderator| moderator == username)
+                {
+                    Role::Moderator
+                } else {
+                    config.default_role
+                }
+            })
+    }
+
+    /// Whether `username` has a role assigned anywhere.
+    pub fn is_assigned(&self, username: &str) -> bool {
+        self.assignments.server.contains_key(username)
+            || self
+                .assignments
+                .channels
+                .values()
+                .any(|roles| roles.contains_key(username))
+    }
+
+    /// Whether `username` is one of the config's admins or moderators, or
+    /// was assigned a role above the default anywhere, so someone else
+    /// logging in as them would gain something.
+    pub fn is_privileged(&self, config: &Config, username: &str) -> bool {
+        config
+            .admins
+            .iter()
+            .chain(&config.moderators)
+            .any(|privileged| privileged == username)
+            || self
+                .assignments
+                .channels
+                .values()
+                .chain([&self.assignments.server])
+                .filter_map(|roles| roles.get(username))
+                .any(|&role| role > config.default_role)
+    }
+
+    pub fn check(
+        &self,
+        config: &Config,
+        username: &str,
+        channel: Option<&chat::ChannelName>,
+        permission: Permission,
+    ) -> Result<(), PermissionDenied> {
+        let role = self.role_of(config, username, channel);
+        if role.allows(permission) {
+            Ok(())
+        } else {
+            Err(PermissionDenied {
+                username: username.to_owned(),
+                role,
+                permission,
+                channel: channel.cloned(),
+            })
+        }
+    }
+
+    /// Gives `username` `role` in `channel`, or server-wide if `None`, or
+    /// clears the assignment if `role` is `None`.
+    pub fn assign(
+        &mut self,
+        channel: Option<chat::ChannelName>,
+        username: String,
+        role: Option<Role>,
+    ) -> io::Result<()> {
+        let roles = match channel {
+            Some(channel) => {
+                self.assignments.channels.entry(channel).or_default()
+            }
+            None => &mut self.assignments.server,
+        };
+        match role {
+            Some(role) => {
+                roles.insert(username, role);
+            }
+            None => {
+                roles.remove(&username);
+            }
+        }
+        let temporary_path = self.path.with_extension("partial");
+        fs::write(
+            &temporary_path,
+            serde_json::to_vec(&self.assignments).map_err(io::Error::other)?,
+        )?;
+        fs::rename(temporary_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use comms::Permission;
+
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            admins: vec!["root".to_owned()],
+            moderators: vec!["mod".to_owned()],
+            ..Config::default()
+        }
+    }
+
+    fn general() -> chat::ChannelName {
+        chat::ChannelName::default()
+    }
+
+    #[test]
+    fn config_roles_apply_without_assignments() {
+        let directory = tempfile::tempdir().unwrap();
+        let roles = Roles::open(directory.path().join("roles.json")).unwrap();
+        let config = config();
+        assert_eq!(roles.role_of(&config, "root", None), Role::Admin);
+        assert_eq!(
+            roles.role_of(&config, "mod", Some(&general())),
+            Role::Moderator
+        );
+        assert_eq!(roles.role_of(&config, "ethan", None), Role::Member);
+
+        let read_only = Config {
+            default_role: Role::ReadOnly,
+            ..config
+        };
+        assert_eq!(roles.role_of(&read_only, "ethan", None), Role::ReadOnly);
+    }
+
+    #[test]
+    fn channel_assignments_win_over_server_ones() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("roles.json");
+        let mut roles = Roles::open(&path).unwrap();
+        let config = config();
+        let random = chat::ChannelName("random".to_owned());
+        roles
+            .assign(None, "ethan".to_owned(), Some(Role::Moderator))
+            .unwrap();
+        roles
+            .assign(Some(general()), "ethan".to_owned(), Some(Role::ReadOnly))
+            .unwrap();
+        // Assignments don't demote the config's admins.
+        roles
+            .assign(None, "root".to_owned(), Some(Role::ReadOnly))
+            .unwrap();
+        // Or promote over an assignment.
+        roles
+            .assign(None, "mod".to_owned(), Some(Role::Member))
+            .unwrap();
+
+        assert_eq!(
+            roles.role_of(&config, "ethan", Some(&general())),
+            Role::ReadOnly
+        );
+        assert_eq!(
+            roles.role_of(&config, "ethan", Some(&random)),
+            Role::Moderator
+        );
+        assert_eq!(roles.role_of(&config, "root", None), Role::Admin);
+        assert_eq!(roles.role_of(&config, "mod", None), Role::Member);
+
+        roles
+            .assign(Some(general()), "ethan".to_owned(), None)
+            .unwrap();
+        let reopened = Roles::open(&path).unwrap();
+        assert_eq!(
+            reopened.role_of(&config, "ethan", Some(&general())),
+            Role::Moderator
+        );
+    }
+
+    #[test]
+    fn followers_are_read_only() {
+        let directory = tempfile::tempdir().unwrap();
+        let roles = Roles::open(directory.path().join("roles.json")).unwrap();
+        let mut config = config();
+        config.replication.leader_address = Some(([127, 0, 0, 1], 7000).into());
+        assert_eq!(roles.role_of(&config, "root", None), Role::ReadOnly);
+    }
+
+    #[test]
+    fn checks_permissions() {
+        let directory = tempfile::tempdir().unwrap();
+        let roles = Roles::open(directory.path().join("roles.json")).unwrap();
+        let config = config();
+        assert!(roles
+            .check(&config, "ethan", Some(&general()), Permission::Post)
+            .is_ok());
+        assert!(roles
+            .check(&config, "mod", None, Permission::DeleteAny)
+            .is_ok());
+        let denial = roles
+            .check(&config, "mod", Some(&general()), Permission::AssignRoles)
+            .unwrap_err();
+        assert_eq!(denial.username, "mod");
+        assert_eq!(denial.role, Role::Moderator);
+        assert_eq!(denial.permission, Permission::AssignRoles);
+        assert_eq!(denial.channel, Some(general()));
+        assert!(roles
+            .check(&config, "root", None, Permission::EditAny)
+            .is_ok());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+type EntryKey = (chat::ChannelName, usize);
+
+/// Lowercased words of `text` with their byte ranges. A word is a maximal run
+/// of alphanumeric characters.
+fn words(text: &str) -> impl Iterator<Item = (Range<usize>, String)> + '_ {
+    let mut start = None;
+    text.char_indices().chain([(text.len(), ' ')]).filter_map(
+        move |(index, c)| {
+            if c.is_alphanumeric() {
+                start.get_or_insert(index);
+                None
+            } else {
+                start.take().map(|start| {
+                    (start..index, text[start..index].to_lowercase())
+                })
+            }
+        },
+    )
+}
+
+/// Inverted index from words to the entries containing them. Each channel
+/// keeps its own.
+#[derive(Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, HashSet<EntryKey>>,
+    /// The words each indexed entry was filed under, so it can be removed.
+    entry_words: HashMap<EntryKey, HashSet<String>>,
+}
+
+impl SearchIndex {
+    /// Adds `entry` to the index, replacing whatever was indexed for its slot
+    /// before. Deleted entries are removed.
+    pub fn index(&mut self, entry: &chat::Entry) {
+        let key = (entry.channel.clone(), entry.slot_number);
+        self.remove(&key);
+        let Some(text) = entry.text_content() else {
+            return;
+        };
+
+        let entry_words =
+            words(text).map(|(_, word)| word).collect::<HashSet<_>>();
+        for word in &entry_words {
+            self.postings
+                .entry(word.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+        self.entry_words.insert(key, entry_words);
+    }
+
+    /// Removes `entry` from the index, such as when it's compacted away.
+    pub fn forget(&mut self, entry: &chat::Entry) {
+        self.remove(&(entry.channel.clone(), entry.slot_number));
+    }
+
+    fn remove(&mut self, key: &EntryKey) {
+        for word in self.entry_words.remove(key).into_iter().flatten() {
+            if let Some(keys) = self.postings.get_mut(&word) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    fn candidates(&self, query_words: &[String]) -> Vec<EntryKey> {
+        let Some((first, rest)) = query_words.split_first() else {
+            return self.entry_words.keys().cloned().collect();
+        };
+        let Some(first_keys) = self.postings.get(first) else {
+            return vec![];
+        };
+        first_keys
+            .iter()
+            .filter(|key| {
+                rest.iter().all(|word| {
+                    self.postings
+                        .get(word)
+                        .is_some_and(|keys| keys.contains(key))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The entries matching `query` that come after `after`, newest first,
+    /// looking up indexed entries with `lookup`. Returns one more than `limit`
+    /// if there are more, so [`page`] can tell whether there's a next page.
+    pub fn matches<'a>(
+        &self,
+        query: &comms::SearchQuery,
+        limit: usize,
+        after: Option<&comms::SearchCursor>,
+        lookup: impl Fn(&chat::ChannelName, usize) -> Option<&'a chat::Entry>,
+    ) -> Vec<(comms::SearchCursor, comms::SearchHit)> {
+        let query_words =
+            words(&query.text).map(|(_, word)| word).collect::<Vec<_>>();
+        let limit = limit.min(comms::MAX_SEARCH_PAGE_SIZE);
+
+        let mut matching = self
+            .candidates(&query_words)
+            .into_iter()
+            .filter_map(|(channel, slot_number)| lookup(&channel, slot_number))
+            .filter(|entry| matches_filters(query, entry))
+            .map(|entry| (cursor_of(entry), entry))
+            .filter(|(cursor, _)| match after {
+                Some(after) => cursor < after,
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        // Newest first.
+        matching.sort_unstable_by(|(left, _), (right, _)| right.cmp(left));
+
+        matching
+            .into_iter()
+            .take(limit + 1)
+            .map(|(cursor, entry)| {
+                let hit = comms::SearchHit {
+                    matches: match_ranges(entry, &query_words),
+                    entry: entry.clone(),
+                };
+                (cursor, hit)
+            })
+            .collect()
+    }
+}
+
+/// The first page of `limit` hits from the [`SearchIndex::matches`] of one or
+/// more indexes, and where the next page starts if there is one.
+pub fn page(
+    mut matches: Vec<(comms::SearchCursor, comms::SearchHit)>,
+    limit: usize,
+) -> (Vec<comms::SearchHit>, Option<comms::SearchCursor>) {
+    let limit = limit.min(comms::MAX_SEARCH_PAGE_SIZE);
+    matches.sort_unstable_by(|(left, _), (right, _)| right.cmp(left));
+    let next_page = (limit > 0 && matches.len() > limit)
+        .then(|| matches[limit - 1].0.clone());
+    let hits = matches
+        .into_iter()
+        .take(limit)
+        .map(|(_, hit)| hit)
+        .collect();
+    (hits, next_page)
+}
+
+fn matches_filters(query: &comms::SearchQuery, entry: &chat::Entry) -> bool {
+    if let Some(channel) = &query.channel {
+        if entry.channel != *channel {
+            return false;
+        }
+    }
+    if let Some(author) = &query.author {
+        if entry.metadata.username != *author {
+            return false;
+        }
+    }
+    if let Some(since) = query.since {
+        if entry.metadata.timestamp < since {
+            return false;
+        }
+    }
+    if let Some(until) = query.until {
+        if entry.metadata.timestamp > until {
+            return false;
+        }
+    }
+    true
+}
+
+fn cursor_of(entry: &chat::Entry) -> comms::SearchCursor {
+    comms::SearchCursor {
+        timestamp: entry.metadata.timestamp,
+        channel: entry.channel.clone(),
+        slot_number: entry.slot_number,
+    }
+}
+
+fn match_ranges(
+    entry: &chat::Entry,
+    query_words: &[String],
+) -> Vec<Range<usize>> {
+    let Some(text) = entry.text_content() else {
+        return vec![];
+    };
+    words(text)
+        .filter(|(_, word)| query_words.contains(word))
+        .map(|(range, _)| range)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+
+    fn entry(slot_number: usize, text: &str) -> chat::Entry {
+        let mut entry = chat::Entry::new_timestamped_now(
+            chat::ChannelName::default(),
+            slot_number,
+            "ethan".to_owned(),
+            chat::Content::Original(chat::MessageText(text.to_owned())),
+        );
+        // Later slots are newer.
+        entry.metadata.timestamp =
+            DateTime::<Utc>::from_timestamp(slot_number as i64, 0).unwrap();
+        entry
+    }
+
+    fn query(text: &str) -> comms::SearchQuery {
+        comms::SearchQuery {
+            text: text.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn search(
+        index: &SearchIndex,
+        entries: &[chat::Entry],
+        text: &str,
+        limit: usize,
+        after: Option<&comms::SearchCursor>,
+    ) -> (Vec<usize>, Option<comms::SearchCursor>) {
+        let matches =
+            index.matches(&query(text), limit, after, |_, slot_number| {
+                entries.get(slot_number)
+            });
+        let (hits, next_page) = page(matches, limit);
+        let slots = hits.iter().map(|hit| hit.entry.slot_number).collect();
+        (slots, next_page)
+    }
+
+    #[test]
+    fn words_are_lowercased_alphanumeric_runs() {
+        let found = words("Hello, wörld! it's 2024").collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (0..5, "hello".to_owned()),
+                (7..13, "wörld".to_owned()),
+                (15..17, "it".to_owned()),
+                (18..19, "s".to_owned()),
+                (20..24, "2024".to_owned()),
+            ]
+        );
+        assert_eq!(words("  ").count(), 0);
+    }
+
+    #[test]
+    fn finds_entries_containing_every_word() {
+        let entries = [
+            entry(0, "deploy the server"),
+            entry(1, "Server is down"),
+            entry(2, "deploying later"),
+        ];
+        let mut index = SearchIndex::default();
+        for entry in &entries {
+            index.index(entry);
+        }
+        assert_eq!(search(&index, &entries, "server", 10, None).0, [1, 0]);
+        assert_eq!(search(&index, &entries, "SERVER deploy", 10, None).0, [0]);
+        assert!(search(&index, &entries, "missing", 10, None).0.is_empty());
+
+        let matches = index
+            .matches(&query("server"), 10, None, |_, slot| entries.get(slot));
+        assert_eq!(matches[0].1.matches, vec![0..6]);
+    }
+
+    #[test]
+    fn reindexing_replaces_and_forgetting_removes() {
+        let mut entries = vec![entry(0, "old words"), entry(1, "old news")];
+        let mut index = SearchIndex::default();
+        for entry in &entries {
+            index.index(entry);
+        }
+
+        entries[0].content =
+            chat::Content::Edited(chat::MessageText("new words".to_owned()));
+        index.index(&entries[0]);
+        assert_eq!(search(&index, &entries, "old", 10, None).0, [1]);
+        assert_eq!(search(&index, &entries, "new", 10, None).0, [0]);
+
+        entries[1].content = chat::Content::Deleted;
+        index.index(&entries[1]);
+        assert!(search(&index, &entries, "news", 10, None).0.is_empty());
+
+        index.forget(&entries[0]);
+        assert!(index.postings.is_empty());
+        assert!(index.entry_words.is_empty());
+    }
+
+    #[test]
+    fn pages_continue_after_the_cursor() {
+        let entries = (0..5)
+            .map(|slot_number| entry(slot_number, "hello"))
+            .collect::<Vec<_>>();
+        let mut index = SearchIndex::default();
+        for entry in &entries {
+            index.index(entry);
+        }
+
+        let (first, next_page) = search(&index, &entries, "hello", 2, None);
+        assert_eq!(first, [4, 3]);
+        let (second, next_page) =
+            search(&index, &entries, "hello", 2, next_page.as_ref());
+        assert_eq!(second, [2, 1]);
+        let (last, next_page) =
+            search(&index, &entries, "hello", 2, next_page.as_ref());
+        assert_eq!(last, [0]);
+        assert!(next_page.is_none());
+    }
+}
@@ -0,0 +1,2291 @@
+//! Everything the server knows outside of the channels themselves, and how
+//! it handles each client message, routing those about a channel to the
+//! channel's task.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    net,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use comms::admin::SessionId;
+use futures_util::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{mpsc, oneshot, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{
+    attachments::{AttachmentStore, UploadStatus},
+    audit::{AuditLog, Event},
+    broadcast,
+    channel::{self, Change, ChannelHandle, History, NewEntry},
+    chat_log::{self, FakeChatLog},
+    commands,
+    config::Config,
+    federation::{Peer, PeerEvent},
+    moderation::{Ban, Moderation, ModerationError, Mute},
+    read_positions::ReadPositions,
+    recent_posts::RecentPosts,
+    replication::{Follower, FollowerEvent, Record},
+    roles::Roles,
+    search,
+    storage::LogStorage,
+    topics::Topics,
+    webhooks::Webhooks,
+    Outbox, Session, Sessions,
+};
+
+pub struct ServerState {
+    pub config: Config,
+    /// Where `config` is reloaded from.
+    pub config_path: PathBuf,
+    pub started_at: DateTime<Utc>,
+    pub messages_handled: u64,
+    pub channels: HashMap<chat::ChannelName, ChannelHandle>,
+    /// Where new channels' files are split off from.
+    pub log_storage: LogStorage,
+    /// Given to each channel, which sends attachments it no longer refers to.
+    pub released_tx: mpsc::UnboundedSender<Vec<chat::Attachment>>,
+    pub attachment_store: AttachmentStore,
+    pub read_positions: ReadPositions,
+    pub topics: Topics,
+    /// Shared with the listener, which turns away banned addresses.
+    pub moderation: Arc<RwLock<Moderation>>,
+    /// Shared with the listener, which records refused connections.
+    pub audit_log: Arc<AuditLog>,
+    pub roles: Roles,
+    pub sessions: Sessions,
+    /// Where records are streamed to each follower, given to every new
+    /// channel.
+    pub followers: Vec<mpsc::UnboundedSender<Record>>,
+    /// The leader this server follows and the task following it, until
+    /// it's promoted.
+    pub leader: Option<(net::SocketAddr, JoinHandle<()>)>,
+    /// Where entries posted here are relayed to each connected peer, by the
+    /// peer's name, given to every new channel shared with that peer.
+    pub peers: Vec<(String, mpsc::UnboundedSender<chat::Entry>)>,
+    /// Where every new channel sends new entries for webhooks.
+    pub webhooks: Webhooks,
+    /// How commands were settled, so resends after a reconnect aren't run
+    /// twice. Shared with the tasks that finish moderating.
+    pub recent_commands: Arc<Mutex<RecentPosts<Settled>>>,
+}
+
+/// How a command sent as a post was settled.
+#[derive(Clone)]
+pub enum Settled {
+    /// With a reply to its sender.
+    Replied(comms::ServerMessage),
+    /// By posting `entry`, which its channel acknowledges again.
+    Posted(NewEntry),
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+/// Whether `given` is `expected`, comparing digests so how long it takes
+/// doesn't reveal how much of it matched.
+fn same_password(given: &str, expected: &str) -> bool {
+    Sha256::digest(given) == Sha256::digest(expected)
+}
+
+/// Whether a session other than `sender` is logged in as `username`.
+fn is_taken(
+    sessions: &HashMap<SessionId, Session>,
+    sender: SessionId,
+    username: &str,
+) -> bool {
+    sessions.iter().any(|(id, session)| {
+        *id != sender && session.username.as_deref() == Some(username)
+    })
+}
+
+/// Sends `reply` every pair that `answers` come to once they all have, from a
+/// task of its own, so channels slow to answer don't hold up the server.
+fn reply_with_all<K, V>(
+    answers: impl IntoIterator<
+        Item = impl Future<Output = Option<(K, V)>> + Send + 'static,
+    >,
+    reply: oneshot::Sender<HashMap<K, V>>,
+) where
+    K: Eq + Hash + Send + 'static,
+    V: Send + 'static,
+{
+    let answers = future::join_all(answers);
+    tokio::spawn(async move {
+        let _ = reply.send(answers.await.into_iter().flatten().collect());
+    });
+}
+
+/// What a moderation action announces, known once it's carried out or, for
+/// one the channel finishes, once the channel replies.
+enum Carried {
+    Now(String),
+    Later(BoxFuture<'static, Result<String, ModerationError>>),
+}
+
+/// Logs what `moderator` did in `channel` and announces it there, through
+/// `handle`.
+fn announce(
+    channel: chat::ChannelName,
+    handle: &ChannelHandle,
+    audit_log: &AuditLog,
+    moderator: String,
+    action: comms::ModerationAction,
+    reason: Option<String>,
+    announcement: String,
+) {
+    let for_reason = reason
+        .as_ref()
+        .map(|reason| format!(": {}", reason))
+        .unwrap_or_default();
+    tracing::info!(
+        "{} {} in {}{}",
+        moderator,
+        announcement,
+        channel,
+        for_reason
+    );
+    audit_log.record(
+        Some(&moderator),
+        Event::Moderation {
+            channel,
+            action,
+            reason,
+        },
+    );
+    handle.send(channel::Request::Append {
+        entry: NewEntry {
+            kind: chat::EntryKind::Notice,
+            username: moderator,
+            content: announcement + &for_reason,
+            attachments: vec![],
+        },
+        ack: None,
+    });
+}
+
+/// What a banned user is told when they're disconnected.
+fn ban_message(ban: &Ban) -> String {
+    let mut message = "You are banned".to_owned();
+    if let Some(until) = ban.until {
+        message += &format!(" until {}", format_time(until));
+    }
+    if let Some(reason) = &ban.reason {
+        message += &format!(": {}", reason);
+    }
+    message
+}
+
+impl ServerState {
+    pub async fn handle(
+        &mut self,
+        sender: SessionId,
+        message: comms::ClientMessage,
+    ) {
+        self.messages_handled += 1;
+        // Kicked sessions may still have messages queued before the close.
+        if self
+            .sessions
+            .read()
+            .await
+            .get(&sender)
+            .is_some_and(|session| session.is_closed())
+        {
+            return;
+        }
+        match message {
+            comms::ClientMessage::LogIn { username, password } => {
+                if username.contains('@') {
+                    self.refuse_login(
+                        sender,
+                        &username,
+                        "Usernames can't contain @, which marks users on \
+                         other servers"
+                            .to_owned(),
+                    )
+                    .await;
+                    return;
+                }
+                if self.refuse_if_banned(sender, &username).await {
+                    return;
+                }
+                let refusal = self.login_refusal(
+                    &*self.sessions.read().await,
+                    sender,
+                    &username,
+                    password.as_deref(),
+                );
+                if let Some(reason) = refusal {
+                    self.refuse_login(sender, &username, reason).await;
+                    return;
+                }
+                let Some(outbox) = self.outbox_of(sender).await else {
+                    return;
+                };
+                let joins = future::join_all(self.channels.iter().map(
+                    |(channel, handle)| {
+                        let topic = self.topics.get(channel).map(str::to_owned);
+                        let role = self.roles.role_of(
+                            &self.config,
+                            &username,
+                            Some(channel),
+                        );
+                        let joined =
+                            handle.request(|reply| channel::Request::Join {
+                                outbox: outbox.clone(),
+                                username: username.clone(),
+                                last_read_slot: self
+                                    .read_positions
+                                    .get(&username, channel),
+                                reply,
+                            });
+                        async move {
+                            let mut read_state = joined.await.ok()?;
+                            read_state.topic = topic;
+                            read_state.role = role;
+                            Some(read_state)
+                        }
+                    },
+                ));
+                let mut sessions = self.sessions.write().await;
+                let Some(session) = sessions.get_mut(&sender) else {
+                    return;
+                };
+                tracing::info!(%username, "Logged in");
+                self.audit_log.record(
+                    Some(&username),
+                    Event::Login {
+                        address: session.ip(),
+                    },
+                );
+                session.set_username(username);
+                // Every channel has the join queued already, so it comes
+                // before anything else this session asks of it.
+                tokio::spawn(async move {
+                    let channels = joins.await.into_iter().flatten().collect();
+                    outbox.send(comms::ServerMessage::Welcome { channels });
+                });
+            }
+            comms::ClientMessage::MarkRead {
+                channel,
+                slot_number,
+            } => {
+                let Some(username) = self.username_of(sender).await else {
+                    tracing::warn!("Ignoring read position before login");
+                    return;
+                };
+                let Some(handle) = self.channels.get(&channel).cloned() else {
+                    tracing::warn!(
+                        %channel,
+                        "Ignoring read position in missing channel"
+                    );
+                    return;
+                };
+                let mut read_positions = self.read_positions.clone();
+                let sessions = self.sessions.clone();
+                // Asked off the loop, so a busy channel doesn't hold up other
+                // sessions.
+                tokio::spawn(async move {
+                    let Some(head_slot) = handle
+                        .ask(|reply| channel::Request::NextSlot { reply })
+                        .await
+                        .and_then(|next_slot| next_slot.checked_sub(1))
+                    else {
+                        return;
+                    };
+                    // Positions only move forward, so one past the head
+                    // would hide everything posted until it's reached.
+                    let slot_number = slot_number.min(head_slot);
+                    if !read_positions.advance(&username, &channel, slot_number)
+                    {
+                        return;
+                    }
+                    for session in
+                        sessions.read().await.values().filter(|session| {
+                            session.username.as_ref() == Some(&username)
+                        })
+                    {
+                        session.send(comms::ServerMessage::ReadPosition {
+                            channel: channel.clone(),
+                            last_read_slot: slot_number,
+                        });
+                    }
+                });
+            }
+            comms::ClientMessage::Post {
+                nonce,
+                channel,
+                content,
+                mut attachments,
+            } => {
+                let Some(username) = self.username_of(sender).await else {
+                    self.reject_post(
+                        sender,
+                        nonce,
+                        channel,
+                        comms::PostRejection::NotLoggedIn,
+                    )
+                    .await;
+                    return;
+                };
+                let mute = self
+                    .moderation
+                    .read()
+                    .await
+                    .mute_of(&channel, &username, Utc::now())
+                    .cloned();
+                if let Some(mute) = mute {
+                    self.reject_post(
+                        sender,
+                        nonce,
+                        channel,
+                        comms::PostRejection::Muted { until: mute.until },
+                    )
+                    .await;
+                    return;
+                }
+                attachments.retain(|attachment| {
+                    let is_stored = self.attachment_store.contains(attachment);
+                    if !is_stored {
+                        tracing::warn!(
+                            attachment = %attachment.id,
+                            "Dropping reference to unknown attachment"
+                        );
+                    }
+                    is_stored
+                });
+                let parsed = commands::parse(&content);
+                let is_command =
+                    matches!(parsed, commands::Parsed::Command { .. });
+                // Renaming is announced under the new name.
+                let mut poster = username.clone();
+                let (kind, content) = match parsed {
+                    commands::Parsed::Message(message) => {
+                        (chat::EntryKind::Message, message.to_owned())
+                    }
+                    commands::Parsed::Command { name, arguments } => {
+                        let settled = self
+                            .recent_commands
+                            .lock()
+                            .unwrap()
+                            .get(&username, &nonce);
+                        if let Some(settled) = settled {
+                            tracing::info!(?nonce, "Settling resent command");
+                            self.settle_again(sender, channel, nonce, settled)
+                                .await;
+                            return;
+                        }
+                        let context = commands::Context {
+                            username: &username,
+                            channel: &channel,
+                            topic: self.topics.get(&channel),
+                        };
+                        match commands::run(&context, name, arguments) {
+                            commands::Outcome::Post { kind, content } => {
+                                (kind, content)
+                            }
+                            commands::Outcome::Respond(text) => {
+                                self.settle_command(
+                                    sender,
+                                    &username,
+                                    nonce.clone(),
+                                    comms::ServerMessage::CommandResponse {
+                                        nonce,
+                                        channel,
+                                        text,
+                                    },
+                                )
+                                .await;
+                                return;
+                            }
+                            commands::Outcome::SetTopic(topic) => {
+                                let checked = self
+                                    .check_post(
+                                        &username,
+                                        &channel,
+                                        chat::EntryKind::Notice,
+                                    )
+                                    .and_then(|()| {
+                                        self.roles.check(
+                                            &self.config,
+                                            &username,
+                                            Some(&channel),
+                                            comms::Permission::SetTopic,
+                                        )
+                                    });
+                                if let Err(denial) = checked {
+                                    self.settle_command(
+                                        sender,
+                                        &username,
+                                        nonce.clone(),
+                                        comms::ServerMessage::PostRejected {
+                                            nonce,
+                                            channel,
+                                            reason: comms::PostRejection::PermissionDenied(denial),
+                                        },
+                                    )
+                                    .await;
+                                    return;
+                                }
+                                if let Err(error) = self
+                                    .topics
+                                    .set(channel.clone(), topic.clone())
+                                {
+                                    tracing::error!(
+                                        "Failed to save topic of {}: {}",
+                                        channel,
+                                        error
+                                    );
+                                }
+                                self.broadcast(&comms::ServerMessage::Topic {
+                                    channel: channel.clone(),
+                                    topic: topic.clone(),
+                                })
+                                .await;
+                                (
+                                    chat::EntryKind::Notice,
+                                    format!("set the topic to: {}", topic),
+                                )
+                            }
+                            commands::Outcome::Rename(new_username) => {
+                                if let Err(denial) = self.check_post(
+                                    &username,
+                                    &channel,
+                                    chat::EntryKind::Notice,
+                                ) {
+                                    self.settle_command(
+                                        sender,
+                                        &username,
+                                        nonce.clone(),
+                                        comms::ServerMessage::PostRejected {
+                                            nonce,
+                                            channel,
+                                            reason: comms::PostRejection::PermissionDenied(denial),
+                                        },
+                                    )
+                                    .await;
+                                    return;
+                                }
+                                if self
+                                    .refuse_if_banned(sender, &new_username)
+                                    .await
+                                {
+                                    return;
+                                }
+                                let mut sessions = self.sessions.write().await;
+                                let refusal = self.rename_refusal(
+                                    &sessions,
+                                    sender,
+                                    &new_username,
+                                );
+                                if let Some(text) = refusal {
+                                    drop(sessions);
+                                    self.settle_command(
+                                        sender,
+                                        &username,
+                                        nonce.clone(),
+                                        comms::ServerMessage::CommandResponse {
+                                            nonce,
+                                            channel,
+                                            text,
+                                        },
+                                    )
+                                    .await;
+                                    return;
+                                }
+                                let Some(session) = sessions.get_mut(&sender)
+                                else {
+                                    return;
+                                };
+                                tracing::info!(
+                                    %username,
+                                    %new_username,
+                                    "Renamed"
+                                );
+                                self.audit_log.record(
+                                    Some(&username),
+                                    Event::Rename {
+                                        username: new_username.clone(),
+                                    },
+                                );
+                                session.set_username(new_username.clone());
+                                session.send(
+                                    comms::ServerMessage::UsernameChanged {
+                                        username: new_username.clone(),
+                                    },
+                                );
+                                poster = new_username;
+                                (
+                                    chat::EntryKind::Notice,
+                                    format!(
+                                        "changed their name from {}",
+                                        username
+                                    ),
+                                )
+                            }
+                            commands::Outcome::Moderate { action, reason } => {
+                                let Some(outbox) = self.outbox_of(sender).await
+                                else {
+                                    return;
+                                };
+                                let recent_commands =
+                                    self.recent_commands.clone();
+                                let moderator = username.clone();
+                                let responding = channel.clone();
+                                let respond =
+                                    move |result: Result<
+                                        (),
+                                        ModerationError,
+                                    >| {
+                                        let text = match result {
+                                            Ok(()) => "Done".to_owned(),
+                                            Err(error) => error.to_string(),
+                                        };
+                                        let reply = comms::ServerMessage::CommandResponse {
+                                        nonce: nonce.clone(),
+                                        channel: responding,
+                                        text,
+                                    };
+                                        recent_commands.lock().unwrap().insert(
+                                            moderator,
+                                            nonce,
+                                            Settled::Replied(reply.clone()),
+                                        );
+                                        outbox.send(reply);
+                                    };
+                                self.moderate(
+                                    sender, &channel, action, reason, respond,
+                                )
+                                .await;
+                                return;
+                            }
+                            commands::Outcome::AssignRole {
+                                username: assignee,
+                                role,
+                            } => {
+                                let text = match self
+                                    .assign_role(
+                                        sender,
+                                        Some(channel.clone()),
+                                        assignee,
+                                        role,
+                                    )
+                                    .await
+                                {
+                                    Ok(()) => "Done".to_owned(),
+                                    Err(error) => error.to_string(),
+                                };
+                                self.settle_command(
+                                    sender,
+                                    &username,
+                                    nonce.clone(),
+                                    comms::ServerMessage::CommandResponse {
+                                        nonce,
+                                        channel,
+                                        text,
+                                    },
+                                )
+                                .await;
+                                return;
+                            }
+                        }
+                    }
+                };
+                if let Err(denial) = self.check_post(&username, &channel, kind)
+                {
+                    let reason = comms::PostRejection::PermissionDenied(denial);
+                    if is_command {
+                        self.settle_command(
+                            sender,
+                            &username,
+                            nonce.clone(),
+                            comms::ServerMessage::PostRejected {
+                                nonce,
+                                channel,
+                                reason,
+                            },
+                        )
+                        .await;
+                    } else {
+                        self.reject_post(sender, nonce, channel, reason).await;
+                    }
+                    return;
+                }
+                let Some(outbox) = self.outbox_of(sender).await else {
+                    return;
+                };
+                let entry = NewEntry {
+                    kind,
+                    username: poster,
+                    content,
+                    attachments,
+                };
+                if is_command {
+                    let mut recent_commands =
+                        self.recent_commands.lock().unwrap();
+                    // A rename is resent under either name, depending on
+                    // which the client logs back in with.
+                    if entry.username != username {
+                        recent_commands.insert(
+                            entry.username.clone(),
+                            nonce.clone(),
+                            Settled::Posted(entry.clone()),
+                        );
+                    }
+                    recent_commands.insert(
+                        username,
+                        nonce.clone(),
+                        Settled::Posted(entry.clone()),
+                    );
+                }
+                self.channel(&channel).await.send(channel::Request::Append {
+                    entry,
+                    ack: Some((outbox, nonce)),
+                });
+            }
+            comms::ClientMessage::Request {
+                client_id,
+                channel,
+                count,
+                up_to_slot_number,
+            } => {
+                self.request_history(
+                    sender,
+                    client_id,
+                    &channel,
+                    History::Before {
+                        count,
+                        up_to_slot_number,
+                    },
+                )
+                .await;
+            }
+            comms::ClientMessage::RequestAfter {
+                client_id,
+                channel,
+                after_slot_number,
+                limit,
+            } => {
+                self.request_history(
+                    sender,
+                    client_id,
+                    &channel,
+                    History::After {
+                        after_slot_number,
+                        limit,
+                    },
+                )
+                .await;
+            }
+            comms::ClientMessage::Edit {
+                channel,
+                slot_number,
+                content,
+            } => {
+                self.change_entry(
+                    sender,
+                    &channel,
+                    slot_number,
+                    Change::Edit(content),
+                )
+                .await;
+            }
+            comms::ClientMessage::Delete {
+                channel,
+                slot_number,
+            } => {
+                self.change_entry(
+                    sender,
+                    &channel,
+                    slot_number,
+                    Change::Delete,
+                )
+                .await;
+            }
+            comms::ClientMessage::Moderate {
+                channel,
+                action,
+                reason,
+            } => {
+                let Some(outbox) = self.outbox_of(sender).await else {
+                    return;
+                };
+                let failed = action.clone();
+                let respond = move |result| {
+                    outbox.send(match result {
+                        Ok(()) => return,
+                        Err(ModerationError::PermissionDenied(denial)) => {
+                            comms::ServerMessage::PermissionDenied(denial)
+                        }
+                        Err(error) => comms::ServerMessage::ModerationFailed {
+                            action: failed,
+                            reason: error.to_string(),
+                        },
+                    });
+                };
+                self.moderate(sender, &channel, action, reason, respond)
+                    .await;
+            }
+            comms::ClientMessage::AssignRole {
+                channel,
+                username,
+                role,
+            } => {
+                match self.assign_role(sender, channel, username, role).await {
+                    Ok(()) => {}
+                    Err(ModerationError::PermissionDenied(denial)) => {
+                        self.reply(
+                            sender,
+                            comms::ServerMessage::PermissionDenied(denial),
+                        )
+                        .await;
+                    }
+                    Err(error) => {
+                        tracing::warn!(%error, "Failed to assign role");
+                    }
+                }
+            }
+            comms::ClientMessage::ListCommands { client_id } => {
+                self.reply(
+                    sender,
+                    comms::ServerMessage::CommandList {
+                        client_id,
+                        commands: commands::COMMANDS
+                            .iter()
+                            .map(commands::Command::info)
+                            .collect(),
+                    },
+                )
+                .await;
+            }
+            comms::ClientMessage::Search {
+                client_id,
+                query,
+                limit,
+                after,
+            } => {
+                let Some(outbox) = self.outbox_of(sender).await else {
+                    return;
+                };
+                let search = self.search(query, limit, after);
+                tokio::spawn(async move {
+                    let (hits, next_page) = search.await;
+                    outbox.send(comms::ServerMessage::SearchResults {
+                        client_id,
+                        hits,
+                        next_page,
+                    });
+                });
+            }
+            comms::ClientMessage::UploadBegin {
+                client_id,
+                file_name,
+                size,
+                id,
+            } => {
+                let Some(username) = self.username_of(sender).await else {
+                    self.reply(
+                        sender,
+                        comms::ServerMessage::AttachmentFailure {
+                            client_id,
+                            error: comms::AttachmentError::NotLoggedIn,
+                        },
+                    )
+                    .await;
+                    return;
+                };
+                if let Err(denial) = self.roles.check(
+                    &self.config,
+                    &username,
+                    None,
+                    comms::Permission::Upload,
+                ) {
+                    self.reply(
+                        sender,
+                        comms::ServerMessage::AttachmentFailure {
+                            client_id,
+                            error: comms::AttachmentError::PermissionDenied(
+                                denial,
+                            ),
+                        },
+                    )
+                    .await;
+                    return;
+                }
+                if let Err(error) = self.attachment_store.begin(
+                    sender,
+                    client_id.clone(),
+                    file_name,
+                    size,
+                    id,
+                ) {
+                    self.reply(
+                        sender,
+                        comms::ServerMessage::AttachmentFailure {
+                            client_id,
+                            error,
+                        },
+                    )
+                    .await;
+                }
+            }
+            comms::ClientMessage::UploadChunk {
+                client_id,
+                offset,
+                bytes,
+            } => {
+                let reply = match self.attachment_store.receive_chunk(
+                    sender,
+                    client_id.clone(),
+                    offset,
+                    bytes,
+                ) {
+                    Ok(UploadStatus::InProgress { received }) => {
+                        comms::ServerMessage::UploadProgress {
+                            client_id,
+                            received,
+                        }
+                    }
+                    Ok(UploadStatus::Complete(attachment)) => {
+                        comms::ServerMessage::UploadComplete {
+                            client_id,
+                            attachment,
+                        }
+                    }
+                    Err(error) => comms::ServerMessage::AttachmentFailure {
+                        client_id,
+                        error,
+                    },
+                };
+                self.reply(sender, reply).await;
+            }
+            comms::ClientMessage::Download { client_id, id } => {
+                let sessions = self.sessions.read().await;
+                let Some(session) = sessions.get(&sender) else {
+                    return;
+                };
+                match self.attachment_store.read(&id) {
+                    Ok(bytes) => {
+                        let total_size = bytes.len() as u64;
+                        // An empty attachment still needs one chunk to tell
+                        // the client its size.
+                        let mut chunks = bytes
+                            .chunks(comms::ATTACHMENT_CHUNK_SIZE)
+                            .peekable();
+                        if chunks.peek().is_none() {
+                            session.send(comms::ServerMessage::DownloadChunk {
+                                client_id: client_id.clone(),
+                                id: id.clone(),
+                                offset: 0,
+                                total_size,
+                                bytes: vec![],
+                            });
+                        }
+                        for (index, chunk) in chunks.enumerate() {
+                            session.send(comms::ServerMessage::DownloadChunk {
+                                client_id: client_id.clone(),
+                                id: id.clone(),
+                                offset: (index * comms::ATTACHMENT_CHUNK_SIZE)
+                                    as u64,
+                                total_size,
+                                bytes: chunk.to_vec(),
+                            });
+                        }
+                    }
+                    Err(error) => {
+                        session.send(comms::ServerMessage::AttachmentFailure {
+                            client_id,
+                            error,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Closes the session at `sender`, which tried to log in or rename itself
+    /// as `username`, telling it `reason`.
+    async fn refuse_login(
+        &self,
+        sender: SessionId,
+        username: &str,
+        reason: String,
+    ) {
+        tracing::info!(%username, %reason, "Refusing username");
+        if let Some(session) = self.sessions.write().await.get_mut(&sender) {
+            self.audit_log.record(
+                Some(username),
+                Event::LoginRefused {
+                    address: session.ip(),
+                    reason: reason.clone(),
+                },
+            );
+            session.close(reason);
+        }
+    }
+
+    /// Closes the session at `sender` if `username`, which it's logging in
+    /// or renaming itself as, is banned, returning whether it was.
+    async fn refuse_if_banned(
+        &self,
+        sender: SessionId,
+        username: &str,
+    ) -> bool {
+        let ban = self
+            .moderation
+            .read()
+            .await
+            .ban_of(username, Utc::now())
+            .cloned();
+        let Some(ban) = ban else {
+            return false;
+        };
+        self.refuse_login(sender, username, ban_message(&ban)).await;
+        true
+    }
+
+    /// Why the session at `sender` can't log in as `username` with
+    /// `password`, since logging in mustn't pick up someone else's role or
+    /// identity.
+    fn login_refusal(
+        &self,
+        sessions: &HashMap<SessionId, Session>,
+        sender: SessionId,
+        username: &str,
+        password: Option<&str>,
+    ) -> Option<String> {
+        if let Some(expected) = self.config.passwords.get(username) {
+            // The password proves who it is, so it may log in more than once.
+            let matches = password
+                .is_some_and(|password| same_password(password, expected));
+            return (!matches)
+                .then(|| format!("Wrong password for {}", username));
+        }
+        if self.roles.is_privileged(&self.config, username) {
+            return Some(format!("{} needs a password", username));
+        }
+        is_taken(sessions, sender, username)
+            .then(|| format!("{} is taken", username))
+    }
+
+    /// Why the session at `sender` can't rename itself to `username`, since
+    /// renaming mustn't pick up someone else's role or identity.
+    fn rename_refusal(
+        &self,
+        sessions: &HashMap<SessionId, Session>,
+        sender: SessionId,
+        username: &str,
+    ) -> Option<String> {
+        let is_privileged = self
+            .config
+            .admins
+            .iter()
+            .chain(&self.config.moderators)
+            .chain(self.config.passwords.keys())
+            .any(|privileged| privileged == username);
+        if is_privileged || self.roles.is_assigned(username) {
+            return Some(format!("{} is reserved", username));
+        }
+        if is_taken(sessions, sender, username) {
+            return Some(format!("{} is taken", username));
+        }
+        None
+    }
+
+    /// The username the session at `sender` logged in as, if it has.
+    async fn username_of(&self, sender: SessionId) -> Option<String> {
+        self.sessions
+            .read()
+            .await
+            .get(&sender)
+            .and_then(|session| session.username.clone())
+    }
+
+    /// Whether `username` may commit an entry of `kind` to `channel`. Notices
+    /// announce something else the user was allowed to do, so they only
+    /// need the channel to exist or be creatable, except on a follower, which
+    /// takes every entry from its leader and so refuses them like any other
+    /// post.
+    fn check_post(
+        &self,
+        username: &str,
+        channel: &chat::ChannelName,
+        kind: chat::EntryKind,
+    ) -> Result<(), comms::PermissionDenied> {
+        if !self.channels.contains_key(channel) {
+            self.roles.check(
+                &self.config,
+                username,
+                Some(channel),
+                comms::Permission::CreateChannel,
+            )?;
+        }
+        if kind == chat::EntryKind::Notice && self.leader.is_none() {
+            return Ok(());
+        }
+        self.roles.check(
+            &self.config,
+            username,
+            Some(channel),
+            comms::Permission::Post,
+        )
+    }
+
+    async fn reject_post(
+        &self,
+        sender: SessionId,
+        nonce: comms::Nonce,
+        channel: chat::ChannelName,
+        reason: comms::PostRejection,
+    ) {
+        tracing::info!(%channel, %reason, "Rejected post");
+        self.reply(
+            sender,
+            comms::ServerMessage::PostRejected {
+                nonce,
+                channel,
+                reason,
+            },
+        )
+        .await;
+    }
+
+    /// Sends `reply` to `username`'s command with `nonce`, remembering it so a
+    /// resend gets it again instead of running the command twice.
+    async fn settle_command(
+        &self,
+        sender: SessionId,
+        username: &str,
+        nonce: comms::Nonce,
+        reply: comms::ServerMessage,
+    ) {
+        self.recent_commands.lock().unwrap().insert(
+            username.to_owned(),
+            nonce,
+            Settled::Replied(reply.clone()),
+        );
+        self.reply(sender, reply).await;
+    }
+
+    /// Settles a resent command in `channel` the way it was settled before.
+    async fn settle_again(
+        &mut self,
+        sender: SessionId,
+        channel: chat::ChannelName,
+        nonce: comms::Nonce,
+        settled: Settled,
+    ) {
+        match settled {
+            Settled::Replied(reply) => self.reply(sender, reply).await,
+            Settled::Posted(entry) => {
+                let Some(outbox) = self.outbox_of(sender).await else {
+                    return;
+                };
+                self.channel(&channel).await.send(channel::Request::Append {
+                    entry,
+                    ack: Some((outbox, nonce)),
+                });
+            }
+        }
+    }
+
+    /// Has `channel`'s task edit or delete an entry for the user logged in at
+    /// `sender`.
+    async fn change_entry(
+        &mut self,
+        sender: SessionId,
+        channel: &chat::ChannelName,
+        slot_number: usize,
+        change: Change,
+    ) {
+        let Some(username) = self.username_of(sender).await else {
+            tracing::warn!("Ignoring change before login");
+            return;
+        };
+        let Some(handle) = self.channels.get(channel) else {
+            tracing::warn!(
+                %channel,
+                slot_number,
+                "Rejected change to missing slot"
+            );
+            return;
+        };
+        let Some(outbox) = self.outbox_of(sender).await else {
+            return;
+        };
+        let role = self.roles.role_of(&self.config, &username, Some(channel));
+        handle.send(channel::Request::Change {
+            outbox,
+            username,
+            role,
+            slot_number,
+            change,
+        });
+    }
+
+    /// Gives `username` `role` in `channel`, or server-wide, for the admin
+    /// logged in at `sender`, then tells everyone.
+    async fn assign_role(
+        &mut self,
+        sender: SessionId,
+        channel: Option<chat::ChannelName>,
+        username: String,
+        role: Option<comms::Role>,
+    ) -> Result<(), ModerationError> {
+        let admin = self
+            .username_of(sender)
+            .await
+            .ok_or(ModerationError::NotLoggedIn)?;
+        self.roles
+            .check(
+                &self.config,
+                &admin,
+                channel.as_ref(),
+                comms::Permission::AssignRoles,
+            )
+            .map_err(ModerationError::PermissionDenied)?;
+        self.roles
+            .assign(channel.clone(), username.clone(), role)
+            .map_err(ModerationError::Storage)?;
+        self.audit_log.record(
+            Some(&admin),
+            Event::RoleChange {
+                channel: channel.clone(),
+                username: username.clone(),
+                role,
+            },
+        );
+        self.broadcast(&comms::ServerMessage::RoleAssigned {
+            channel: channel.clone(),
+            username: username.clone(),
+            role,
+        })
+        .await;
+        if let Some(channel) = channel {
+            let announcement = match role {
+                Some(role) => format!("made {} {}", username, role),
+                None => format!("cleared {}'s role", username),
+            };
+            self.post_notice(&channel, admin, announcement).await;
+        }
+        Ok(())
+    }
+
+    /// Sends `message` to every session, encoding it only once.
+    pub async fn broadcast(&self, message: &comms::ServerMessage) {
+        let encoded = broadcast::Encoded::new(message);
+        for session in self.sessions.read().await.values() {
+            session.outbox.send_encoded(&encoded);
+        }
+    }
+
+    /// Sends `message` to the session at `sender`, unless it has
+    /// disconnected.
+    async fn reply(&self, sender: SessionId, message: comms::ServerMessage) {
+        if let Some(session) = self.sessions.read().await.get(&sender) {
+            session.send(message);
+        }
+    }
+
+    /// The outbox of the session at `sender`, unless it has disconnected.
+    async fn outbox_of(&self, sender: SessionId) -> Option<Outbox> {
+        self.sessions
+            .read()
+            .await
+            .get(&sender)
+            .map(|session| session.outbox.clone())
+    }
+
+    /// The task for `channel`, started now if the channel is new, with every
+    /// logged-in session subscribed.
+    async fn channel(&mut self, channel: &chat::ChannelName) -> &ChannelHandle {
+        if !self.channels.contains_key(channel) {
+            let subscribers = self
+                .sessions
+                .read()
+                .await
+                .values()
+                .filter(|session| {
+                    session.username.is_some() && !session.is_closed()
+                })
+                .map(|session| session.outbox.clone())
+                .collect();
+            let mut chat_log = FakeChatLog::new(channel.clone());
+            chat_log.set_server(self.config.federation.name.clone());
+            let handle = channel::spawn(
+                chat_log,
+                self.log_storage.split_off(channel),
+                self.config.retention_for(channel).clone(),
+                subscribers,
+                channel::Streams {
+                    followers: self.followers.clone(),
+                    peers: self
+                        .peers
+                        .iter()
+                        .filter(|(server, _)| {
+                            self.config.federation.shares(server, channel)
+                        })
+                        .cloned()
+                        .collect(),
+                    webhooks: self.webhooks.entries_tx(),
+                },
+                self.audit_log.clone(),
+                self.released_tx.clone(),
+            );
+            self.channels.insert(channel.clone(), handle);
+        }
+        &self.channels[channel]
+    }
+
+    /// Commits a notice announcing what `username` did.
+    async fn post_notice(
+        &mut self,
+        channel: &chat::ChannelName,
+        username: String,
+        content: String,
+    ) {
+        self.channel(channel).await.send(channel::Request::Append {
+            entry: NewEntry {
+                kind: chat::EntryKind::Notice,
+                username,
+                content,
+                attachments: vec![],
+            },
+            ack: None,
+        });
+    }
+
+    /// A page of the hits for `query` across channels, newest first, and
+    /// where the next page starts. The channels are asked now, and the page
+    /// is waited for off the server's loop.
+    pub fn search(
+        &self,
+        query: comms::SearchQuery,
+        limit: usize,
+        after: Option<comms::SearchCursor>,
+    ) -> impl Future<Output = (Vec<comms::SearchHit>, Option<comms::SearchCursor>)>
+           + Send
+           + 'static {
+        let searches = future::join_all(
+            self.channels
+                .iter()
+                .filter(|(channel, _)| {
+                    query.channel.is_none()
+                        || query.channel.as_ref() == Some(*channel)
+                })
+                .map(|(_, handle)| {
+                    handle.request(|reply| channel::Request::Search {
+                        query: query.clone(),
+                        limit,
+                        after: after.clone(),
+                        reply,
+                    })
+                }),
+        );
+        async move {
+            let matches =
+                searches.await.into_iter().flatten().flatten().collect();
+            search::page(matches, limit)
+        }
+    }
+
+    async fn request_history(
+        &self,
+        sender: SessionId,
+        client_id: comms::ClientId,
+        channel: &chat::ChannelName,
+        range: History,
+    ) {
+        let Some(outbox) = self.outbox_of(sender).await else {
+            return;
+        };
+        match self.channels.get(channel) {
+            Some(handle) => handle.send(channel::Request::History {
+                outbox,
+                client_id,
+                range,
+            }),
+            None => outbox.send(comms::ServerMessage::EntryRange {
+                client_id,
+                entries: vec![],
+                head_slot: None,
+                history_start: 0,
+            }),
+        }
+    }
+
+    /// Forgets `session` once its connection has ended, along with any
+    /// uploads it didn't finish.
+    pub async fn end_session(&mut self, session: SessionId) {
+        self.attachment_store.forget_session(session);
+        if let Some(session) = self.sessions.write().await.remove(&session) {
+            tracing::info!(parent: &session.span, "Session ended");
+        }
+    }
+
+    /// Closes every session logged in as `username`, returning the addresses
+    /// that banning them should cover.
+    async fn disconnect(
+        &self,
+        username: &str,
+        reason: &str,
+    ) -> Vec<net::IpAddr> {
+        let mut sessions = self.sessions.write().await;
+        let mut addresses = vec![];
+        for session in sessions.values_mut() {
+            if session.username.as_deref() == Some(username) {
+                session.close(reason.to_owned());
+                addresses.extend(session.origin.bannable_ip());
+            }
+        }
+        addresses
+    }
+
+    /// Carries out `action` in `channel` for the moderator logged in at
+    /// `sender`, then logs it, announces it in the channel, and passes
+    /// `respond` the outcome. Removing an entry finishes in a task of its own
+    /// once the channel has removed it.
+    async fn moderate(
+        &mut self,
+        sender: SessionId,
+        channel: &chat::ChannelName,
+        action: comms::ModerationAction,
+        reason: Option<String>,
+        respond: impl FnOnce(Result<(), ModerationError>) + Send + 'static,
+    ) {
+        let moderator = match self.authorize(sender, channel, &action).await {
+            Ok(moderator) => moderator,
+            Err(error) => return respond(Err(error)),
+        };
+        match self.carry_out(channel, &moderator, &action, &reason).await {
+            Ok(Carried::Now(announcement)) => {
+                let handle = self.channel(channel).await.clone();
+                announce(
+                    channel.clone(),
+                    &handle,
+                    &self.audit_log,
+                    moderator,
+                    action,
+                    reason,
+                    announcement,
+                );
+                respond(Ok(()));
+            }
+            Ok(Carried::Later(announcement)) => {
+                let channel = channel.clone();
+                let handle = self.channel(&channel).await.clone();
+                let audit_log = self.audit_log.clone();
+                tokio::spawn(async move {
+                    match announcement.await {
+                        Ok(announcement) => {
+                            announce(
+                                channel,
+                                &handle,
+                                &audit_log,
+                                moderator,
+                                action,
+                                reason,
+                                announcement,
+                            );
+                            respond(Ok(()));
+                        }
+                        Err(error) => respond(Err(error)),
+                    }
+                });
+            }
+            Err(error) => respond(Err(error)),
+        }
+    }
+
+    /// The username of the moderator logged in at `sender`, if they may carry
+    /// out `action` in `channel`.
+    async fn authorize(
+        &self,
+        sender: SessionId,
+        channel: &chat::ChannelName,
+        action: &comms::ModerationAction,
+    ) -> Result<String, ModerationError> {
+        let moderator = self
+            .username_of(sender)
+            .await
+            .ok_or(ModerationError::NotLoggedIn)?;
+        let permission = match action {
+            comms::ModerationAction::RemoveEntry { .. } => {
+                comms::Permission::DeleteAny
+            }
+            _ => comms::Permission::Moderate,
+        };
+        // Kicks and bans act on the whole server, so they go by server-wide
+        // roles, not the moderator's role in the channel they're sent in.
+        let scope = match action {
+            comms::ModerationAction::Kick { .. }
+            | comms::ModerationAction::Ban { .. }
+            | comms::ModerationAction::Unban { .. } => None,
+            comms::ModerationAction::Mute { .. }
+            | comms::ModerationAction::Unmute { .. }
+            | comms::ModerationAction::RemoveEntry { .. } => Some(channel),
+        };
+        self.roles
+            .check(&self.config, &moderator, scope, permission)
+            .map_err(ModerationError::PermissionDenied)?;
+        if let comms::ModerationAction::Kick { username }
+        | comms::ModerationAction::Ban { username, .. }
+        | comms::ModerationAction::Mute { username, .. } = action
+        {
+            let role = self.roles.role_of(&self.config, username, scope);
+            if role >= self.roles.role_of(&self.config, &moderator, scope) {
+                return Err(ModerationError::Outranked {
+                    username: username.clone(),
+                    role,
+                });
+            }
+        }
+        Ok(moderator)
+    }
+
+    /// Carries out `action` in `channel` for `moderator`, returning what to
+    /// announce.
+    async fn carry_out(
+        &self,
+        channel: &chat::ChannelName,
+        moderator: &str,
+        action: &comms::ModerationAction,
+        reason: &Option<String>,
+    ) -> Result<Carried, ModerationError> {
+        let now = Utc::now();
+        let for_reason = reason
+            .as_ref()
+            .map(|reason| format!(": {}", reason))
+            .unwrap_or_default();
+        let until = |until: Option<DateTime<Utc>>| {
+            until
+                .map(|until| format!(" until {}", format_time(until)))
+                .unwrap_or_default()
+        };
+
+        let announcement = match action {
+            comms::ModerationAction::Kick { username } => {
+                self.disconnect(
+                    username,
+                    &format!("You were kicked by {}{}", moderator, for_reason),
+                )
+                .await;
+                format!("kicked {}", username)
+            }
+            comms::ModerationAction::Ban {
+                username,
+                until: ban_until,
+            } => {
+                let mut ban = Ban {
+                    until: *ban_until,
+                    reason: reason.clone(),
+                    addresses: vec![],
+                };
+                ban.addresses =
+                    self.disconnect(username, &ban_message(&ban)).await;
+                ban.addresses.sort();
+                ban.addresses.dedup();
+                self.moderation
+                    .write()
+                    .await
+                    .ban(username.clone(), ban)
+                    .map_err(ModerationError::Storage)?;
+                format!("banned {}{}", username, until(*ban_until))
+            }
+            comms::ModerationAction::Unban { username } => {
+                let was_banned = self
+                    .moderation
+                    .write()
+                    .await
+                    .unban(username, now)
+                    .map_err(ModerationError::Storage)?;
+                if !was_banned {
+                    return Err(ModerationError::NotBanned(username.clone()));
+                }
+                format!("unbanned {}", username)
+            }
+            comms::ModerationAction::Mute {
+                username,
+                until: mute_until,
+            } => {
+                self.moderation
+                    .write()
+                    .await
+                    .mute(
+                        channel.clone(),
+                        username.clone(),
+                        Mute {
+                            until: *mute_until,
+                            reason: reason.clone(),
+                        },
+                    )
+                    .map_err(ModerationError::Storage)?;
+                format!("muted {}{}", username, until(*mute_until))
+            }
+            comms::ModerationAction::Unmute { username } => {
+                let was_muted = self
+                    .moderation
+                    .write()
+                    .await
+                    .unmute(channel, username, now)
+                    .map_err(ModerationError::Storage)?;
+                if !was_muted {
+                    return Err(ModerationError::NotMuted(username.clone()));
+                }
+                format!("unmuted {}", username)
+            }
+            comms::ModerationAction::RemoveEntry { slot_number } => {
+                let slot_number = *slot_number;
+                let no_such_slot = move || {
+                    ModerationError::Change(chat_log::ChangeError::NoSuchSlot(
+                        slot_number,
+                    ))
+                };
+                let removed = self
+                    .channels
+                    .get(channel)
+                    .ok_or_else(no_such_slot)?
+                    .request(|reply| channel::Request::Remove {
+                        slot_number,
+                        reply,
+                    });
+                return Ok(Carried::Later(
+                    async move {
+                        let entry = removed
+                            .await
+                            .map_err(|_| no_such_slot())?
+                            .map_err(ModerationError::Change)?;
+                        Ok(format!(
+                            "removed a message by {} (slot {})",
+                            entry.metadata.username, slot_number
+                        ))
+                    }
+                    .boxed(),
+                ));
+            }
+        };
+        Ok(Carried::Now(announcement))
+    }
+
+    /// Applies every channel's retention policy, unless this is a follower,
+    /// whose channels are compacted when the leader's are.
+    pub fn compact_all(&self) {
+        if self.leader.is_some() {
+            return;
+        }
+        for handle in self.channels.values() {
+            handle.send(channel::Request::Compact);
+        }
+    }
+
+    /// Deletes `attachments` that a channel released unless another channel
+    /// still refers to them. Posts can't refer to them until that's decided,
+    /// so none can start referring to them meanwhile.
+    pub fn release_attachments(&self, attachments: Vec<chat::Attachment>) {
+        let references: Vec<_> = self
+            .channels
+            .values()
+            .map(|handle| {
+                handle.request(|reply| channel::Request::References {
+                    attachments: attachments.clone(),
+                    reply,
+                })
+            })
+            .collect();
+        let release = self.attachment_store.release(attachments);
+        tokio::spawn(async move {
+            let referenced: Vec<_> = future::join_all(references)
+                .await
+                .into_iter()
+                .flatten()
+                .flatten()
+                .collect();
+            release.finish(&referenced);
+        });
+    }
+
+    /// Catches `follower` up on every channel and streams it everything
+    /// stored from then on, including in channels created later.
+    pub fn add_follower(&mut self, follower: Follower) {
+        for (channel, handle) in &self.channels {
+            handle.send(channel::Request::Follow {
+                records_tx: follower.records_tx.clone(),
+                next_slot: follower
+                    .next_slots
+                    .get(channel)
+                    .copied()
+                    .unwrap_or_default(),
+            });
+        }
+        self.followers.retain(|records_tx| !records_tx.is_closed());
+        self.followers.push(follower.records_tx);
+    }
+
+    /// Applies what the task following the leader received. Anything still
+    /// queued when this server was promoted is dropped.
+    pub async fn handle_follower_event(&mut self, event: FollowerEvent) {
+        if self.leader.is_none() {
+            return;
+        }
+        match event {
+            FollowerEvent::NextSlots(reply) => {
+                let next_slots =
+                    self.channels.iter().map(|(channel, handle)| {
+                        let channel = channel.clone();
+                        let next_slot = handle.request(|reply| {
+                            channel::Request::NextSlot { reply }
+                        });
+                        async move { Some((channel, next_slot.await.ok()?)) }
+                    });
+                reply_with_all(next_slots, reply);
+            }
+            FollowerEvent::Record(record) => {
+                let channel = record.channel().clone();
+                self.channel(&channel)
+                    .await
+                    .send(channel::Request::Replicate(record));
+            }
+        }
+    }
+
+    /// Catches `peer` up on every channel it shares with this server and
+    /// relays it everything posted there from then on, including in shared
+    /// channels created later.
+    pub fn add_peer(&mut self, peer: Peer) {
+        for (channel, handle) in &self.channels {
+            if !self.config.federation.shares(&peer.server, channel) {
+                continue;
+            }
+            handle.send(channel::Request::Federate {
+                server: peer.server.clone(),
+                entries_tx: peer.entries_tx.clone(),
+                next_slot: peer
+                    .next_slots
+                    .get(channel)
+                    .copied()
+                    .unwrap_or_default(),
+            });
+        }
+        self.peers.retain(|(server, entries_tx)| {
+            *server != peer.server && !entries_tx.is_closed()
+        });
+        self.peers.push((peer.server, peer.entries_tx));
+    }
+
+    /// Applies what a task connected to a peer received, dropping entries in
+    /// channels that aren't shared with that peer.
+    pub async fn handle_peer_event(&mut self, event: PeerEvent) {
+        match event {
+            PeerEvent::NextSlots { server, reply } => {
+                let next_slots = self
+                    .channels
+                    .iter()
+                    .filter(|(channel, _)| {
+                        self.config.federation.shares(&server, channel)
+                    })
+                    .map(|(channel, handle)| {
+                        let channel = channel.clone();
+                        let next_slot = handle.request(|reply| {
+                            channel::Request::OriginNextSlot {
+                                server: server.clone(),
+                                reply,
+                            }
+                        });
+                        async move { Some((channel, next_slot.await.ok()?)) }
+                    });
+                reply_with_all(next_slots, reply);
+            }
+            PeerEvent::Relay { server, entry } => {
+                if !self.config.federation.shares(&server, &entry.channel) {
+                    tracing::warn!(
+                        %server,
+                        channel = %entry.channel,
+                        "Dropping entry relayed in a channel that isn't shared"
+                    );
+                    return;
+                }
+                let channel = entry.channel.clone();
+                self.channel(&channel)
+                    .await
+                    .send(channel::Request::Relay { server, entry });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Origin};
+
+    fn general() -> chat::ChannelName {
+        chat::ChannelName::default()
+    }
+
+    fn post(content: &str) -> comms::ClientMessage {
+        comms::ClientMessage::Post {
+            nonce: comms::Nonce::new_unique(),
+            channel: general(),
+            content: content.to_owned(),
+            attachments: vec![],
+        }
+    }
+
+    fn local() -> Origin {
+        Origin::Loopback(([127, 0, 0, 1], 4000).into())
+    }
+
+    #[tokio::test]
+    async fn followers_refuse_every_local_write() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        state.channel(&general()).await;
+        let leader = ([127, 0, 0, 1], 7000).into();
+        state.config.replication.leader_address = Some(leader);
+        state.leader = Some((leader, tokio::spawn(async {})));
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, local(), Some("ethan")).await;
+
+        for content in ["hello", "/me waves", "/nick alice", "/topic Rust"] {
+            state.handle(ethan, post(content)).await;
+            assert!(
+                matches!(
+                    &testing::received(&mut ethan_rx)[..],
+                    [comms::ServerMessage::PostRejected {
+                        reason: comms::PostRejection::PermissionDenied(_),
+                        ..
+                    }]
+                ),
+                "{:?} should be refused",
+                content
+            );
+        }
+        let sessions = state.sessions.read().await;
+        assert_eq!(sessions[&ethan].username.as_deref(), Some("ethan"));
+        assert_eq!(state.topics.get(&general()), None);
+    }
+
+    /// What the session got in reply to `content`, which must be a command
+    /// that only responds.
+    async fn respond(
+        state: &mut ServerState,
+        sender: SessionId,
+        rx: &mut mpsc::UnboundedReceiver<transport::Frame>,
+        content: &str,
+    ) -> String {
+        state.handle(sender, post(content)).await;
+        match &testing::received(rx)[..] {
+            [comms::ServerMessage::CommandResponse { text, .. }] => {
+                text.clone()
+            }
+            other => panic!("expected a response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_log_in_before_posting_or_uploading() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        let (anonymous, mut anonymous_rx) =
+            testing::connect(&state.sessions, local(), None).await;
+
+        for content in ["hello", "/nick root"] {
+            state.handle(anonymous, post(content)).await;
+            assert!(matches!(
+                &testing::received(&mut anonymous_rx)[..],
+                [comms::ServerMessage::PostRejected {
+                    reason: comms::PostRejection::NotLoggedIn,
+                    ..
+                }]
+            ));
+        }
+        state
+            .handle(
+                anonymous,
+                comms::ClientMessage::UploadBegin {
+                    client_id: comms::ClientId::new_unique_per_client(),
+                    file_name: "cat.png".to_owned(),
+                    size: 3,
+                    id: chat::AttachmentId("a".repeat(64)),
+                },
+            )
+            .await;
+        assert!(matches!(
+            &testing::received(&mut anonymous_rx)[..],
+            [comms::ServerMessage::AttachmentFailure {
+                error: comms::AttachmentError::NotLoggedIn,
+                ..
+            }]
+        ));
+        assert!(state.channels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn nick_refuses_names_that_arent_free() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        state.config.admins = vec!["root".to_owned()];
+        state.config.moderators = vec!["peter".to_owned()];
+        state
+            .roles
+            .assign(
+                Some(general()),
+                "bob".to_owned(),
+                Some(comms::Role::Member),
+            )
+            .unwrap();
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, local(), Some("ethan")).await;
+        let _alice =
+            testing::connect(&state.sessions, local(), Some("alice")).await;
+
+        for (content, refusal) in [
+            ("/nick root", "root is reserved"),
+            ("/nick peter", "peter is reserved"),
+            ("/nick bob", "bob is reserved"),
+            ("/nick alice", "alice is taken"),
+        ] {
+            assert_eq!(
+                respond(&mut state, ethan, &mut ethan_rx, content).await,
+                refusal
+            );
+        }
+        assert_eq!(
+            state.sessions.read().await[&ethan].username.as_deref(),
+            Some("ethan")
+        );
+
+        state.handle(ethan, post("/nick carol")).await;
+        assert!(testing::received(&mut ethan_rx).iter().any(|message| {
+            matches!(
+                message,
+                comms::ServerMessage::UsernameChanged { username }
+                    if username == "carol"
+            )
+        }));
+    }
+
+    #[tokio::test]
+    async fn channel_moderators_cant_kick_or_ban_server_wide() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        state.channel(&general()).await;
+        state
+            .roles
+            .assign(
+                Some(general()),
+                "peter".to_owned(),
+                Some(comms::Role::Moderator),
+            )
+            .unwrap();
+        let (peter, mut peter_rx) =
+            testing::connect(&state.sessions, local(), Some("peter")).await;
+        let (bob, _bob_rx) =
+            testing::connect(&state.sessions, local(), Some("bob")).await;
+
+        for content in ["/kick bob", "/ban bob", "/unban bob"] {
+            let response =
+                respond(&mut state, peter, &mut peter_rx, content).await;
+            assert!(
+                response.contains("can't moderate"),
+                "{:?} got {:?}",
+                content,
+                response
+            );
+        }
+        assert!(!state.sessions.read().await[&bob].is_closed());
+        assert!(state
+            .moderation
+            .read()
+            .await
+            .ban_of("bob", Utc::now())
+            .is_none());
+
+        // Muting is up to the channel's moderators.
+        assert_eq!(
+            respond(&mut state, peter, &mut peter_rx, "/mute bob").await,
+            "Done"
+        );
+    }
+
+    fn mark_read(slot_number: usize) -> comms::ClientMessage {
+        comms::ClientMessage::MarkRead {
+            channel: general(),
+            slot_number,
+        }
+    }
+
+    /// The next read position sent to a session, waiting for it.
+    async fn next_read_position(
+        rx: &mut mpsc::UnboundedReceiver<transport::Frame>,
+    ) -> usize {
+        loop {
+            let frame = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                rx.recv(),
+            )
+            .await
+            .expect("no read position was sent")
+            .unwrap();
+            let transport::Frame::Data(bytes) = frame else {
+                continue;
+            };
+            if let Ok(comms::ServerMessage::ReadPosition {
+                last_read_slot,
+                ..
+            }) =
+                <comms::ServerMessage as comms::Codable>::try_from_bytes(&bytes)
+            {
+                return last_read_slot;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read_positions_stop_at_the_head() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, local(), Some("ethan")).await;
+
+        // Channels that don't exist have nothing to read.
+        state.handle(ethan, mark_read(5)).await;
+        assert!(state.channels.is_empty());
+        assert_eq!(state.read_positions.get("ethan", &general()), None);
+
+        for content in ["one", "two", "three"] {
+            state.handle(ethan, post(content)).await;
+        }
+        state.handle(ethan, mark_read(1)).await;
+        assert_eq!(next_read_position(&mut ethan_rx).await, 1);
+        state.handle(ethan, mark_read(usize::MAX)).await;
+        assert_eq!(next_read_position(&mut ethan_rx).await, 2);
+        assert_eq!(state.read_positions.get("ethan", &general()), Some(2));
+
+        // Later entries are still unread.
+        state.handle(ethan, post("four")).await;
+        state.handle(ethan, mark_read(3)).await;
+        assert_eq!(next_read_position(&mut ethan_rx).await, 3);
+    }
+
+    fn log_in(username: &str, password: Option<&str>) -> comms::ClientMessage {
+        comms::ClientMessage::LogIn {
+            username: username.to_owned(),
+            password: password.map(str::to_owned),
+        }
+    }
+
+    /// Logs a new session in as `username` with `password`, returning why
+    /// it was disconnected, if it was.
+    async fn log_in_refusal(
+        state: &mut ServerState,
+        username: &str,
+        password: Option<&str>,
+    ) -> Option<String> {
+        let (session, mut session_rx) =
+            testing::connect(&state.sessions, local(), None).await;
+        state.handle(session, log_in(username, password)).await;
+        testing::received(&mut session_rx)
+            .into_iter()
+            .find_map(|message| match message {
+                comms::ServerMessage::Disconnected { reason } => Some(reason),
+                _ => None,
+            })
+    }
+
+    #[tokio::test]
+    async fn logging_in_refuses_names_that_arent_free() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        state.config.admins = vec!["root".to_owned(), "ethan".to_owned()];
+        state.config.moderators = vec!["peter".to_owned()];
+        state.config.passwords =
+            HashMap::from([("ethan".to_owned(), "hunter2".to_owned())]);
+        state
+            .roles
+            .assign(None, "bob".to_owned(), Some(comms::Role::Moderator))
+            .unwrap();
+        let _alice =
+            testing::connect(&state.sessions, local(), Some("alice")).await;
+
+        for (username, password, refusal) in [
+            ("root", None, "root needs a password"),
+            ("peter", None, "peter needs a password"),
+            ("bob", None, "bob needs a password"),
+            ("ethan", None, "Wrong password for ethan"),
+            ("ethan", Some("hunter3"), "Wrong password for ethan"),
+            ("alice", None, "alice is taken"),
+        ] {
+            assert_eq!(
+                log_in_refusal(&mut state, username, password)
+                    .await
+                    .as_deref(),
+                Some(refusal),
+                "{}",
+                username
+            );
+        }
+
+        // A password proves who it is, so it can log in twice.
+        for _ in 0..2 {
+            assert_eq!(
+                log_in_refusal(&mut state, "ethan", Some("hunter2")).await,
+                None
+            );
+        }
+        assert_eq!(log_in_refusal(&mut state, "carol", None).await, None);
+        let sessions = state.sessions.read().await;
+        assert_eq!(
+            sessions
+                .values()
+                .filter_map(|session| session.username.as_deref())
+                .filter(|username| *username == "ethan")
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn nick_to_a_banned_name_is_refused_like_logging_in() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        state
+            .moderation
+            .write()
+            .await
+            .ban(
+                "mallory".to_owned(),
+                Ban {
+                    until: None,
+                    reason: Some("spam".to_owned()),
+                    addresses: vec![],
+                },
+            )
+            .unwrap();
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, local(), Some("ethan")).await;
+
+        state.handle(ethan, post("/nick mallory")).await;
+        assert!(matches!(
+            &testing::received(&mut ethan_rx)[..],
+            [comms::ServerMessage::Disconnected { reason }]
+                if reason == "You are banned: spam"
+        ));
+        assert!(state.sessions.read().await[&ethan].is_closed());
+    }
+
+    #[tokio::test]
+    async fn a_held_up_channel_doesnt_hold_up_other_sessions() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        state.config.admins = vec!["ethan".to_owned()];
+        state.channel(&general()).await;
+        let (stalled, _requests) = ChannelHandle::stalled();
+        let held_up = chat::ChannelName("held-up".to_owned());
+        state.channels.insert(held_up.clone(), stalled);
+        let (alice, _alice_rx) =
+            testing::connect(&state.sessions, local(), None).await;
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, local(), Some("ethan")).await;
+        state.handle(ethan, post("hello")).await;
+
+        // Each of these waits on the held-up channel, but only in the
+        // background.
+        let waiting = [
+            (alice, log_in("alice", None)),
+            (
+                alice,
+                comms::ClientMessage::Search {
+                    client_id: comms::ClientId::new_unique_per_client(),
+                    query: comms::SearchQuery {
+                        text: "hello".to_owned(),
+                        ..Default::default()
+                    },
+                    limit: 10,
+                    after: None,
+                },
+            ),
+            (
+                ethan,
+                comms::ClientMessage::Moderate {
+                    channel: held_up.clone(),
+                    action: comms::ModerationAction::RemoveEntry {
+                        slot_number: 0,
+                    },
+                    reason: None,
+                },
+            ),
+        ];
+        for (sender, message) in waiting {
+            tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                state.handle(sender, message),
+            )
+            .await
+            .expect("the server waited on a held-up channel");
+        }
+        state.release_attachments(vec![]);
+        let (reply, _) = oneshot::channel();
+        state
+            .handle_peer_event(PeerEvent::NextSlots {
+                server: "peer".to_owned(),
+                reply,
+            })
+            .await;
+
+        state
+            .handle(
+                ethan,
+                comms::ClientMessage::Search {
+                    client_id: comms::ClientId::new_unique_per_client(),
+                    query: comms::SearchQuery {
+                        text: "hello".to_owned(),
+                        channel: Some(general()),
+                        ..Default::default()
+                    },
+                    limit: 10,
+                    after: None,
+                },
+            )
+            .await;
+        let hits = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let frame = ethan_rx.recv().await.unwrap();
+                let transport::Frame::Data(bytes) = frame else {
+                    continue;
+                };
+                if let Ok(comms::ServerMessage::SearchResults { hits, .. }) =
+                    <comms::ServerMessage as comms::Codable>::try_from_bytes(
+                        &bytes,
+                    )
+                {
+                    return hits;
+                }
+            }
+        })
+        .await
+        .expect("the search waited on a held-up channel");
+        assert_eq!(hits.len(), 1);
+    }
+
+    fn resend(nonce: &comms::Nonce, content: &str) -> comms::ClientMessage {
+        comms::ClientMessage::Post {
+            nonce: nonce.clone(),
+            channel: general(),
+            content: content.to_owned(),
+            attachments: vec![],
+        }
+    }
+
+    /// The slot of the next post acknowledged to `rx`.
+    async fn next_ack(
+        rx: &mut mpsc::UnboundedReceiver<transport::Frame>,
+    ) -> usize {
+        loop {
+            let frame = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                rx.recv(),
+            )
+            .await
+            .expect("no post was acknowledged")
+            .unwrap();
+            let transport::Frame::Data(bytes) = frame else {
+                continue;
+            };
+            if let Ok(comms::ServerMessage::PostAck { slot_number, .. }) =
+                <comms::ServerMessage as comms::Codable>::try_from_bytes(&bytes)
+            {
+                return slot_number;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn resent_commands_arent_run_twice() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut state = testing::state(directory.path());
+        state.config.admins = vec!["root".to_owned()];
+        let (root, mut root_rx) =
+            testing::connect(&state.sessions, local(), Some("root")).await;
+        let (_bob, _bob_rx) =
+            testing::connect(&state.sessions, local(), Some("bob")).await;
+
+        let kick = comms::Nonce::new_unique();
+        state.handle(root, resend(&kick, "/kick bob")).await;
+        let (bob, _bob_rx) =
+            testing::connect(&state.sessions, local(), Some("bob")).await;
+        state.handle(root, resend(&kick, "/kick bob")).await;
+        assert!(!state.sessions.read().await[&bob].is_closed());
+        let texts = testing::received(&mut root_rx)
+            .into_iter()
+            .filter_map(|message| match message {
+                comms::ServerMessage::CommandResponse { text, .. } => {
+                    Some(text)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["Done", "Done"]);
+
+        // A rename is resent under whichever name the client logs back in
+        // with.
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, local(), Some("ethan")).await;
+        let nick = comms::Nonce::new_unique();
+        state.handle(ethan, resend(&nick, "/nick carol")).await;
+        let slot_number = next_ack(&mut ethan_rx).await;
+        state.end_session(ethan).await;
+        let (carol, mut carol_rx) =
+            testing::connect(&state.sessions, local(), Some("carol")).await;
+        state.handle(carol, resend(&nick, "/nick carol")).await;
+        assert_eq!(next_ack(&mut carol_rx).await, slot_number);
+        let (ethan, mut ethan_rx) =
+            testing::connect(&state.sessions, local(), Some("ethan")).await;
+        state.handle(ethan, resend(&nick, "/nick carol")).await;
+        assert_eq!(next_ack(&mut ethan_rx).await, slot_number);
+        assert_eq!(
+            state.sessions.read().await[&ethan].username.as_deref(),
+            Some("ethan")
+        );
+
+        let page = state.channels[&general()]
+            .ask(|reply| channel::Request::Read {
+                range: History::Before {
+                    count: 10,
+                    up_to_slot_number: None,
+                },
+                reply,
+            })
+            .await
+            .unwrap();
+        let notices = page
+            .entries
+            .iter()
+            .filter(|entry| entry.kind == chat::EntryKind::Notice)
+            .filter_map(|entry| match &entry.content {
+                chat::Content::Original(chat::MessageText(content)) => {
+                    Some((entry.metadata.username.as_str(), content.as_str()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            notices,
+            [
+                ("root", "kicked bob"),
+                ("carol", "changed their name from ethan")
+            ]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{chat_log::FakeChatLog, metrics::METRICS};
+
+/// A line of a channel's log file. Later records win: an entry record for a
+/// slot that was already recorded is an edit or deletion.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    HistoryStart(usize),
+    Entry(chat::Entry),
+    /// The next slot expected from a server entries are relayed from, in
+    /// case compaction removed the entries that show it.
+    OriginNextSlot {
+        server: String,
+        next_slot: usize,
+    },
+}
+
+/// Keeps each channel's log in a JSON Lines file of [`Record`]s, so the chat
+/// logs survive restarts and can be read offline.
+pub struct LogStorage {
+    directory: PathBuf,
+    /// Records in each channel's file, to tell when it's worth rewriting it
+    /// without the superseded ones.
+    record_counts: HashMap<chat::ChannelName, usize>,
+}
+
+/// Channel names come from clients, so anything that isn't safe in a file
+/// name is percent-encoded.
+fn file_stem(channel: &chat::ChannelName) -> String {
+    let mut stem = String::new();
+    for byte in channel.0.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            stem.push(byte as char);
+        } else {
+            stem.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    stem
+}
+
+fn channel_of(file_stem: &str) -> Option<chat::ChannelName> {
+    let mut bytes = vec![];
+    let mut rest = file_stem.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok().map(chat::ChannelName)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl LogStorage {
+    pub fn open(directory: impl AsRef<Path>) -> io::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            record_counts: HashMap::new(),
+        })
+    }
+
+    /// Moves `channel`'s file out into its own storage, for the task that
+    /// owns the channel.
+    pub fn split_off(&mut self, channel: &chat::ChannelName) -> Self {
+        Self {
+            directory: self.directory.clone(),
+            record_counts: self
+                .record_counts
+                .remove_entry(channel)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn path_of(&self, channel: &chat::ChannelName) -> PathBuf {
+        self.directory
+            .join(file_stem(channel))
+            .with_extension("jsonl")
+    }
+
+    /// Reads every stored channel's log.
+    pub fn load(
+        &mut self,
+    ) -> io::Result<HashMap<chat::ChannelName, FakeChatLog>> {
+        let mut chat_logs = HashMap::new();
+        for directory_entry in fs::read_dir(&self.directory)? {
+            let path = directory_entry?.path();
+            if path.extension() != Some(OsStr::new("jsonl")) {
+                continue;
+            }
+            let Some(channel) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(channel_of)
+            else {
+                tracing::warn!("Skipping unexpected file {}", path.display());
+                continue;
+            };
+            let (chat_log, record_count) =
+                self.load_channel(&path, channel.clone())?;
+            self.record_counts.insert(channel.clone(), record_count);
+            chat_logs.insert(channel, chat_log);
+        }
+        Ok(chat_logs)
+    }
+
+    fn load_channel(
+        &self,
+        path: &Path,
+        channel: chat::ChannelName,
+    ) -> io::Result<(FakeChatLog, usize)> {
+        let mut history_start = 0;
+        let mut entries = BTreeMap::new();
+        let mut origin_next_slots = HashMap::new();
+        let mut record_count = 0;
+        for line in io::BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            record_count += 1;
+            match serde_json::from_str(&line).map_err(io::Error::other)? {
+                Record::HistoryStart(slot_number) => {
+                    history_start = slot_number;
+                    entries = entries.split_off(&slot_number);
+                }
+                Record::Entry(entry) if entry.slot_number >= history_start => {
+                    entries.insert(entry.slot_number, entry);
+                }
+                Record::Entry(_) => {}
+                Record::OriginNextSlot { server, next_slot } => {
+                    origin_next_slots.insert(server, next_slot);
+                }
+            }
+        }
+        let entries = entries.into_values().collect::<Vec<_>>();
+        for (expected_slot, entry) in (history_start..).zip(&entries) {
+            if entry.slot_number != expected_slot {
+                return Err(invalid_data(format!(
+                    "{} is missing slot {}",
+                    path.display(),
+                    expected_slot
+                )));
+            }
+        }
+        Ok((
+            FakeChatLog::restore(
+                channel,
+                history_start,
+                entries,
+                origin_next_slots,
+            ),
+            record_count,
+        ))
+    }
+
+    /// Records a new, edited, or deleted entry of `chat_log`.
+    pub fn record_entry(
+        &mut self,
+        chat_log: &FakeChatLog,
+        entry: &chat::Entry,
+    ) -> io::Result<()> {
+        self.append(chat_log, &Record::Entry(entry.clone()))
+    }
+
+    /// Records that `chat_log` was compacted up to its history start, along
+    /// with where each origin it relays is up to.
+    pub fn record_history_start(
+        &mut self,
+        chat_log: &FakeChatLog,
+    ) -> io::Result<()> {
+        self.append(chat_log, &Record::HistoryStart(chat_log.history_start()))?;
+        for (server, next_slot) in chat_log.origin_next_slots() {
+            self.append(
+                chat_log,
+                &Record::OriginNextSlot {
+                    server: server.clone(),
+                    next_slot: *next_slot,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        chat_log: &FakeChatLog,
+        record: &Record,
+    ) -> io::Result<()> {
+        let start = Instant::now();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path_of(chat_log.channel()))?;
+        let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        METRICS.storage_write_duration.observe(start.elapsed());
+
+        let record_count = self
+            .record_counts
+            .entry(chat_log.channel().clone())
+            .or_default();
+        *record_count += 1;
+        // Once most records are superseded, reclaim the space they take up.
+        if *record_count
+            > 2 * (chat_log.len() + 1 + chat_log.origin_next_slots().len())
+        {
+            self.rewrite(chat_log)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces `chat_log`'s file with just the records needed to restore it.
+    pub fn rewrite(&mut self, chat_log: &FakeChatLog) -> io::Result<()> {
+        let mut contents = vec![];
+        let mut record_count = 0;
+        for record in [Record::HistoryStart(chat_log.history_start())]
+            .into_iter()
+            .chain(chat_log.origin_next_slots().iter().map(
+                |(server, next_slot)| Record::OriginNextSlot {
+                    server: server.clone(),
+                    next_slot: *next_slot,
+                },
+            ))
+            .chain(
+                chat_log
+                    .entries_after(None)
+                    .iter()
+                    .cloned()
+                    .map(Record::Entry),
+            )
+        {
+            record_count += 1;
+            serde_json::to_writer(&mut contents, &record)
+                .map_err(io::Error::other)?;
+            contents.push(b'\n');
+        }
+        let path = self.path_of(chat_log.channel());
+        let temporary_path = path.with_extension("partial");
+        fs::write(&temporary_path, contents)?;
+        fs::rename(temporary_path, path)?;
+        self.record_counts
+            .insert(chat_log.channel().clone(), record_count);
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! Server state and sessions for unit tests, without any listeners.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{atomic::AtomicUsize, Arc},
+};
+
+use comms::{admin::SessionId, Codable};
+use tokio::sync::{mpsc, RwLock};
+use transport::Frame;
+
+use crate::{
+    attachments::AttachmentStore, audit::AuditLog, config::Config,
+    moderation::Moderation, read_positions::ReadPositions, roles::Roles,
+    state::ServerState, storage::LogStorage, topics::Topics,
+    webhooks::Webhooks, Origin, Outbox, Session, Sessions,
+};
+
+/// A server keeping everything in `data_directory`, with no channels or
+/// sessions yet.
+pub fn state(data_directory: &Path) -> ServerState {
+    ServerState {
+        config: Config::default(),
+        config_path: data_directory.join("config.json"),
+        started_at: chrono::Utc::now(),
+        messages_handled: 0,
+        channels: HashMap::new(),
+        log_storage: LogStorage::open(data_directory.join("channels")).unwrap(),
+        released_tx: mpsc::unbounded_channel().0,
+        attachment_store: AttachmentStore::open(
+            data_directory.join("attachments"),
+        )
+        .unwrap(),
+        read_positions: ReadPositions::open(
+            data_directory.join("read_positions.json"),
+        )
+        .unwrap(),
+        topics: Topics::open(data_directory.join("topics.json")).unwrap(),
+        moderation: Arc::new(RwLock::new(
+            Moderation::open(data_directory.join("moderation.json")).unwrap(),
+        )),
+        audit_log: Arc::new(
+            AuditLog::open(data_directory.join("audit.jsonl")).unwrap(),
+        ),
+        roles: Roles::open(data_directory.join("roles.json")).unwrap(),
+        sessions: Arc::new(RwLock::new(HashMap::new())),
+        followers: vec![],
+        leader: None,
+        peers: vec![],
+        webhooks: Webhooks::default(),
+        recent_commands: Arc::default(),
+    }
+}
+
+/// Adds a session from `origin`, logged in as `username` if set, returning
+/// its ID and where its messages arrive. The session counts as closed once
+/// they're dropped.
+pub async fn connect(
+    sessions: &Sessions,
+    origin: Origin,
+    username: Option<&str>,
+) -> (SessionId, mpsc::UnboundedReceiver<Frame>) {
+    let mut sessions = sessions.write().await;
+    let id = SessionId(sessions.len() as u64);
+    let (to_client_tx, to_client_rx) = mpsc::unbounded_channel();
+    sessions.insert(
+        id,
+        Session {
+            origin,
+            username: username.map(str::to_owned),
+            outbox: Outbox {
+                session: id,
+                to_client_tx,
+                queued: Arc::new(AtomicUsize::new(0)),
+            },
+            closed: false,
+            connected_at: chrono::Utc::now(),
+            span: tracing::Span::none(),
+            _join_handle: tokio::spawn(async {}),
+        },
+    );
+    (id, to_client_rx)
+}
+
+/// The messages queued for a session so far, without the close frame, if
+/// any.
+pub fn received(
+    to_client_rx: &mut mpsc::UnboundedReceiver<Frame>,
+) -> Vec<comms::ServerMessage> {
+    let mut messages = vec![];
+    while let Ok(frame) = to_client_rx.try_recv() {
+        if let Frame::Data(bytes) = frame {
+            messages
+                .push(comms::ServerMessage::try_from_bytes(&bytes).unwrap());
+        }
+    }
+    messages
+}
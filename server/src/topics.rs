@@ -0,0 +1,45 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Each channel's topic, saved to a JSON file whenever one changes.
+pub struct Topics {
+    path: PathBuf,
+    topics: HashMap<chat::ChannelName, String>,
+}
+
+impl Topics {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let topics = match fs::read(&path) {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).map_err(io::Error::other)?
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                HashMap::new()
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(Self { path, topics })
+    }
+
+    pub fn get(&self, channel: &chat::ChannelName) -> Option<&str> {
+        self.topics.get(channel).map(String::as_str)
+    }
+
+    pub fn set(
+        &mut self,
+        channel: chat::ChannelName,
+        topic: String,
+    ) -> io::Result<()> {
+        self.topics.insert(channel, topic);
+        let temporary_path = self.path.with_extension("partial");
+        fs::write(
+            &temporary_path,
+            serde_json::to_vec(&self.topics).map_err(io::Error::other)?,
+        )?;
+        fs::rename(temporary_path, &self.path)
+    }
+}
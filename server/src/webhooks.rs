@@ -0,0 +1,329 @@
+//! Outgoing webhooks. Channels send every entry posted or relayed to them to
+//! one task, which queues those matching each webhook's filter for that
+//! webhook's own task. It delivers them one at a time, in the order they were
+//! committed, retrying with backoff, and keeps the webhook's delivery status
+//! for the admin socket. A webhook whose receiver is down only holds up its
+//! own queue, and once [`QUEUE_CAPACITY`] entries are waiting, new ones are
+//! dropped and counted as failed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use comms::admin::WebhookStatus;
+use tokio::sync::mpsc;
+use webhook::{Backoff, Filter, Payload};
+
+use crate::config;
+
+/// How many entries can wait for each webhook before new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+type Statuses = Arc<Mutex<BTreeMap<String, WebhookStatus>>>;
+
+/// Where channels send new entries for webhooks, and how delivering them has
+/// gone.
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    /// `None` if there are no webhooks.
+    entries_tx: Option<mpsc::UnboundedSender<chat::Entry>>,
+    statuses: Statuses,
+}
+
+impl Webhooks {
+    /// Starts delivering to `webhooks`, or fails with the name of one whose
+    /// pattern is invalid.
+    pub fn start(
+        webhooks: HashMap<String, config::Webhook>,
+    ) -> Result<Self, (String, webhook::Error)> {
+        if webhooks.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut hooks = vec![];
+        let mut statuses = BTreeMap::new();
+        for (name, webhook) in webhooks {
+            let filter = Filter::new(
+                webhook.channels.clone(),
+                webhook.keywords.clone(),
+                webhook.pattern.as_deref(),
+            )
+            .map_err(|error| (name.clone(), error))?;
+            statuses.insert(
+                name.clone(),
+                WebhookStatus {
+                    name: name.clone(),
+                    url: webhook.url.clone(),
+                    delivered: 0,
+                    pending: 0,
+                    failed: 0,
+                    last_delivered_at: None,
+                    last_error: None,
+                },
+            );
+            hooks.push((name, webhook, filter));
+        }
+        let statuses = Arc::new(Mutex::new(statuses));
+
+        let queues = hooks
+            .into_iter()
+            .map(|(name, webhook, filter)| {
+                let (payloads_tx, payloads_rx) = mpsc::channel(QUEUE_CAPACITY);
+                tokio::spawn(deliver_queued(
+                    name.clone(),
+                    webhook,
+                    payloads_rx,
+                    statuses.clone(),
+                ));
+                (name, filter, payloads_tx)
+            })
+            .collect::<Vec<_>>();
+        let (entries_tx, mut entries_rx) = mpsc::unbounded_channel();
+        let statuses_for_task = statuses.clone();
+        tokio::spawn(async move {
+            while let Some(entry) = entries_rx.recv().await {
+                for (name, filter, payloads_tx) in &queues {
+                    if !filter.matches(&entry) {
+                        continue;
+                    }
+                    let Some(payload) = Payload::new(name, &entry) else {
+                        continue;
+                    };
+                    queue(name, payloads_tx, payload, &statuses_for_task);
+                }
+            }
+        });
+        Ok(Self {
+            entries_tx: Some(entries_tx),
+            statuses,
+        })
+    }
+
+    pub fn entries_tx(&self) -> Option<mpsc::UnboundedSender<chat::Entry>> {
+        self.entries_tx.clone()
+    }
+
+    pub fn statuses(&self) -> Vec<WebhookStatus> {
+        lock(&self.statuses).values().cloned().collect()
+    }
+}
+
+fn lock(
+    statuses: &Statuses,
+) -> std::sync::MutexGuard<'_, BTreeMap<String, WebhookStatus>> {
+    statuses.lock().expect("webhook statuses lock poisoned")
+}
+
+fn update(
+    statuses: &Statuses,
+    name: &str,
+    change: impl FnOnce(&mut WebhookStatus),
+) {
+    if let Some(status) = lock(statuses).get_mut(name) {
+        change(status);
+    }
+}
+
+/// Queues `payload` for the webhook called `name`, or drops it and counts it
+/// as failed if the queue is full.
+fn queue(
+    name: &str,
+    payloads_tx: &mpsc::Sender<Payload>,
+    payload: Payload,
+    statuses: &Statuses,
+) {
+    // Counted before it's queued, so it's never delivered before it's counted.
+    update(statuses, name, |status| status.pending += 1);
+    let Err(error) = payloads_tx.try_send(payload) else {
+        return;
+    };
+    let payload = error.into_inner();
+    tracing::warn!(
+        webhook = %name,
+        slot_number = payload.slot_number,
+        "Dropping webhook delivery, {} are already waiting",
+        QUEUE_CAPACITY
+    );
+    update(statuses, name, |status| {
+        status.pending -= 1;
+        status.failed += 1;
+        status.last_error = Some((
+            chrono::Utc::now(),
+            format!("Dropped slot {}, the queue is full", payload.slot_number),
+        ));
+    });
+}
+
+/// Delivers the webhook called `name` everything queued for it, in order.
+async fn deliver_queued(
+    name: String,
+    webhook: config::Webhook,
+    mut payloads_rx: mpsc::Receiver<Payload>,
+    statuses: Statuses,
+) {
+    while let Some(payload) = payloads_rx.recv().await {
+        deliver(&name, &webhook, payload, &statuses).await;
+    }
+}
+
+async fn deliver(
+    name: &str,
+    webhook: &config::Webhook,
+    payload: Payload,
+    statuses: &Statuses,
+) {
+    let record_error = |error: &webhook::Error| {
+        tracing::warn!(
+            webhook = %name,
+            slot_number = payload.slot_number,
+            "Failed to deliver webhook: {}",
+            error
+        );
+        update(statuses, name, |status| {
+            status.last_error = Some((chrono::Utc::now(), error.to_string()));
+        });
+    };
+
+    let result = webhook::deliver_with_retries(
+        &webhook.url,
+        &webhook.secret,
+        &payload,
+        Backoff::default(),
+        &record_error,
+    )
+    .await;
+    if let Err(error) = &result {
+        record_error(error);
+    }
+    update(statuses, name, |status| {
+        status.pending -= 1;
+        match &result {
+            Ok(()) => {
+                status.delivered += 1;
+                status.last_delivered_at = Some(chrono::Utc::now());
+            }
+            Err(_) => status.failed += 1,
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn webhook(url: String) -> config::Webhook {
+        config::Webhook {
+            url,
+            secret: "secret".to_owned(),
+            channels: vec![],
+            keywords: vec![],
+            pattern: None,
+        }
+    }
+
+    fn entry(slot_number: usize) -> chat::Entry {
+        chat::Entry::new_timestamped_now(
+            chat::ChannelName::default(),
+            slot_number,
+            "ethan".to_owned(),
+            chat::Content::Original(chat::MessageText("hi".to_owned())),
+        )
+    }
+
+    fn status(webhooks: &Webhooks) -> WebhookStatus {
+        webhooks.statuses().pop().unwrap()
+    }
+
+    /// Stands in for a receiver, answering every request `200 OK` and
+    /// sending the slot number of each payload on the returned channel.
+    async fn stand_in() -> (String, mpsc::UnboundedReceiver<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (slots_tx, slots_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut bytes = vec![];
+                let mut buffer = [0; 1024];
+                let payload = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    bytes.extend_from_slice(&buffer[..read]);
+                    let Some(head_length) = bytes
+                        .windows(4)
+                        .position(|window| window == b"\r\n\r\n")
+                    else {
+                        continue;
+                    };
+                    if let Ok(payload) = serde_json::from_slice::<Payload>(
+                        &bytes[head_length + 4..],
+                    ) {
+                        break payload;
+                    }
+                };
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+                slots_tx.send(payload.slot_number).unwrap();
+            }
+        });
+        (url, slots_rx)
+    }
+
+    #[tokio::test]
+    async fn delivers_in_order() {
+        let (url, mut slots_rx) = stand_in().await;
+        let webhooks =
+            Webhooks::start(HashMap::from([("ci".to_owned(), webhook(url))]))
+                .unwrap();
+        let entries_tx = webhooks.entries_tx().unwrap();
+        for slot_number in 0..20 {
+            entries_tx.send(entry(slot_number)).unwrap();
+        }
+
+        for slot_number in 0..20 {
+            let received =
+                tokio::time::timeout(Duration::from_secs(5), slots_rx.recv())
+                    .await
+                    .unwrap();
+            assert_eq!(received, Some(slot_number));
+        }
+        // The status is updated just after the receiver answers.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let status = status(&webhooks);
+        assert_eq!(
+            (status.delivered, status.pending, status.failed),
+            (20, 0, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_entries_once_the_queue_is_full() {
+        let webhooks = Webhooks::start(HashMap::from([(
+            "ci".to_owned(),
+            webhook("http://127.0.0.1:9/".to_owned()),
+        )]))
+        .unwrap();
+        // Nothing takes from this queue, so it fills up.
+        let (payloads_tx, _payloads_rx) = mpsc::channel(1);
+        for slot_number in 0..3 {
+            queue(
+                "ci",
+                &payloads_tx,
+                Payload::new("ci", &entry(slot_number)).unwrap(),
+                &webhooks.statuses,
+            );
+        }
+
+        let status = status(&webhooks);
+        assert_eq!((status.pending, status.failed), (1, 2));
+        let (_, last_error) = status.last_error.unwrap();
+        assert_eq!(last_error, "Dropped slot 2, the queue is full");
+    }
+}
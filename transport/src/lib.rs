@@ -0,0 +1,198 @@
+//! Connections between clients and the server, whichever transport carries
+//! them. Either side sends and receives [`Frame`]s in order, each holding one
+//! encoded `comms` message, without caring whether they travel over a
+//! WebSocket or [QUIC](quic).
+
+use std::{error, fmt, io, pin::Pin};
+
+use futures_util::{stream::BoxStream, Sink, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+pub use tokio_tungstenite::tungstenite::Bytes;
+use tokio_tungstenite::{
+    tungstenite::{self, protocol, Message},
+    WebSocketStream,
+};
+
+pub mod quic;
+
+/// What's sent and received on a [`Connection`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// One encoded message.
+    Data(Bytes),
+    /// The connection is closing, and why, if the other side said.
+    Close(Option<CloseFrame>),
+}
+
+/// Why a connection was closed, using the codes of WebSocket close frames on
+/// every transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,
+    /// The other side broke a rule, e.g. a moderator kicked the user.
+    Policy,
+    Other(u16),
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1008 => CloseCode::Policy,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::Policy => 1008,
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl From<protocol::CloseFrame> for CloseFrame {
+    fn from(frame: protocol::CloseFrame) -> Self {
+        Self {
+            code: u16::from(frame.code).into(),
+            reason: frame.reason.to_string(),
+        }
+    }
+}
+
+impl From<CloseFrame> for protocol::CloseFrame {
+    fn from(frame: CloseFrame) -> Self {
+        Self {
+            code: u16::from(frame.code).into(),
+            reason: frame.reason.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    WebSocket(Box<tungstenite::Error>),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WebSocket(error) => error.fmt(f),
+            Error::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+type WebSocketSink =
+    Pin<Box<dyn Sink<Message, Error = tungstenite::Error> + Send>>;
+
+enum SenderInner {
+    WebSocket(WebSocketSink),
+    Quic(quic::Sender),
+}
+
+/// The half of a [`Connection`] that sends frames.
+pub struct Sender {
+    inner: SenderInner,
+}
+
+impl Sender {
+    /// Sends `frame`. After sending [`Frame::Close`], nothing more can be
+    /// sent.
+    pub async fn send(&mut self, frame: Frame) -> Result<(), Error> {
+        match &mut self.inner {
+            SenderInner::WebSocket(sink) => sink
+                .send(match frame {
+                    Frame::Data(bytes) => Message::Binary(bytes),
+                    Frame::Close(frame) => {
+                        Message::Close(frame.map(Into::into))
+                    }
+                })
+                .await
+                .map_err(|error| Error::WebSocket(Box::new(error))),
+            SenderInner::Quic(sender) => sender.send(frame).await,
+        }
+    }
+}
+
+enum ReceiverInner {
+    WebSocket(BoxStream<'static, Result<Message, tungstenite::Error>>),
+    Quic(quic::Receiver),
+}
+
+/// The half of a [`Connection`] that receives frames.
+pub struct Receiver {
+    inner: ReceiverInner,
+}
+
+impl Receiver {
+    /// The next frame, or `None` once the connection has ended.
+    pub async fn receive(&mut self) -> Option<Result<Frame, Error>> {
+        match &mut self.inner {
+            ReceiverInner::WebSocket(stream) => loop {
+                // Pings are answered by the WebSocket itself.
+                match stream.next().await? {
+                    Ok(Message::Binary(bytes)) => {
+                        return Some(Ok(Frame::Data(bytes)))
+                    }
+                    Ok(Message::Close(frame)) => {
+                        return Some(Ok(Frame::Close(frame.map(Into::into))))
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        return Some(Err(Error::WebSocket(Box::new(error))))
+                    }
+                }
+            },
+            ReceiverInner::Quic(receiver) => receiver.receive().await,
+        }
+    }
+}
+
+/// An established connection to a client or the server.
+pub struct Connection {
+    sender: Sender,
+    receiver: Receiver,
+}
+
+impl Connection {
+    /// Splits the connection so frames can be sent and received at once.
+    pub fn split(self) -> (Sender, Receiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+impl<S> From<WebSocketStream<S>> for Connection
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn from(websocket: WebSocketStream<S>) -> Self {
+        let (sink, stream) = websocket.split();
+        Self {
+            sender: Sender {
+                inner: SenderInner::WebSocket(Box::pin(sink)),
+            },
+            receiver: Receiver {
+                inner: ReceiverInner::WebSocket(stream.boxed()),
+            },
+        }
+    }
+}
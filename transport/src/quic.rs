@@ -0,0 +1,367 @@
+//! QUIC, which keeps a connection open when the client's address changes,
+//! e.g. when a laptop moves between networks, and resumes with 0-RTT when
+//! reconnecting to a server it has connected to before.
+//!
+//! Each connection carries frames on one bidirectional stream that the client
+//! opens, which the server sees once the client first sends on it. Each frame
+//! is prefixed with its length as a big-endian `u32`. Closing finishes the
+//! stream and then closes the connection with the close frame's code and
+//! reason.
+//!
+//! Frames a client sends with 0-RTT can be replayed by anyone who captured
+//! them, which the server should only allow for messages that are safe to
+//! handle twice.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::FutureExt;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls, ConnectionError, ReadError, ReadExactError, VarInt, WriteError,
+    ZeroRttAccepted,
+};
+use tokio::sync::Mutex;
+
+use crate::{Bytes, CloseFrame, Connection, Error, Frame};
+
+/// The application protocol negotiated with TLS.
+pub const ALPN: &[u8] = b"nerdtalk";
+
+/// The longest frame accepted, as for WebSocket messages.
+pub const MAX_FRAME_LENGTH: usize = 64 << 20;
+
+/// How long closing waits for the other side to acknowledge what was sent.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Accepts QUIC connections from clients.
+pub struct Listener {
+    endpoint: quinn::Endpoint,
+}
+
+impl Listener {
+    /// Listens on `address` with the server's TLS configuration, which is
+    /// adjusted for QUIC.
+    pub fn bind(
+        address: SocketAddr,
+        mut tls_config: rustls::ServerConfig,
+    ) -> Result<Self, Error> {
+        tls_config.alpn_protocols = vec![ALPN.to_vec()];
+        tls_config.max_early_data_size = u32::MAX;
+        let crypto =
+            QuicServerConfig::try_from(tls_config).map_err(io::Error::other)?;
+        let endpoint = quinn::Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+            address,
+        )?;
+        Ok(Self { endpoint })
+    }
+
+    /// The next client trying to connect, or `None` once the listener has
+    /// stopped.
+    pub async fn accept(&self) -> Option<Incoming> {
+        self.endpoint
+            .accept()
+            .await
+            .map(|incoming| Incoming { incoming })
+    }
+}
+
+/// A client trying to connect, before the handshake.
+pub struct Incoming {
+    incoming: quinn::Incoming,
+}
+
+impl Incoming {
+    pub fn remote_address(&self) -> SocketAddr {
+        self.incoming.remote_address()
+    }
+
+    /// Turns the client away without a handshake.
+    pub fn refuse(self) {
+        self.incoming.refuse();
+    }
+
+    /// Completes the handshake and waits for the client's stream.
+    pub async fn accept(self) -> Result<Connection, Error> {
+        let connection = self.incoming.await.map_err(io::Error::from)?;
+        let (send, receive) =
+            connection.accept_bi().await.map_err(io::Error::from)?;
+        Ok(new_connection(connection, send, receive, None, None))
+    }
+}
+
+/// How clients connect. Reuse it across connections to the same server, since
+/// it remembers the sessions that 0-RTT resumes.
+#[derive(Clone)]
+pub struct ClientConfig {
+    config: quinn::ClientConfig,
+}
+
+impl ClientConfig {
+    /// Adjusts the client's TLS configuration for QUIC.
+    pub fn new(mut tls_config: rustls::ClientConfig) -> Result<Self, Error> {
+        tls_config.alpn_protocols = vec![ALPN.to_vec()];
+        tls_config.enable_early_data = true;
+        let crypto =
+            QuicClientConfig::try_from(tls_config).map_err(io::Error::other)?;
+        Ok(Self {
+            config: quinn::ClientConfig::new(Arc::new(crypto)),
+        })
+    }
+}
+
+/// Connects to the server at `address`, whose certificate is for
+/// `server_name`, sending with 0-RTT if `config` has a session to resume.
+pub async fn connect(
+    address: SocketAddr,
+    server_name: &str,
+    config: &ClientConfig,
+) -> Result<Connection, Error> {
+    let local_address: SocketAddr = if address.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let endpoint = quinn::Endpoint::client(local_address)?;
+    let connecting = endpoint
+        .connect_with(config.config.clone(), address, server_name)
+        .map_err(io::Error::other)?;
+    let (connection, accepted) = match connecting.into_0rtt() {
+        Ok((connection, accepted)) => (connection, Some(accepted)),
+        Err(connecting) => (connecting.await.map_err(io::Error::from)?, None),
+    };
+    let (send, receive) =
+        connection.open_bi().await.map_err(io::Error::from)?;
+    Ok(new_connection(
+        connection,
+        send,
+        receive,
+        accepted,
+        Some(endpoint),
+    ))
+}
+
+/// What both halves of a connection need.
+struct Shared {
+    connection: quinn::Connection,
+    send: quinn::SendStream,
+    /// Whether the server accepted what was sent with 0-RTT, until it's
+    /// known.
+    accepted: Option<ZeroRttAccepted>,
+    /// Frames sent with 0-RTT, resent if the server rejects them.
+    early_frames: Vec<Bytes>,
+    /// The stream replacing one the server rejected, until the receiver
+    /// takes it.
+    replacement: Option<quinn::RecvStream>,
+    /// Kept by clients, whose endpoint is theirs alone.
+    endpoint: Option<quinn::Endpoint>,
+}
+
+impl Shared {
+    /// Forgets the frames sent with 0-RTT once the server accepted them, or
+    /// resends them if it rejected them.
+    async fn settle_early_frames(&mut self) -> Result<(), Error> {
+        match self.accepted.as_mut().and_then(FutureExt::now_or_never) {
+            Some(true) => {
+                self.accepted = None;
+                self.early_frames.clear();
+                Ok(())
+            }
+            Some(false) => self.reopen().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Opens a new stream after the server rejected 0-RTT and resends what
+    /// was sent on the rejected one.
+    async fn reopen(&mut self) -> Result<(), Error> {
+        let (send, receive) =
+            self.connection.open_bi().await.map_err(io::Error::from)?;
+        self.send = send;
+        self.replacement = Some(receive);
+        self.accepted = None;
+        for bytes in std::mem::take(&mut self.early_frames) {
+            write_frame(&mut self.send, bytes)
+                .await
+                .map_err(io::Error::from)?;
+        }
+        Ok(())
+    }
+}
+
+fn new_connection(
+    connection: quinn::Connection,
+    send: quinn::SendStream,
+    receive: quinn::RecvStream,
+    accepted: Option<ZeroRttAccepted>,
+    endpoint: Option<quinn::Endpoint>,
+) -> Connection {
+    let shared = Arc::new(Mutex::new(Shared {
+        connection,
+        send,
+        accepted,
+        early_frames: vec![],
+        replacement: None,
+        endpoint,
+    }));
+    Connection {
+        sender: crate::Sender {
+            inner: crate::SenderInner::Quic(Sender {
+                shared: shared.clone(),
+            }),
+        },
+        receiver: crate::Receiver {
+            inner: crate::ReceiverInner::Quic(Receiver { shared, receive }),
+        },
+    }
+}
+
+async fn write_frame(
+    send: &mut quinn::SendStream,
+    bytes: Bytes,
+) -> Result<(), WriteError> {
+    let length = u32::try_from(bytes.len())
+        .expect("frames are shorter than 4 GiB")
+        .to_be_bytes();
+    send.write_all(&length).await?;
+    send.write_chunk(bytes).await
+}
+
+pub(crate) struct Sender {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Sender {
+    pub(crate) async fn send(&mut self, frame: Frame) -> Result<(), Error> {
+        let mut shared = self.shared.lock().await;
+        match frame {
+            Frame::Data(bytes) => {
+                shared.settle_early_frames().await?;
+                if shared.accepted.is_some() {
+                    shared.early_frames.push(bytes.clone());
+                }
+                match write_frame(&mut shared.send, bytes).await {
+                    // Resent along with every other early frame.
+                    Err(WriteError::ZeroRttRejected) => shared.reopen().await,
+                    result => {
+                        result.map_err(|error| io::Error::from(error).into())
+                    }
+                }
+            }
+            Frame::Close(frame) => {
+                let CloseFrame { code, reason } = frame.unwrap_or(CloseFrame {
+                    code: crate::CloseCode::Normal,
+                    reason: String::new(),
+                });
+                if shared.send.finish().is_ok() {
+                    let _ = tokio::time::timeout(
+                        CLOSE_TIMEOUT,
+                        shared.send.stopped(),
+                    )
+                    .await;
+                }
+                shared
+                    .connection
+                    .close(VarInt::from(u16::from(code)), reason.as_bytes());
+                if let Some(endpoint) = &shared.endpoint {
+                    let _ = tokio::time::timeout(
+                        CLOSE_TIMEOUT,
+                        endpoint.wait_idle(),
+                    )
+                    .await;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) struct Receiver {
+    shared: Arc<Mutex<Shared>>,
+    receive: quinn::RecvStream,
+}
+
+/// How a connection the other side closed should be reported.
+fn closed(error: ConnectionError) -> Option<Result<Frame, Error>> {
+    match error {
+        ConnectionError::ApplicationClosed(close) => {
+            Some(Ok(Frame::Close(Some(CloseFrame {
+                code: u16::try_from(close.error_code.into_inner())
+                    .map_or(crate::CloseCode::Other(u16::MAX), Into::into),
+                reason: String::from_utf8_lossy(&close.reason).into_owned(),
+            }))))
+        }
+        ConnectionError::LocallyClosed => None,
+        error => Some(Err(io::Error::from(error).into())),
+    }
+}
+
+impl Receiver {
+    pub(crate) async fn receive(&mut self) -> Option<Result<Frame, Error>> {
+        loop {
+            let mut length = [0; 4];
+            let error = match self.receive.read_exact(&mut length).await {
+                Ok(()) => {
+                    let length = u32::from_be_bytes(length) as usize;
+                    if length > MAX_FRAME_LENGTH {
+                        return Some(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "frame is too long",
+                        )
+                        .into()));
+                    }
+                    let mut bytes = vec![0; length];
+                    match self.receive.read_exact(&mut bytes).await {
+                        Ok(()) => return Some(Ok(Frame::Data(bytes.into()))),
+                        Err(error) => error,
+                    }
+                }
+                Err(error) => error,
+            };
+            match error {
+                // The other side finished the stream to close the
+                // connection, which says why once it's closed.
+                ReadExactError::FinishedEarly(0) => {
+                    let connection =
+                        self.shared.lock().await.connection.clone();
+                    return match tokio::time::timeout(
+                        CLOSE_TIMEOUT,
+                        connection.closed(),
+                    )
+                    .await
+                    {
+                        Ok(error) => closed(error),
+                        Err(_) => Some(Ok(Frame::Close(None))),
+                    };
+                }
+                ReadExactError::ReadError(ReadError::ZeroRttRejected) => {
+                    let mut shared = self.shared.lock().await;
+                    if shared.replacement.is_none() {
+                        if let Err(error) = shared.reopen().await {
+                            return Some(Err(error));
+                        }
+                    }
+                    self.receive = shared
+                        .replacement
+                        .take()
+                        .expect("the rejected stream was just replaced");
+                }
+                ReadExactError::ReadError(ReadError::ConnectionLost(error)) => {
+                    return closed(error)
+                }
+                error => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        error,
+                    )
+                    .into()))
+                }
+            }
+        }
+    }
+}
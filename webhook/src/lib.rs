@@ -0,0 +1,373 @@
+//! Outgoing webhooks, which POST a JSON [`Payload`] to a URL for each new
+//! entry that matches a [`Filter`], so other tooling can react to chat, e.g.
+//! starting a job when someone says "deploy prod".
+//!
+//! Each request is signed with the webhook's secret: the [`TIMESTAMP_HEADER`]
+//! header holds when it was sent, in seconds since the Unix epoch, and the
+//! [`SIGNATURE_HEADER`] header holds `sha256=` and the hex HMAC-SHA256 of that
+//! timestamp, a `.`, and the body. Receivers check both with [`verify`] before
+//! trusting the payload, which also refuses old requests, so one that's
+//! captured can't be replayed later.
+
+use std::{
+    error, fmt, io,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName},
+    TlsConnector,
+};
+
+/// The header holding a request's signature.
+pub const SIGNATURE_HEADER: &str = "X-Nerdtalk-Signature";
+
+/// The header holding when a request was signed, in seconds since the Unix
+/// epoch.
+pub const TIMESTAMP_HEADER: &str = "X-Nerdtalk-Timestamp";
+
+/// How far a request's timestamp can be from the receiver's clock for
+/// [`verify`] to accept it.
+pub const MAX_CLOCK_DIFFERENCE: Duration = Duration::from_secs(5 * 60);
+
+/// How long one attempt to deliver waits for the receiver to answer.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Responses longer than this before the status line ends are malformed.
+const MAX_STATUS_LINE_LENGTH: usize = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Not an `http://` or `https://` URL with a host.
+    InvalidUrl(String),
+    InvalidPattern(regex::Error),
+    Io(io::Error),
+    /// The receiver answered with this status line instead of a `2xx` one.
+    Status(String),
+    TimedOut,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUrl(url) => write!(f, "Invalid webhook URL {}", url),
+            Error::InvalidPattern(error) => error.fmt(f),
+            Error::Io(error) => error.fmt(f),
+            Error::Status(status_line) => {
+                write!(f, "Receiver answered {}", status_line)
+            }
+            Error::TimedOut => {
+                write!(f, "Receiver didn't answer within {:?}", ATTEMPT_TIMEOUT)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// Which entries a webhook is sent.
+pub struct Filter {
+    channels: Vec<chat::ChannelName>,
+    /// Lowercased.
+    keywords: Vec<String>,
+    pattern: Option<Regex>,
+}
+
+impl Filter {
+    /// Matches entries in `channels`, or in any channel if there are none,
+    /// whose text contains one of `keywords`, ignoring case, or matches
+    /// `pattern`. Without keywords or a pattern, every entry with text
+    /// matches.
+    pub fn new(
+        channels: Vec<chat::ChannelName>,
+        keywords: Vec<String>,
+        pattern: Option<&str>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            channels,
+            keywords: keywords
+                .iter()
+                .map(|keyword| keyword.to_lowercase())
+                .collect(),
+            pattern: pattern
+                .map(Regex::new)
+                .transpose()
+                .map_err(Error::InvalidPattern)?,
+        })
+    }
+
+    pub fn matches(&self, entry: &chat::Entry) -> bool {
+        let Some(text) = entry.text_content() else {
+            return false;
+        };
+        if !self.channels.is_empty() && !self.channels.contains(&entry.channel)
+        {
+            return false;
+        }
+        if self.keywords.is_empty() && self.pattern.is_none() {
+            return true;
+        }
+        let lowercase_text = text.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|keyword| lowercase_text.contains(keyword))
+            || self
+                .pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.is_match(text))
+    }
+}
+
+/// What a webhook is sent about an entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payload {
+    /// The webhook's name, for receivers behind more than one.
+    pub webhook: String,
+    pub channel: chat::ChannelName,
+    pub slot_number: usize,
+    pub kind: chat::EntryKind,
+    /// Qualified as `user@server` if the entry was relayed from another
+    /// server.
+    pub username: String,
+    pub timestamp: DateTime<Utc>,
+    pub text: String,
+    pub attachments: Vec<chat::Attachment>,
+}
+
+impl Payload {
+    /// What the webhook called `webhook` is sent about `entry`, or `None` if
+    /// it was deleted.
+    pub fn new(webhook: &str, entry: &chat::Entry) -> Option<Self> {
+        Some(Self {
+            webhook: webhook.to_owned(),
+            channel: entry.channel.clone(),
+            slot_number: entry.slot_number,
+            kind: entry.kind,
+            username: entry.metadata.username.clone(),
+            timestamp: entry.metadata.timestamp,
+            text: entry.text_content()?.to_owned(),
+            attachments: entry.attachments.clone(),
+        })
+    }
+}
+
+fn keyed(secret: &str, timestamp: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+/// The signature of `body` sent at `timestamp`, in seconds since the Unix
+/// epoch, under `secret`, as sent in [`SIGNATURE_HEADER`].
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let digest = keyed(secret, &timestamp.to_string(), body)
+        .finalize()
+        .into_bytes();
+    let hex = digest
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("sha256={}", hex)
+}
+
+/// Whether `signature`, from [`SIGNATURE_HEADER`], signs `body` sent at
+/// `timestamp`, from [`TIMESTAMP_HEADER`], under `secret`, compared in
+/// constant time, and `timestamp` is within [`MAX_CLOCK_DIFFERENCE`] of now.
+pub fn verify(
+    secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let Ok(seconds) = timestamp.parse::<i64>() else {
+        return false;
+    };
+    if Utc::now().timestamp().abs_diff(seconds) > MAX_CLOCK_DIFFERENCE.as_secs()
+    {
+        return false;
+    }
+    let Some(hex) = signature.strip_prefix("sha256=") else {
+        return false;
+    };
+    let Some(digest) = (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            hex.get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return false;
+    };
+    keyed(secret, timestamp, body).verify_slice(&digest).is_ok()
+}
+
+/// How often to retry a delivery that failed, and how long to wait between
+/// attempts, doubling from `first_delay` up to `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub attempts: u32,
+    pub first_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            attempts: 8,
+            first_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sends `payload` to `url`, signed with `secret`, retrying failed attempts
+/// as `backoff` says. `on_failure` is told why each attempt that's retried
+/// failed, and the error of the last attempt is returned.
+pub async fn deliver_with_retries(
+    url: &str,
+    secret: &str,
+    payload: &Payload,
+    backoff: Backoff,
+    mut on_failure: impl FnMut(&Error),
+) -> Result<(), Error> {
+    let mut delay = backoff.first_delay;
+    let mut attempt = 1;
+    loop {
+        match deliver(url, secret, payload).await {
+            Ok(()) => return Ok(()),
+            // Retrying can't fix the URL.
+            Err(error @ Error::InvalidUrl(_)) => return Err(error),
+            Err(error) if attempt >= backoff.attempts => return Err(error),
+            Err(error) => on_failure(&error),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(backoff.max_delay);
+        attempt += 1;
+    }
+}
+
+/// Sends `payload` to `url` once, signed with `secret` and the current
+/// time.
+pub async fn deliver(
+    url: &str,
+    secret: &str,
+    payload: &Payload,
+) -> Result<(), Error> {
+    let invalid_url = || Error::InvalidUrl(url.to_owned());
+    let (https, rest) = match url.split_once("://") {
+        Some(("http", rest)) => (false, rest),
+        Some(("https", rest)) => (true, rest),
+        _ => return Err(invalid_url()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    // IPv6 hosts are bracketed, with colons inside the brackets.
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse().map_err(|_| invalid_url())?)
+        }
+        _ => (authority, if https { 443 } else { 80 }),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid_url());
+    }
+
+    let body = serde_json::to_vec(payload).map_err(io::Error::other)?;
+    let timestamp = Utc::now().timestamp();
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: nerdtalk\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}: {}\r\n{}: {}\r\nConnection: close\r\n\r\n",
+        path,
+        authority,
+        body.len(),
+        TIMESTAMP_HEADER,
+        timestamp,
+        SIGNATURE_HEADER,
+        sign(secret, timestamp, &body),
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+
+    let attempt = async {
+        let tcp_stream = TcpStream::connect((host, port)).await?;
+        if https {
+            let server_name = ServerName::try_from(host.to_owned())
+                .map_err(|_| invalid_url())?;
+            let tls_stream =
+                tls_connector().connect(server_name, tcp_stream).await?;
+            exchange(tls_stream, &request).await
+        } else {
+            exchange(tcp_stream, &request).await
+        }
+    };
+    tokio::time::timeout(ATTEMPT_TIMEOUT, attempt)
+        .await
+        .map_err(|_| Error::TimedOut)?
+}
+
+/// Verifies receivers against the usual web roots, shared by every delivery.
+fn tls_connector() -> TlsConnector {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| {
+        let root_store = rustls::RootCertStore::from_iter(
+            webpki_roots::TLS_SERVER_ROOTS.iter().cloned(),
+        );
+        Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth(),
+        )
+    });
+    TlsConnector::from(config.clone())
+}
+
+/// Sends `request` and reads the response's status line, succeeding if it's
+/// `2xx`.
+async fn exchange(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &[u8],
+) -> Result<(), Error> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let mut response = vec![];
+    let mut buffer = [0; 256];
+    while !response.windows(2).any(|window| window == b"\r\n") {
+        if response.len() > MAX_STATUS_LINE_LENGTH {
+            return Err(Error::Status("(too long)".to_owned()));
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..read]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::Status(status_line.to_owned())),
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use chat::{ChannelName, Content, Entry, MessageText};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+use webhook::{
+    Backoff, Error, Filter, Payload, MAX_CLOCK_DIFFERENCE, SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+};
+
+fn entry(channel: &str, text: &str) -> Entry {
+    Entry::new_timestamped_now(
+        ChannelName(channel.to_owned()),
+        3,
+        "ethan".to_owned(),
+        Content::Original(MessageText(text.to_owned())),
+    )
+}
+
+fn payload() -> Payload {
+    Payload::new("ci", &entry("ops", "deploy prod please")).unwrap()
+}
+
+/// A request the stand-in received.
+struct Received {
+    head: String,
+    body: Vec<u8>,
+}
+
+impl Received {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header.eq_ignore_ascii_case(name).then_some(value.trim())
+        })
+    }
+}
+
+/// Stands in for a webhook receiver, answering each request with the next of
+/// `statuses`, and returning what it received once they run out.
+async fn stand_in(
+    statuses: Vec<&'static str>,
+) -> (String, JoinHandle<Vec<Received>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url =
+        format!("http://{}/hooks/nerdtalk", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        let mut received = vec![];
+        for status in statuses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut bytes = vec![];
+            let mut buffer = [0; 1024];
+            let head_length = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                bytes.extend_from_slice(&buffer[..read]);
+                if let Some(index) =
+                    bytes.windows(4).position(|window| window == b"\r\n\r\n")
+                {
+                    break index + 4;
+                }
+            };
+            let head =
+                String::from_utf8(bytes[..head_length].to_vec()).unwrap();
+            let mut request = Received {
+                head,
+                body: bytes[head_length..].to_vec(),
+            };
+            let content_length: usize =
+                request.header("Content-Length").unwrap().parse().unwrap();
+            while request.body.len() < content_length {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.body.extend_from_slice(&buffer[..read]);
+            }
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            received.push(request);
+        }
+        received
+    });
+    (url, handle)
+}
+
+fn quick_backoff(attempts: u32) -> Backoff {
+    Backoff {
+        attempts,
+        first_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(20),
+    }
+}
+
+#[test]
+fn filters_match_channels_keywords_and_patterns() {
+    let everything = Filter::new(vec![], vec![], None).unwrap();
+    assert!(everything.matches(&entry("general", "hi")));
+    assert!(!everything.matches(&Entry::new_timestamped_now(
+        ChannelName::default(),
+        0,
+        "ethan".to_owned(),
+        Content::Deleted,
+    )));
+
+    let ops = Filter::new(
+        vec![ChannelName("ops".to_owned())],
+        vec!["Deploy Prod".to_owned()],
+        Some(r"\brollback v\d+"),
+    )
+    .unwrap();
+    assert!(ops.matches(&entry("ops", "please DEPLOY PROD now")));
+    assert!(ops.matches(&entry("ops", "rollback v12")));
+    assert!(!ops.matches(&entry("ops", "deploy staging")));
+    assert!(!ops.matches(&entry("general", "deploy prod")));
+
+    assert!(matches!(
+        Filter::new(vec![], vec![], Some("(unclosed")),
+        Err(Error::InvalidPattern(_))
+    ));
+}
+
+#[test]
+fn signatures_verify_only_the_signed_timestamp_body_and_secret() {
+    let now = chrono::Utc::now().timestamp();
+    let timestamp = now.to_string();
+    let signature = webhook::sign("secret", now, b"body");
+    assert!(signature.starts_with("sha256="));
+    assert!(webhook::verify("secret", &timestamp, b"body", &signature));
+    assert!(!webhook::verify(
+        "secret", &timestamp, b"bodies", &signature
+    ));
+    assert!(!webhook::verify("other", &timestamp, b"body", &signature));
+    assert!(!webhook::verify("secret", &timestamp, b"body", "sha256=zz"));
+    assert!(!webhook::verify(
+        "secret",
+        &(now - 1).to_string(),
+        b"body",
+        &signature
+    ));
+    assert!(!webhook::verify("secret", "soon", b"body", &signature));
+}
+
+#[test]
+fn signatures_expire() {
+    let difference = MAX_CLOCK_DIFFERENCE.as_secs() as i64;
+    let now = chrono::Utc::now().timestamp();
+    for (sent, accepted) in [
+        (now - difference + 5, true),
+        (now - difference - 5, false),
+        (now + difference + 5, false),
+    ] {
+        let signature = webhook::sign("secret", sent, b"body");
+        assert_eq!(
+            webhook::verify("secret", &sent.to_string(), b"body", &signature),
+            accepted,
+            "{}",
+            sent - now
+        );
+    }
+}
+
+#[tokio::test]
+async fn delivers_a_signed_payload() {
+    let (url, stand_in) = stand_in(vec!["204 No Content"]).await;
+    let payload = payload();
+    webhook::deliver(&url, "secret", &payload).await.unwrap();
+
+    let received = stand_in.await.unwrap();
+    let request = &received[0];
+    assert!(request
+        .head
+        .starts_with("POST /hooks/nerdtalk HTTP/1.1\r\n"));
+    assert_eq!(request.header("Content-Type"), Some("application/json"));
+    assert!(webhook::verify(
+        "secret",
+        request.header(TIMESTAMP_HEADER).unwrap(),
+        &request.body,
+        request.header(SIGNATURE_HEADER).unwrap()
+    ));
+    let delivered: Payload = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(delivered, payload);
+    assert_eq!(delivered.text, "deploy prod please");
+}
+
+#[tokio::test]
+async fn retries_until_delivered() {
+    let (url, stand_in) = stand_in(vec![
+        "500 Internal Server Error",
+        "503 Service Unavailable",
+        "200 OK",
+    ])
+    .await;
+    let mut failures = vec![];
+    webhook::deliver_with_retries(
+        &url,
+        "secret",
+        &payload(),
+        quick_backoff(5),
+        |error| failures.push(error.to_string()),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(stand_in.await.unwrap().len(), 3);
+    assert_eq!(failures.len(), 2);
+    assert!(failures[0].contains("500"));
+}
+
+#[tokio::test]
+async fn gives_up_after_the_last_attempt() {
+    let (url, stand_in) = stand_in(vec!["500 Internal Server Error"; 3]).await;
+    let mut failures = 0;
+    let result = webhook::deliver_with_retries(
+        &url,
+        "secret",
+        &payload(),
+        quick_backoff(3),
+        |_| failures += 1,
+    )
+    .await;
+
+    assert!(matches!(result, Err(Error::Status(_))));
+    assert_eq!(failures, 2);
+    assert_eq!(stand_in.await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn invalid_urls_are_not_retried() {
+    for url in ["ftp://example.com/", "http://", "http://host:port/"] {
+        let mut failures = 0;
+        let result = webhook::deliver_with_retries(
+            url,
+            "secret",
+            &payload(),
+            quick_backoff(3),
+            |_| failures += 1,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::InvalidUrl(_))), "{}", url);
+        assert_eq!(failures, 0);
+    }
+}
@@ -18,7 +18,7 @@ pub struct DiagnosticContext<'a> {
 }
 
 impl DiagnosticContext<'_> {
-    pub fn new_snippet(&self) -> Snippet {
+    pub fn new_snippet(&self) -> Snippet<'_> {
         Snippet::source(self.source).origin(self.path)
     }
 